@@ -0,0 +1,22 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+extern crate slow_stac;
+use slow_stac::earthdata::mod09ga;
+use slow_stac::earthdata::Provider;
+use slow_stac::image_selection::ImageSelection;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let output_dir = PathBuf::from("./outputs/earthdata");
+
+    let selection = ImageSelection::from_template(&mod09ga::image_selection_toml());
+
+    let plan = mod09ga::generate_download_plan(&selection, output_dir.clone()).await?;
+    let _ = plan.write(output_dir.join("download_plan.json"))?;
+
+    let provider = Provider::from_env().await?;
+    let _ = plan.execute(&provider).await?;
+
+    Ok(())
+}
@@ -0,0 +1,370 @@
+//! Read areas of interest from GIS files (Shapefile, GeoPackage) as GeoJSON geometries
+//!
+//! Geometries must already be in WGS84 longitude/latitude, as required by STAC `intersects`
+//! queries. All polygons in the file are combined into a single MultiPolygon.
+use anyhow::{anyhow, Result};
+use rusqlite::{Connection, OpenFlags};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+
+type Ring = Vec<[f64; 2]>;
+type Polygon = Vec<Ring>;
+
+const SHAPEFILE_CODE: i32 = 9994;
+const SHAPEFILE_HEADER_LEN: usize = 100;
+const WGS84_SRS_ID: i32 = 4326;
+
+/// Read the polygons of a `.shp` or `.gpkg` file as a GeoJSON MultiPolygon.
+pub fn read_aoi<P: AsRef<Path>>(path: P) -> Result<Value> {
+    let path = path.as_ref();
+    let polygons = match path.extension().and_then(|e| e.to_str()) {
+        Some("shp") => read_shapefile(path)?,
+        Some("gpkg") => read_geopackage(path)?,
+        _ => {
+            return Err(anyhow!(
+                "Unsupported AOI file {:?}, expected .shp or .gpkg",
+                path
+            ))
+        }
+    };
+    if polygons.is_empty() {
+        return Err(anyhow!("No polygons found in {:?}", path));
+    }
+    check_lon_lat(&polygons)?;
+    Ok(json!({ "type": "MultiPolygon", "coordinates": polygons }))
+}
+
+fn check_lon_lat(polygons: &[Polygon]) -> Result<()> {
+    let valid = polygons
+        .iter()
+        .flatten()
+        .flatten()
+        .all(|[lon, lat]| (-180.0..=180.0).contains(lon) && (-90.0..=90.0).contains(lat));
+    match valid {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "AOI coordinates are not longitude/latitude; reproject the file to EPSG:4326"
+        )),
+    }
+}
+
+fn read_shapefile(path: &Path) -> Result<Vec<Polygon>> {
+    let data = fs::read(path)?;
+    let mut reader = Reader::new(&data);
+    if data.len() < SHAPEFILE_HEADER_LEN || reader.i32_be()? != SHAPEFILE_CODE {
+        return Err(anyhow!("{:?} is not a shapefile", path));
+    }
+    reader.pos = SHAPEFILE_HEADER_LEN;
+
+    let mut polygons = vec![];
+    while reader.remaining() >= 8 {
+        let _record_number = reader.i32_be()?;
+        // In 16-bit words
+        let words = reader.i32_be()?;
+        let content_len = reader.count(words, 2)? * 2;
+        let end = reader.pos + content_len;
+        let shape_type = reader.i32_le()?;
+        // Polygon, PolygonZ and PolygonM share the same layout up to the points
+        if matches!(shape_type, 5 | 15 | 25) {
+            reader.skip(32)?; // bbox
+            let (num_parts, num_points) = (reader.i32_le()?, reader.i32_le()?);
+            let num_parts = reader.count(num_parts, 4)?;
+            let num_points = reader.count(num_points, 16)?;
+            let mut parts = (0..num_parts)
+                .map(|_| Ok(reader.i32_le()? as usize))
+                .collect::<Result<Vec<_>>>()?;
+            parts.push(num_points);
+            let points = (0..num_points)
+                .map(|_| Ok([reader.f64_le()?, reader.f64_le()?]))
+                .collect::<Result<Vec<_>>>()?;
+            let rings = parts
+                .windows(2)
+                .map(|w| {
+                    let ring = points.get(w[0]..w[1]);
+                    Ok(ring
+                        .ok_or(anyhow!("Invalid polygon parts in {:?}", path))?
+                        .to_vec())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            polygons.extend(group_shapefile_rings(rings));
+        } else if shape_type != 0 {
+            return Err(anyhow!(
+                "Unsupported shape type {} in {:?}",
+                shape_type,
+                path
+            ));
+        }
+        reader.pos = end;
+    }
+    Ok(polygons)
+}
+
+/// Shapefile rings are clockwise for outer boundaries and counter-clockwise for holes. GeoJSON
+/// expects the opposite winding, so rings are reversed as they are grouped into polygons.
+fn group_shapefile_rings(rings: Vec<Ring>) -> Vec<Polygon> {
+    let mut polygons: Vec<Polygon> = vec![];
+    for mut ring in rings {
+        let is_hole = signed_area(&ring) > 0.0;
+        ring.reverse();
+        match (is_hole, polygons.last_mut()) {
+            (true, Some(polygon)) => polygon.push(ring),
+            _ => polygons.push(vec![ring]),
+        }
+    }
+    polygons
+}
+
+fn signed_area(ring: &Ring) -> f64 {
+    ring.windows(2)
+        .map(|w| w[0][0] * w[1][1] - w[1][0] * w[0][1])
+        .sum::<f64>()
+        / 2.0
+}
+
+fn read_geopackage(path: &Path) -> Result<Vec<Polygon>> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let (table, column, srs_id): (String, String, i32) = conn.query_row(
+        "SELECT table_name, column_name, srs_id FROM gpkg_geometry_columns LIMIT 1",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    if srs_id != WGS84_SRS_ID {
+        return Err(anyhow!(
+            "GeoPackage layer {} uses srs_id {}; reproject it to EPSG:4326",
+            table,
+            srs_id
+        ));
+    }
+
+    let sql = format!(
+        "SELECT \"{}\" FROM \"{}\"",
+        column.replace('"', "\"\""),
+        table.replace('"', "\"\"")
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let blobs = stmt
+        .query_map([], |row| row.get::<_, Option<Vec<u8>>>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut polygons = vec![];
+    for blob in blobs.into_iter().flatten() {
+        polygons.extend(parse_gpkg_geometry(&blob)?);
+    }
+    Ok(polygons)
+}
+
+/// Strip the GeoPackage binary header and parse the WKB geometry that follows it.
+fn parse_gpkg_geometry(blob: &[u8]) -> Result<Vec<Polygon>> {
+    if blob.len() < 8 || &blob[0..2] != b"GP" {
+        return Err(anyhow!("Invalid GeoPackage geometry"));
+    }
+    let flags = blob[3];
+    if flags & 0b0001_0000 != 0 {
+        return Ok(vec![]); // empty geometry
+    }
+    let envelope_len = match (flags >> 1) & 0b111 {
+        0 => 0,
+        1 => 32,
+        2 | 3 => 48,
+        4 => 64,
+        other => return Err(anyhow!("Invalid GeoPackage envelope indicator {}", other)),
+    };
+    let mut reader = Reader::new(&blob[8 + envelope_len..]);
+    parse_wkb(&mut reader)
+}
+
+/// Parse a WKB Polygon or MultiPolygon (2D, Z, M or ZM; ISO or EWKB flags), keeping x and y.
+fn parse_wkb(reader: &mut Reader) -> Result<Vec<Polygon>> {
+    let little_endian = reader.u8()? == 1;
+    let raw_type = reader.u32(little_endian)?;
+    let ewkb_dims =
+        ((raw_type & 0x8000_0000) != 0) as usize + ((raw_type & 0x4000_0000) != 0) as usize;
+    let raw_type = raw_type & 0x0FFF_FFFF;
+    let geometry_type = raw_type % 1000;
+    let dims = 2
+        + ewkb_dims
+        + match raw_type / 1000 {
+            1 | 2 => 1,
+            3 => 2,
+            _ => 0,
+        };
+
+    match geometry_type {
+        3 => {
+            let num_rings = reader.u32(little_endian)?;
+            let mut polygon = vec![];
+            for _ in 0..num_rings {
+                let num_points = reader.u32(little_endian)?;
+                let mut ring = vec![];
+                for _ in 0..num_points {
+                    let x = reader.f64(little_endian)?;
+                    let y = reader.f64(little_endian)?;
+                    reader.skip(8 * (dims - 2))?;
+                    ring.push([x, y]);
+                }
+                polygon.push(ring);
+            }
+            Ok(vec![polygon])
+        }
+        6 => {
+            let num_polygons = reader.u32(little_endian)?;
+            let mut polygons = vec![];
+            for _ in 0..num_polygons {
+                polygons.extend(parse_wkb(reader)?);
+            }
+            Ok(polygons)
+        }
+        other => Err(anyhow!(
+            "Unsupported WKB geometry type {}, expected polygons",
+            other
+        )),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn remaining(self: &Self) -> usize {
+        self.data.len().saturating_sub(self.pos)
+    }
+
+    fn take<const N: usize>(self: &mut Self) -> Result<[u8; N]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + N)
+            .ok_or(anyhow!("Unexpected end of geometry data"))?;
+        self.pos += N;
+        Ok(bytes.try_into().expect("Slice length should equal N"))
+    }
+
+    /// A count read from the data, of items of `item_len` bytes that must fit in what's left of
+    /// it, so a corrupt count fails rather than allocating for it
+    fn count(self: &Self, count: i32, item_len: usize) -> Result<usize> {
+        usize::try_from(count)
+            .ok()
+            .filter(|count| count.saturating_mul(item_len) <= self.remaining())
+            .ok_or(anyhow!("Unexpected end of geometry data"))
+    }
+
+    fn skip(self: &mut Self, n: usize) -> Result<()> {
+        if self.remaining() < n {
+            return Err(anyhow!("Unexpected end of geometry data"));
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    fn u8(self: &mut Self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn i32_be(self: &mut Self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.take()?))
+    }
+
+    fn i32_le(self: &mut Self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take()?))
+    }
+
+    fn f64_le(self: &mut Self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take()?))
+    }
+
+    fn u32(self: &mut Self, little_endian: bool) -> Result<u32> {
+        let bytes = self.take()?;
+        Ok(match little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn f64(self: &mut Self, little_endian: bool) -> Result<f64> {
+        let bytes = self.take()?;
+        Ok(match little_endian {
+            true => f64::from_le_bytes(bytes),
+            false => f64::from_be_bytes(bytes),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square_wkb() -> Vec<u8> {
+        let mut wkb = vec![1u8];
+        wkb.extend(3u32.to_le_bytes());
+        wkb.extend(1u32.to_le_bytes());
+        wkb.extend(5u32.to_le_bytes());
+        for [x, y] in [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0], [0.0, 0.0]] {
+            wkb.extend(f64::to_le_bytes(x));
+            wkb.extend(f64::to_le_bytes(y));
+        }
+        wkb
+    }
+
+    #[test]
+    fn test_parse_gpkg_geometry() {
+        // Header: magic, version 0, little endian with no envelope, srs_id 4326
+        let mut blob = vec![b'G', b'P', 0, 0b0000_0001];
+        blob.extend(4326i32.to_le_bytes());
+        blob.extend(square_wkb());
+
+        let polygons = parse_gpkg_geometry(&blob).unwrap();
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0][0].len(), 5);
+        assert_eq!(polygons[0][0][2], [1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_group_shapefile_rings() {
+        let outer = vec![[0.0, 0.0], [0.0, 4.0], [4.0, 4.0], [4.0, 0.0], [0.0, 0.0]];
+        let hole = vec![[1.0, 1.0], [2.0, 1.0], [2.0, 2.0], [1.0, 2.0], [1.0, 1.0]];
+        let polygons = group_shapefile_rings(vec![outer, hole]);
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].len(), 2);
+        // Outer ring is counter-clockwise after reversing
+        assert!(signed_area(&polygons[0][0]) > 0.0);
+    }
+
+    #[test]
+    fn test_read_corrupt_shapefile() {
+        let shapefile = |num_points: i32, part: i32| {
+            let mut data = vec![0; SHAPEFILE_HEADER_LEN];
+            data[..4].copy_from_slice(&SHAPEFILE_CODE.to_be_bytes());
+            data.extend(1i32.to_be_bytes());
+            data.extend(((4 + 32 + 8 + 4 + 16) / 2i32).to_be_bytes());
+            data.extend(5i32.to_le_bytes());
+            data.extend([0; 32]);
+            data.extend(1i32.to_le_bytes());
+            data.extend(num_points.to_le_bytes());
+            data.extend(part.to_le_bytes());
+            data.extend(f64::to_le_bytes(1.0));
+            data.extend(f64::to_le_bytes(1.0));
+            data
+        };
+        let path = Path::new("/tmp/slow_stac_aoi.shp");
+        fs::write(path, shapefile(1, 0)).unwrap();
+        assert_eq!(read_shapefile(path).unwrap(), [vec![vec![[1.0, 1.0]]]]);
+        fs::write(path, shapefile(1, 2)).unwrap();
+        assert!(read_shapefile(path).is_err());
+        fs::write(path, shapefile(i32::MAX, 0)).unwrap();
+        assert!(read_shapefile(path).is_err());
+        fs::write(path, shapefile(-1, 0)).unwrap();
+        assert!(read_shapefile(path).is_err());
+    }
+
+    #[test]
+    fn test_check_lon_lat() {
+        assert!(check_lon_lat(&[vec![vec![[500000.0, 6650000.0]]]]).is_err());
+        assert!(check_lon_lat(&[vec![vec![[-135.0, 60.0]]]]).is_ok());
+    }
+}
@@ -0,0 +1,200 @@
+//! Re-verifying an archive against the checksums recorded for it, to catch bit rot on cheap
+//! external drives before the only copy is lost. Checksums come from an inventory (see
+//! `inventory`) or from the plan the files were downloaded with, which carries the catalogue's.
+//!
+//! When each file was last verified is kept in `AUDITED_FILE` in the archive directory, so a
+//! scheduled audit can re-read only the files that haven't been checked recently.
+use crate::download_plan::DownloadPlan;
+use crate::inventory::InventoryEntry;
+use crate::verify::{self, VerificationFailed};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch each file was last verified, keyed by path
+pub const AUDITED_FILE: &str = ".slow-stac-audited.json";
+
+/// A file and what was recorded about it when it was archived
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recorded {
+    pub path: PathBuf,
+    /// `<algorithm>:<hex digest>`
+    pub checksum: Option<String>,
+    pub size: Option<u64>,
+}
+
+impl Recorded {
+    pub fn from_inventory(dir: &Path, entries: &[InventoryEntry]) -> Vec<Self> {
+        entries
+            .iter()
+            .map(|entry| Recorded {
+                path: dir.join(&entry.path),
+                checksum: entry.checksum.clone(),
+                size: Some(entry.size),
+            })
+            .collect()
+    }
+
+    pub fn from_plan(plan: &DownloadPlan) -> Vec<Self> {
+        plan.tasks()
+            .iter()
+            .map(|task| Recorded {
+                path: plan.output_path(task),
+                checksum: task.checksum().map(|checksum| checksum.to_string()),
+                size: task.size(),
+            })
+            .collect()
+    }
+
+    /// Why the file no longer matches the record, if it doesn't
+    fn check(self: &Self) -> Result<Option<String>> {
+        if let Some(size) = self.size {
+            let actual = fs::metadata(&self.path)?.len();
+            if actual != size {
+                return Ok(Some(format!("{} bytes, {} were recorded", actual, size)));
+            }
+        }
+        if let Some(checksum) = &self.checksum {
+            if !verify::is_supported(checksum) {
+                println!(
+                    "Warning: cannot check {:?} against {}, the algorithm is not supported",
+                    self.path, checksum
+                );
+            } else if let Err(e) = verify::check_checksum(&self.path, checksum) {
+                return Ok(Some(e.to_string()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    pub verified: usize,
+    /// Verified by an earlier audit within `recheck_after`
+    pub recent: usize,
+    /// Neither a checksum nor a size was recorded
+    pub unchecked: usize,
+    pub missing: Vec<PathBuf>,
+    pub corrupt: Vec<(PathBuf, String)>,
+}
+
+impl AuditReport {
+    pub fn to_text(self: &Self) -> String {
+        let mut lines = vec![format!(
+            "{} files verified, {} corrupt, {} missing, {} verified recently, {} unchecked",
+            self.verified,
+            self.corrupt.len(),
+            self.missing.len(),
+            self.recent,
+            self.unchecked
+        )];
+        for (path, reason) in &self.corrupt {
+            lines.push(format!("  corrupt: {:?}: {}", path, reason));
+        }
+        for path in &self.missing {
+            lines.push(format!("  missing: {:?}", path));
+        }
+        lines.join("\n")
+    }
+
+    /// An error listing the corrupt files, if there are any.
+    pub fn into_result(self) -> Result<Self> {
+        if !self.corrupt.is_empty() {
+            return Err(VerificationFailed(format!(
+                "{} files no longer match their checksums",
+                self.corrupt.len()
+            ))
+            .into());
+        }
+        Ok(self)
+    }
+}
+
+/// Verify the recorded files under `dir`, skipping those verified within `recheck_after`.
+pub fn audit(dir: &Path, records: &[Recorded], recheck_after: Duration) -> Result<AuditReport> {
+    let audited_path = dir.join(AUDITED_FILE);
+    let mut audited: BTreeMap<String, u64> = match audited_path.exists() {
+        true => serde_json::from_slice(&fs::read(&audited_path)?)?,
+        false => BTreeMap::new(),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut report = AuditReport::default();
+    for record in records {
+        let key = record.path.to_string_lossy().to_string();
+        if !record.path.exists() {
+            report.missing.push(record.path.clone());
+            continue;
+        }
+        if record.checksum.is_none() && record.size.is_none() {
+            report.unchecked += 1;
+            continue;
+        }
+        let verified_at = audited.get(&key).copied().unwrap_or(0);
+        if now.saturating_sub(verified_at) < recheck_after.as_secs() {
+            report.recent += 1;
+            continue;
+        }
+        match record.check()? {
+            Some(reason) => {
+                audited.remove(&key);
+                report.corrupt.push((record.path.clone(), reason));
+            }
+            None => {
+                audited.insert(key, now);
+                report.verified += 1;
+            }
+        }
+        // Saved as it goes, auditing a large archive can be interrupted
+        fs::write(&audited_path, serde_json::to_vec_pretty(&audited)?)?;
+    }
+    Ok(report)
+}
+
+/// Delete the corrupt files, so downloading the plan again fetches them anew.
+pub fn requeue(report: &AuditReport) -> Result<()> {
+    for (path, _) in &report.corrupt {
+        fs::remove_file(path)?;
+        println!("Removed {:?}, download the plan again to replace it", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit() {
+        let dir = Path::new("/tmp/slow_stac_audit");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("B04.tif"), "b04").unwrap();
+        fs::write(dir.join("B08.tif"), "b08").unwrap();
+        let digest = verify::file_digest(&dir.join("B04.tif"), "sha256").unwrap();
+        let record = |name: &str, checksum: Option<String>| Recorded {
+            path: dir.join(name),
+            checksum,
+            size: Some(3),
+        };
+        let records = [
+            record("B04.tif", Some(format!("sha256:{}", digest))),
+            record("B08.tif", Some(format!("sha256:{}", digest))),
+            record("B02.tif", None),
+        ];
+        let report = audit(dir, &records, Duration::ZERO).unwrap();
+        assert_eq!(report.verified, 1);
+        assert_eq!(report.corrupt[0].0, dir.join("B08.tif"));
+        assert_eq!(report.missing, [dir.join("B02.tif")]);
+
+        // Only the corrupt file is checked again within a day
+        let report = audit(dir, &records, Duration::from_secs(86400)).unwrap();
+        assert_eq!((report.verified, report.recent), (0, 1));
+        assert_eq!(report.corrupt.len(), 1);
+        requeue(&report).unwrap();
+        assert!(!dir.join("B08.tif").exists());
+        assert!(report.into_result().is_err());
+    }
+}
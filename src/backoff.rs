@@ -0,0 +1,274 @@
+//! Backoff state shared by every task talking to the same host, so one task being throttled slows
+//! the others down too instead of them piling more requests onto a struggling link
+use crate::control::Control;
+use crate::s3::{ListedObject, S3ObjOps};
+use crate::units;
+use anyhow::Result;
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Throttled requests are retried this many times before the error is returned, by default
+pub const MAX_THROTTLE_RETRIES: u32 = 5;
+
+/// Markers of throttling in S3 SDK and HTTP errors
+const THROTTLE_ERROR_MARKERS: [&str; 7] = [
+    "SlowDown",
+    "Throttling",
+    "TooManyRequests",
+    "RequestLimitExceeded",
+    "StatusCode(429)",
+    "StatusCode(503)",
+    "429 Too Many Requests",
+];
+
+pub fn is_throttle_error(error: &anyhow::Error) -> bool {
+    let error = format!("{:?}", error);
+    THROTTLE_ERROR_MARKERS
+        .iter()
+        .any(|marker| error.contains(marker))
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    /// Consecutive throttled requests
+    throttled: u32,
+    /// No requests are sent to the host before this
+    paused_until: Option<Instant>,
+}
+
+/// Exponential backoff per host. A host that keeps throttling is paused for up to `MAX_DELAY`,
+/// and the first successful request resets it.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl Backoff {
+    /// How long to wait before sending a request to `host`.
+    pub fn delay(self: &Self, host: &str) -> Duration {
+        let hosts = self.hosts.lock().expect("Backoff lock poisoned");
+        hosts
+            .get(host)
+            .and_then(|state| state.paused_until)
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::ZERO)
+    }
+
+    pub fn record_throttle(self: &Self, host: &str) -> Duration {
+        let mut hosts = self.hosts.lock().expect("Backoff lock poisoned");
+        let state = hosts.entry(host.to_string()).or_default();
+        state.throttled += 1;
+        let delay = BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(state.throttled - 1))
+            .min(MAX_DELAY);
+        state.paused_until = Some(Instant::now() + delay);
+        delay
+    }
+
+    pub fn record_success(self: &Self, host: &str) {
+        let mut hosts = self.hosts.lock().expect("Backoff lock poisoned");
+        hosts.remove(host);
+    }
+}
+
+/// Wraps a provider so every request first waits out its host's backoff, and throttled requests
+/// are retried after backing off. Tasks sharing the wrapper share the backoff state.
+pub struct Throttled<P> {
+    inner: P,
+    backoff: Backoff,
+    max_retries: u32,
+    /// Told of each backoff, so pausing the plan can record when to resume it
+    control: Option<Arc<Control>>,
+}
+
+impl<P: S3ObjOps> Throttled<P> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            backoff: Backoff::default(),
+            max_retries: MAX_THROTTLE_RETRIES,
+            control: None,
+        }
+    }
+
+    pub fn with_max_retries(self, max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ..self
+        }
+    }
+
+    pub fn with_control(self, control: Option<Arc<Control>>) -> Self {
+        Self { control, ..self }
+    }
+
+    async fn send<T, F, Fut>(self: &Self, host: &str, request: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retries = 0;
+        loop {
+            let delay = self.backoff.delay(host);
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            match request().await {
+                Ok(output) => {
+                    self.backoff.record_success(host);
+                    return Ok(output);
+                }
+                Err(e) if is_throttle_error(&e) && retries < self.max_retries => {
+                    let delay = self.backoff.record_throttle(host);
+                    if let Some(control) = &self.control {
+                        control.hold(delay);
+                    }
+                    println!(
+                        "Warning: {} is throttling requests, backing off for {}",
+                        host,
+                        units::duration(delay)
+                    );
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// The bucket stands in for the host: HTTPS providers store the host as the bucket, and S3
+/// throttling applies per bucket.
+impl<P: S3ObjOps> S3ObjOps for Throttled<P> {
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        self.send(bucket, || self.inner.head_object(bucket, key))
+            .await
+    }
+
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        self.send(bucket, || self.inner.get_object(bucket, key))
+            .await
+    }
+
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        self.send(bucket, || {
+            self.inner
+                .get_object_range(bucket, key, start_byte, end_byte)
+        })
+        .await
+    }
+
+    async fn head_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectOutput> {
+        self.send(bucket, || {
+            self.inner.head_object_version(bucket, key, version_id)
+        })
+        .await
+    }
+
+    async fn get_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectOutput> {
+        self.send(bucket, || {
+            self.inner.get_object_version(bucket, key, version_id)
+        })
+        .await
+    }
+
+    async fn get_object_range_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        self.send(bucket, || {
+            self.inner
+                .get_object_range_version(bucket, key, version_id, start_byte, end_byte)
+        })
+        .await
+    }
+
+    fn md5_etags(self: &Self) -> bool {
+        self.inner.md5_etags()
+    }
+
+    async fn refresh_credentials(self: &Self) -> Result<bool> {
+        self.inner.refresh_credentials().await
+    }
+
+    async fn list_objects(self: &Self, bucket: &str, prefix: &str) -> Result<Vec<ListedObject>> {
+        self.send(bucket, || self.inner.list_objects(bucket, prefix))
+            .await
+    }
+
+    async fn presign_get(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String> {
+        self.inner.presign_get(bucket, key, expires_in).await
+    }
+
+    async fn presign_get_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String> {
+        self.inner
+            .presign_get_version(bucket, key, version_id, expires_in)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_backoff_per_host() {
+        let backoff = Backoff::default();
+        assert_eq!(
+            backoff.record_throttle("a.example.com"),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            backoff.record_throttle("a.example.com"),
+            Duration::from_secs(2)
+        );
+        assert!(backoff.delay("a.example.com") > Duration::ZERO);
+        assert_eq!(backoff.delay("b.example.com"), Duration::ZERO);
+
+        backoff.record_success("a.example.com");
+        assert_eq!(backoff.delay("a.example.com"), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_is_throttle_error() {
+        assert!(is_throttle_error(&anyhow!("service error: SlowDown")));
+        assert!(!is_throttle_error(&anyhow!("service error: NoSuchKey")));
+    }
+}
@@ -0,0 +1,165 @@
+//! A download cache shared by several users of one machine or LAN share, so overlapping plans
+//! download each object over the slow uplink once. Objects are downloaded to
+//! `<cache>/<bucket>/<key>` and hard linked into each user's output directory, or copied where a
+//! link isn't possible (another filesystem, or a file owned by another user under
+//! `fs.protected_hardlinks`). Linked files share their content with the cache, so outputs should
+//! be replaced rather than modified in place.
+//!
+//! A `<object>.lock` file, created exclusively, lets one process at a time download an object;
+//! the others wait for it and then link the finished file. A lock of a process on this host is
+//! taken over once that process is gone, and never while it runs. Locks from other hosts are taken
+//! over once neither the lock nor the partial download has changed for `STALE_LOCK`.
+use anyhow::Result;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const LOCK_SUFFIX: &str = ".lock";
+const STALE_LOCK: Duration = Duration::from_secs(30 * 60);
+const LOCK_POLL: Duration = Duration::from_secs(5);
+
+/// Where an object is kept in the cache
+pub fn cached_path(cache: &Path, bucket: &str, key: &str) -> PathBuf {
+    cache.join(bucket).join(key.trim_start_matches('/'))
+}
+
+/// Held while downloading an object into the cache, removing the lock file when dropped
+#[derive(Debug)]
+pub struct CacheLock {
+    path: PathBuf,
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn lock_path(cached: &Path) -> PathBuf {
+    PathBuf::from(format!("{}{}", cached.to_string_lossy(), LOCK_SUFFIX))
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Wait until no other process is downloading `cached`, then lock it.
+pub async fn lock(cached: &Path) -> Result<CacheLock> {
+    if let Some(parent) = cached.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let path = lock_path(cached);
+    let mut waiting = false;
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                writeln!(file, "{}@{}", std::process::id(), hostname())?;
+                return Ok(CacheLock { path });
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                if is_stale(cached, &path) {
+                    println!(
+                        "Warning: taking over the abandoned download of {:?}",
+                        cached
+                    );
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                if !waiting {
+                    println!("Waiting for another download of {:?}", cached);
+                    waiting = true;
+                }
+                tokio::time::sleep(LOCK_POLL).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn is_stale(cached: &Path, lock: &Path) -> bool {
+    let Ok(owner) = fs::read_to_string(lock) else {
+        // Removed since, or not written yet
+        return false;
+    };
+    if let Some((pid, host)) = owner.trim().split_once('@') {
+        // A live process on this host may be paused or waiting for its window
+        if host == hostname() {
+            return !Path::new("/proc").join(pid).exists();
+        }
+    }
+    let partial = PathBuf::from(format!("{}.partial", cached.to_string_lossy()));
+    let modified = [lock, partial.as_path()]
+        .iter()
+        .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max();
+    match modified.and_then(|modified| SystemTime::now().duration_since(modified).ok()) {
+        Some(age) => age > STALE_LOCK,
+        None => false,
+    }
+}
+
+/// Put a cached object at `output`, hard linked or else copied.
+pub fn link(cached: &Path, output: &Path) -> Result<()> {
+    if output.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::hard_link(cached, output).is_ok() {
+        return Ok(());
+    }
+    // Copied under another name so an interrupted copy isn't taken as complete
+    let copying = PathBuf::from(format!("{}.copying", output.to_string_lossy()));
+    fs::copy(cached, &copying)?;
+    fs::rename(&copying, output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[tokio::test]
+    async fn test_lock_and_link() {
+        let dir = Path::new("/tmp/slow_stac_cache");
+        let _ = fs::remove_dir_all(dir);
+        let cached = cached_path(&dir.join("cache"), "bucket", "/tiles/B04.tif");
+        assert_eq!(cached, dir.join("cache/bucket/tiles/B04.tif"));
+
+        let lock = lock(&cached).await.unwrap();
+        assert!(lock_path(&cached).exists());
+        assert!(!is_stale(&cached, &lock_path(&cached)));
+        fs::write(&cached, "b04").unwrap();
+        drop(lock);
+        assert!(!lock_path(&cached).exists());
+
+        // A lock of a live process on this host, however old, and the same lock from another host
+        let age = |owner: String| {
+            fs::write(lock_path(&cached), owner).unwrap();
+            let file = fs::File::options().write(true).open(lock_path(&cached));
+            file.and_then(|file| file.set_modified(SystemTime::now() - 2 * STALE_LOCK))
+                .unwrap();
+        };
+        age(format!("{}@{}\n", std::process::id(), hostname()));
+        assert!(!is_stale(&cached, &lock_path(&cached)));
+        age(format!("{}@elsewhere\n", std::process::id()));
+        assert!(is_stale(&cached, &lock_path(&cached)));
+
+        // A lock of a process on this host that has exited
+        fs::write(lock_path(&cached), format!("{}@{}\n", u32::MAX, hostname())).unwrap();
+        assert!(is_stale(&cached, &lock_path(&cached)));
+
+        let output = dir.join("alice/S2A/B04.tif");
+        link(&cached, &output).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "b04");
+        assert_eq!(
+            fs::metadata(&output).unwrap().ino(),
+            fs::metadata(&cached).unwrap().ino()
+        );
+    }
+}
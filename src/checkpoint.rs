@@ -0,0 +1,294 @@
+//! Copies of a plan's state pushed to remote storage while it downloads, with
+//! `download --push-state`, so a field machine that dies outright can be replaced. On the new
+//! machine `plan pull-state` writes the plan, its skip list and the record of files uploaded and
+//! removed (see `upload`), and downloading the plan continues where the last checkpoint left it.
+//!
+//! Only the bookkeeping travels: files that were downloaded but not uploaded elsewhere are lost
+//! with the machine and download again, partial files included. Presigned URLs are left out of the
+//! pushed plan, and the state of an encrypted plan (see `plan_crypt`) is encrypted with its
+//! passphrase.
+use crate::download_plan::DownloadPlan;
+use crate::skip_list::SkipList;
+use crate::task_list::{self, TaskStatus};
+use crate::{plan_crypt, s3, units, upload, user_agent};
+use age::secrecy::SecretString;
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Least time between checkpoints pushed as tasks complete; the last one is pushed when the run
+/// ends whenever it is
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest a push may take, so one over a failing link gives up rather than outliving the run
+pub const PUSH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Where the state is pushed, `s3://bucket/key` or an http(s) URL accepting PUT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateRemote {
+    S3 {
+        bucket: String,
+        key: String,
+        /// AWS profile with credentials for the bucket
+        profile: String,
+    },
+    Http(String),
+}
+
+impl StateRemote {
+    pub fn new(destination: &str, profile: &str) -> Result<Self> {
+        if destination.starts_with("https://") || destination.starts_with("http://") {
+            return Ok(StateRemote::Http(destination.to_string()));
+        }
+        let path = destination
+            .strip_prefix("s3://")
+            .ok_or(anyhow!("Expected an s3://bucket/key or http(s) URL"))?;
+        match path.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(StateRemote::S3 {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                profile: profile.to_string(),
+            }),
+            _ => Err(anyhow!("No bucket or key in {}", destination)),
+        }
+    }
+
+    async fn put(self: &Self, body: Vec<u8>) -> Result<()> {
+        let content_type = match plan_crypt::is_ciphertext(&body) {
+            true => "application/octet-stream",
+            false => "application/json",
+        };
+        match self {
+            StateRemote::S3 {
+                bucket,
+                key,
+                profile,
+            } => {
+                s3::client_from_profile(profile)
+                    .await
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .content_type(content_type)
+                    .body(ByteStream::from(body))
+                    .send()
+                    .await?;
+            }
+            StateRemote::Http(url) => {
+                user_agent::client()
+                    .put(url)
+                    .header("Content-Type", content_type)
+                    .body(body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get(self: &Self) -> Result<Vec<u8>> {
+        match self {
+            StateRemote::S3 {
+                bucket,
+                key,
+                profile,
+            } => {
+                let output = s3::client_from_profile(profile)
+                    .await
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await?;
+                Ok(output.body.collect().await?.to_vec())
+            }
+            StateRemote::Http(url) => {
+                let response = user_agent::get(url).await?.error_for_status()?;
+                Ok(response.bytes().await?.to_vec())
+            }
+        }
+    }
+}
+
+/// Pushing a plan's state as it downloads
+#[derive(Debug, Clone)]
+pub struct PushState {
+    pub remote: StateRemote,
+    /// File name of the plan, which `pull` writes it as
+    pub plan_file: String,
+    /// Passphrase of an encrypted plan, which its state is encrypted with
+    pub passphrase: Option<SecretString>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanState {
+    /// Unix time the state was saved
+    pub saved_at: u64,
+    pub plan_file: String,
+    pub plan: DownloadPlan,
+    /// Outputs of the tasks uploaded and removed, as given in the plan
+    #[serde(default)]
+    pub uploaded: Vec<String>,
+    /// Outputs of the tasks done only on the machine that pushed the state
+    #[serde(default)]
+    pub done: Vec<String>,
+    #[serde(default)]
+    pub skipped_items: Vec<String>,
+}
+
+impl PlanState {
+    /// The state of `plan` as its outputs are on disk now, without its presigned URLs
+    pub fn new(plan: &DownloadPlan, plan_file: &str, skip_list: Option<&SkipList>) -> Result<Self> {
+        let mut uploaded = vec![];
+        let mut done = vec![];
+        for task in plan.tasks() {
+            if upload::was_uploaded(&plan.output_path(task)) {
+                uploaded.push(task.output().to_string());
+            } else if task_list::status(plan, task) == TaskStatus::Done {
+                done.push(task.output().to_string());
+            }
+        }
+        let mut skipped_items: Vec<_> = match skip_list {
+            Some(skip_list) => skip_list.items()?.into_iter().collect(),
+            None => vec![],
+        };
+        skipped_items.sort();
+        Ok(Self {
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            plan_file: plan_file.to_string(),
+            plan: plan.clone().without_urls(),
+            uploaded,
+            done,
+            skipped_items,
+        })
+    }
+
+    /// Write the plan into `dir` as its new root, with its skip list and uploaded files, returning
+    /// the path of the plan
+    pub fn restore(self: Self, dir: &Path) -> Result<PathBuf> {
+        let plan = self.plan.with_root(dir);
+        for output in &self.uploaded {
+            let Some(task) = plan.tasks().iter().find(|task| task.output() == output) else {
+                continue;
+            };
+            let path = plan.output_path(task);
+            let (Some(item_dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            if !upload::was_uploaded(&path) {
+                fs::create_dir_all(item_dir)?;
+                upload::record_uploaded(item_dir, &file_name.to_string_lossy())?;
+            }
+        }
+        // Only a file name, so a crafted state can't write outside `dir`
+        let file_name = Path::new(&self.plan_file)
+            .file_name()
+            .filter(|file_name| Path::new(file_name) == Path::new(&self.plan_file))
+            .ok_or(anyhow!("Invalid plan file name {:?}", self.plan_file))?;
+        let path = dir.join(file_name);
+        plan.write(&path)?;
+        SkipList::of_plan(&path).add(&self.skipped_items)?;
+        Ok(path)
+    }
+}
+
+/// Push `state`, giving up after `PUSH_TIMEOUT`
+pub async fn push_state(state: &PlanState, push: &PushState) -> Result<()> {
+    let body = serde_json::to_vec(state)?;
+    let body = match &push.passphrase {
+        Some(passphrase) => plan_crypt::encrypt(&body, passphrase.clone())?,
+        None => body,
+    };
+    tokio::time::timeout(PUSH_TIMEOUT, push.remote.put(body))
+        .await
+        .map_err(|_| anyhow!("Timed out after {}", units::duration(PUSH_TIMEOUT)))?
+}
+
+/// The state last pushed to `remote`, asking for the plan passphrase if it is encrypted
+pub async fn pull(remote: &StateRemote) -> Result<PlanState> {
+    let body = remote.get().await?;
+    let body = match plan_crypt::is_ciphertext(&body) {
+        true => plan_crypt::decrypt(&body, plan_crypt::passphrase()?)?,
+        false => body,
+    };
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_plan::DownloadTask;
+    use serde_json::json;
+
+    #[test]
+    fn test_restore() {
+        let root = Path::new("/tmp/slow_stac_checkpoint");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("old/S2A")).unwrap();
+        fs::write(root.join("old/S2A/B04.tif"), "b04").unwrap();
+        fs::write(
+            root.join(format!("old/S2A/{}", upload::UPLOADED_MARKER)),
+            "B08.tif\n",
+        )
+        .unwrap();
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("bucket", "B04", "S2A/B04.tif"),
+                DownloadTask::new("bucket", "B08", "S2A/B08.tif"),
+                serde_json::from_value(json!({
+                    "bucket": "bucket",
+                    "key": "B11",
+                    "output": "S2A/B11.tif",
+                    "url": "https://bucket.s3.amazonaws.com/B11?X-Amz-Signature=abc",
+                    "url_expires": 1700000000u64,
+                }))
+                .unwrap(),
+            ],
+        )
+        .with_root(root.join("old"));
+        let skip_list = SkipList::of_plan(&root.join("old/plan.json"));
+        skip_list.add(&["S2B".to_string()]).unwrap();
+        let state = PlanState::new(&plan, "plan.json", Some(&skip_list)).unwrap();
+        assert_eq!(state.uploaded, ["S2A/B08.tif"]);
+        assert_eq!(state.done, ["S2A/B04.tif"]);
+        let pushed = serde_json::to_string(&state).unwrap();
+        assert!(!pushed.contains("X-Amz-Signature") && !pushed.contains("url_expires"));
+
+        let state: PlanState =
+            serde_json::from_slice(&serde_json::to_vec(&state).unwrap()).unwrap();
+        let path = state.restore(&root.join("new")).unwrap();
+        assert_eq!(path, root.join("new/plan.json"));
+        let plan = DownloadPlan::read(&path).unwrap();
+        assert_eq!(plan.root(), Some("/tmp/slow_stac_checkpoint/new"));
+        assert!(upload::was_uploaded(&root.join("new/S2A/B08.tif")));
+        assert!(!upload::was_uploaded(&root.join("new/S2A/B11.tif")));
+        assert!(SkipList::of_plan(&path).contains("S2B"));
+
+        for plan_file in ["../plan.json", "/tmp/slow_stac_checkpoint/plan.json", ".."] {
+            let mut state = PlanState::new(&plan, "plan.json", None).unwrap();
+            state.plan_file = plan_file.to_string();
+            assert!(state.restore(&root.join("new")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_state_remote() {
+        assert_eq!(
+            StateRemote::new("s3://bucket/field/plan.state.json", "default").unwrap(),
+            StateRemote::S3 {
+                bucket: "bucket".to_string(),
+                key: "field/plan.state.json".to_string(),
+                profile: "default".to_string()
+            }
+        );
+        assert!(StateRemote::new("s3://bucket", "default").is_err());
+        assert!(StateRemote::new("https://example.com/state", "default").is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+//! Conversion of downloaded JPEG 2000 bands (e.g. Copernicus Sentinel 2) to Cloud Optimized
+//! GeoTIFFs, which most tools read far faster, by running GDAL's `gdal_translate`.
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_COMPRESSION: &str = "DEFLATE";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CogOptions {
+    /// GDAL COG driver compression, e.g. DEFLATE, ZSTD, LZW or JPEG
+    pub compression: String,
+    /// `gdal_translate` executable, found on the PATH by default
+    pub gdal_translate: PathBuf,
+}
+
+impl Default for CogOptions {
+    fn default() -> Self {
+        Self {
+            compression: DEFAULT_COMPRESSION.to_string(),
+            gdal_translate: PathBuf::from("gdal_translate"),
+        }
+    }
+}
+
+/// Whether a downloaded file is converted
+pub fn is_convertible(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.eq_ignore_ascii_case("jp2"))
+        .unwrap_or(false)
+}
+
+/// The COG written alongside a band, e.g. `B04_10m.tif` for `B04_10m.jp2`
+pub fn cog_path(path: &Path) -> PathBuf {
+    path.with_extension("tif")
+}
+
+impl CogOptions {
+    /// Fail early, before downloading anything, when GDAL isn't installed.
+    pub async fn check(self: &Self) -> Result<()> {
+        let output = tokio::process::Command::new(&self.gdal_translate)
+            .arg("--version")
+            .output()
+            .await
+            .with_context(|| {
+                anyhow!(
+                    "Could not run {:?}, is GDAL installed?",
+                    self.gdal_translate
+                )
+            })?;
+        if !output.status.success() {
+            return Err(anyhow!("{:?} --version failed", self.gdal_translate));
+        }
+        Ok(())
+    }
+
+    /// Convert a band unless it isn't a JPEG 2000 file or an earlier run already converted it.
+    /// Returns the COG written. The COG is written under a temporary name and renamed when
+    /// complete, so an interrupted conversion is redone rather than taken as finished.
+    pub async fn convert(self: &Self, path: &Path) -> Result<Option<PathBuf>> {
+        let cog = cog_path(path);
+        if !is_convertible(path) || cog.exists() {
+            return Ok(None);
+        }
+        let converting = path.with_extension("converting.tif");
+        let output = tokio::process::Command::new(&self.gdal_translate)
+            .args(["-q", "-of", "COG"])
+            .args(["-co", &format!("COMPRESS={}", self.compression)])
+            .args(["-co", "BIGTIFF=IF_SAFER"])
+            .arg(path)
+            .arg(&converting)
+            .output()
+            .await?;
+        if !output.status.success() {
+            let _ = std::fs::remove_file(&converting);
+            return Err(anyhow!(
+                "gdal_translate failed to convert {:?}: {}",
+                path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        std::fs::rename(&converting, &cog)?;
+        Ok(Some(cog))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn test_convert() {
+        let dir = Path::new("/tmp/slow_stac_cog");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        // Stands in for gdal_translate, copying its input to its output
+        let fake = dir.join("gdal_translate");
+        let script = "#!/bin/sh\n[ \"$1\" = --version ] && exit 0\ncp \"$8\" \"$9\"\n";
+        std::fs::write(&fake, script).unwrap();
+        std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let options = CogOptions {
+            gdal_translate: fake,
+            ..Default::default()
+        };
+        options.check().await.unwrap();
+
+        let band = dir.join("B04_10m.jp2");
+        std::fs::write(&band, "jp2").unwrap();
+        let cog = options.convert(&band).await.unwrap();
+        assert_eq!(cog, Some(dir.join("B04_10m.tif")));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("B04_10m.tif")).unwrap(),
+            "jp2"
+        );
+        // Converted already, or not a JPEG 2000 band
+        assert_eq!(options.convert(&band).await.unwrap(), None);
+        assert_eq!(options.convert(&dir.join("MTD.xml")).await.unwrap(), None);
+
+        let missing = CogOptions {
+            gdal_translate: dir.join("missing"),
+            ..Default::default()
+        };
+        assert!(missing.check().await.is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//! User settings kept between runs, e.g. the download settings recommended by `speedtest`
+use crate::environment::Environment;
+use crate::notify::EmailConfig;
+use crate::probe::Recommendation;
+use crate::profile::Profile;
+use crate::provider_config::ProviderConfig;
+use crate::stack::StackOptions;
+use crate::units::RateUnit;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Overrides the location of the config file
+pub const CONFIG_ENV: &str = "SLOW_STAC_CONFIG";
+/// `download` asks before fetching more than this, unless `confirm_above_bytes` is set
+pub const DEFAULT_CONFIRM_ABOVE_BYTES: u64 = 50 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    /// Profile used by `download` when none is given, e.g. `profile = "flaky"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<Profile>,
+    /// Download cache shared with other users, see `cache`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_dir: Option<PathBuf>,
+    /// Instances on the local network `download` asks for objects first, see `peer`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<String>,
+    /// Token shared by the instances of `peers` and `serve`, unless `SLOW_STAC_PEER_TOKEN` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_token: Option<String>,
+    /// Where to email the outcome of each download, if anywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<EmailConfig>,
+    /// How `download --stack` stacks each item's bands
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stack: Option<StackOptions>,
+    /// Whether rates are shown in bytes or bits per second, e.g. `rate_unit = "bits"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_unit: Option<RateUnit>,
+    /// Ask before downloading a plan with more than this many bytes left, 0 to never ask
+    /// [default: 50 GiB]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm_above_bytes: Option<u64>,
+    /// Settings from the last `speedtest`, per collection
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recommendations: Vec<CollectionRecommendation>,
+    /// Endpoint, region, addressing and signing of providers' S3 clients by provider name,
+    /// overriding the built-in ones, see `provider_config`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub providers: BTreeMap<String, ProviderConfig>,
+    /// Settings selected together with `--env`, by name, see `environment`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environments: BTreeMap<String, Environment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionRecommendation {
+    pub selection_id: String,
+    #[serde(flatten)]
+    pub recommendation: Recommendation,
+}
+
+impl Config {
+    /// `$SLOW_STAC_CONFIG`, otherwise `slow-stac/config.toml` in `$XDG_CONFIG_HOME` or `~/.config`
+    pub fn path() -> Result<PathBuf> {
+        if let Ok(path) = env::var(CONFIG_ENV) {
+            return Ok(PathBuf::from(path));
+        }
+        let config_dir = match env::var("XDG_CONFIG_HOME") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => env::var("HOME")
+                .map(|home| PathBuf::from(home).join(".config"))
+                .map_err(|_| anyhow!("Set HOME or {} to locate the config file", CONFIG_ENV))?,
+        };
+        Ok(config_dir.join("slow-stac").join("config.toml"))
+    }
+
+    /// Read the config file, which is empty until something is saved to it
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn write<P: AsRef<Path>>(self: &Self, path: P) -> Result<()> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The built-in settings of the provider called `name` with any set here replacing them
+    pub fn provider(self: &Self, name: &str) -> ProviderConfig {
+        let defaults = ProviderConfig::defaults(name);
+        match self.providers.get(name) {
+            Some(overrides) => defaults.overridden_by(overrides),
+            None => defaults,
+        }
+    }
+
+    pub fn environment(self: &Self, name: &str) -> Result<Environment> {
+        self.environments.get(name).cloned().ok_or(anyhow!(
+            "No environment {} in the config file, which has: {}",
+            name,
+            self.environments
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+
+    pub fn recommendation(self: &Self, selection_id: &str) -> Option<&Recommendation> {
+        self.recommendations
+            .iter()
+            .find(|r| r.selection_id == selection_id)
+            .map(|r| &r.recommendation)
+    }
+
+    /// Replace the recommendation for a selection
+    pub fn with_recommendation(
+        mut self,
+        selection_id: &str,
+        recommendation: Recommendation,
+    ) -> Self {
+        self.recommendations
+            .retain(|r| r.selection_id != selection_id);
+        self.recommendations.push(CollectionRecommendation {
+            selection_id: selection_id.to_string(),
+            recommendation,
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_CONFIG_PATH: &str = "/tmp/slow_stac_test_config/config.toml";
+
+    fn mock_recommendation(jobs: usize) -> Recommendation {
+        Recommendation {
+            source: "element84".to_string(),
+            jobs,
+            chunk_size: 1024 * 1024,
+            latency_ms: 120,
+            bytes_per_sec: 500_000,
+        }
+    }
+
+    #[test]
+    fn test_write_read_config() {
+        let _ = fs::remove_file(TEST_CONFIG_PATH);
+        assert_eq!(Config::read(TEST_CONFIG_PATH).unwrap(), Config::default());
+
+        let id = "element84.sentinel2collection1level2a";
+        let config = Config::default()
+            .with_recommendation(id, mock_recommendation(1))
+            .with_recommendation(id, mock_recommendation(3));
+        config.write(TEST_CONFIG_PATH).unwrap();
+
+        let config = Config::read(TEST_CONFIG_PATH).unwrap();
+        assert_eq!(config.recommendations.len(), 1);
+        assert_eq!(config.recommendation(id).unwrap().jobs, 3);
+    }
+}
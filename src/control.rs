@@ -0,0 +1,373 @@
+//! Steering a plan while it executes: pausing and resuming it, skipping tasks, lowering or
+//! raising how many run at once and capping the rate of all of them together, along with the
+//! progress of each running task for a live view (see `tui` and `control_socket`). Tasks are
+//! identified by `<bucket>/<key>` of the object they download.
+//!
+//! Pausing takes effect between requests, so a transfer requested whole finishes first; request
+//! in chunks to pause promptly. A skipped task keeps its partial file and is resumed by the next
+//! run of the plan, and so do tasks stopped to pause the plan between runs (see `pause`).
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tokio::sync::{watch, Notify};
+
+/// Outcome of a task that was skipped through its `Control`, or whose item is in the plan's
+/// skip list (see `skip_list`)
+#[derive(Debug, Error)]
+#[error("Skipped")]
+pub struct Skipped;
+
+/// Outcome of a task stopped through its `Control` so the plan can be resumed later
+#[derive(Debug, Error)]
+#[error("Stopped to pause the plan")]
+pub struct Stopped;
+
+/// Id of the task downloading `key` of `bucket`
+pub fn task_id(bucket: &str, key: &str) -> String {
+    format!("{}/{}", bucket, key)
+}
+
+/// A running task
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskProgress {
+    pub id: String,
+    pub output: PathBuf,
+    /// Bytes of the file downloaded, including those of a resumed partial file
+    pub received: u64,
+    pub size: Option<u64>,
+    /// Times the download was requested, more than once when it was retried
+    pub attempts: u32,
+}
+
+#[derive(Debug)]
+struct Slots {
+    limit: usize,
+    active: usize,
+}
+
+/// Bytes received since the rate was last set or caught up with
+#[derive(Debug)]
+struct RateLimit {
+    bytes_per_sec: Option<u64>,
+    since: Instant,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct Control {
+    /// Most tasks that can run at once, `set_jobs` lowers and raises the limit within it
+    max_jobs: usize,
+    slots: Mutex<Slots>,
+    slot_freed: Notify,
+    paused: watch::Sender<bool>,
+    stopped: AtomicBool,
+    /// Time until which a host asked for no more requests, see `backoff`
+    held_until: Mutex<Option<SystemTime>>,
+    skipped: Mutex<HashSet<String>>,
+    running: Mutex<Vec<TaskProgress>>,
+    rate: Mutex<RateLimit>,
+    /// Bytes received by every task since the plan started, for throughput
+    received: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// A task's place among those running at once, given up when dropped
+pub struct Slot {
+    control: Arc<Control>,
+}
+
+impl Drop for Slot {
+    fn drop(self: &mut Self) {
+        self.control
+            .slots
+            .lock()
+            .expect("Slots lock poisoned")
+            .active -= 1;
+        self.control.slot_freed.notify_waiters();
+    }
+}
+
+impl Control {
+    pub fn new(jobs: usize) -> Arc<Self> {
+        let jobs = jobs.max(1);
+        Arc::new(Self {
+            max_jobs: jobs,
+            slots: Mutex::new(Slots {
+                limit: jobs,
+                active: 0,
+            }),
+            slot_freed: Notify::new(),
+            paused: watch::channel(false).0,
+            stopped: AtomicBool::new(false),
+            held_until: Mutex::new(None),
+            skipped: Mutex::new(HashSet::new()),
+            running: Mutex::new(vec![]),
+            rate: Mutex::new(RateLimit {
+                bytes_per_sec: None,
+                since: Instant::now(),
+                bytes: 0,
+            }),
+            received: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    pub fn pause(self: &Self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(self: &Self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(self: &Self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Stop the plan: tasks not started yet don't start and running ones stop at their next
+    /// request, keeping their partial files
+    pub fn stop(self: &Self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wakes paused tasks so they stop too
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_stopped(self: &Self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Record that a host is backing off for `delay`
+    pub fn hold(self: &Self, delay: Duration) {
+        let until = SystemTime::now() + delay;
+        let mut held_until = self.held_until.lock().expect("Held lock poisoned");
+        if held_until.map_or(true, |held_until| held_until < until) {
+            *held_until = Some(until);
+        }
+    }
+
+    /// Time until which requests are held back, if it's still ahead
+    pub fn held_until(self: &Self) -> Option<SystemTime> {
+        let held_until = *self.held_until.lock().expect("Held lock poisoned");
+        held_until.filter(|until| *until > SystemTime::now())
+    }
+
+    /// Wait until the plan isn't paused, returning whether it was
+    pub async fn wait_while_paused(self: &Self) -> bool {
+        let mut paused = self.paused.subscribe();
+        if !*paused.borrow_and_update() {
+            return false;
+        }
+        // The sender lives as long as self, so this only ends by resuming
+        let _ = paused.wait_for(|paused| !paused).await;
+        true
+    }
+
+    /// Skip a task, before it starts or at its next request if it's running
+    pub fn skip(self: &Self, id: &str) {
+        self.skipped
+            .lock()
+            .expect("Skipped lock poisoned")
+            .insert(id.to_string());
+    }
+
+    pub fn is_skipped(self: &Self, id: &str) -> bool {
+        self.skipped
+            .lock()
+            .expect("Skipped lock poisoned")
+            .contains(id)
+    }
+
+    /// Run at most `jobs` tasks at once, up to the plan's `--jobs`. Running tasks finish first.
+    pub fn set_jobs(self: &Self, jobs: usize) -> usize {
+        let jobs = jobs.clamp(1, self.max_jobs);
+        self.slots.lock().expect("Slots lock poisoned").limit = jobs;
+        self.slot_freed.notify_waiters();
+        jobs
+    }
+
+    pub fn jobs(self: &Self) -> usize {
+        self.slots.lock().expect("Slots lock poisoned").limit
+    }
+
+    /// Hold every task together under `bytes_per_sec`, or lift the limit with None. This is on top
+    /// of the `--nice` pacing of each transfer.
+    pub fn set_max_rate(self: &Self, bytes_per_sec: Option<u64>) {
+        *self.rate.lock().expect("Rate lock poisoned") = RateLimit {
+            bytes_per_sec,
+            since: Instant::now(),
+            bytes: 0,
+        };
+    }
+
+    pub fn max_rate(self: &Self) -> Option<u64> {
+        self.rate.lock().expect("Rate lock poisoned").bytes_per_sec
+    }
+
+    /// How long a task that just received `bytes` should pause to keep under the max rate
+    pub fn throttle(self: &Self, bytes: u64) -> Duration {
+        let mut rate = self.rate.lock().expect("Rate lock poisoned");
+        let Some(bytes_per_sec) = rate.bytes_per_sec else {
+            return Duration::ZERO;
+        };
+        rate.bytes += bytes;
+        let due = Duration::from_secs_f64(rate.bytes as f64 / bytes_per_sec.max(1) as f64);
+        let elapsed = rate.since.elapsed();
+        // Start over after falling behind, e.g. while paused, rather than bursting to catch up
+        if elapsed > due + Duration::from_secs(1) {
+            rate.since = Instant::now();
+            rate.bytes = 0;
+        }
+        due.saturating_sub(elapsed)
+    }
+
+    /// Wait for a slot to run a task in
+    pub async fn slot(self: &Arc<Self>) -> Slot {
+        loop {
+            let freed = self.slot_freed.notified();
+            {
+                let mut slots = self.slots.lock().expect("Slots lock poisoned");
+                if slots.active < slots.limit {
+                    slots.active += 1;
+                    return Slot {
+                        control: self.clone(),
+                    };
+                }
+            }
+            freed.await;
+        }
+    }
+
+    /// Record that a task has started
+    pub fn start(self: &Self, id: &str, output: &Path, size: Option<u64>) {
+        let mut running = self.running.lock().expect("Running lock poisoned");
+        if !running.iter().any(|task| task.id == id) {
+            running.push(TaskProgress {
+                id: id.to_string(),
+                output: output.to_path_buf(),
+                received: 0,
+                size,
+                attempts: 0,
+            });
+        }
+    }
+
+    /// Record that a running task has requested its download, again if it's being retried
+    pub fn attempt(self: &Self, id: &str) {
+        let mut running = self.running.lock().expect("Running lock poisoned");
+        if let Some(task) = running.iter_mut().find(|task| task.id == id) {
+            task.attempts += 1;
+        }
+    }
+
+    /// Record `bytes` more received by a task, which now has `received` of `size` bytes
+    pub fn progress(self: &Self, id: &str, bytes: u64, received: u64, size: Option<u64>) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+        let mut running = self.running.lock().expect("Running lock poisoned");
+        if let Some(task) = running.iter_mut().find(|task| task.id == id) {
+            task.received = received;
+            task.size = size.or(task.size);
+        }
+    }
+
+    /// Record that a task has ended, skipped and stopped tasks counting as neither completed nor
+    /// failed
+    pub fn finish(self: &Self, id: &str, outcome: &anyhow::Result<()>) {
+        self.running
+            .lock()
+            .expect("Running lock poisoned")
+            .retain(|task| task.id != id);
+        match outcome {
+            Ok(()) => self.completed.fetch_add(1, Ordering::Relaxed),
+            Err(e) if e.is::<Skipped>() || e.is::<Stopped>() => 0,
+            Err(_) => self.failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// The running tasks, in the order they started
+    pub fn running(self: &Self) -> Vec<TaskProgress> {
+        self.running.lock().expect("Running lock poisoned").clone()
+    }
+
+    pub fn received(self: &Self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn completed(self: &Self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(self: &Self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_control() {
+        let control = Control::new(2);
+        assert_eq!(control.set_jobs(5), 2);
+        assert_eq!(control.set_jobs(1), 1);
+        let first = control.slot().await;
+        // The second slot waits for the first
+        let second = tokio::spawn({
+            let control = control.clone();
+            async move {
+                let _slot = control.slot().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), second)
+            .await
+            .unwrap()
+            .unwrap();
+
+        control.pause();
+        let waiting = tokio::spawn({
+            let control = control.clone();
+            async move { control.wait_while_paused().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        control.resume();
+        assert!(waiting.await.unwrap());
+        assert!(!control.wait_while_paused().await);
+
+        let id = task_id("bucket", "S2A/B04.tif");
+        control.start(&id, Path::new("S2A/B04.tif"), None);
+        control.attempt(&id);
+        control.attempt(&id);
+        control.progress(&id, 10, 60, Some(100));
+        assert_eq!(control.running()[0].attempts, 2);
+        assert_eq!(control.running()[0].received, 60);
+        control.skip(&id);
+        assert!(control.is_skipped(&id));
+        control.finish(&id, &Err(Skipped.into()));
+        assert!(control.running().is_empty());
+        assert_eq!((control.completed(), control.failed()), (0, 0));
+        assert_eq!(control.received(), 10);
+
+        control.set_max_rate(Some(1000));
+        assert!(control.throttle(500) > Duration::from_millis(400));
+        assert!(control.throttle(500) > Duration::from_millis(900));
+        control.set_max_rate(None);
+        assert_eq!(control.throttle(500), Duration::ZERO);
+
+        control.hold(Duration::from_secs(60));
+        control.hold(Duration::from_secs(1));
+        assert!(control.held_until().unwrap() > SystemTime::now() + Duration::from_secs(50));
+        control.pause();
+        control.stop();
+        assert!(control.is_stopped() && !control.is_paused());
+    }
+}
@@ -0,0 +1,296 @@
+//! A Unix socket next to a plan, `<plan>.sock`, open while `download --control` executes it, to
+//! steer a download lasting days from another terminal without restarting it, e.g.
+//! `slow-stac control plan.json rate 2000000`.
+//!
+//! Each connection sends one command on a line and reads the reply until the socket closes:
+//! `pause`, `resume`, `rate <bytes per second>` or `rate off`, `jobs <n>`, `skip <bucket>/<key>`,
+//! `status` (`status porcelain` for `--porcelain` records) and `stop`, which ends the run to pause
+//! the plan until `slow-stac resume`.
+use crate::control::Control;
+use crate::{porcelain, units};
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// The socket of the plan at `plan`
+pub fn socket_path(plan: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.sock", plan.to_string_lossy()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Pause,
+    Resume,
+    /// Max bytes per second of every task together, None to lift the limit
+    Rate(Option<u64>),
+    Jobs(usize),
+    /// Skip the task with this id (see `control::task_id`)
+    Skip(String),
+    Status,
+    /// The status as `--porcelain` records
+    PorcelainStatus,
+    /// Stop the download, see `pause`
+    Stop,
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let (name, argument) = match line.trim().split_once(' ') {
+            Some((name, argument)) => (name, Some(argument.trim())),
+            None => (line.trim(), None),
+        };
+        match (name, argument) {
+            ("pause", None) => Ok(Command::Pause),
+            ("resume", None) => Ok(Command::Resume),
+            ("rate", Some("off")) => Ok(Command::Rate(None)),
+            ("rate", Some(rate)) => Ok(Command::Rate(Some(rate.parse()?))),
+            ("jobs", Some(jobs)) => Ok(Command::Jobs(jobs.parse()?)),
+            ("skip", Some(id)) => Ok(Command::Skip(id.to_string())),
+            ("status", None) => Ok(Command::Status),
+            ("status", Some("porcelain")) => Ok(Command::PorcelainStatus),
+            ("stop", None) => Ok(Command::Stop),
+            _ => Err(anyhow!(
+                "Unknown command {:?}, expected pause, resume, rate <bytes per second|off>, \
+                 jobs <n>, skip <bucket>/<key>, status or stop",
+                line.trim()
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Pause => write!(f, "pause"),
+            Command::Resume => write!(f, "resume"),
+            Command::Rate(None) => write!(f, "rate off"),
+            Command::Rate(Some(rate)) => write!(f, "rate {}", rate),
+            Command::Jobs(jobs) => write!(f, "jobs {}", jobs),
+            Command::Skip(id) => write!(f, "skip {}", id),
+            Command::Status => write!(f, "status"),
+            Command::PorcelainStatus => write!(f, "status porcelain"),
+            Command::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+/// Run `command` on `control`, returning the reply
+pub fn apply(control: &Control, command: &Command) -> String {
+    match command {
+        Command::Pause => {
+            control.pause();
+            "Paused, running transfers stop at their next request".to_string()
+        }
+        Command::Resume => {
+            control.resume();
+            "Resumed".to_string()
+        }
+        Command::Rate(rate) => {
+            control.set_max_rate(*rate);
+            match rate {
+                Some(rate) => format!("Limited to {}", units::rate(*rate as f64)),
+                None => "Rate limit lifted".to_string(),
+            }
+        }
+        Command::Jobs(jobs) => format!("Running up to {} tasks at once", control.set_jobs(*jobs)),
+        Command::Skip(id) => {
+            control.skip(id);
+            format!("Skipping {}", id)
+        }
+        Command::Status => status(control),
+        Command::PorcelainStatus => porcelain_status(control),
+        Command::Stop => {
+            control.stop();
+            "Stopping, running transfers save their progress at their next request".to_string()
+        }
+    }
+}
+
+fn status(control: &Control) -> String {
+    let running = control.running();
+    let mut lines = vec![format!(
+        "{}, {} of {} jobs running, {} done, {} failed, {} received, rate limit {}",
+        match control.is_paused() {
+            true => "Paused",
+            false => "Downloading",
+        },
+        running.len(),
+        control.jobs(),
+        control.completed(),
+        control.failed(),
+        units::size(control.received()),
+        control
+            .max_rate()
+            .map_or("off".to_string(), |rate| units::rate(rate as f64))
+    )];
+    for task in running {
+        let mut line = format!(
+            "  {} {} of {}",
+            task.id,
+            units::size(task.received),
+            task.size.map_or("?".to_string(), units::size)
+        );
+        if task.attempts > 1 {
+            line += &format!(", retry {}", task.attempts - 1);
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// `status` with the state, running and configured jobs, completed and failed tasks, bytes
+/// received and rate limit, then a `running` record per task with its bytes received, size and
+/// attempts
+fn porcelain_status(control: &Control) -> String {
+    let state = match control.is_paused() {
+        true => "paused",
+        false => "downloading",
+    };
+    let running = control.running();
+    let mut lines = vec![porcelain::record(
+        "status",
+        &[
+            &state,
+            &running.len(),
+            &control.jobs(),
+            &control.completed(),
+            &control.failed(),
+            &control.received(),
+            &porcelain::optional(control.max_rate()),
+        ],
+    )];
+    for task in running {
+        lines.push(porcelain::record(
+            "running",
+            &[
+                &task.id,
+                &task.received,
+                &porcelain::optional(task.size),
+                &task.attempts,
+            ],
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Removes the socket file when dropped, so neither an error nor the end of the run leaves it
+/// behind
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Bind the socket at `path`, replacing one left behind by a run that didn't remove it. The file
+/// is removed when the returned `SocketFile` is dropped.
+pub fn bind(path: &Path) -> Result<(UnixListener, SocketFile)> {
+    if path.exists() && std::os::unix::net::UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    let listener =
+        UnixListener::bind(path).map_err(|e| anyhow!("Could not open {:?}: {}", path, e))?;
+    Ok((listener, SocketFile(path.to_path_buf())))
+}
+
+/// Answer commands for `control` until the future is dropped
+pub async fn serve(listener: UnixListener, control: Arc<Control>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &control).await {
+                println!("Warning: answering a control command failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(stream: UnixStream, control: &Control) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let reply = match line.parse::<Command>() {
+        Ok(command) => {
+            println!("Control command: {}", command);
+            apply(control, &command)
+        }
+        Err(e) => format!("Error: {}", e),
+    };
+    stream
+        .get_mut()
+        .write_all(format!("{}\n", reply).as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Send `command` to the download listening at `path`, returning its reply
+pub async fn send(path: &Path, command: &Command) -> Result<String> {
+    let mut stream = UnixStream::connect(path).await.map_err(|e| {
+        anyhow!(
+            "Could not connect to {:?}, is the plan downloading with --control? {}",
+            path,
+            e
+        )
+    })?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    match reply.strip_prefix("Error: ") {
+        Some(error) => Err(anyhow!(error.trim_end().to_string())),
+        None => Ok(reply.trim_end().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send() {
+        assert_eq!("rate off".parse::<Command>().unwrap(), Command::Rate(None));
+        assert_eq!(
+            " skip bucket/S2A/B04.tif\n".parse::<Command>().unwrap(),
+            Command::Skip("bucket/S2A/B04.tif".to_string())
+        );
+        assert!("jobs".parse::<Command>().is_err());
+
+        let path = socket_path(Path::new("/tmp/slow_stac_control_socket.json"));
+        let control = Control::new(4);
+        let (listener, socket_file) = bind(&path).unwrap();
+        let server = tokio::spawn(serve(listener, control.clone()));
+        assert_eq!(
+            send(&path, &Command::Pause).await.unwrap(),
+            "Paused, running transfers stop at their next request"
+        );
+        assert!(control.is_paused());
+        send(&path, &Command::Jobs(2)).await.unwrap();
+        send(&path, &Command::Rate(Some(1024))).await.unwrap();
+        control.start("bucket/B04.tif", Path::new("B04.tif"), Some(2048));
+        assert_eq!(
+            send(&path, &Command::Status).await.unwrap(),
+            "Paused, 1 of 2 jobs running, 0 done, 0 failed, 0 B received, rate limit 1.0 KiB/s\n  \
+             bucket/B04.tif 0 B of 2.0 KiB"
+        );
+        assert_eq!(
+            "status porcelain".parse::<Command>().unwrap(),
+            Command::PorcelainStatus
+        );
+        assert_eq!(
+            send(&path, &Command::PorcelainStatus).await.unwrap(),
+            "status\tpaused\t1\t2\t0\t0\t0\t1024\nrunning\tbucket/B04.tif\t0\t2048\t0"
+        );
+        server.abort();
+        drop(socket_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,44 @@
+//! Locating Copernicus SAFE products in the Google Cloud public Sentinel-2 mirror, which stores
+//! the same files under `L2/tiles/<zone>/<latitude band>/<square>/<product>.SAFE/`
+use crate::ids::Acquisition;
+
+pub const SOURCE: &str = "gcs";
+pub const BUCKET: &str = "gcp-public-data-sentinel-2";
+
+/// The mirror bucket and key of an object inside a SAFE product in the Copernicus `eodata` bucket
+pub fn locate(_bucket: &str, key: &str) -> Option<(String, String)> {
+    let (product, path) = key
+        .split('/')
+        .enumerate()
+        .find(|(_, segment)| segment.ends_with(".SAFE"))
+        .map(|(i, segment)| (segment, key.split('/').skip(i + 1).collect::<Vec<_>>()))?;
+    let tile = Acquisition::from_id(product)?.tile;
+    let (zone, square) = tile.split_at(2);
+    let (band, square) = square.split_at(1);
+    let key = format!(
+        "L2/tiles/{}/{}/{}/{}/{}",
+        zone,
+        band,
+        square,
+        product,
+        path.join("/")
+    );
+    Some((BUCKET.to_string(), key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let key = "Sentinel-2/MSI/L2A/2024/05/04/S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE/GRANULE/L2A_T08VPH_A046266_20240504T195929/IMG_DATA/R10m/T08VPH_20240504T195901_TCI_10m.jp2";
+        let (bucket, key) = locate("eodata", key).unwrap();
+        assert_eq!(bucket, BUCKET);
+        assert_eq!(
+            key,
+            "L2/tiles/08/V/PH/S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE/GRANULE/L2A_T08VPH_A046266_20240504T195929/IMG_DATA/R10m/T08VPH_20240504T195901_TCI_10m.jp2"
+        );
+        assert_eq!(locate("eodata", "Sentinel-2/not/a/product.jp2"), None);
+    }
+}
@@ -1,25 +1,63 @@
+use crate::copernicus::odata;
+use crate::resolve::{parse_s3_path, resolve_asset, Location, Transport};
 use crate::s3::S3ObjOps;
+use crate::user_agent;
 use anyhow::{anyhow, Result};
+use reqwest::StatusCode;
 use roxmltree::Node;
 use stac::Item;
+use tracing::instrument;
 
 pub struct Manifest {
     pub bucket: String,
     pub prefix: String,
+    pub item: Item,
     content: String,
 }
 
 impl Manifest {
-    pub async fn fetch(provider: &impl S3ObjOps, id: &str) -> anyhow::Result<Self> {
+    #[instrument(skip(provider, stac_api))]
+    pub async fn fetch(
+        provider: &impl S3ObjOps,
+        stac_api: &str,
+        collection: &str,
+        id: &str,
+    ) -> anyhow::Result<Self> {
         // Get the STAC Item corresponding to the provided id
-        let url = format!(
-            "https://catalogue.dataspace.copernicus.eu/stac/collections/SENTINEL-2/items/{id}",
-        );
-        let item = reqwest::get(url).await?.json::<Item>().await?;
+        let url = format!("{stac_api}/collections/{collection}/items/{id}");
+        let response = user_agent::get(url).await?;
+        let item = match response.status() {
+            // The STAC API can lag behind the OData catalogue for newly published products
+            StatusCode::NOT_FOUND => {
+                println!("Warning: {id} not found in the STAC API, trying the OData catalogue");
+                odata::fetch_item(collection, id).await?
+            }
+            _ => response.error_for_status()?.json::<Item>().await?,
+        };
+        Self::from_item(provider, item).await
+    }
 
+    /// Fetch the manifest of a STAC Item that has already been retrieved, e.g. from a saved search.
+    #[instrument(skip_all, fields(item_id = %item.id))]
+    pub async fn from_item(provider: &impl S3ObjOps, item: Item) -> anyhow::Result<Self> {
         // Extract the bucket and directory key from the STAC Item
-        let (bucket, prefix) = extract_bucket_and_prefix(&item)
-            .ok_or(anyhow!("Error extracting bucket and directory key"))?;
+        let product = item
+            .assets
+            .get("PRODUCT")
+            .ok_or(anyhow!("No PRODUCT asset found for id: {}", item.id))?;
+        // Published as a bare `/eodata/<key>` path, by the OData catalogue and older STAC items
+        let alternate = product
+            .additional_fields
+            .get("alternate")
+            .and_then(|alternate| alternate.get("s3")?.get("href")?.as_str());
+        let location = match alternate.unwrap_or(&product.href) {
+            path if path.starts_with('/') => parse_s3_path(path)?,
+            _ => resolve_asset(product, Transport::S3)?,
+        };
+        let (bucket, prefix) = match location {
+            Location::S3 { bucket, key, .. } => (bucket, key),
+            _ => return Err(anyhow!("No S3 location found for id: {}", item.id)),
+        };
 
         let key = format!("{}/manifest.safe", &prefix);
 
@@ -31,6 +69,7 @@ impl Manifest {
         Ok(Manifest {
             bucket,
             prefix,
+            item,
             content,
         })
     }
@@ -54,23 +93,6 @@ impl Manifest {
     }
 }
 
-fn extract_bucket_and_prefix(item: &Item) -> Option<(String, String)> {
-    let s3_dir = item
-        .assets
-        .get("PRODUCT")?
-        .additional_fields
-        .get("alternate")?
-        .get("s3")?
-        .get("href")?
-        .as_str()?;
-
-    let parts: Vec<&str> = s3_dir.split("/").collect();
-    let bucket = parts[1].to_owned();
-    let prefix = parts[2..].join("/");
-
-    Some((bucket, prefix))
-}
-
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct DataObject {
     pub id: String,
@@ -1,5 +1,8 @@
+pub mod gcs_mirror;
 mod manifest;
+pub mod odata;
 mod provider;
+pub mod quota;
 pub mod sentinel2level2a;
 
 pub use provider::Provider;
@@ -0,0 +1,110 @@
+//! Fallback to the Copernicus OData catalogue for products that the STAC API has not indexed yet
+use crate::user_agent;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use stac::Item;
+use url::Url;
+
+pub const ODATA_API: &str = "https://catalogue.dataspace.copernicus.eu/odata/v1";
+
+/// OData filter selecting a product by its exact name, e.g. `S2A_MSIL2A_..._20240505T015750.SAFE`
+pub fn name_filter(name: &str) -> String {
+    format!("Name eq '{}'", name.replace('\'', "''"))
+}
+
+/// OData filter selecting products with a string attribute, e.g. `productType` = `S2MSI2A`
+pub fn attribute_filter(name: &str, value: &str) -> String {
+    format!(
+        "Attributes/OData.CSC.StringAttribute/any(att:att/Name eq '{}' and att/OData.CSC.StringAttribute/Value eq '{}')",
+        name.replace('\'', "''"),
+        value.replace('\'', "''")
+    )
+}
+
+/// Query the OData `Products` endpoint with a filter, returning the raw product entries.
+pub async fn search_products(filter: &str) -> Result<Vec<Value>> {
+    let url = Url::parse_with_params(&format!("{ODATA_API}/Products"), &[("$filter", filter)])?;
+    let response = user_agent::get(url).await?.error_for_status()?;
+    let body: Value = response.json().await?;
+    let products = body
+        .get("value")
+        .and_then(|v| v.as_array())
+        .ok_or(anyhow!("OData response has no 'value' array"))?;
+    Ok(products.clone())
+}
+
+/// Look up a product by name and convert it to a minimal STAC Item with a `PRODUCT` asset, which
+/// is all `Manifest::from_item` needs to locate the manifest.
+pub async fn fetch_item(collection: &str, name: &str) -> Result<Item> {
+    let products = search_products(&name_filter(name)).await?;
+    let product = products.first().ok_or(anyhow!(
+        "No product named {} found in the OData catalogue",
+        name
+    ))?;
+    item_from_product(collection, product)
+}
+
+fn item_from_product(collection: &str, product: &Value) -> Result<Item> {
+    let name = product
+        .get("Name")
+        .and_then(|n| n.as_str())
+        .ok_or(anyhow!("OData product has no Name"))?;
+    let s3_path = product
+        .get("S3Path")
+        .and_then(|p| p.as_str())
+        .ok_or(anyhow!("OData product {} has no S3Path", name))?;
+    let datetime = product
+        .get("ContentDate")
+        .and_then(|d| d.get("Start"))
+        .cloned()
+        .unwrap_or(Value::Null);
+    let geometry = product.get("GeoFootprint").cloned().unwrap_or(Value::Null);
+
+    let item = json!({
+        "type": "Feature",
+        "stac_version": "1.0.0",
+        "id": name,
+        "geometry": geometry,
+        "properties": { "datetime": datetime },
+        "links": [],
+        "assets": {
+            "PRODUCT": {
+                "href": s3_path,
+                "title": "Product",
+                "file:size": product.get("ContentLength"),
+            }
+        },
+        "collection": collection,
+    });
+    Ok(serde_json::from_value(item)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_item_from_product() {
+        let product = json!({
+            "Id": "a7b1c2d3",
+            "Name": "S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE",
+            "ContentLength": 1024,
+            "ContentDate": { "Start": "2024-05-04T19:59:01.024Z", "End": "2024-05-04T19:59:01.024Z" },
+            "S3Path": "/eodata/Sentinel-2/MSI/L2A/2024/05/04/S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE",
+            "GeoFootprint": { "type": "Point", "coordinates": [-134.5, 59.5] }
+        });
+        let item = item_from_product("SENTINEL-2", &product).unwrap();
+        assert_eq!(
+            item.id,
+            "S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE"
+        );
+        assert!(item.assets["PRODUCT"]
+            .href
+            .starts_with("/eodata/Sentinel-2/"));
+    }
+
+    #[test]
+    fn test_name_filter_escapes_quotes() {
+        assert_eq!(name_filter("a'b"), "Name eq 'a''b'");
+    }
+}
@@ -1,63 +1,152 @@
 use aws_sdk_s3::Client;
 use aws_sdk_s3::operation::get_object::GetObjectOutput;
 use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::presigning::PresigningConfig;
 use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use std::sync::RwLock;
+use std::time::Duration;
 use thiserror::Error;
+use crate::copernicus::quota::{Quota, QuotaTracker};
+use crate::provider_config::{self, ProviderConfig};
 use crate::s3;
+use tracing::instrument;
 
 pub struct Provider {
-    client: Client,
+    client: RwLock<Client>,
+    /// Settings the client was created from, reloaded to pick up renewed credentials
+    config: Option<ProviderConfig>,
+    quota: Option<QuotaTracker>,
 }
 
 impl Provider {
     #[allow(dead_code)]
     pub fn new(client: Client) -> Self {
-        Self { client }
+        Self {
+            client: RwLock::new(client),
+            config: None,
+            quota: None,
+        }
     }
 
+    pub async fn from_config(config: ProviderConfig) -> Self {
+        let client = config.client().await;
+        Self {
+            config: Some(config),
+            ..Self::new(client)
+        }
+    }
+
+    /// The built-in Copernicus settings with the credentials of another AWS profile
     pub async fn from_profile(profile_name: &str) -> Self {
-        let client = s3::client_from_profile(profile_name).await;
-        Self { client }
+        let config = ProviderConfig::defaults(provider_config::COPERNICUS)
+            .with_profile(Some(profile_name.to_string()));
+        Self::from_config(config).await
+    }
+
+    /// With the settings of the config file (see `provider_config`)
+    pub async fn configured() -> anyhow::Result<Self> {
+        Ok(Self::from_config(provider_config::load(provider_config::COPERNICUS)?).await)
+    }
+
+    /// Pace requests to stay under the account's quotas.
+    pub fn with_quota(self, quota: &Quota) -> Self {
+        Self {
+            quota: Some(QuotaTracker::new(quota)),
+            ..self
+        }
+    }
+
+    fn client(self: &Self) -> Client {
+        self.client.read().expect("Client lock poisoned").clone()
     }
 }
 impl s3::S3ObjOps for Provider {
     async fn head_object(self: &Self, bucket: &str, key: &str) -> anyhow::Result<HeadObjectOutput> {
+        self.head_object_version(bucket, key, None).await
+    }
+
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> anyhow::Result<GetObjectOutput> {
+        self.get_object_version(bucket, key, None).await
+    }
+
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> anyhow::Result<GetObjectOutput> {
+        self.get_object_range_version(bucket, key, None, start_byte, end_byte)
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn head_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> anyhow::Result<HeadObjectOutput> {
+        if let Some(quota) = &self.quota {
+            quota.request().await;
+        }
         let head = self
-            .client
+            .client()
             .head_object()
             .bucket(bucket)
             .key(key)
+            .set_version_id(version_id.map(str::to_string))
             .send()
             .await?;
         Ok(head)
     }
 
-    async fn get_object(self: &Self, bucket: &str, key: &str) -> anyhow::Result<GetObjectOutput> {
+    #[instrument(skip(self))]
+    async fn get_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> anyhow::Result<GetObjectOutput> {
+        if let Some(quota) = &self.quota {
+            quota.request().await;
+        }
         let object = self
-            .client
+            .client()
             .get_object()
             .bucket(bucket)
             .key(key)
+            .set_version_id(version_id.map(str::to_string))
             .customize()
             .map_request(strip_x_id_get_object_param_from_uri)
             .send()
             .await?;
+        if let (Some(quota), Some(length)) = (&self.quota, object.content_length()) {
+            quota.record_transfer(length.max(0) as u64).await;
+        }
         Ok(object)
     }
 
-    async fn get_object_range(
+    #[instrument(skip(self))]
+    async fn get_object_range_version(
         self: &Self,
         bucket: &str,
         key: &str,
+        version_id: Option<&str>,
         start_byte: u64,
         end_byte: u64,
     ) -> anyhow::Result<GetObjectOutput> {
+        if let Some(quota) = &self.quota {
+            quota.request().await;
+            quota.transfer(end_byte + 1 - start_byte).await;
+        }
         let range = format!("bytes={}-{}", start_byte, end_byte);
         let object = self
-            .client
+            .client()
             .get_object()
             .bucket(bucket)
             .key(key)
+            .set_version_id(version_id.map(str::to_string))
             .range(range)
             .customize()
             .map_request(strip_x_id_get_object_param_from_uri)
@@ -65,6 +154,63 @@ impl s3::S3ObjOps for Provider {
             .await?;
         Ok(object)
     }
+
+    #[instrument(skip(self))]
+    async fn list_objects(
+        self: &Self,
+        bucket: &str,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<s3::ListedObject>> {
+        if let Some(quota) = &self.quota {
+            quota.request().await;
+        }
+        s3::list_objects_v2(&self.client(), bucket, prefix, None).await
+    }
+
+    fn md5_etags(self: &Self) -> bool {
+        true
+    }
+
+    /// Reload the profile, e.g. after an external tool has renewed the session credentials.
+    async fn refresh_credentials(self: &Self) -> anyhow::Result<bool> {
+        let Some(config) = self.config.as_ref().filter(|config| config.profile.is_some()) else {
+            return Ok(false);
+        };
+        let client = config.client().await;
+        *self.client.write().expect("Client lock poisoned") = client;
+        Ok(true)
+    }
+
+    async fn presign_get(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> anyhow::Result<String> {
+        self.presign_get_version(bucket, key, None, expires_in)
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn presign_get_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expires_in: Duration,
+    ) -> anyhow::Result<String> {
+        let request = self
+            .client()
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .set_version_id(version_id.map(str::to_string))
+            .customize()
+            .map_request(strip_x_id_get_object_param_from_uri)
+            .presigned(PresigningConfig::expires_in(expires_in)?)
+            .await?;
+        Ok(request.uri().to_string())
+    }
 }
 
 /// The copernicus S3 API throws a fit if the param 'x-id=GetObject' is present in the request. This
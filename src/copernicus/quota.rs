@@ -0,0 +1,176 @@
+//! Pacing requests to stay under Copernicus Data Space quotas, which block users who exceed them
+//! for the rest of the quota period
+use crate::units;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+/// Fraction of a quota at which a warning is printed
+const WARN_FRACTION: f64 = 0.8;
+
+/// Limits to stay under. Unset and zero limits are not tracked.
+#[derive(Debug, Clone, Default)]
+pub struct Quota {
+    pub requests_per_minute: Option<u64>,
+    pub bytes_per_day: Option<u64>,
+}
+
+/// Usage within a rolling window
+#[derive(Debug)]
+struct Window {
+    name: &'static str,
+    length: Duration,
+    limit: u64,
+    events: VecDeque<(Instant, u64)>,
+    warned: bool,
+}
+
+impl Window {
+    fn new(name: &'static str, length: Duration, limit: u64) -> Self {
+        Self {
+            name,
+            length,
+            limit,
+            events: VecDeque::new(),
+            warned: false,
+        }
+    }
+
+    fn used(self: &mut Self, now: Instant) -> u64 {
+        while let Some(&(at, _)) = self.events.front() {
+            if now.duration_since(at) < self.length {
+                break;
+            }
+            self.events.pop_front();
+        }
+        self.events.iter().map(|(_, amount)| amount).sum()
+    }
+
+    /// How long until `amount` more fits in the window. An amount larger than the whole limit
+    /// only has to wait for the window to empty.
+    fn wait(self: &mut Self, amount: u64, now: Instant) -> Duration {
+        let mut used = self.used(now);
+        if used + amount <= self.limit {
+            return Duration::ZERO;
+        }
+        for &(at, event) in self.events.iter() {
+            used -= event;
+            if used + amount <= self.limit || used == 0 {
+                return (at + self.length).saturating_duration_since(now);
+            }
+        }
+        Duration::ZERO
+    }
+
+    fn record(self: &mut Self, amount: u64, now: Instant) {
+        self.events.push_back((now, amount));
+        let fraction = self.used(now) as f64 / self.limit as f64;
+        if fraction >= WARN_FRACTION && !self.warned {
+            println!(
+                "Warning: {:.0}% of the Copernicus {} quota used",
+                fraction * 100.0,
+                self.name
+            );
+        }
+        self.warned = fraction >= WARN_FRACTION;
+    }
+}
+
+/// Tracks requests and bytes against a `Quota`, pausing before requests that would exceed it.
+#[derive(Debug)]
+pub struct QuotaTracker {
+    requests: Mutex<Option<Window>>,
+    bytes: Mutex<Option<Window>>,
+}
+
+impl QuotaTracker {
+    pub fn new(quota: &Quota) -> Self {
+        Self {
+            requests: Mutex::new(
+                quota
+                    .requests_per_minute
+                    .filter(|limit| *limit > 0)
+                    .map(|limit| Window::new("requests per minute", MINUTE, limit)),
+            ),
+            bytes: Mutex::new(
+                quota
+                    .bytes_per_day
+                    .filter(|limit| *limit > 0)
+                    .map(|limit| Window::new("bytes per day", DAY, limit)),
+            ),
+        }
+    }
+
+    /// Wait until one more request fits under the request quota, then count it.
+    pub async fn request(self: &Self) {
+        Self::acquire(&self.requests, 1).await
+    }
+
+    /// Wait until `bytes` more fit under the transfer quota, then count them.
+    pub async fn transfer(self: &Self, bytes: u64) {
+        Self::acquire(&self.bytes, bytes).await
+    }
+
+    /// Count bytes whose size was only known after the transfer, e.g. a whole-object GET.
+    pub async fn record_transfer(self: &Self, bytes: u64) {
+        if let Some(window) = self.bytes.lock().await.as_mut() {
+            window.record(bytes, Instant::now());
+        }
+    }
+
+    async fn acquire(window: &Mutex<Option<Window>>, amount: u64) {
+        loop {
+            let wait = {
+                let mut guard = window.lock().await;
+                let Some(window) = guard.as_mut() else {
+                    return;
+                };
+                let now = Instant::now();
+                let wait = window.wait(amount, now);
+                if wait.is_zero() {
+                    window.record(amount, now);
+                    return;
+                }
+                println!(
+                    "Pausing {} to stay under the Copernicus {} quota",
+                    units::duration(wait.max(Duration::from_secs(1))),
+                    window.name
+                );
+                wait
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_wait() {
+        let start = Instant::now();
+        let mut window = Window::new("requests per minute", MINUTE, 2);
+        assert_eq!(window.wait(1, start), Duration::ZERO);
+        window.record(1, start);
+        window.record(1, start + Duration::from_secs(30));
+
+        // Full until the first request leaves the window
+        let now = start + Duration::from_secs(40);
+        assert_eq!(window.wait(1, now), Duration::from_secs(20));
+        // After it has, there is room again
+        let now = start + Duration::from_secs(61);
+        assert_eq!(window.wait(1, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_window_wait_larger_than_limit() {
+        let start = Instant::now();
+        let mut window = Window::new("bytes per day", DAY, 100);
+        window.record(10, start);
+        assert_eq!(window.wait(500, start), DAY);
+        assert_eq!(window.wait(500, start + DAY), Duration::ZERO);
+    }
+}
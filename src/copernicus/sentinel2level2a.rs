@@ -1,11 +1,20 @@
 use crate::copernicus::manifest::{DataObject, Manifest};
 use crate::download_plan::{DownloadPlan, DownloadTask};
 use crate::image_selection::{ImageSelection, Product};
+use crate::prepare::PrepareOptions;
 use crate::s3::S3ObjOps;
+use crate::search::search_items;
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use regex::Regex;
+use stac::Item;
 use std::path::{Path, PathBuf};
 use toml;
+use tracing::instrument;
+
+/// Resolutions (in metres) that Sentinel-2 L2A bands are published at, finest first
+const RESOLUTIONS: [u32; 3] = [10, 20, 60];
+pub const STAC_API: &str = "https://catalogue.dataspace.copernicus.eu/stac";
+pub const COLLECTION_ID: &str = "SENTINEL-2";
 
 #[allow(dead_code)]
 pub fn image_selection_toml() -> toml::Table {
@@ -65,70 +74,254 @@ pub async fn generate_download_plan(
     selection: &ImageSelection,
     output_dir: PathBuf,
 ) -> Result<DownloadPlan> {
-    let ids_to_download = selection
-        .ids_to_download()
-        .ok_or(anyhow!("No ids to download"))?;
+    generate_download_plan_with(provider, selection, output_dir, &PrepareOptions::default()).await
+}
+
+/// Generate a plan, fetching the selection's manifests as `options` set out
+#[instrument(skip_all, fields(selection = %selection.id))]
+pub async fn generate_download_plan_with(
+    provider: &impl S3ObjOps,
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+    options: &PrepareOptions,
+) -> Result<DownloadPlan> {
     let products_to_download = selection
         .products_to_download()
         .ok_or(anyhow!("No products selected for download"))?;
+    let products = &products_to_download;
 
-    let mut tasks: Vec<DownloadTask> = vec![];
+    let stac_api = selection.stac_api(STAC_API);
+
+    let tasks = match (selection.ids_to_download(), &selection.search) {
+        (Some(ids_to_download), _) => {
+            let inputs = ids_to_download
+                .into_iter()
+                .map(|id| (id.clone(), id))
+                .collect();
+            options
+                .resolve(selection, inputs, |id: String| async move {
+                    let manifest = Manifest::fetch(provider, stac_api, COLLECTION_ID, &id).await?;
+                    manifest_tasks(selection, products, &manifest)
+                })
+                .await?
+        }
+        (None, Some(search)) => {
+            let items = search_items(stac_api, COLLECTION_ID, search).await?;
+            resolve_items(provider, selection, products, items, options).await?
+        }
+        (None, None) => return Err(anyhow!("No ids to download or search defined")),
+    };
+    Ok(DownloadPlan::new(&selection.id, tasks).with_root(&output_dir))
+}
+
+/// Generate a plan for STAC Items that have already been retrieved (e.g. an ItemCollection saved
+/// from a search), ignoring the selection's `ids_to_download`. Their manifests are fetched as
+/// `options` set out.
+#[instrument(skip_all, fields(selection = %selection.id))]
+pub async fn generate_download_plan_from_items(
+    provider: &impl S3ObjOps,
+    selection: &ImageSelection,
+    items: Vec<Item>,
+    output_dir: PathBuf,
+    options: &PrepareOptions,
+) -> Result<DownloadPlan> {
+    let products_to_download = selection
+        .products_to_download()
+        .ok_or(anyhow!("No products selected for download"))?;
+    let tasks = resolve_items(provider, selection, &products_to_download, items, options).await?;
+    Ok(DownloadPlan::new(&selection.id, tasks).with_root(&output_dir))
+}
+
+async fn resolve_items(
+    provider: &impl S3ObjOps,
+    selection: &ImageSelection,
+    products_to_download: &[Product],
+    items: Vec<Item>,
+    options: &PrepareOptions,
+) -> Result<Vec<DownloadTask>> {
+    let inputs = items
+        .into_iter()
+        .map(|item| (item.id.clone(), item))
+        .collect();
+    options
+        .resolve(selection, inputs, |item: Item| async move {
+            let manifest = Manifest::from_item(provider, item).await?;
+            manifest_tasks(selection, products_to_download, &manifest)
+        })
+        .await
+}
 
-    for id in ids_to_download {
-        let manifest = Manifest::fetch(provider, &id).await?;
-        let data_objects = manifest.parse()?;
-        let filtered_data_objects = filter_data_objects(&products_to_download, &data_objects)?;
+/// The tasks downloading the selected files of a product's manifest
+fn manifest_tasks(
+    selection: &ImageSelection,
+    products_to_download: &[Product],
+    manifest: &Manifest,
+) -> Result<Vec<DownloadTask>> {
+    let mut tasks: Vec<DownloadTask> = vec![];
+    let id = &manifest.item.id;
+    let data_objects = manifest.parse()?;
+    let filtered_data_objects = filter_data_objects(
+        id,
+        products_to_download,
+        &data_objects,
+        selection.resolution_fallback,
+    )?;
 
-        // Create a DownloadTask for each filtered_data_object
-        for data_obj in filtered_data_objects {
-            let key = format!("{}/{}", &manifest.prefix, data_obj.relative_href);
+    // Create a DownloadTask for each filtered_data_object
+    for (product, data_obj, substituted_for) in filtered_data_objects {
+        let key = format!("{}/{}", &manifest.prefix, data_obj.relative_href);
 
-            let file_name = Path::new(&key).file_name().unwrap();
-            let output = output_dir.join(&id).join(file_name);
+        let file_name = Path::new(&key).file_name().unwrap();
+        let output = Path::new(id).join(file_name);
 
-            let task = DownloadTask::new(&manifest.bucket, &key, output.to_str().unwrap());
-            tasks.push(task)
-        }
+        let task = DownloadTask::new(&manifest.bucket, &key, output.to_str().unwrap())
+            .with_size(Some(data_obj.filesize))
+            .with_checksum(Some(format!(
+                "{}:{}",
+                data_obj.checksum_algorithm.to_lowercase(),
+                data_obj.checksum.to_lowercase()
+            )))
+            .with_asset_key(&product.id)
+            .with_overviews_only(product.overviews_only)
+            .with_item(&manifest.item)
+            .with_substituted_for(substituted_for);
+        tasks.push(task)
     }
-    Ok(DownloadPlan::new(&selection.id, tasks))
+    Ok(tasks)
 }
 
+/// Match each product to the DataObjects it selects. With `resolution_fallback`, a band that is
+/// missing at the requested resolution is substituted with the finest resolution available, and
+/// the originally requested product id is returned alongside it.
 fn filter_data_objects(
+    item_id: &str,
     products_to_download: &[Product],
     data_objects: &[DataObject],
-) -> Result<Vec<DataObject>> {
-    // Create a HashMap for faster lookup
-    let data_object_map: HashMap<_, _> = data_objects.iter().map(|obj| (&obj.id, obj)).collect();
+    resolution_fallback: bool,
+) -> Result<Vec<(Product, DataObject, Option<String>)>> {
+    let mut filtered = vec![];
+    for product in products_to_download {
+        if product.is_pattern() {
+            // A pattern selects every DataObject whose id it matches
+            let mut matched = vec![];
+            for obj in data_objects {
+                if product.matches_within(&obj.id)? {
+                    matched.push(obj);
+                }
+            }
+            if matched.is_empty() {
+                return Err(anyhow!(
+                    "Pattern '{}' did not match any DataObject in the Manifest of {}",
+                    product.id,
+                    item_id
+                ));
+            }
+            let ids = matched
+                .iter()
+                .map(|obj| obj.id.as_str())
+                .collect::<Vec<_>>();
+            println!(
+                "{}: pattern '{}' matched {}",
+                item_id,
+                product.id,
+                ids.join(", ")
+            );
+            filtered.extend(
+                matched
+                    .into_iter()
+                    .map(|obj| (product.expanded(&obj.id), obj.clone(), None)),
+            );
+            continue;
+        }
+        // The Product.id is a substring of the corresponding DataObject.id
+        if let Some(obj) = data_objects.iter().find(|obj| obj.id.contains(&product.id)) {
+            filtered.push((product.clone(), obj.clone(), None));
+            continue;
+        }
+        let substitute = match resolution_fallback {
+            true => fallback_product_ids(&product.id)
+                .into_iter()
+                .find_map(|id| {
+                    let obj = data_objects.iter().find(|obj| obj.id.contains(&id))?;
+                    Some((id, obj))
+                }),
+            false => None,
+        };
+        let (id, obj) = substitute.ok_or_else(|| {
+            anyhow!(
+                "No corresponding DataObject found in Manifest for Product with id: {}",
+                product.id
+            )
+        })?;
+        println!(
+            "{}: {} is not available, substituting {}",
+            item_id, product.id, id
+        );
+        filtered.push((product.expanded(&id), obj.clone(), Some(product.id.clone())));
+    }
+    Ok(filtered)
+}
 
-    products_to_download
+/// Alternative ids for a band at the other resolutions Sentinel-2 L2A publishes, finest first,
+/// e.g. `B05_10m` falls back to `B05_20m` then `B05_60m`.
+fn fallback_product_ids(product_id: &str) -> Vec<String> {
+    let re = Regex::new(r"^(?<band>.+)_(?<resolution>\d+)m$")
+        .expect("Regex pattern should always compile");
+    let Some(captures) = re.captures(product_id) else {
+        return vec![];
+    };
+    let (_, [band, resolution]) = captures.extract();
+    RESOLUTIONS
         .iter()
-        .map(|product| {
-            data_object_map
-                .iter()
-                // The Product.id is a substring of the corresponding DataObject.id
-                .find(|(&id, _)| id.contains(&product.id))
-                .map(|(_, &obj)| obj.clone())
-                .ok_or_else(|| {
-                    anyhow!(
-                        "No corresponding DataObject found in Manifest for Product with id: {}",
-                        product.id
-                    )
-                })
-        })
-        .collect::<Result<Vec<_>>>() // Collect into Result<Vec<DataObject>>
+        .filter(|r| r.to_string() != resolution)
+        .map(|r| format!("{}_{}m", band, r))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::copernicus::Provider;
-    use crate::s3;
 
     const TEST_OUTPUT_DIR: &str = "/tmp";
+
+    fn mock_data_object(id: &str) -> DataObject {
+        DataObject {
+            id: id.to_string(),
+            filesize: 100,
+            relative_href: format!("GRANULE/{}.jp2", id),
+            checksum_algorithm: "MD5".to_string(),
+            checksum: "0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_fallback_product_ids() {
+        assert_eq!(fallback_product_ids("B05_10m"), vec!["B05_20m", "B05_60m"]);
+        assert_eq!(fallback_product_ids("B01_60m"), vec!["B01_10m", "B01_20m"]);
+        assert!(fallback_product_ids("SCL").is_empty());
+    }
+
+    #[test]
+    fn test_filter_data_objects_resolution_fallback() {
+        let selection = ImageSelection::from_template(&image_selection_toml());
+        let products = selection
+            .products_to_download()
+            .unwrap()
+            .into_iter()
+            .map(|p| p.expanded("B05_10m"))
+            .collect::<Vec<_>>();
+        let data_objects = vec![mock_data_object("IMG_DATA_Band_B05_20m_Tile1_Data")];
+
+        assert!(filter_data_objects("item", &products, &data_objects, false).is_err());
+
+        let filtered = filter_data_objects("item", &products, &data_objects, true).unwrap();
+        assert_eq!(filtered[0].0.id, "B05_20m");
+        assert_eq!(filtered[0].2, Some("B05_10m".to_string()));
+    }
     #[tokio::test]
     async fn test_generate_download_plan() {
-        let client = s3::client_from_profile("copernicus").await;
-        let provider = Provider::new(client);
+        let provider = Provider::from_profile("copernicus").await;
         let selection = ImageSelection::from_template(&image_selection_toml());
         let output_dir = PathBuf::from(TEST_OUTPUT_DIR);
         let download_plan = generate_download_plan(&provider, &selection, output_dir)
@@ -0,0 +1,130 @@
+//! How much of an area of interest an item's footprint covers, to skip the edge tiles that barely
+//! touch it (see `Search::min_aoi_coverage`). The AOI's bounding box is sampled on a grid and the
+//! share of the samples inside the AOI that also fall inside the footprint is the coverage, which
+//! is accurate to about a percent whatever the shape of the polygons, holes included. Samples are
+//! weighted by the cosine of their latitude so AOIs spanning many degrees aren't skewed poleward.
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+/// Samples along each side of the AOI's bounding box
+const GRID_SIZE: usize = 200;
+
+/// Rings of a polygon as `[lon, lat]` points, the first being the exterior and the rest holes
+pub type Polygon = Vec<Vec<[f64; 2]>>;
+
+/// The polygons of a GeoJSON Polygon, MultiPolygon, GeometryCollection, Feature or
+/// FeatureCollection
+pub fn polygons(geojson: &Value) -> Result<Vec<Polygon>> {
+    let coordinates = || {
+        geojson
+            .get("coordinates")
+            .ok_or(anyhow!("GeoJSON geometry has no coordinates"))
+    };
+    match geojson.get("type").and_then(Value::as_str) {
+        Some("Polygon") => Ok(vec![serde_json::from_value(coordinates()?.clone())?]),
+        Some("MultiPolygon") => Ok(serde_json::from_value(coordinates()?.clone())?),
+        Some("Feature") => polygons(
+            geojson
+                .get("geometry")
+                .ok_or(anyhow!("GeoJSON feature has no geometry"))?,
+        ),
+        Some("GeometryCollection") | Some("FeatureCollection") => {
+            let members = geojson
+                .get("geometries")
+                .or(geojson.get("features"))
+                .and_then(Value::as_array)
+                .ok_or(anyhow!("GeoJSON collection has no members"))?;
+            let mut all = vec![];
+            for member in members {
+                all.extend(polygons(member)?);
+            }
+            Ok(all)
+        }
+        // Points and lines cover no area
+        _ => Ok(vec![]),
+    }
+}
+
+pub fn bbox_polygon(bbox: [f64; 4]) -> Polygon {
+    let [west, south, east, north] = bbox;
+    vec![vec![
+        [west, south],
+        [east, south],
+        [east, north],
+        [west, north],
+        [west, south],
+    ]]
+}
+
+/// Even-odd rule over every ring, so points in holes are outside
+fn contains(polygons: &[Polygon], [x, y]: [f64; 2]) -> bool {
+    polygons.iter().any(|polygon| {
+        let mut inside = false;
+        for ring in polygon {
+            for edge in ring.windows(2) {
+                let ([x1, y1], [x2, y2]) = (edge[0], edge[1]);
+                if (y1 > y) != (y2 > y) && x < (x2 - x1) * (y - y1) / (y2 - y1) + x1 {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    })
+}
+
+/// Percentage of `aoi` covered by `footprint`, 0 for an AOI without area
+pub fn coverage(aoi: &[Polygon], footprint: &[Polygon]) -> f64 {
+    let points = aoi.iter().flatten().flatten();
+    let (mut west, mut south) = (f64::INFINITY, f64::INFINITY);
+    let (mut east, mut north) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for [lon, lat] in points {
+        west = west.min(*lon);
+        east = east.max(*lon);
+        south = south.min(*lat);
+        north = north.max(*lat);
+    }
+    let (mut in_aoi, mut covered) = (0.0, 0.0);
+    for row in 0..GRID_SIZE {
+        let lat = south + (north - south) * (row as f64 + 0.5) / GRID_SIZE as f64;
+        let weight = lat.to_radians().cos();
+        for column in 0..GRID_SIZE {
+            let lon = west + (east - west) * (column as f64 + 0.5) / GRID_SIZE as f64;
+            if !contains(aoi, [lon, lat]) {
+                continue;
+            }
+            in_aoi += weight;
+            if contains(footprint, [lon, lat]) {
+                covered += weight;
+            }
+        }
+    }
+    match in_aoi > 0.0 {
+        true => 100.0 * covered / in_aoi,
+        false => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_coverage() {
+        let aoi = bbox_polygon([10.0, 45.0, 11.0, 46.0]);
+        let footprint = polygons(&json!({
+            "type": "MultiPolygon",
+            "coordinates": [[[
+                [10.75, 44.0], [12.0, 44.0], [12.0, 47.0], [10.75, 47.0], [10.75, 44.0]
+            ]]]
+        }))
+        .unwrap();
+        assert!((coverage(&[aoi.clone()], &footprint) - 25.0).abs() < 1.0);
+
+        // A footprint with the AOI in its hole
+        let ring = |w: f64, s: f64, e: f64, n: f64| bbox_polygon([w, s, e, n]).remove(0);
+        let holed = vec![ring(9.0, 44.0, 12.0, 47.0), ring(9.5, 44.5, 11.5, 46.5)];
+        assert_eq!(coverage(&[aoi.clone()], &[holed]), 0.0);
+        assert_eq!(coverage(&[aoi.clone()], &[aoi]), 100.0);
+    }
+}
@@ -0,0 +1,230 @@
+//! Datatakes split across tiles. A Sentinel-2 datatake is one continuous acquisition along an
+//! orbit, published as an item per MGRS tile, so an AOI straddling tiles gets several items that
+//! are really one image. Plans group their items by platform, sensing day and relative orbit (in
+//! SAFE ids) and split the groups where sensing times are further apart than `MAX_GAP`, which
+//! separates consecutive orbits over the same day. `plan stats` and `prepare` report the
+//! datatakes with more than one tile, and `download --merge-datatakes` mosaics each band of them
+//! into a VRT per UTM zone under `<output dir>/datatakes/`.
+use crate::download_plan::DownloadPlan;
+use crate::ids::Acquisition;
+use crate::stack;
+use anyhow::Result;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Largest difference in sensing time between tiles of one datatake, in seconds. A datatake
+/// crosses a tile in seconds, consecutive orbits are 100 minutes apart.
+const MAX_GAP: u32 = 30 * 60;
+pub const VRT_DIR: &str = "datatakes";
+/// The GDAL programs merging runs, for `stack::check`
+pub const PROGRAMS: [&str; 1] = ["gdalbuildvrt"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datatake {
+    pub platform: String,
+    /// `YYYYMMDD`
+    pub date: String,
+    /// Relative orbit, for ids that have one
+    pub orbit: Option<String>,
+    pub items: Vec<String>,
+    /// MGRS tiles, sorted
+    pub tiles: Vec<String>,
+}
+
+impl Datatake {
+    /// e.g. `S2A_20240611_R065`
+    pub fn name(self: &Self) -> String {
+        match &self.orbit {
+            Some(orbit) => format!("{}_{}_R{}", self.platform, self.date, orbit),
+            None => format!("{}_{}", self.platform, self.date),
+        }
+    }
+}
+
+/// Relative orbit of a SAFE id, e.g. `065` of `S2A_MSIL2A_20240611T101601_N0510_R065_T33UUP_...`
+fn orbit(id: &str) -> Option<String> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| Regex::new(r"_N\d{4}_R(\d{3})_T").unwrap());
+    Some(pattern.captures(id)?[1].to_string())
+}
+
+/// Seconds since midnight of an RFC 3339 (or chrono display) datetime
+fn seconds_of_day(datetime: &str) -> Option<u32> {
+    let field = |range: std::ops::Range<usize>| datetime.get(range)?.parse::<u32>().ok();
+    Some(field(11..13)? * 3600 + field(14..16)? * 60 + field(17..19)?)
+}
+
+/// The datatakes of the plan's items that span more than one tile, in date order
+pub fn split_datatakes(plan: &DownloadPlan) -> Vec<Datatake> {
+    let mut sensed: BTreeMap<String, Option<u32>> = BTreeMap::new();
+    for task in plan.tasks() {
+        let seconds = task.datetime().and_then(seconds_of_day);
+        let entry = sensed.entry(task.item()).or_insert(seconds);
+        *entry = entry.or(seconds);
+    }
+    let mut groups: BTreeMap<(String, String, Option<String>), Vec<_>> = BTreeMap::new();
+    for (item, seconds) in sensed {
+        let Some(acquisition) = Acquisition::from_id(&item) else {
+            continue;
+        };
+        groups
+            .entry((acquisition.platform, acquisition.date, orbit(&item)))
+            .or_default()
+            .push((seconds, item, acquisition.tile));
+    }
+
+    let mut datatakes = vec![];
+    for ((platform, date, orbit), mut items) in groups {
+        items.sort();
+        let mut clusters: Vec<Vec<(Option<u32>, String, String)>> = vec![];
+        for item in items {
+            let previous = clusters.last().and_then(|cluster| cluster.last());
+            let gap = match (previous.and_then(|(seconds, _, _)| *seconds), item.0) {
+                (Some(previous), Some(seconds)) => seconds - previous,
+                _ => 0,
+            };
+            match clusters.last_mut() {
+                Some(cluster) if gap <= MAX_GAP => cluster.push(item),
+                _ => clusters.push(vec![item]),
+            }
+        }
+        for cluster in clusters {
+            let tiles: BTreeSet<_> = cluster.iter().map(|(_, _, tile)| tile.clone()).collect();
+            if tiles.len() < 2 {
+                continue;
+            }
+            datatakes.push(Datatake {
+                platform: platform.clone(),
+                date: date.clone(),
+                orbit: orbit.clone(),
+                items: cluster.into_iter().map(|(_, item, _)| item).collect(),
+                tiles: tiles.into_iter().collect(),
+            });
+        }
+    }
+    datatakes
+}
+
+/// A line per datatake listing its tiles, empty without any
+pub fn to_text(datatakes: &[Datatake]) -> String {
+    if datatakes.is_empty() {
+        return String::new();
+    }
+    let mut lines = vec![format!(
+        "{} acquisition(s) split across tiles of the same datatake:",
+        datatakes.len()
+    )];
+    for datatake in datatakes {
+        lines.push(format!(
+            "{}: {} items, tiles {}",
+            datatake.name(),
+            datatake.items.len(),
+            datatake.tiles.join(", ")
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Mosaic each downloaded band of each datatake into `<name>_UTM<zone>_<band>.vrt` with
+/// `gdalbuildvrt`, a VRT per UTM zone since a VRT holds a single projection. Returns the VRTs
+/// written.
+pub async fn build_vrts(plan: &DownloadPlan, datatakes: &[Datatake]) -> Result<Vec<PathBuf>> {
+    let dir = PathBuf::from(plan.root().unwrap_or(".")).join(VRT_DIR);
+    let mut vrts = vec![];
+    for datatake in datatakes {
+        let mut bands: BTreeMap<(String, String), Vec<PathBuf>> = BTreeMap::new();
+        for task in plan.tasks() {
+            let item = task.item();
+            let output = plan.output_path(task);
+            let is_raster = output
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .is_some_and(|extension| stack::RASTER_EXTENSIONS.contains(&extension.as_str()));
+            let tile = match Acquisition::from_id(&item) {
+                Some(acquisition) if datatake.items.contains(&item) => acquisition.tile,
+                _ => continue,
+            };
+            if !is_raster || !output.exists() {
+                continue;
+            }
+            let band = match task.asset_key() {
+                Some(asset_key) => asset_key.to_string(),
+                None => output
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            };
+            bands
+                .entry((tile[..2].to_string(), band))
+                .or_default()
+                .push(fs::canonicalize(&output)?);
+        }
+        for ((zone, band), files) in bands {
+            if files.len() < 2 {
+                continue;
+            }
+            fs::create_dir_all(&dir)?;
+            let vrt = dir.join(format!("{}_UTM{}_{}.vrt", datatake.name(), zone, band));
+            stack::run(
+                tokio::process::Command::new("gdalbuildvrt")
+                    .arg("-q")
+                    .arg(&vrt)
+                    .args(&files),
+            )
+            .await?;
+            vrts.push(vrt);
+        }
+    }
+    Ok(vrts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_plan::DownloadTask;
+    use serde_json::json;
+
+    #[test]
+    fn test_split_datatakes() {
+        let task = |item: &str, datetime: &str| -> DownloadTask {
+            serde_json::from_value(json!({
+                "bucket": "bucket",
+                "key": format!("{}/B04.tif", item),
+                "output": format!("{}/B04.tif", item),
+                "item_id": item,
+                "datetime": datetime,
+            }))
+            .unwrap()
+        };
+        let plan = DownloadPlan::new(
+            "element84.sentinel2collection1level2a",
+            vec![
+                task("S2A_T33UUP_20240611T101601_L2A", "2024-06-11T10:16:01Z"),
+                task("S2A_T33UVP_20240611T101558_L2A", "2024-06-11T10:15:58Z"),
+                task("S2A_T32UQD_20240611T101604_L2A", "2024-06-11T10:16:04Z"),
+                // The next orbit, over the same day
+                task("S2A_T33UUP_20240611T115601_L2A", "2024-06-11T11:56:01Z"),
+                task("S2B_T33UUP_20240503T101559_L2A", "2024-05-03T10:15:59Z"),
+            ],
+        );
+        let datatakes = split_datatakes(&plan);
+        assert_eq!(datatakes.len(), 1);
+        assert_eq!(datatakes[0].name(), "S2A_20240611");
+        assert_eq!(datatakes[0].tiles, ["32UQD", "33UUP", "33UVP"]);
+        assert_eq!(
+            datatakes[0].items,
+            [
+                "S2A_T33UVP_20240611T101558_L2A",
+                "S2A_T33UUP_20240611T101601_L2A",
+                "S2A_T32UQD_20240611T101604_L2A"
+            ]
+        );
+        assert_eq!(
+            orbit("S2A_MSIL2A_20240611T101601_N0510_R065_T33UUP_20240611T150000"),
+            Some("065".to_string())
+        );
+    }
+}
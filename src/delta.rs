@@ -0,0 +1,161 @@
+//! Delta plans that bring an existing archive up to date: of a freshly prepared plan, only the
+//! files whose published checksum (or size) no longer matches the local copy, and the items that
+//! were reprocessed with a newer baseline since the archived version was downloaded.
+use crate::download_plan::{DownloadPlan, DownloadTask};
+use crate::prune;
+use crate::verify;
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Suffix of a local file replaced upstream, kept until the delta plan has downloaded its successor
+pub const OUTDATED_SUFFIX: &str = ".outdated";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The local file differs from the one now published
+    Changed(String),
+    /// A newer processing of an archived item, whose id is given
+    Reprocessed(String),
+    Unchanged,
+    /// The item isn't in the archive in any version, or this file of it was never downloaded
+    NotLocal,
+}
+
+/// How a task of a freshly prepared plan relates to the archive at its output path
+pub fn compare(task: &DownloadTask, output: &Path) -> Result<Change> {
+    let Some(item_dir) = output.parent() else {
+        return Ok(Change::NotLocal);
+    };
+    if !item_dir.exists() {
+        return Ok(match archived_version(item_dir)? {
+            Some(archived) => Change::Reprocessed(archived),
+            None => Change::NotLocal,
+        });
+    }
+    if !output.exists() {
+        return Ok(Change::NotLocal);
+    }
+    let size = fs::metadata(output)?.len();
+    if let Some(expected) = task.size().filter(|expected| *expected != size) {
+        return Ok(Change::Changed(format!("{} bytes, now {}", size, expected)));
+    }
+    if let Some((algorithm, expected)) = task.checksum().and_then(|c| c.split_once(':')) {
+        // Checksums in algorithms that can't be computed locally fall back to the size
+        if let Ok(actual) = verify::file_digest(output, algorithm) {
+            if actual != expected.to_lowercase() {
+                return Ok(Change::Changed(format!("{} changed", algorithm)));
+            }
+        }
+    }
+    Ok(Change::Unchanged)
+}
+
+/// Id of an older version of the item in `item_dir` that is in the archive, if any
+fn archived_version(item_dir: &Path) -> Result<Option<String>> {
+    let (Some(root), Some(item_id)) = (item_dir.parent(), item_dir.file_name()) else {
+        return Ok(None);
+    };
+    let Some((acquisition, version)) = prune::version(&item_id.to_string_lossy()) else {
+        return Ok(None);
+    };
+    if !root.exists() {
+        return Ok(None);
+    }
+    for entry in fs::read_dir(root)? {
+        let archived = entry?.file_name().to_string_lossy().to_string();
+        match prune::version(&archived) {
+            Some((a, v)) if a == acquisition && v < version => return Ok(Some(archived)),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Reduce a plan to the files that changed upstream. Returns the plan and the local files it
+/// replaces, to set aside with `set_aside` once the plan is written.
+pub fn delta(plan: DownloadPlan) -> Result<(DownloadPlan, Vec<PathBuf>)> {
+    let mut changes = vec![];
+    for task in plan.tasks() {
+        let output = plan.output_path(task);
+        changes.push((compare(task, &output)?, output));
+    }
+    let mut outdated = vec![];
+    let mut reprocessed = vec![];
+    for (change, output) in &changes {
+        match change {
+            Change::Changed(reason) => {
+                println!("Changed upstream: {:?} ({})", output, reason);
+                outdated.push(output.clone());
+            }
+            Change::Reprocessed(archived) if !reprocessed.contains(archived) => {
+                let item = output.parent().and_then(|dir| dir.file_name());
+                println!(
+                    "Reprocessed: {:?} supersedes {}",
+                    item.unwrap_or_default(),
+                    archived
+                );
+                reprocessed.push(archived.clone());
+            }
+            _ => {}
+        }
+    }
+    let mut changes = changes.into_iter();
+    let plan = plan.retain_tasks(|_| {
+        let (change, _) = changes.next().expect("One change per task");
+        matches!(change, Change::Changed(_) | Change::Reprocessed(_))
+    });
+    Ok((plan, outdated))
+}
+
+/// Rename files replaced upstream to `<file>.outdated`, so downloading the delta plan fetches
+/// their successors rather than skipping them as complete.
+pub fn set_aside(outdated: &[PathBuf]) -> Result<()> {
+    for path in outdated {
+        let renamed = PathBuf::from(format!("{}{}", path.to_string_lossy(), OUTDATED_SUFFIX));
+        fs::rename(path, &renamed)?;
+        println!("Kept the outdated {:?} as {:?}", path, renamed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let root = Path::new("/tmp/slow_stac_delta");
+        let _ = fs::remove_dir_all(root);
+        let old = "S2A_MSIL2A_20240504T195901_N0500_R128_T08VPH_20240504T235436.SAFE";
+        let new = "S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE";
+        let c1 = "S2A_T08VPH_20240504T195929_L2A";
+        for item in [old, c1] {
+            fs::create_dir_all(root.join(item)).unwrap();
+        }
+        fs::write(root.join(c1).join("B04.tif"), "b04").unwrap();
+        fs::write(root.join(c1).join("B08.tif"), "b08").unwrap();
+        let b04 = verify::file_digest(&root.join(c1).join("B04.tif"), "md5").unwrap();
+        let task = |output: String| DownloadTask::new("bucket", "key", &output);
+        let tasks = vec![
+            task(format!("{}/B04.tif", c1)).with_checksum(Some(format!("md5:{}", b04))),
+            task(format!("{}/B08.tif", c1)).with_checksum(Some(format!("md5:{}", b04))),
+            task(format!("{}/B02.tif", c1)),
+            task(format!("{}/B04_10m.jp2", new)),
+            task("S2B_T08VPH_20240507T195929_L2A/B04.tif".to_string()),
+        ];
+        let plan = DownloadPlan::new("provider.collection", tasks).with_root(root);
+
+        let (plan, outdated) = delta(plan).unwrap();
+        assert_eq!(
+            plan.output_paths(),
+            [
+                root.join(c1).join("B08.tif"),
+                root.join(new).join("B04_10m.jp2")
+            ]
+        );
+        assert_eq!(outdated, [root.join(c1).join("B08.tif")]);
+        set_aside(&outdated).unwrap();
+        assert!(root.join(c1).join("B08.tif.outdated").exists());
+    }
+}
@@ -1,60 +1,2162 @@
-use crate::s3::S3ObjOps;
+use crate::backoff;
+use crate::cache;
+use crate::checkpoint::{self, PlanState, PushState};
+use crate::cog::CogOptions;
+use crate::control::{self, Control, Skipped, Stopped};
+use crate::datatake;
+use crate::failure::TaskFailure;
+use crate::geotiff;
+use crate::head_cache::{HeadCache, HEAD_CACHE_TTL};
+use crate::hooks::{self, CompletedItem, ItemHook};
+use crate::multipart::{self, Parts, RemoteObject};
+use crate::partial::{self, PartialCheck, PartialState};
+use crate::peer::Peers;
+use crate::plan_crypt;
+use crate::porcelain;
+use crate::presign;
+use crate::provenance::{self, Provenance};
+use crate::resolve::{parse_href, Location};
+use crate::s3::{is_auth_error, S3ObjOps};
+use crate::scl::{self, SceneScore};
+use crate::skip_list::SkipList;
+use crate::stack;
+use crate::timeseries;
+use crate::units;
+use crate::upload;
+use crate::user_agent;
+use crate::verify::{self, Verification, VerificationFailed};
 use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use futures_util::stream::{self, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use stac::Item;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::Path;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{instrument, Instrument};
+use url::Url;
 
-#[derive(Deserialize, Serialize, Debug)]
+const LARGEST_TASK_COUNT: usize = 10;
+const NICE_CHUNK_DELAY: Duration = Duration::from_millis(50);
+/// Labels and exclusive upper bounds of the size histogram bins
+const HISTOGRAM_BINS: [(&str, u64); 5] = [
+    ("< 1 MiB", 1 << 20),
+    ("1-10 MiB", 10 << 20),
+    ("10-100 MiB", 100 << 20),
+    ("100 MiB-1 GiB", 1 << 30),
+    (">= 1 GiB", u64::MAX),
+];
+
+/// Outcome of one task, yielded by `DownloadPlan::execute_stream` as soon as the task finishes
+#[derive(Debug)]
+pub struct TaskResult {
+    pub bucket: String,
+    pub key: String,
+    pub output: PathBuf,
+    pub item_id: Option<String>,
+    pub asset_key: Option<String>,
+    pub outcome: Result<()>,
+    /// How the file was verified, if it was downloaded and verification is enabled
+    pub verification: Option<Verification>,
+}
+
+/// How a plan is executed
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Check each downloaded file against the checksum, ETag or size of the object
+    pub verify: bool,
+    /// Times a file failing verification is deleted and downloaded again from the first byte
+    pub max_verify_restarts: u32,
+    pub pacing: Pacing,
+    /// Tasks downloaded at the same time
+    pub jobs: usize,
+    /// Request objects in ranges of at most this many bytes instead of all at once
+    pub chunk_size: Option<u64>,
+    /// Download large objects in several ranges at the same time (see `multipart`)
+    pub parts: Option<Parts>,
+    /// Times a throttled request is retried (see `backoff::Throttled`)
+    pub max_retries: u32,
+    /// Abort a transfer whose throughput drops below a threshold
+    pub stall: Option<StallThreshold>,
+    /// Abort a task that takes longer than this, including verification restarts
+    pub task_timeout: Option<Duration>,
+    /// Stop executing the plan after this long
+    pub timeout: Option<Duration>,
+    pub order: TaskOrder,
+    /// Fired when every task of an item has completed
+    pub item_hooks: Vec<ItemHook>,
+    /// Convert JPEG 2000 bands to COGs as they complete
+    pub cog: Option<CogOptions>,
+    /// Download into a cache shared with other users and link the files into the output
+    /// directory (see `cache`)
+    pub cache: Option<PathBuf>,
+    /// Other instances on the local network sharing their files (see `peer`), asked for each
+    /// object before the provider
+    pub peers: Arc<Peers>,
+    /// Pauses, skips and limits tasks while the plan executes, and follows their progress (see
+    /// `control`)
+    pub control: Option<Arc<Control>>,
+    /// Items whose tasks are skipped, checked before each task
+    pub skip_list: Option<SkipList>,
+    /// Push the plan's state to remote storage as tasks complete (see `checkpoint`)
+    pub push_state: Option<PushState>,
+    /// Reuse HEAD responses this long while the plan executes (see `head_cache`), zero to send
+    /// every request
+    pub head_cache_ttl: Duration,
+    /// What to do with objects served without a Content-Length (see `MissingLength`)
+    pub missing_length: MissingLength,
+    /// Write a time-series manifest of the downloaded items once the plan has executed (see
+    /// `timeseries`)
+    pub timeseries: bool,
+    /// Mosaic the bands of datatakes split across tiles into VRTs once the plan has executed (see
+    /// `datatake`)
+    pub merge_datatakes: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            verify: true,
+            max_verify_restarts: 2,
+            pacing: Pacing::default(),
+            jobs: 1,
+            chunk_size: None,
+            parts: None,
+            max_retries: backoff::MAX_THROTTLE_RETRIES,
+            stall: Some(StallThreshold::default()),
+            task_timeout: None,
+            timeout: None,
+            order: TaskOrder::Plan,
+            item_hooks: vec![],
+            cog: None,
+            cache: None,
+            peers: Arc::default(),
+            control: None,
+            skip_list: None,
+            push_state: None,
+            head_cache_ttl: HEAD_CACHE_TTL,
+            missing_length: MissingLength::default(),
+            timeseries: false,
+            merge_datatakes: false,
+        }
+    }
+}
+
+/// The order tasks are started in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskOrder {
+    /// As listed in the plan
+    #[default]
+    Plan,
+    /// One item at a time, in plan order, and the smallest files of each item first, so complete
+    /// items accumulate early even if the run is interrupted. Files of unknown size go last.
+    ItemSmallestFirst,
+}
+
+impl std::str::FromStr for TaskOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "plan" => Ok(TaskOrder::Plan),
+            "item-smallest-first" => Ok(TaskOrder::ItemSmallestFirst),
+            _ => Err(anyhow!(
+                "Unknown order {}, expected plan or item-smallest-first",
+                name
+            )),
+        }
+    }
+}
+
+/// What to do with an object whose size the server doesn't report, as some endpoints omit the
+/// Content-Length
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingLength {
+    /// Download it in one request until the body ends, then check it against its ETag. It can't
+    /// be resumed, so an interrupted transfer starts over.
+    #[default]
+    Stream,
+    /// Fail the task
+    Fail,
+}
+
+impl std::str::FromStr for MissingLength {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "stream" => Ok(MissingLength::Stream),
+            "fail" => Ok(MissingLength::Fail),
+            _ => Err(anyhow!(
+                "Unknown behavior {}, expected stream or fail",
+                name
+            )),
+        }
+    }
+}
+
+/// Minimum throughput a transfer must sustain over each `window`, e.g. 1 KiB/s over 60 s
+#[derive(Debug, Clone)]
+pub struct StallThreshold {
+    pub min_bytes_per_sec: u64,
+    pub window: Duration,
+}
+
+impl Default for StallThreshold {
+    fn default() -> Self {
+        Self {
+            min_bytes_per_sec: 1024,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+impl StallThreshold {
+    /// Whether `bytes` received over `elapsed` falls below the threshold, once a full window has
+    /// passed.
+    pub(crate) fn is_stalled(self: &Self, bytes: u64, elapsed: Duration) -> bool {
+        elapsed >= self.window
+            && (bytes as f64) < self.min_bytes_per_sec as f64 * elapsed.as_secs_f64()
+    }
+
+    pub(crate) fn stalled(self: &Self, bytes: u64, elapsed: Duration) -> Stalled {
+        Stalled(format!(
+            "{} received in {}, below {}",
+            units::size(bytes),
+            units::duration(elapsed),
+            units::rate(self.min_bytes_per_sec as f64)
+        ))
+    }
+}
+
+/// A task that was still making (too little) progress when it was aborted, as opposed to one that
+/// failed outright. The partial file is kept, so stalled tasks are worth retrying later or on
+/// another connection.
+#[derive(Debug, Error)]
+#[error("Transfer stalled: {0}")]
+pub struct Stalled(String);
+
+/// A plan that ran to the end with some tasks stalled, which a later run can resume
+#[derive(Debug, Error)]
+#[error("{completed} tasks completed, {stalled} stalled, run the plan again to resume them")]
+pub struct Incomplete {
+    pub completed: usize,
+    pub stalled: usize,
+}
+
+/// A plan stopped by `DownloadOptions::timeout` before every task ran, which a later run can
+/// resume
+#[derive(Debug, Error)]
+#[error("Plan did not finish within {0:?}, run it again to resume")]
+pub struct TimedOut(pub Duration);
+
+/// Whether a task failed by stalling or timing out rather than with an error
+pub fn is_stalled(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<Stalled>())
+}
+
+/// How the tasks of an executed plan ended
+#[derive(Debug, Default)]
+pub struct ExecutionSummary {
+    pub completed: usize,
+    /// Outputs of tasks that stalled or timed out
+    pub stalled: Vec<PathBuf>,
+    /// Outputs of tasks skipped through `DownloadOptions::control` or `skip_list`
+    pub skipped: Vec<PathBuf>,
+    /// Tasks stopped to pause the plan, which the next run resumes
+    pub stopped: usize,
+    /// Error that stopped execution before every task was attempted
+    pub error: Option<anyhow::Error>,
+    /// JPEG 2000 bands converted to COGs (see `DownloadOptions::cog`)
+    pub converted: Vec<PathBuf>,
+    /// Bands that failed to convert, which the next run of the plan tries again
+    pub conversion_failed: Vec<(PathBuf, String)>,
+    /// Usability of the completed items whose SCL band was selected
+    pub scenes: Vec<SceneScore>,
+    /// Outputs of the stalled tasks and of the one whose error stopped execution, with why they
+    /// failed
+    pub failures: Vec<(PathBuf, TaskFailure)>,
+}
+
+impl ExecutionSummary {
+    fn record_conversion(
+        self: &mut Self,
+        conversion: Result<(PathBuf, Result<Option<PathBuf>>), tokio::task::JoinError>,
+    ) {
+        match conversion {
+            Ok((_, Ok(Some(cog)))) => self.converted.push(cog),
+            Ok((_, Ok(None))) => {}
+            Ok((output, Err(e))) => {
+                println!("Warning: {}", e);
+                self.conversion_failed.push((output, e.to_string()));
+            }
+            Err(e) => println!("Warning: conversion did not finish: {}", e),
+        }
+    }
+
+    pub fn to_text(self: &Self) -> String {
+        let mut lines = vec![format!(
+            "{} tasks completed, {} stalled",
+            self.completed,
+            self.stalled.len()
+        )];
+        for output in &self.stalled {
+            lines.push(format!("  stalled: {:?}", output));
+        }
+        if !self.skipped.is_empty() {
+            lines.push(format!("{} tasks skipped", self.skipped.len()));
+        }
+        for output in &self.skipped {
+            lines.push(format!("  skipped: {:?}", output));
+        }
+        if self.stopped > 0 {
+            lines.push(format!("{} tasks stopped to pause the plan", self.stopped));
+        }
+        if !self.converted.is_empty() || !self.conversion_failed.is_empty() {
+            lines.push(format!(
+                "{} bands converted to COG, {} failed",
+                self.converted.len(),
+                self.conversion_failed.len()
+            ));
+        }
+        for (output, error) in &self.conversion_failed {
+            lines.push(format!("  not converted: {:?}: {}", output, error));
+        }
+        if !self.scenes.is_empty() {
+            let unusable = self.scenes.iter().filter(|s| !s.is_usable()).count();
+            lines.push(format!(
+                "{} scenes classified, {} likely unusable",
+                self.scenes.len(),
+                unusable
+            ));
+        }
+        for scene in &self.scenes {
+            lines.push(format!("  {}", scene));
+        }
+        if !self.failures.is_empty() {
+            let mut counts = BTreeMap::new();
+            for (_, failure) in &self.failures {
+                *counts.entry(failure).or_insert(0) += 1;
+            }
+            let counts: Vec<_> = counts
+                .iter()
+                .map(|(failure, count)| format!("{} {}", count, failure))
+                .collect();
+            lines.push(format!("Task failures: {}", counts.join(", ")));
+        }
+        if let Some(error) = &self.error {
+            lines.push(format!("Stopped by: {:#}", error));
+        }
+        lines.join("\n")
+    }
+
+    /// The summary as `--porcelain` records: `summary` with the counts of completed, stalled,
+    /// skipped, stopped, converted and unconverted tasks, then a record per task that didn't
+    /// complete, per scene scored and per failure
+    pub fn to_porcelain(self: &Self) -> String {
+        let mut lines = vec![porcelain::record(
+            "summary",
+            &[
+                &self.completed,
+                &self.stalled.len(),
+                &self.skipped.len(),
+                &self.stopped,
+                &self.converted.len(),
+                &self.conversion_failed.len(),
+            ],
+        )];
+        for output in &self.stalled {
+            lines.push(porcelain::record("stalled", &[&output.display()]));
+        }
+        for output in &self.skipped {
+            lines.push(porcelain::record("skipped", &[&output.display()]));
+        }
+        for (output, error) in &self.conversion_failed {
+            lines.push(porcelain::record(
+                "not_converted",
+                &[&output.display(), error],
+            ));
+        }
+        for scene in &self.scenes {
+            lines.push(porcelain::record(
+                "scene",
+                &[
+                    &scene.item_id,
+                    &scene.valid_pixels,
+                    &scene.cloud,
+                    &scene.shadow,
+                    &scene.snow,
+                    &scene.is_usable(),
+                ],
+            ));
+        }
+        for (output, failure) in &self.failures {
+            lines.push(porcelain::record("failure", &[&output.display(), failure]));
+        }
+        if let Some(error) = &self.error {
+            lines.push(porcelain::record("error", &[&format!("{:#}", error)]));
+        }
+        lines.join("\n")
+    }
+
+    pub fn print(self: &Self) {
+        match porcelain::enabled() {
+            true => println!("{}", self.to_porcelain()),
+            false => println!("{}", self.to_text()),
+        }
+    }
+
+    /// The error that stopped execution, or one reporting stalled tasks.
+    pub fn into_result(self) -> Result<Self> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.stopped > 0 {
+            return Err(Stopped.into());
+        }
+        if !self.stalled.is_empty() {
+            return Err(Incomplete {
+                completed: self.completed,
+                stalled: self.stalled.len(),
+            }
+            .into());
+        }
+        Ok(self)
+    }
+}
+
+/// Slows transfers down so a background download leaves room for calls and video on a shared
+/// connection. Combine with a single job so only one connection is open.
+#[derive(Debug, Clone, Default)]
+pub struct Pacing {
+    /// Average rate each transfer is held under
+    pub max_bytes_per_sec: Option<u64>,
+    /// Pause after every received chunk, smoothing out bursts
+    pub chunk_delay: Duration,
+}
+
+impl Pacing {
+    /// The `--nice` preset: a low rate cap and short pauses between chunks.
+    pub fn nice(max_bytes_per_sec: u64) -> Self {
+        Self {
+            max_bytes_per_sec: Some(max_bytes_per_sec),
+            chunk_delay: NICE_CHUNK_DELAY,
+        }
+    }
+
+    /// How long to pause after receiving `bytes` in `elapsed` since the transfer started.
+    pub(crate) fn pause(self: &Self, bytes: u64, elapsed: Duration) -> Duration {
+        let rate_pause = match self.max_bytes_per_sec {
+            Some(rate) => {
+                Duration::from_secs_f64(bytes as f64 / rate.max(1) as f64).saturating_sub(elapsed)
+            }
+            None => Duration::ZERO,
+        };
+        rate_pause + self.chunk_delay
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DownloadTask {
     bucket: String,
     key: String,
     output: String,
+    /// Size in bytes as published by the catalogue, if known at plan generation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Asset key (or product id) in the source catalogue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    asset_key: Option<String>,
+    /// Id of the STAC Item the asset belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    item_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    collection: Option<String>,
+    /// Acquisition datetime of the item (RFC 3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    datetime: Option<String>,
+    /// Product id originally requested when a substitute (e.g. another resolution) was planned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    substituted_for: Option<String>,
+    /// Checksum published by the catalogue, as `<algorithm>:<hex digest>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    /// S3 version of the object to download rather than whatever is current, so a provider
+    /// republishing the file doesn't change what the plan downloads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version_id: Option<String>,
+    /// Presigned URL that downloads the object without credentials (see `presign`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    /// When `url` expires, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url_expires: Option<u64>,
+    /// Download only the overviews of the COG (see `geotiff::download_overviews`)
+    #[serde(default, skip_serializing_if = "is_false")]
+    overviews_only: bool,
+}
+fn is_false(value: &bool) -> bool {
+    !value
 }
+
 impl DownloadTask {
     pub fn new(bucket: &str, key: &str, output: &str) -> Self {
         DownloadTask {
             bucket: bucket.to_string(),
             key: key.to_string(),
             output: output.to_string(),
+            size: None,
+            asset_key: None,
+            item_id: None,
+            collection: None,
+            datetime: None,
+            substituted_for: None,
+            checksum: None,
+            version_id: None,
+            url: None,
+            url_expires: None,
+            overviews_only: false,
+        }
+    }
+
+    pub fn with_size(self, size: Option<u64>) -> Self {
+        Self { size, ..self }
+    }
+
+    pub fn with_asset_key(self, asset_key: &str) -> Self {
+        Self {
+            asset_key: Some(asset_key.to_string()),
+            ..self
+        }
+    }
+
+    /// Record which STAC Item the task belongs to.
+    pub fn with_item(self, item: &Item) -> Self {
+        Self {
+            item_id: Some(item.id.clone()),
+            collection: item.collection.clone(),
+            datetime: item.properties.datetime.as_ref().map(|d| d.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_substituted_for(self, substituted_for: Option<String>) -> Self {
+        Self {
+            substituted_for,
+            ..self
+        }
+    }
+
+    pub fn with_checksum(self, checksum: Option<String>) -> Self {
+        Self { checksum, ..self }
+    }
+
+    pub fn with_version_id(self, version_id: Option<String>) -> Self {
+        Self { version_id, ..self }
+    }
+
+    /// Plan just the overviews of a GeoTIFF, written next to where the full file would go as
+    /// `<name>_overviews.tif`. Their size and checksum aren't published, so neither is recorded.
+    /// Other files are planned unchanged.
+    pub fn with_overviews_only(self, overviews_only: bool) -> Self {
+        let is_tiff = [".tif", ".tiff"]
+            .iter()
+            .any(|extension| self.key.to_lowercase().ends_with(extension));
+        if !overviews_only || !is_tiff {
+            return self;
+        }
+        let output = Path::new(&self.output);
+        let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+        let output = output.with_file_name(format!("{}_overviews.tif", stem));
+        Self {
+            output: output.to_string_lossy().to_string(),
+            size: None,
+            checksum: None,
+            overviews_only: true,
+            ..self
+        }
+    }
+
+    pub fn overviews_only(self: &Self) -> bool {
+        self.overviews_only
+    }
+
+    pub fn item_id(self: &Self) -> Option<&str> {
+        self.item_id.as_deref()
+    }
+
+    pub fn asset_key(self: &Self) -> Option<&str> {
+        self.asset_key.as_deref()
+    }
+
+    pub fn collection(self: &Self) -> Option<&str> {
+        self.collection.as_deref()
+    }
+
+    pub fn datetime(self: &Self) -> Option<&str> {
+        self.datetime.as_deref()
+    }
+
+    pub fn bucket(self: &Self) -> &str {
+        &self.bucket
+    }
+
+    pub fn key(self: &Self) -> &str {
+        &self.key
+    }
+
+    /// Output path, relative to the plan root when the plan has one
+    pub fn output(self: &Self) -> &str {
+        &self.output
+    }
+
+    pub fn size(self: &Self) -> Option<u64> {
+        self.size
+    }
+
+    pub fn checksum(self: &Self) -> Option<&str> {
+        self.checksum.as_deref()
+    }
+
+    pub fn version_id(self: &Self) -> Option<&str> {
+        self.version_id.as_deref()
+    }
+
+    /// The item id, or for plans written before tasks recorded it, the name of the parent
+    /// directory since tasks are written to `<output_dir>/<item id>/<file>`.
+    pub fn item(self: &Self) -> String {
+        if let Some(item_id) = &self.item_id {
+            return item_id.clone();
+        }
+        Path::new(&self.output)
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Number of files and summed size of a group of tasks, e.g. those belonging to one item
+#[derive(Debug, PartialEq, Eq)]
+pub struct GroupSize {
+    pub name: String,
+    pub files: usize,
+    pub bytes: u64,
+    /// Number of files whose size was not published and is missing from `bytes`
+    pub unknown: usize,
+}
+
+/// Serialization format of a plan file. TOML and YAML are easier to hand-edit, and NDJSON suits
+/// very large plans since it can be streamed and appended to. Anything other than a `.toml`,
+/// `.yaml`, `.yml`, `.ndjson` or `.jsonl` extension is treated as JSON.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PlanFormat {
+    Json,
+    Toml,
+    Yaml,
+    Ndjson,
+}
+
+impl PlanFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => PlanFormat::Toml,
+            Some("yaml") | Some("yml") => PlanFormat::Yaml,
+            Some("ndjson") | Some("jsonl") => PlanFormat::Ndjson,
+            _ => PlanFormat::Json,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DownloadPlan {
+    pub selection_id: String,
+    /// Directory that task outputs are relative to. Plans written before outputs were relative
+    /// have no root and store the full output path on each task.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    /// Provider the tasks were routed to by a probe, when it differs from the selection's own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// HMAC of the rest of the plan (see `provenance`), cleared when the plan is modified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
+    /// `[min lon, min lat, max lon, max lat]` the selection searched, which scenes are scored over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aoi_bbox: Option<[f64; 4]>,
+    tasks: Vec<DownloadTask>,
+}
+
+impl DownloadPlan {
+    pub fn new(selection_id: &str, tasks: Vec<DownloadTask>) -> Self {
+        Self {
+            selection_id: selection_id.to_string(),
+            root: None,
+            source: None,
+            signature: None,
+            provenance: None,
+            aoi_bbox: None,
+            tasks: dedup_tasks(tasks),
+        }
+    }
+
+    /// Set the directory task outputs are relative to, e.g. to re-root a plan that was prepared
+    /// on another machine.
+    pub fn with_root<P: AsRef<Path>>(self, root: P) -> Self {
+        Self {
+            root: Some(root.as_ref().to_string_lossy().to_string()),
+            ..self
+        }
+    }
+
+    pub fn root(self: &Self) -> Option<&str> {
+        self.root.as_deref()
+    }
+
+    pub fn source(self: &Self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// Bucket and key of the first task with a known size, used to probe sources.
+    pub fn sample_object(self: &Self) -> Option<(&str, &str)> {
+        self.tasks
+            .iter()
+            .find(|t| t.size.is_some())
+            .or(self.tasks.first())
+            .map(|t| (t.bucket.as_str(), t.key.as_str()))
+    }
+
+    /// Route every task to another source, mapping each bucket and key to its location there.
+    /// Fails without changing the plan if any object has no counterpart at the source.
+    pub fn routed_to<F>(self, source: &str, locate: F) -> Result<Self>
+    where
+        F: Fn(&str, &str) -> Option<(String, String)>,
+    {
+        let mut tasks = vec![];
+        for task in self.tasks {
+            let (bucket, key) = locate(&task.bucket, &task.key).ok_or(anyhow!(
+                "No location at {} for {}/{}",
+                source,
+                task.bucket,
+                task.key
+            ))?;
+            tasks.push(DownloadTask {
+                bucket,
+                key,
+                ..task
+            });
+        }
+        Ok(Self {
+            source: Some(source.to_string()),
+            signature: None,
+            tasks,
+            ..self
+        })
+    }
+
+    /// Keep only the tasks `keep` returns true for, in order.
+    pub fn retain_tasks<F>(self, mut keep: F) -> Self
+    where
+        F: FnMut(&DownloadTask) -> bool,
+    {
+        let tasks = self.tasks.into_iter().filter(|task| keep(task)).collect();
+        Self {
+            signature: None,
+            tasks,
+            ..self
+        }
+    }
+
+    pub fn with_provenance(self, provenance: Provenance) -> Self {
+        Self {
+            provenance: Some(provenance),
+            ..self
+        }
+    }
+
+    pub fn provenance(self: &Self) -> Option<&Provenance> {
+        self.provenance.as_ref()
+    }
+
+    pub fn with_aoi_bbox(self, aoi_bbox: Option<[f64; 4]>) -> Self {
+        Self { aoi_bbox, ..self }
+    }
+
+    pub fn aoi_bbox(self: &Self) -> Option<[f64; 4]> {
+        self.aoi_bbox
+    }
+
+    pub fn is_signed(self: &Self) -> bool {
+        self.signature.is_some()
+    }
+
+    /// Sign the plan with a key shared with its recipients. The signature covers the content rather
+    /// than the file, so converting the plan to another format keeps it valid.
+    pub fn with_signature(self, key: &[u8]) -> Result<Self> {
+        let signature = provenance::sign(&self.signed_content()?, key);
+        Ok(Self {
+            signature: Some(signature),
+            ..self
+        })
+    }
+
+    pub fn verify_signature(self: &Self, key: &[u8]) -> Result<()> {
+        let signature = self
+            .signature
+            .as_deref()
+            .ok_or(anyhow!("Plan is not signed"))?;
+        provenance::verify(&self.signed_content()?, key, signature)
+    }
+
+    /// The plan as compact JSON without its signature, independent of the file format
+    fn signed_content(self: &Self) -> Result<Vec<u8>> {
+        let content: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_value(serde_json::to_value(self)?)?;
+        // Filtered rather than removed, which may reorder the remaining keys
+        let content = content
+            .into_iter()
+            .filter(|(key, _)| key != "signature")
+            .collect::<serde_json::Map<_, _>>();
+        Ok(serde_json::to_vec(&content)?)
+    }
+
+    /// Sign a URL valid for `expires_in` for every task, so the plan can be executed without
+    /// credentials (see `presign::Provider`).
+    pub async fn presigned(self, provider: &impl S3ObjOps, expires_in: Duration) -> Result<Self> {
+        if let Some(source) = self.source.as_deref().filter(|s| *s != presign::SOURCE) {
+            return Err(anyhow!(
+                "Plan has been routed to {}, only plans for the selection's own source can be presigned",
+                source
+            ));
+        }
+        let (plan, _) = self.signed(provider, expires_in, |_| true).await?;
+        Ok(plan)
+    }
+
+    /// Pin each task to the version of its object current now, as its HEAD response reports it, so
+    /// the plan downloads the same objects however long it waits. Returns the plan and the number
+    /// of tasks pinned; objects in buckets without versioning are left unpinned. A signature is
+    /// dropped, as it no longer matches.
+    pub async fn pinned(self, provider: &impl S3ObjOps) -> Result<(Self, usize)> {
+        if let Some(source) = self.source() {
+            return Err(anyhow!(
+                "Plan has been routed to {}, only plans for the selection's own source can be pinned",
+                source
+            ));
+        }
+        let mut tasks = vec![];
+        let mut pinned = 0;
+        for task in self.tasks {
+            if task.version_id.is_some() {
+                tasks.push(task);
+                continue;
+            }
+            let head = provider.head_object(&task.bucket, &task.key).await?;
+            // S3 reports the version of objects written before versioning was enabled as "null"
+            let version_id = head.version_id().filter(|version_id| *version_id != "null");
+            pinned += version_id.is_some() as usize;
+            tasks.push(task.with_version_id(version_id.map(str::to_string)));
+        }
+        let plan = Self {
+            signature: None,
+            tasks,
+            ..self
+        };
+        Ok((plan, pinned))
+    }
+
+    /// The plan without the presigned URLs of its tasks, e.g. for copies leaving the machine. A
+    /// signature is dropped with them, as it no longer matches.
+    pub fn without_urls(self) -> Self {
+        if self.tasks.iter().all(|task| task.url.is_none()) {
+            return self;
+        }
+        let tasks = self
+            .tasks
+            .into_iter()
+            .map(|task| DownloadTask {
+                url: None,
+                url_expires: None,
+                ..task
+            })
+            .collect();
+        Self {
+            signature: None,
+            tasks,
+            ..self
+        }
+    }
+
+    /// Re-sign the tasks of a presigned plan whose URLs expire within `margin`, returning the plan
+    /// and the number of tasks re-signed.
+    pub async fn refreshed(
+        self,
+        provider: &impl S3ObjOps,
+        expires_in: Duration,
+        margin: Duration,
+    ) -> Result<(Self, usize)> {
+        if self.source() != Some(presign::SOURCE) {
+            return Err(anyhow!("Plan is not presigned"));
+        }
+        let cutoff = presign::now() + margin.as_secs();
+        self.signed(provider, expires_in, |task| {
+            task.url_expires.map_or(true, |expires| expires <= cutoff)
+        })
+        .await
+    }
+
+    async fn signed<F>(
+        self,
+        provider: &impl S3ObjOps,
+        expires_in: Duration,
+        needs_signing: F,
+    ) -> Result<(Self, usize)>
+    where
+        F: Fn(&DownloadTask) -> bool,
+    {
+        let mut tasks = vec![];
+        let mut signed = 0;
+        for task in self.tasks {
+            if !needs_signing(&task) {
+                tasks.push(task);
+                continue;
+            }
+            let version_id = task.version_id.as_deref();
+            let url = provider
+                .presign_get_version(&task.bucket, &task.key, version_id, expires_in)
+                .await?;
+            tasks.push(DownloadTask {
+                url: Some(url),
+                url_expires: Some(presign::now() + expires_in.as_secs()),
+                ..task
+            });
+            signed += 1;
+        }
+        let plan = Self {
+            source: Some(presign::SOURCE.to_string()),
+            signature: None,
+            tasks,
+            ..self
+        };
+        Ok((plan, signed))
+    }
+
+    /// The presigned URL of each task by bucket, key and version, failing if any is missing or
+    /// expired.
+    pub fn presigned_urls(self: &Self) -> Result<HashMap<presign::ObjectVersion, String>> {
+        let now = presign::now();
+        let mut urls = HashMap::new();
+        let mut expired = 0;
+        for task in &self.tasks {
+            match (&task.url, task.url_expires) {
+                (Some(_), Some(expires)) if expires <= now => expired += 1,
+                (Some(url), _) => {
+                    let object = (
+                        task.bucket.clone(),
+                        task.key.clone(),
+                        task.version_id.clone(),
+                    );
+                    urls.insert(object, url.clone());
+                }
+                (None, _) => {
+                    return Err(anyhow!("{}/{} has no presigned URL", task.bucket, task.key))
+                }
+            }
+        }
+        if expired > 0 {
+            return Err(anyhow!(
+                "{} presigned URLs have expired, run `plan refresh` on a machine with credentials",
+                expired
+            ));
+        }
+        Ok(urls)
+    }
+
+    /// Where a task's output is written, after joining it onto the plan root.
+    pub fn output_path(self: &Self, task: &DownloadTask) -> PathBuf {
+        match &self.root {
+            Some(root) => Path::new(root).join(&task.output),
+            None => PathBuf::from(&task.output),
+        }
+    }
+
+    /// Whether a task has already downloaded, including files since stacked or uploaded
+    pub fn is_done(self: &Self, task: &DownloadTask) -> bool {
+        let output = self.output_path(task);
+        output.exists() || stack::was_stacked(&output) || upload::was_uploaded(&output)
+    }
+
+    /// Append the tasks of another plan for the same selection, dropping duplicates.
+    pub fn merge(self, other: DownloadPlan) -> Result<Self> {
+        if self.selection_id != other.selection_id {
+            return Err(anyhow!(
+                "Cannot merge plans for different selections: {} and {}",
+                self.selection_id,
+                other.selection_id
+            ));
+        }
+        if self.root != other.root {
+            return Err(anyhow!("Cannot merge plans with different output roots"));
+        }
+        if self.source != other.source {
+            return Err(anyhow!("Cannot merge plans routed to different sources"));
+        }
+        let mut tasks = self.tasks;
+        tasks.extend(other.tasks);
+        Ok(Self {
+            root: self.root,
+            source: self.source,
+            aoi_bbox: self.aoi_bbox.or(other.aoi_bbox),
+            ..Self::new(&self.selection_id, tasks)
+        })
+    }
+
+    /// Read a plan, choosing the format from the file extension (see `PlanFormat`).
+    #[allow(dead_code)]
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if plan_crypt::is_encrypted(path) {
+            let content = plan_crypt::decrypt(&fs::read(path)?, plan_crypt::passphrase()?)?;
+            return Self::parse(&plan_crypt::inner_path(path), &content);
+        }
+        if PlanFormat::from_path(path) == PlanFormat::Ndjson {
+            return Self::read_ndjson(BufReader::new(fs::File::open(path)?));
+        }
+        Self::parse(path, &fs::read(path)?)
+    }
+
+    fn parse(path: &Path, content: &[u8]) -> Result<Self> {
+        let plan: Self = match PlanFormat::from_path(path) {
+            PlanFormat::Json => serde_json::from_slice(content)?,
+            PlanFormat::Toml => toml::from_str(std::str::from_utf8(content)?)?,
+            PlanFormat::Yaml => serde_yaml::from_slice(content)?,
+            PlanFormat::Ndjson => Self::read_ndjson(content)?,
+        };
+        Ok(plan)
+    }
+
+    /// Write a plan, choosing the format from the file extension (see `PlanFormat`). Plans written
+    /// to a `.age` path are encrypted with a passphrase (see `plan_crypt`).
+    pub fn write<P: AsRef<Path>>(self: &Self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let content = self.to_string(&plan_crypt::inner_path(path))?;
+        if plan_crypt::is_encrypted(path) {
+            let ciphertext = plan_crypt::encrypt(content.as_bytes(), plan_crypt::passphrase()?)?;
+            fs::write(path, ciphertext)?;
+            return Ok(());
+        }
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    fn to_string(self: &Self, path: &Path) -> Result<String> {
+        let content = match PlanFormat::from_path(path) {
+            PlanFormat::Json => serde_json::to_string_pretty(self)?,
+            PlanFormat::Toml => toml::to_string_pretty(self)?,
+            PlanFormat::Yaml => serde_yaml::to_string(self)?,
+            PlanFormat::Ndjson => {
+                let mut lines = vec![serde_json::to_string(&PlanHeader {
+                    selection_id: self.selection_id.clone(),
+                    root: self.root.clone(),
+                    source: self.source.clone(),
+                    signature: self.signature.clone(),
+                    provenance: self.provenance.clone(),
+                    aoi_bbox: self.aoi_bbox,
+                })?];
+                for task in self.tasks.iter() {
+                    lines.push(serde_json::to_string(task)?);
+                }
+                lines.join("\n") + "\n"
+            }
+        };
+        Ok(content)
+    }
+
+    /// Whether any task's URL carries temporary credentials, which encrypting the plan protects
+    pub fn has_credentials(self: &Self) -> bool {
+        self.tasks
+            .iter()
+            .any(|task| task.url.is_some() || plan_crypt::has_credentials(&task.key))
+    }
+
+    /// NDJSON plans are a header line followed by one task per line. A task appended later
+    /// replaces an earlier line for the same object and output, so updates never rewrite the file.
+    fn read_ndjson(reader: impl BufRead) -> Result<Self> {
+        let mut lines = reader.lines();
+        let header = lines.next().ok_or(anyhow!("Plan file is empty"))??;
+        let header: PlanHeader = serde_json::from_str(&header)?;
+
+        let mut tasks: Vec<DownloadTask> = vec![];
+        let mut index: HashMap<(String, String, String), usize> = HashMap::new();
+        for task in stream_ndjson_tasks(lines) {
+            let task = task?;
+            let id = (task.bucket.clone(), task.key.clone(), task.output.clone());
+            match index.get(&id) {
+                Some(&i) => tasks[i] = task,
+                None => {
+                    index.insert(id, tasks.len());
+                    tasks.push(task);
+                }
+            }
+        }
+        Ok(Self {
+            selection_id: header.selection_id,
+            root: header.root,
+            source: header.source,
+            signature: header.signature,
+            provenance: header.provenance,
+            aoi_bbox: header.aoi_bbox,
+            tasks,
+        })
+    }
+
+    /// Summarise the plan per item, in the order items first appear in the plan.
+    pub fn size_by_item(self: &Self) -> Vec<GroupSize> {
+        self.size_by(|task| task.item())
+    }
+
+    /// Summarise the plan per product (asset key), in the order products first appear in the plan.
+    pub fn size_by_product(self: &Self) -> Vec<GroupSize> {
+        self.size_by(|task| task.asset_key.clone().unwrap_or("unknown".to_string()))
+    }
+
+    fn size_by<F: Fn(&DownloadTask) -> String>(self: &Self, group: F) -> Vec<GroupSize> {
+        let mut groups: Vec<GroupSize> = vec![];
+        for task in self.tasks.iter() {
+            let name = group(task);
+            let index = match groups.iter().position(|g| g.name == name) {
+                Some(index) => index,
+                None => {
+                    groups.push(GroupSize {
+                        name,
+                        files: 0,
+                        bytes: 0,
+                        unknown: 0,
+                    });
+                    groups.len() - 1
+                }
+            };
+            let entry = &mut groups[index];
+            entry.files += 1;
+            match task.size {
+                Some(size) => entry.bytes += size,
+                None => entry.unknown += 1,
+            }
+        }
+        groups
+    }
+
+    /// Summarise the plan per bucket, in the order buckets first appear in the plan.
+    pub fn size_by_bucket(self: &Self) -> Vec<GroupSize> {
+        self.size_by(|task| task.bucket.clone())
+    }
+
+    /// Count tasks per size range, with tasks of unknown size counted last.
+    pub fn size_histogram(self: &Self) -> Vec<(&'static str, usize)> {
+        let mut counts = HISTOGRAM_BINS
+            .iter()
+            .map(|(label, _)| (*label, 0))
+            .collect::<Vec<_>>();
+        let mut unknown = 0;
+        for task in self.tasks.iter() {
+            match task.size {
+                Some(size) => {
+                    let bin = HISTOGRAM_BINS
+                        .iter()
+                        .position(|(_, upper)| size < *upper)
+                        .unwrap_or(HISTOGRAM_BINS.len() - 1);
+                    counts[bin].1 += 1;
+                }
+                None => unknown += 1,
+            }
+        }
+        counts.push(("unknown", unknown));
+        counts
+    }
+
+    /// The `n` largest tasks with a published size, largest first.
+    pub fn largest_tasks(self: &Self, n: usize) -> Vec<&DownloadTask> {
+        let mut tasks = self
+            .tasks
+            .iter()
+            .filter(|t| t.size.is_some())
+            .collect::<Vec<_>>();
+        tasks.sort_by(|a, b| b.size.cmp(&a.size));
+        tasks.truncate(n);
+        tasks
+    }
+
+    /// Print task counts and sizes per item, product and bucket, a size histogram and the largest
+    /// files in the plan.
+    pub fn print_stats(self: &Self) {
+        if porcelain::enabled() {
+            println!("{}", self.stats_porcelain());
+            return;
+        }
+        println!("Selection: {}", self.selection_id);
+        println!("Tasks: {}\n", self.tasks.len());
+        println!("{}", size_table("Item", &self.size_by_item()));
+        println!("{}", size_table("Product", &self.size_by_product()));
+        println!("{}", size_table("Bucket", &self.size_by_bucket()));
+
+        println!("{:<12}  {:>5}", "Size range", "Files");
+        for (label, count) in self.size_histogram() {
+            println!("{:<12}  {:>5}", label, count);
+        }
+
+        let substituted = self
+            .tasks
+            .iter()
+            .filter(|t| t.substituted_for.is_some())
+            .collect::<Vec<_>>();
+        if !substituted.is_empty() {
+            println!("\nSubstituted products");
+            for task in substituted {
+                println!(
+                    "{}: {} -> {}",
+                    task.item(),
+                    task.substituted_for.as_deref().unwrap_or_default(),
+                    task.asset_key.as_deref().unwrap_or_default()
+                );
+            }
+        }
+
+        let datatakes = datatake::split_datatakes(self);
+        if !datatakes.is_empty() {
+            println!("\n{}", datatake::to_text(&datatakes));
+        }
+
+        println!("\nLargest files");
+        for task in self.largest_tasks(LARGEST_TASK_COUNT) {
+            println!(
+                "{:>12}  {}",
+                units::size(task.size.unwrap_or(0)),
+                task.output
+            );
+        }
+    }
+
+    /// The stats as `--porcelain` records
+    pub fn stats_porcelain(self: &Self) -> String {
+        let mut lines = vec![
+            porcelain::record("selection", &[&self.selection_id]),
+            porcelain::record("tasks", &[&self.tasks.len()]),
+        ];
+        lines.extend(size_records("item", &self.size_by_item()));
+        lines.extend(size_records("product", &self.size_by_product()));
+        lines.extend(size_records("bucket", &self.size_by_bucket()));
+        for (label, count) in self.size_histogram() {
+            lines.push(porcelain::record("histogram", &[&label, &count]));
+        }
+        for task in self.tasks.iter() {
+            if let Some(substituted_for) = &task.substituted_for {
+                let asset_key = porcelain::optional(task.asset_key.as_ref());
+                lines.push(porcelain::record(
+                    "substituted",
+                    &[&task.item(), substituted_for, &asset_key],
+                ));
+            }
+        }
+        for datatake in datatake::split_datatakes(self) {
+            lines.push(porcelain::record(
+                "datatake",
+                &[
+                    &datatake.name(),
+                    &datatake.tiles.join(","),
+                    &datatake.items.join(","),
+                ],
+            ));
+        }
+        for task in self.largest_tasks(LARGEST_TASK_COUNT) {
+            let size = porcelain::optional(task.size);
+            lines.push(porcelain::record("largest", &[&size, &task.output]));
+        }
+        lines.join("\n")
+    }
+
+    /// Tasks yet to download, with the bytes still missing from any partial download
+    pub fn remaining(self: &Self) -> GroupSize {
+        let mut remaining = GroupSize {
+            name: "Remaining".to_string(),
+            files: 0,
+            bytes: 0,
+            unknown: 0,
+        };
+        for task in self.tasks.iter() {
+            if self.is_done(task) {
+                continue;
+            }
+            remaining.files += 1;
+            let output = self.output_path(task);
+            let partial = PathBuf::from(format!("{}.partial", output.to_string_lossy()));
+            let received = fs::metadata(&partial).map_or(0, |metadata| metadata.len());
+            match task.size {
+                Some(size) => remaining.bytes += size.saturating_sub(received),
+                None => remaining.unknown += 1,
+            }
+        }
+        remaining
+    }
+
+    /// Number of tasks in the plan
+    pub fn task_count(self: &Self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Summed size of all tasks with a published size
+    pub fn total_bytes(self: &Self) -> u64 {
+        self.tasks.iter().filter_map(|t| t.size).sum()
+    }
+
+    /// Print a table of each item with the summed size of its selected products.
+    pub fn print_preview(self: &Self) {
+        match porcelain::enabled() {
+            true => println!("{}", size_records("item", &self.size_by_item()).join("\n")),
+            false => {
+                print!("{}", size_table("Item", &self.size_by_item()));
+                let datatakes = datatake::to_text(&datatake::split_datatakes(self));
+                if !datatakes.is_empty() {
+                    println!("{}", datatakes);
+                }
+            }
+        }
+    }
+
+    /// Fail with a breakdown by item and product if the plan is larger than `max_total_bytes`.
+    pub fn check_size_limit(self: &Self, max_total_bytes: u64) -> Result<()> {
+        let total = self.total_bytes();
+        if total <= max_total_bytes {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "Plan size of {} exceeds the limit of {} set by max_total_bytes\n\n{}\n{}",
+            units::size(total),
+            units::size(max_total_bytes),
+            size_table("Item", &self.size_by_item()),
+            size_table("Product", &self.size_by_product()),
+        ))
+    }
+
+    pub async fn execute(self: &Self, provider: &impl S3ObjOps) -> Result<()> {
+        self.execute_with(provider, &DownloadOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    /// Execute the plan, printing the summary and returning an error if any task failed or stalled.
+    pub async fn execute_with(
+        self: &Self,
+        provider: &impl S3ObjOps,
+        options: &DownloadOptions,
+    ) -> Result<ExecutionSummary> {
+        let summary = self.execute_summarized(provider, options).await;
+        summary.print();
+        summary.into_result()
+    }
+
+    /// Execute the plan, recording rather than returning the error that stops it, e.g. to report
+    /// the outcome before exiting.
+    #[instrument(skip_all, fields(plan = %self.selection_id, tasks = self.tasks.len()))]
+    pub async fn execute_summarized(
+        self: &Self,
+        provider: &impl S3ObjOps,
+        options: &DownloadOptions,
+    ) -> ExecutionSummary {
+        let deadline = options
+            .timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+        let mut summary = ExecutionSummary::default();
+        // Tasks left per item directory, to score the scene and fire the item hooks once the last
+        // one completes
+        let mut remaining: HashMap<PathBuf, (CompletedItem, usize)> = HashMap::new();
+        for task in &self.tasks {
+            let dir = self.output_path(task).parent().map(Path::to_path_buf);
+            let dir = dir.unwrap_or_default();
+            let item = CompletedItem {
+                selection_id: self.selection_id.clone(),
+                item_id: task.item(),
+                dir: dir.clone(),
+                files: vec![],
+            };
+            let (item, left) = remaining.entry(dir).or_insert((item, 0));
+            // In plan order whatever order the tasks complete in, e.g. for stacking bands
+            let output = self.output_path(task);
+            let file_name = output.file_name().unwrap_or_default();
+            let asset_key = task.asset_key.clone();
+            let asset_key = asset_key.unwrap_or(file_name.to_string_lossy().to_string());
+            item.files.push((asset_key, output.clone()));
+            *left += 1;
+        }
+        let has_scl = self
+            .tasks
+            .iter()
+            .any(|task| task.asset_key().is_some_and(scl::is_scl));
+        let scoring = has_scl && scl::is_available().await;
+        let mut completed_items = vec![];
+        // One conversion at a time, GDAL already uses several threads
+        let conversion_permit = std::sync::Arc::new(tokio::sync::Semaphore::new(1));
+        // By item directory, so an item's hooks (e.g. uploading and removing its files) wait for
+        // its conversions
+        let mut conversions: HashMap<PathBuf, Vec<_>> = HashMap::new();
+        let provider = &HeadCache::new(provider, options.head_cache_ttl);
+        let mut results = std::pin::pin!(self.execute_stream(provider, options));
+        let mut checkpointed = Instant::now();
+        let mut pushing: Option<JoinHandle<()>> = None;
+        loop {
+            let next = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, results.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        summary.error = Some(TimedOut(options.timeout.unwrap_or_default()).into());
+                        break;
+                    }
+                },
+                None => results.next().await,
+            };
+            let Some(result) = next else { break };
+            match result.outcome {
+                Ok(()) => {
+                    tracing::info!(output = ?result.output, "Task completed");
+                    summary.completed += 1;
+                    let dir = result.output.parent().map(Path::to_path_buf);
+                    let dir = dir.unwrap_or_default();
+                    if let Some(cog) = &options.cog {
+                        let cog = cog.clone();
+                        let output = result.output.clone();
+                        let permit = conversion_permit.clone();
+                        conversions
+                            .entry(dir.clone())
+                            .or_default()
+                            .push(tokio::spawn(async move {
+                                let _permit = permit.acquire_owned().await;
+                                let converted = cog.convert(&output).await;
+                                (output, converted)
+                            }));
+                    }
+                    if let Some((item, left)) = remaining.get_mut(&dir) {
+                        *left -= 1;
+                        if *left == 0 {
+                            // Run alongside the remaining downloads rather than holding them up
+                            let item_hooks = options.item_hooks.clone();
+                            let item = item.clone();
+                            let aoi_bbox = self.aoi_bbox;
+                            let item_conversions = conversions.remove(&dir).unwrap_or_default();
+                            completed_items.push(tokio::spawn(async move {
+                                let mut converted = vec![];
+                                for conversion in item_conversions {
+                                    converted.push(conversion.await);
+                                }
+                                // Before the hooks, which may stack and remove the SCL band
+                                let scl = scl::scl_file(&item.files)
+                                    .filter(|path| scoring && path.exists());
+                                let score = match scl {
+                                    Some(scl) => Some(
+                                        scl::score(&item.item_id, scl, aoi_bbox).await.map_err(
+                                            |e| anyhow!("Could not score {}: {}", item.item_id, e),
+                                        ),
+                                    ),
+                                    None => None,
+                                };
+                                let fired =
+                                    hooks::fire_all(&item_hooks, &item).await.map_err(|e| {
+                                        anyhow!("Hook for {} failed: {:?}", item.item_id, e)
+                                    });
+                                (converted, score, fired)
+                            }));
+                        }
+                    }
+                }
+                Err(e) if e.is::<Skipped>() => {
+                    println!("Skipped {:?}", result.output);
+                    summary.skipped.push(result.output);
+                }
+                Err(e) if e.is::<Stopped>() => summary.stopped += 1,
+                // Stalled tasks keep their partial file, move on and report them at the end
+                Err(e) if is_stalled(&e) => {
+                    tracing::warn!(output = ?result.output, error = %e, "Task stalled");
+                    println!("Warning: {:?} {}", result.output, e);
+                    summary
+                        .failures
+                        .push((result.output.clone(), TaskFailure::classify(&e)));
+                    summary.stalled.push(result.output);
+                }
+                Err(e) => {
+                    let failure = TaskFailure::classify(&e);
+                    tracing::error!(output = ?result.output, error = %e, %failure, "Task failed");
+                    summary.failures.push((result.output, failure));
+                    summary.error = Some(e);
+                    break;
+                }
+            }
+            // One push at a time, in the background so the downloads carry on meanwhile
+            if options.push_state.is_some()
+                && checkpointed.elapsed() >= checkpoint::CHECKPOINT_INTERVAL
+                && pushing.as_ref().map_or(true, |push| push.is_finished())
+            {
+                pushing = self.spawn_push_state(options);
+                checkpointed = Instant::now();
+            }
+        }
+        // Of items that didn't complete
+        for conversion in conversions.into_values().flatten() {
+            summary.record_conversion(conversion.await);
+        }
+        // A failing hook is reported but doesn't fail the plan, the files are all there
+        for completed_item in completed_items {
+            match completed_item.await {
+                Ok((converted, score, fired)) => {
+                    for conversion in converted {
+                        summary.record_conversion(conversion);
+                    }
+                    match score {
+                        Some(Ok(score)) => summary.scenes.push(score),
+                        Some(Err(e)) => println!("Warning: {}", e),
+                        None => {}
+                    }
+                    if let Err(e) = fired {
+                        println!("Warning: {}", e);
+                    }
+                }
+                Err(e) => println!("Warning: hook did not finish: {}", e),
+            }
+        }
+        // After the hooks, which may stack or move the band files
+        if options.timeseries {
+            match timeseries::write(self, &options.item_hooks) {
+                Ok(path) => println!("Wrote the time-series manifest to {:?}", path),
+                Err(e) => println!("Warning: could not write the time-series manifest: {}", e),
+            }
+        }
+        if options.merge_datatakes {
+            match datatake::build_vrts(self, &datatake::split_datatakes(self)).await {
+                Ok(vrts) => println!("Wrote {} datatake mosaic(s)", vrts.len()),
+                Err(e) => println!("Warning: could not mosaic the datatakes: {}", e),
+            }
+        }
+        // The last state is pushed after any earlier one
+        if let Some(pushing) = pushing {
+            let _ = pushing.await;
+        }
+        if let Some(pushing) = self.spawn_push_state(options) {
+            let _ = pushing.await;
+        }
+        summary
+    }
+
+    /// Push the plan's state to `options.push_state` as it is now from a task of its own, warning
+    /// rather than failing the plan when it can't be pushed
+    fn spawn_push_state(self: &Self, options: &DownloadOptions) -> Option<JoinHandle<()>> {
+        let push_state = options.push_state.clone()?;
+        let state = PlanState::new(self, &push_state.plan_file, options.skip_list.as_ref());
+        Some(tokio::spawn(async move {
+            let pushed = match state {
+                Ok(state) => checkpoint::push_state(&state, &push_state).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = pushed {
+                println!("Warning: could not push the plan's state: {}", e);
+            }
+        }))
+    }
+
+    /// Tasks in the order they are executed.
+    pub fn ordered_tasks(self: &Self, order: TaskOrder) -> Vec<&DownloadTask> {
+        let mut tasks: Vec<&DownloadTask> = self.tasks.iter().collect();
+        if order == TaskOrder::ItemSmallestFirst {
+            // Items by first appearance in the plan
+            let mut items: HashMap<String, usize> = HashMap::new();
+            for task in &self.tasks {
+                let next = items.len();
+                items.entry(task.item()).or_insert(next);
+            }
+            // Stable, so files of the same size keep their plan order
+            tasks.sort_by_key(|task| (items[&task.item()], task.size.is_none(), task.size));
+        }
+        tasks
+    }
+
+    /// Execute `jobs` tasks at a time, yielding each result in `options.order` as it completes so
+    /// callers can start processing a file while the rest of the plan downloads. A failed task
+    /// does not stop the stream; stop polling it to abandon the remaining tasks.
+    pub fn execute_stream<'a, P: S3ObjOps>(
+        self: &'a Self,
+        provider: &'a P,
+        options: &'a DownloadOptions,
+    ) -> impl Stream<Item = TaskResult> + 'a {
+        let span = tracing::info_span!("execute_stream", plan = %self.selection_id);
+        // Boxed so the nested provider, download and span futures don't inflate the layout of
+        // every caller's future
+        stream::iter(self.ordered_tasks(options.order))
+            .map(move |task| {
+                let run = async move {
+                    let _slot = match &options.control {
+                        Some(control) => Some(control.slot().await),
+                        None => None,
+                    };
+                    self.run_task(provider, task, options).await
+                };
+                Box::pin(run.instrument(span.clone()))
+            })
+            .buffered(options.jobs.max(1))
+    }
+
+    /// `attempt` counts the downloads of the task from the first byte, see `verify_with_restarts`
+    #[instrument(
+        skip_all,
+        fields(item_id = task.item_id, asset_key = task.asset_key, key = %task.key, attempt = 1)
+    )]
+    async fn run_task(
+        self: &Self,
+        provider: &impl S3ObjOps,
+        task: &DownloadTask,
+        options: &DownloadOptions,
+    ) -> TaskResult {
+        println!("Current task: {:?}", task);
+        let output = self.output_path(task);
+        let id = control::task_id(&task.bucket, &task.key);
+        let run = async {
+            if let Some(skip_list) = &options.skip_list {
+                if skip_list.contains(&task.item()) {
+                    println!("{} is in {:?}", task.item(), skip_list.path());
+                    return Err(Skipped.into());
+                }
+            }
+            if let Some(control) = &options.control {
+                control.wait_while_paused().await;
+                if control.is_stopped() {
+                    return Err(Stopped.into());
+                }
+                if control.is_skipped(&id) {
+                    return Err(Skipped.into());
+                }
+                control.start(&id, &output, task.size);
+            }
+            if !output.exists() && stack::was_stacked(&output) {
+                println!("Already stacked into the item's multiband GeoTIFF");
+                return Ok(None);
+            }
+            if !output.exists() && upload::was_uploaded(&output) {
+                println!("Already uploaded and removed");
+                return Ok(None);
+            }
+            if task.overviews_only {
+                if !output.exists() {
+                    geotiff::download_overviews(
+                        provider,
+                        &task.bucket,
+                        &task.key,
+                        geotiff::DEFAULT_HEADER_BYTES,
+                        &output,
+                        options,
+                    )
+                    .await?;
+                }
+                return Ok(None);
+            }
+            download_task(provider, task, &output, options).await?;
+            if !options.verify {
+                return Ok(None);
+            }
+            let level = verify_with_restarts(provider, task, &output, options).await?;
+            println!("Verified {} ({:?})", task.key, level);
+            Ok(Some(level))
+        };
+        let outcome = match options.task_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, run).await {
+                Ok(outcome) => outcome,
+                Err(_) => Err(Stalled(format!("task did not finish within {:?}", timeout)).into()),
+            },
+            None => run.await,
+        };
+        let (outcome, verification) = match outcome {
+            Ok(verification) => (Ok(()), verification),
+            Err(e) => (Err(e), None),
+        };
+        if let Some(control) = &options.control {
+            control.finish(&id, &outcome);
+        }
+        TaskResult {
+            bucket: task.bucket.clone(),
+            key: task.key.clone(),
+            output,
+            item_id: task.item_id.clone(),
+            asset_key: task.asset_key.clone(),
+            outcome,
+            verification,
+        }
+    }
+
+    pub fn tasks(self: &Self) -> &[DownloadTask] {
+        &self.tasks
+    }
+
+    /// Output paths of all tasks, in plan order.
+    pub fn output_paths(self: &Self) -> Vec<PathBuf> {
+        self.tasks.iter().map(|t| self.output_path(t)).collect()
+    }
+
+    /// Print which tasks are complete and whether each partial download can be resumed, e.g.
+    /// after copying the output directory to another machine. Returns the number of partial
+    /// downloads that will restart.
+    pub fn check_partials(self: &Self) -> Result<usize> {
+        let (mut complete, mut resumable, mut restart) = (0, 0, 0);
+        for task in &self.tasks {
+            let output = self.output_path(task);
+            let partial = PathBuf::from(format!("{}.partial", output.to_string_lossy()));
+            if output.exists() {
+                complete += 1;
+                continue;
+            }
+            if !partial.exists() {
+                continue;
+            }
+            let state = PartialState::read(&partial)?;
+            // The remote ETag is only compared when the download resumes
+            let object_mismatch = state.as_ref().and_then(|state| {
+                let size = task.size.unwrap_or(state.size);
+                state.object_mismatch(&task.bucket, &task.key, None, size)
+            });
+            let check = match object_mismatch {
+                Some(reason) => PartialCheck::Restart(reason),
+                None => partial::check(&partial)?,
+            };
+            match check {
+                PartialCheck::Resume(_) if porcelain::enabled() => {
+                    resumable += 1;
+                    let received = fs::metadata(&partial)?.len();
+                    let size = porcelain::optional(state.map(|state| state.size).or(task.size));
+                    println!(
+                        "{}",
+                        porcelain::record("resumable", &[&output.display(), &received, &size])
+                    );
+                }
+                PartialCheck::Restart(reason) if porcelain::enabled() => {
+                    restart += 1;
+                    println!(
+                        "{}",
+                        porcelain::record("restart", &[&output.display(), &reason])
+                    );
+                }
+                PartialCheck::Resume(_) => {
+                    resumable += 1;
+                    let received = fs::metadata(&partial)?.len();
+                    match state.map(|state| state.size).or(task.size) {
+                        Some(size) => println!(
+                            "{:?} resumable from {} of {}",
+                            output,
+                            units::size(received),
+                            units::size(size)
+                        ),
+                        None => println!("{:?} resumable from {}", output, units::size(received)),
+                    }
+                }
+                PartialCheck::Restart(reason) => {
+                    restart += 1;
+                    println!("Warning: {:?} will restart: {}", output, reason);
+                }
+            }
+        }
+        match porcelain::enabled() {
+            true => println!(
+                "{}",
+                porcelain::record(
+                    "partials",
+                    &[&complete, &self.tasks.len(), &resumable, &restart]
+                )
+            ),
+            false => println!(
+                "{} of {} tasks complete, {} partial downloads resumable, {} will restart",
+                complete,
+                self.tasks.len(),
+                resumable,
+                restart
+            ),
+        }
+        Ok(restart)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct PlanHeader {
+    selection_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aoi_bbox: Option<[f64; 4]>,
+}
+
+/// File name a remote plan is saved as: the last segment of the URL path, e.g. `plan.json.age`
+fn remote_plan_file_name(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|segments| segments.last())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("download_plan.json")
+        .to_string()
+}
+
+/// Download a plan prepared elsewhere, e.g. `https://example.org/plans/plan.json`, into `dir` so
+/// it can be executed (and resumed) like a local plan. An existing copy is replaced, so the latest
+/// version of the plan is always run.
+pub async fn fetch_plan(url: &str, dir: &Path) -> Result<PathBuf> {
+    let url = Url::parse(url)?;
+    let path = dir.join(remote_plan_file_name(&url));
+    println!("Fetching plan from {}", url);
+    let content = user_agent::get(url)
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    fs::create_dir_all(dir)?;
+    fs::write(&path, content)?;
+    Ok(path)
+}
+
+/// Stream the tasks of an NDJSON plan without loading the whole file, skipping the header line.
+/// Tasks that were updated by appending are yielded once per line.
+pub fn stream_tasks<P: AsRef<Path>>(path: P) -> Result<impl Iterator<Item = Result<DownloadTask>>> {
+    if plan_crypt::is_encrypted(path.as_ref()) {
+        return Err(anyhow!(
+            "Encrypted plans cannot be streamed, read them whole"
+        ));
+    }
+    let lines = BufReader::new(fs::File::open(path)?).lines().skip(1);
+    Ok(stream_ndjson_tasks(lines))
+}
+
+fn stream_ndjson_tasks(
+    lines: impl Iterator<Item = std::io::Result<String>>,
+) -> impl Iterator<Item = Result<DownloadTask>> {
+    lines
+        .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str::<DownloadTask>(&line?)?))
+}
+
+/// Append a task to an NDJSON plan, adding it or replacing an earlier line for the same task.
+pub fn append_task<P: AsRef<Path>>(path: P, task: &DownloadTask) -> Result<()> {
+    if plan_crypt::is_encrypted(path.as_ref()) {
+        return Err(anyhow!("Tasks cannot be appended to an encrypted plan"));
+    }
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(task)?)?;
+    Ok(())
+}
+
+/// Remove tasks that download the same object to the same output, keeping the first occurrence.
+fn dedup_tasks(tasks: Vec<DownloadTask>) -> Vec<DownloadTask> {
+    let mut seen = HashSet::new();
+    let mut unique = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let id = (task.bucket.clone(), task.key.clone(), task.output.clone());
+        if seen.insert(id) {
+            unique.push(task);
+        } else {
+            println!(
+                "Warning: dropping duplicate task for {}/{} -> {}",
+                task.bucket, task.key, task.output
+            );
         }
     }
+    unique
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct DownloadPlan {
-    pub selection_id: String,
-    tasks: Vec<DownloadTask>,
+/// `--porcelain` records of each group's name, files, bytes and files of unknown size
+fn size_records(kind: &str, groups: &[GroupSize]) -> Vec<String> {
+    groups
+        .iter()
+        .map(|g| porcelain::record(kind, &[&g.name, &g.files, &g.bytes, &g.unknown]))
+        .collect()
 }
 
-impl DownloadPlan {
-    pub fn new(selection_id: &str, tasks: Vec<DownloadTask>) -> Self {
-        Self {
-            selection_id: selection_id.to_string(),
-            tasks,
+fn size_table(title: &str, groups: &[GroupSize]) -> String {
+    let mut table = String::new();
+    let width = groups
+        .iter()
+        .map(|g| g.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(title.len());
+    table += &format!("{:<width$}  {:>5}  {:>12}\n", title, "Files", "Size");
+    let mut total_files = 0;
+    let mut total_bytes = 0;
+    let mut total_unknown = 0;
+    for group in groups.iter() {
+        let marker = if group.unknown > 0 { "*" } else { "" };
+        table += &format!(
+            "{:<width$}  {:>5}  {:>12}{}\n",
+            group.name,
+            group.files,
+            units::size(group.bytes),
+            marker
+        );
+        total_files += group.files;
+        total_bytes += group.bytes;
+        total_unknown += group.unknown;
+    }
+    table += &format!(
+        "{:<width$}  {:>5}  {:>12}\n",
+        "Total",
+        total_files,
+        units::size(total_bytes)
+    );
+    if total_unknown > 0 {
+        table += &format!(
+            "* {} file(s) have no published size and are not counted\n",
+            total_unknown
+        );
+    }
+    table
+}
+
+/// Download a task, refreshing the provider's credentials and resuming once if they were rejected.
+async fn download_task(
+    provider: &impl S3ObjOps,
+    task: &DownloadTask,
+    output: &Path,
+    options: &DownloadOptions,
+) -> Result<()> {
+    // The cache and peers hold whichever version was current, so pinned versions bypass them
+    let Some(cache) = options.cache.as_ref().filter(|_| task.version_id.is_none()) else {
+        return download_object(provider, task, output, options).await;
+    };
+    if output.exists() {
+        return Ok(());
+    }
+    let cached = cache::cached_path(cache, &task.bucket, &task.key);
+    {
+        let _lock = cache::lock(&cached).await?;
+        if !cached.exists() {
+            download_object(provider, task, &cached, options).await?;
         }
     }
+    cache::link(&cached, output)
+}
 
-    #[allow(dead_code)]
-    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
-        let plan: Self = serde_json::from_str(&content)?;
-        Ok(plan)
+async fn download_object(
+    provider: &impl S3ObjOps,
+    task: &DownloadTask,
+    output: &Path,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let output = output.to_string_lossy();
+    let version_id = task.version_id.as_deref();
+    let peers = match version_id {
+        None => options.peers.holding(&task.bucket, &task.key).await,
+        Some(_) => vec![],
+    };
+    for peer in peers {
+        println!("Downloading {} from {}", task.key, peer.url());
+        match try_download_with(peer, &task.bucket, &task.key, &output, options).await {
+            Ok(()) => return Ok(()),
+            Err(e) => println!(
+                "Warning: {} failed, trying the next source: {}",
+                peer.url(),
+                e
+            ),
+        }
+    }
+    let download = || {
+        try_download_version(
+            provider,
+            &task.bucket,
+            &task.key,
+            version_id,
+            task.size,
+            &output,
+            options,
+        )
+    };
+    let outcome = download().await;
+    if matches!(&outcome, Err(e) if is_auth_error(e)) {
+        match provider.refresh_credentials().await {
+            Ok(true) => {
+                println!("Credentials refreshed, resuming {}", task.key);
+                return download().await;
+            }
+            Ok(false) => {}
+            Err(e) => println!("Warning: could not refresh credentials: {}", e),
+        }
     }
+    outcome
+}
 
-    pub fn write<P: AsRef<Path>>(self: &Self, path: P) -> Result<()> {
-        let content = serde_json::to_string_pretty(self)?;
-        fs::write(path, content)?;
-        Ok(())
+/// Verify a downloaded file, deleting it and downloading again from the first byte when it fails,
+/// e.g. because it was resumed from a corrupt partial file. A file failing the same way every time
+/// points at the remote object rather than the transfer.
+async fn verify_with_restarts(
+    provider: &impl S3ObjOps,
+    task: &DownloadTask,
+    output: &Path,
+    options: &DownloadOptions,
+) -> Result<Verification> {
+    let mut failures: Vec<String> = vec![];
+    loop {
+        match verify_task(provider, task, output).await {
+            Ok(level) if failures.is_empty() => return Ok(level),
+            Ok(level) => {
+                println!(
+                    "{} verified after {} restart(s); the earlier copies were corrupted in transit",
+                    task.key,
+                    failures.len()
+                );
+                return Ok(level);
+            }
+            Err(e) => failures.push(e.to_string()),
+        }
+        if failures.len() > options.max_verify_restarts as usize {
+            break;
+        }
+        println!(
+            "Warning: {} failed verification, restarting from the first byte",
+            task.key
+        );
+        tracing::Span::current().record("attempt", failures.len() + 1);
+        // The object may have been republished, which a cached HEAD would hide
+        provider.invalidate(&task.bucket, &task.key, task.version_id.as_deref());
+        remove_download(output)?;
+        // Or the corrupt copy in the cache would be linked again
+        if let Some(cache) = &options.cache {
+            remove_download(&cache::cached_path(cache, &task.bucket, &task.key))?;
+        }
+        download_task(provider, task, output, options).await?;
     }
+    let last = failures.last().cloned().unwrap_or_default();
+    match failures.windows(2).all(|pair| pair[0] == pair[1]) {
+        true => Err(VerificationFailed(format!(
+            "{} failed verification identically on {} downloads, the remote object does not match the plan: {}",
+            task.key,
+            failures.len(),
+            last
+        ))
+        .into()),
+        false => Err(VerificationFailed(format!(
+            "{} failed verification differently on {} downloads, the transfer is being corrupted: {}",
+            task.key,
+            failures.len(),
+            last
+        ))
+        .into()),
+    }
+}
 
-    pub async fn execute(self: &Self, provider: &impl S3ObjOps) -> Result<()> {
-        for task in self.tasks.iter() {
-            println!("Current task: {:?}", task);
-            try_download(provider, &task.bucket, &task.key, &task.output).await?;
+/// Check a downloaded file as strongly as the available information allows: its size, then the
+/// published checksum or, lacking one, an MD5 ETag.
+async fn verify_task(
+    provider: &impl S3ObjOps,
+    task: &DownloadTask,
+    output: &Path,
+) -> Result<Verification> {
+    verify_size(task, output)?;
+    match &task.checksum {
+        Some(checksum) if verify::is_supported(checksum) => {
+            verify::check_checksum(output, checksum)?;
+            return Ok(Verification::Checksum);
         }
-        Ok(())
+        Some(checksum) => println!(
+            "Warning: cannot check {} against checksum {}, its algorithm is not supported",
+            task.key, checksum
+        ),
+        None => {}
+    }
+    let head = provider
+        .head_object_version(&task.bucket, &task.key, task.version_id.as_deref())
+        .await?;
+    let e_tag = head.e_tag().filter(|_| provider.md5_etags());
+    if let Some(md5) = e_tag.and_then(verify::etag_md5) {
+        verify::check_checksum(output, &format!("md5:{}", md5))?;
+        return Ok(Verification::ETag);
+    }
+    match task.size {
+        Some(_) => Ok(Verification::Size),
+        None => Ok(Verification::None),
+    }
+}
+
+/// Delete a downloaded file and any partial download of it.
+fn remove_download(output: &Path) -> Result<()> {
+    let partial = PathBuf::from(format!("{}.partial", output.to_string_lossy()));
+    for path in [output, partial.as_path(), &partial::state_path(&partial)] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compare a downloaded file with the size the catalogue published for it, if any.
+fn verify_size(task: &DownloadTask, output: &Path) -> Result<()> {
+    let Some(expected) = task.size else {
+        return Ok(());
+    };
+    let actual = fs::metadata(output)?.len();
+    if actual != expected {
+        return Err(anyhow!(
+            "{:?} is {} bytes but the plan expected {}",
+            output,
+            actual,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// Download a single object by url (e.g. `s3://bucket/key` or a virtual-hosted S3 url) without
+/// building a plan, using the same resumable `.partial` download as plan execution.
+pub async fn download_s3_object<P: AsRef<Path>>(
+    provider: &impl S3ObjOps,
+    url: &str,
+    path: P,
+) -> Result<()> {
+    download_s3_object_with(provider, url, path, &DownloadOptions::default()).await
+}
+
+/// `download_s3_object` with the options of plan execution, e.g. a stall threshold or pacing
+pub async fn download_s3_object_with<P: AsRef<Path>>(
+    provider: &impl S3ObjOps,
+    url: &str,
+    path: P,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let location = parse_href(url, None)?;
+    if !matches!(location, Location::S3 { .. }) {
+        return Err(anyhow!("{} is not an S3 url", url));
+    }
+    let output = path.as_ref().to_string_lossy();
+    try_download_with(
+        provider,
+        location.bucket(),
+        location.key(),
+        &output,
+        options,
+    )
+    .await
+}
+
+/// Whether a ranged GET was answered with the requested range rather than the whole object. Some
+/// mirrors ignore the Range header and respond 200 with the full body.
+pub(crate) fn is_requested_range(
+    response: &GetObjectOutput,
+    start_byte: u64,
+    total_size: u64,
+) -> bool {
+    match response.content_range() {
+        Some(range) => range.starts_with(&format!("bytes {}-", start_byte)),
+        None => start_byte == 0 || response.content_length() != Some(total_size as i64),
     }
 }
 
@@ -63,6 +2165,31 @@ pub async fn try_download(
     bucket: &str,
     key: &str,
     output: &str,
+) -> Result<()> {
+    try_download_with(provider, bucket, key, output, &DownloadOptions::default()).await
+}
+
+pub async fn try_download_with(
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+    output: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
+    try_download_version(provider, bucket, key, None, None, output, options).await
+}
+
+/// Download a pinned version of the object, or the current one for None. `planned_size` is the
+/// size the plan recorded, reported in place of one the server doesn't send.
+#[instrument(skip(provider, options))]
+pub async fn try_download_version(
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+    version_id: Option<&str>,
+    planned_size: Option<u64>,
+    output: &str,
+    options: &DownloadOptions,
 ) -> Result<()> {
     // Check if the output file already exists; return early if so
     let dst = Path::new(output);
@@ -87,34 +2214,231 @@ pub async fn try_download(
     let mut byte_count = partial_file.metadata()?.len();
 
     // Get object details from S3
-    let head_object = provider.head_object(bucket, key).await?;
+    let head_object = provider
+        .head_object_version(bucket, key, version_id)
+        .await?;
 
-    let total_size = head_object
+    let size = head_object
         .content_length()
-        .ok_or(anyhow!("Error reading size of remote object"))? as u64;
+        .map(|length| length.max(0) as u64);
+    if size.is_none() && options.missing_length == MissingLength::Fail {
+        return Err(anyhow!("Error reading size of remote object"));
+    }
+    // Without a size the object streams until its body ends, which the loop below stops at. The
+    // planned size only stands in for it in progress reports.
+    let reported_size = size.or(planned_size.filter(|size| *size > 0));
+    let total_size = reported_size.unwrap_or(u64::MAX);
+
+    // A server that doesn't support ranges would send the whole object again
+    if byte_count > 0 && head_object.accept_ranges() == Some("none") {
+        println!("Server does not accept range requests, restarting download");
+        partial_file.set_len(0)?;
+        byte_count = 0;
+    }
+    if byte_count > 0 && size.is_none() {
+        println!("Size of remote object unknown, restarting download");
+        partial_file.set_len(0)?;
+        byte_count = 0;
+    }
+
+    // A partial file copied from another machine, or of an object that has since changed, is
+    // checked against its checkpoint before anything is appended to it
+    let partial_path = Path::new(&partial);
+    let e_tag = head_object.e_tag();
+    let mut hasher = Sha256::new();
+    if byte_count > 0 {
+        let object_mismatch = PartialState::read(partial_path)?
+            .and_then(|state| state.object_mismatch(bucket, key, e_tag, total_size));
+        let check = match object_mismatch {
+            Some(reason) => PartialCheck::Restart(reason),
+            None => partial::check(partial_path)?,
+        };
+        match check {
+            PartialCheck::Resume(resumed) => hasher = resumed,
+            PartialCheck::Restart(reason) => {
+                println!("Warning: {}, restarting download", reason);
+                partial_file.set_len(0)?;
+                byte_count = 0;
+            }
+        }
+    }
+
+    if byte_count > 0 {
+        println!(
+            "Resuming download from {} of {}",
+            units::size(byte_count),
+            units::size(total_size)
+        );
+    }
+    match (size, reported_size) {
+        (None, Some(planned)) => println!(
+            "Warning: size of remote object unknown, downloading until the response ends \
+             (planned as {})",
+            units::size(planned)
+        ),
+        (None, None) => {
+            println!("Warning: size of remote object unknown, downloading until the response ends")
+        }
+        _ => {}
+    }
 
-    let progress = (byte_count as f64 / total_size as f64) * 100.;
-    if progress > 0.0 {
-        println!("Resuming download from {:.2}% completion", progress);
+    // A download already started in one stream continues in one
+    let mut in_parts = false;
+    if let (Some(parts), Some(size)) = (&options.parts, size) {
+        let ranges_accepted = head_object.accept_ranges() != Some("none");
+        if byte_count == 0 && ranges_accepted && parts.ranges(size).len() > 1 {
+            let object = RemoteObject {
+                bucket,
+                key,
+                version_id,
+                e_tag,
+                size,
+            };
+            multipart::download(provider, &object, partial_path, options).await?;
+            byte_count = fs::metadata(partial_path)?.len();
+            in_parts = true;
+        }
+    }
+    if !in_parts {
+        // Left by a run that died while appending the ranges to the `.partial` file
+        multipart::remove_parts(partial_path)?;
     }
 
     if byte_count < total_size {
         println!("Downloading...");
 
-        let mut response = provider
-            .get_object_range(bucket, key, byte_count, total_size - 1)
-            .await?;
+        let started = Instant::now();
+        let mut received = 0;
+        let mut window_started = Instant::now();
+        let mut window_bytes = 0;
+        let id = control::task_id(bucket, key);
+        if let Some(control) = &options.control {
+            control.attempt(&id);
+            control.progress(&id, 0, byte_count, reported_size);
+        }
+        let transfer = async {
+            while byte_count < total_size {
+                if let Some(control) = &options.control {
+                    // The stall window starts over, rather than counting the pause against it
+                    if control.wait_while_paused().await {
+                        window_started = Instant::now();
+                        window_bytes = 0;
+                    }
+                    if control.is_stopped() {
+                        return Err(Stopped.into());
+                    }
+                    if control.is_skipped(&id) {
+                        return Err(Skipped.into());
+                    }
+                }
+                let end_byte = match options.chunk_size {
+                    Some(chunk_size) => (byte_count + chunk_size.max(1)).min(total_size) - 1,
+                    None => total_size - 1,
+                };
+                let mut response = match size {
+                    Some(_) => {
+                        provider
+                            .get_object_range_version(bucket, key, version_id, byte_count, end_byte)
+                            .await?
+                    }
+                    None => provider.get_object_version(bucket, key, version_id).await?,
+                };
+                if size.is_some() && !is_requested_range(&response, byte_count, total_size) {
+                    // Appending a full body to the partial file would corrupt it
+                    println!("Warning: server ignored the range request, restarting download");
+                    partial_file.set_len(0)?;
+                    byte_count = 0;
+                    hasher = Sha256::new();
+                }
+
+                loop {
+                    // A connection that goes silent never yields another chunk to check the rate with
+                    let next = response.body.try_next();
+                    let chunk = match &options.stall {
+                        Some(threshold) => tokio::time::timeout(threshold.window, next)
+                            .await
+                            .map_err(|_| {
+                                threshold.stalled(window_bytes, window_started.elapsed())
+                            })??,
+                        None => next.await?,
+                    };
+                    let Some(bytes) = chunk else { break };
+                    let bytes_len = bytes.len() as u64;
+                    partial_file.write_all(&bytes)?;
+                    hasher.update(&bytes);
+                    byte_count += bytes_len;
+                    received += bytes_len;
+                    if let Some(control) = &options.control {
+                        control.progress(&id, bytes_len, byte_count, reported_size);
+                    }
 
-        while let Some(bytes) = response.body.try_next().await? {
-            let bytes_len = bytes.len() as u64;
-            partial_file.write_all(&bytes)?;
-            byte_count += bytes_len;
+                    window_bytes += bytes_len;
+                    if let Some(threshold) = &options.stall {
+                        let elapsed = window_started.elapsed();
+                        if threshold.is_stalled(window_bytes, elapsed) {
+                            return Err(threshold.stalled(window_bytes, elapsed).into());
+                        }
+                        if elapsed >= threshold.window {
+                            window_started = Instant::now();
+                            window_bytes = 0;
+                        }
+                    }
+
+                    let mut pause = options.pacing.pause(received, started.elapsed());
+                    if let Some(control) = &options.control {
+                        pause = pause.max(control.throttle(bytes_len));
+                    }
+                    if !pause.is_zero() {
+                        tokio::time::sleep(pause).await;
+                    }
+                }
+
+                if size.is_some() {
+                    PartialState::new(bucket, key, e_tag, total_size, byte_count, &hasher)
+                        .write(partial_path)?;
+                }
+
+                // Only a body that ended where requested continues with the next chunk
+                if size.is_none() || byte_count != end_byte + 1 {
+                    break;
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+        if transfer.is_err() && size.is_some() {
+            PartialState::new(bucket, key, e_tag, total_size, byte_count, &hasher)
+                .write(partial_path)?;
         }
+        transfer?;
+    }
+
+    if size.is_none() {
+        // The whole body arrived, but only a checksum tells whether it was the whole object
+        let e_tag = e_tag.filter(|_| provider.md5_etags());
+        match e_tag.and_then(verify::etag_md5) {
+            Some(md5) => verify::check_checksum(partial_path, &format!("md5:{}", md5))?,
+            None => println!(
+                "Warning: no size or checksum to check {}/{} against",
+                bucket, key
+            ),
+        }
+    } else if byte_count != total_size {
+        // A short (or overlong) file must not be renamed into place as complete
+        return Err(anyhow!(
+            "Received {} of {} bytes for {}/{}, keeping {} for inspection",
+            byte_count,
+            total_size,
+            bucket,
+            key,
+            partial
+        ));
     }
 
     println!("Download complete");
     // Rename the file to remove .partial suffix
-    fs::rename(partial, dst)?;
+    partial::remove_state(partial_path)?;
+    fs::rename(&partial, dst)?;
 
     Ok(())
 }
@@ -122,27 +2446,67 @@ pub async fn try_download(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+    use aws_sdk_s3::primitives::ByteStream;
 
     const TEST_OUTPUT_PATH: &str = "/tmp/download_plan.json";
 
     fn mock_download_plan() -> DownloadPlan {
         DownloadPlan {
             selection_id: "provider.collection".to_string(),
+            root: None,
+            source: None,
+            signature: None,
+            provenance: None,
+            aoi_bbox: None,
             tasks: vec![
                 DownloadTask {
                     bucket: "mybucket".to_string(),
                     key: "path/to/file1.txt".to_string(),
                     output: "path/to/write/file1.txt".to_string(),
+                    size: Some(100),
+                    asset_key: Some("B02".to_string()),
+                    item_id: None,
+                    collection: None,
+                    datetime: None,
+                    substituted_for: None,
+                    checksum: None,
+                    version_id: None,
+                    url: None,
+                    url_expires: None,
+                    overviews_only: false,
                 },
                 DownloadTask {
                     bucket: "mybucket".to_string(),
                     key: "path/to/file2.txt".to_string(),
                     output: "path/to/write/file2.txt".to_string(),
+                    size: Some(200),
+                    asset_key: Some("B03".to_string()),
+                    item_id: None,
+                    collection: None,
+                    datetime: None,
+                    substituted_for: None,
+                    checksum: None,
+                    version_id: None,
+                    url: None,
+                    url_expires: None,
+                    overviews_only: false,
                 },
                 DownloadTask {
                     bucket: "mybucket".to_string(),
                     key: "path/to/file3.txt".to_string(),
-                    output: "path/to/write/file3.txt".to_string(),
+                    output: "path/to/other/file3.txt".to_string(),
+                    size: None,
+                    asset_key: Some("B02".to_string()),
+                    item_id: None,
+                    collection: None,
+                    datetime: None,
+                    substituted_for: None,
+                    checksum: None,
+                    version_id: None,
+                    url: None,
+                    url_expires: None,
+                    overviews_only: false,
                 },
             ],
         }
@@ -165,4 +2529,723 @@ mod tests {
         let plan = DownloadPlan::read(path).unwrap();
         assert_eq!(plan.tasks.len(), 3);
     }
+
+    #[test]
+    fn test_read_toml_and_yaml() {
+        for path in ["/tmp/download_plan.toml", "/tmp/download_plan.yaml"] {
+            let plan = mock_download_plan();
+            plan.write(path).unwrap();
+
+            let plan = DownloadPlan::read(path).unwrap();
+            assert_eq!(plan.tasks.len(), 3);
+            assert_eq!(plan.tasks[0].size, Some(100));
+        }
+    }
+
+    #[test]
+    fn test_ndjson_append_replaces_task() {
+        let path = "/tmp/download_plan.ndjson";
+        let plan = mock_download_plan();
+        plan.write(path).unwrap();
+
+        let updated = DownloadTask::new("mybucket", "path/to/file1.txt", "path/to/write/file1.txt")
+            .with_size(Some(150));
+        append_task(path, &updated).unwrap();
+
+        let plan = DownloadPlan::read(path).unwrap();
+        assert_eq!(plan.selection_id, "provider.collection");
+        assert_eq!(plan.tasks.len(), 3);
+        assert_eq!(plan.tasks[0].size, Some(150));
+        assert_eq!(stream_tasks(path).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_output_path_with_root() {
+        let plan = mock_download_plan();
+        let task = &plan.tasks[0];
+        assert_eq!(
+            plan.output_path(task),
+            PathBuf::from("path/to/write/file1.txt")
+        );
+
+        let plan = plan.with_root("/mnt/archive");
+        let task = &plan.tasks[0];
+        assert_eq!(
+            plan.output_path(task),
+            PathBuf::from("/mnt/archive/path/to/write/file1.txt")
+        );
+    }
+
+    #[test]
+    fn test_size_by_item() {
+        let plan = mock_download_plan();
+        let items = plan.size_by_item();
+        assert_eq!(
+            items,
+            vec![
+                GroupSize {
+                    name: "write".to_string(),
+                    files: 2,
+                    bytes: 300,
+                    unknown: 0,
+                },
+                GroupSize {
+                    name: "other".to_string(),
+                    files: 1,
+                    bytes: 0,
+                    unknown: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_size_limit() {
+        let plan = mock_download_plan();
+        assert!(plan.check_size_limit(300).is_ok());
+
+        let err = plan.check_size_limit(299).unwrap_err().to_string();
+        assert!(err.contains("Product"));
+        assert!(err.contains("B03"));
+    }
+
+    #[test]
+    fn test_size_histogram() {
+        let plan = mock_download_plan();
+        let histogram = plan.size_histogram();
+        assert_eq!(histogram[0], ("< 1 MiB", 2));
+        assert_eq!(histogram.last(), Some(&("unknown", 1)));
+    }
+
+    #[test]
+    fn test_remaining() {
+        let root = Path::new("/tmp/slow_stac_remaining");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        fs::write(root.join("done.tif"), "done").unwrap();
+        fs::write(root.join("half.tif.partial"), [0; 40]).unwrap();
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("bucket", "done.tif", "done.tif").with_size(Some(4)),
+                DownloadTask::new("bucket", "half.tif", "half.tif").with_size(Some(100)),
+                DownloadTask::new("bucket", "new.tif", "new.tif").with_size(Some(100)),
+                DownloadTask::new("bucket", "unknown.tif", "unknown.tif"),
+            ],
+        )
+        .with_root(root);
+        let remaining = plan.remaining();
+        assert_eq!(
+            (remaining.files, remaining.bytes, remaining.unknown),
+            (3, 160, 1)
+        );
+    }
+
+    #[test]
+    fn test_largest_tasks() {
+        let plan = mock_download_plan();
+        let largest = plan.largest_tasks(1);
+        assert_eq!(largest.len(), 1);
+        assert_eq!(largest[0].size, Some(200));
+    }
+
+    #[test]
+    fn test_new_drops_duplicate_tasks() {
+        let tasks = vec![
+            DownloadTask::new("mybucket", "path/to/file1.txt", "out/file1.txt"),
+            DownloadTask::new("mybucket", "path/to/file1.txt", "out/file1.txt"),
+            DownloadTask::new("mybucket", "path/to/file1.txt", "other/file1.txt"),
+        ];
+        let plan = DownloadPlan::new("provider.collection", tasks);
+        assert_eq!(plan.tasks.len(), 2);
+    }
+
+    #[test]
+    fn test_merge() {
+        let plan = mock_download_plan();
+        let merged = plan.merge(mock_download_plan()).unwrap();
+        assert_eq!(merged.tasks.len(), 3);
+
+        let other = DownloadPlan::new("other.collection", vec![]);
+        assert!(mock_download_plan().merge(other).is_err());
+    }
+
+    #[test]
+    fn test_routed_to() {
+        let plan = mock_download_plan()
+            .routed_to("mirror", |bucket, key| {
+                Some((format!("{}-mirror", bucket), key.to_string()))
+            })
+            .unwrap();
+        assert_eq!(plan.source(), Some("mirror"));
+        assert!(plan.tasks.iter().all(|t| t.bucket == "mybucket-mirror"));
+
+        assert!(mock_download_plan()
+            .routed_to("mirror", |_, _| None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_size() {
+        let path = Path::new("/tmp/slow_stac_verify_size.txt");
+        fs::write(path, "0123456789").unwrap();
+        let task = DownloadTask::new("mybucket", "key", "out");
+        assert!(verify_size(&task, path).is_ok());
+        assert!(verify_size(&task.with_size(Some(10)), path).is_ok());
+        let task = DownloadTask::new("mybucket", "key", "out").with_size(Some(11));
+        assert!(verify_size(&task, path).is_err());
+    }
+
+    struct MockProvider {
+        content: &'static [u8],
+        /// Serve at most this many bytes, e.g. to simulate a dropped connection
+        truncate_to: Option<usize>,
+    }
+
+    impl S3ObjOps for MockProvider {
+        async fn head_object(self: &Self, _: &str, _: &str) -> Result<HeadObjectOutput> {
+            Ok(HeadObjectOutput::builder()
+                .content_length(self.content.len() as i64)
+                .build())
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Ok(GetObjectOutput::builder()
+                .body(ByteStream::from_static(self.content))
+                .build())
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            start_byte: u64,
+            end_byte: u64,
+        ) -> Result<GetObjectOutput> {
+            let end = match self.truncate_to {
+                Some(limit) => (end_byte as usize + 1).min(limit),
+                None => end_byte as usize + 1,
+            };
+            let range = &self.content[start_byte as usize..end];
+            Ok(GetObjectOutput::builder()
+                .body(ByteStream::from_static(range))
+                .build())
+        }
+
+        async fn presign_get(self: &Self, bucket: &str, key: &str, _: Duration) -> Result<String> {
+            Ok(format!(
+                "https://{bucket}.example.com/{key}?X-Amz-Signature=0"
+            ))
+        }
+
+        async fn presign_get_version(
+            self: &Self,
+            bucket: &str,
+            key: &str,
+            version_id: Option<&str>,
+            expires_in: Duration,
+        ) -> Result<String> {
+            match version_id {
+                Some(version_id) => Ok(format!(
+                    "https://{bucket}.example.com/{key}?versionId={version_id}&X-Amz-Signature=0"
+                )),
+                None => self.presign_get(bucket, key, expires_in).await,
+            }
+        }
+    }
+
+    #[test]
+    fn test_ordered_tasks() {
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("b", "a/big", "a/big").with_size(Some(30)),
+                DownloadTask::new("b", "b/small", "b/small").with_size(Some(1)),
+                DownloadTask::new("b", "a/unknown", "a/unknown"),
+                DownloadTask::new("b", "a/small", "a/small").with_size(Some(10)),
+            ],
+        );
+        let keys = |order| {
+            plan.ordered_tasks(order)
+                .iter()
+                .map(|task| task.key.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(TaskOrder::Plan),
+            ["a/big", "b/small", "a/unknown", "a/small"]
+        );
+        assert_eq!(
+            keys(TaskOrder::ItemSmallestFirst),
+            ["a/small", "a/big", "a/unknown", "b/small"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream() {
+        let root = Path::new("/tmp/slow_stac_execute_stream");
+        let _ = fs::remove_dir_all(root);
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("mybucket", "a.txt", "item/a.txt").with_size(Some(10)),
+                DownloadTask::new("mybucket", "b.txt", "item/b.txt").with_size(Some(11)),
+            ],
+        )
+        .with_root(root);
+        let provider = MockProvider {
+            content: b"0123456789",
+            truncate_to: None,
+        };
+        let options = DownloadOptions::default();
+
+        let results = plan
+            .execute_stream(&provider, &options)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].outcome.is_ok());
+        assert_eq!(results[0].output, root.join("item/a.txt"));
+        // The size check fails but the stream carries on
+        assert!(results[1].outcome.is_err());
+    }
+
+    #[test]
+    fn test_is_requested_range() {
+        let ranged = GetObjectOutput::builder()
+            .content_range("bytes 4-9/10")
+            .content_length(6)
+            .build();
+        assert!(is_requested_range(&ranged, 4, 10));
+        let full = GetObjectOutput::builder().content_length(10).build();
+        assert!(!is_requested_range(&full, 4, 10));
+        assert!(is_requested_range(&full, 0, 10));
+    }
+
+    #[tokio::test]
+    async fn test_try_download_truncated() {
+        let output = "/tmp/slow_stac_truncated/file.txt";
+        let _ = fs::remove_dir_all("/tmp/slow_stac_truncated");
+        let provider = MockProvider {
+            content: b"0123456789",
+            truncate_to: Some(4),
+        };
+        assert!(try_download(&provider, "mybucket", "file.txt", output)
+            .await
+            .is_err());
+        assert!(!Path::new(output).exists());
+        assert_eq!(fs::read(format!("{}.partial", output)).unwrap(), b"0123");
+    }
+
+    #[tokio::test]
+    async fn test_try_download_checks_partial() {
+        let output = "/tmp/slow_stac_checks_partial/file.txt";
+        let partial = format!("{}.partial", output);
+        let _ = fs::remove_dir_all("/tmp/slow_stac_checks_partial");
+        let truncated = MockProvider {
+            content: b"0123456789",
+            truncate_to: Some(4),
+        };
+        assert!(try_download(&truncated, "mybucket", "file.txt", output)
+            .await
+            .is_err());
+        assert!(partial::state_path(Path::new(&partial)).exists());
+
+        // Damaged on the way to another machine: the checkpoint no longer matches
+        fs::write(&partial, b"0x23").unwrap();
+        let provider = MockProvider {
+            content: b"0123456789",
+            truncate_to: None,
+        };
+        try_download(&provider, "mybucket", "file.txt", output)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(output).unwrap(), b"0123456789");
+        assert!(!partial::state_path(Path::new(&partial)).exists());
+    }
+
+    #[tokio::test]
+    async fn test_try_download_chunked() {
+        let output = "/tmp/slow_stac_chunked/file.txt";
+        let _ = fs::remove_dir_all("/tmp/slow_stac_chunked");
+        let provider = MockProvider {
+            content: b"0123456789",
+            truncate_to: None,
+        };
+        let options = DownloadOptions {
+            chunk_size: Some(3),
+            ..Default::default()
+        };
+        try_download_with(&provider, "mybucket", "file.txt", output, &options)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(output).unwrap(), b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_try_download_parts() {
+        let dir = Path::new("/tmp/slow_stac_parts");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let output = dir.join("file.txt");
+        let partial = dir.join("file.txt.partial");
+        // Left by an interrupted run: half of the second range, and a range of another split
+        fs::write(multipart::part_path(&partial, (4, 7)), "45").unwrap();
+        fs::write(multipart::part_path(&partial, (0, 4)), "xxxxx").unwrap();
+        let provider = MockProvider {
+            content: b"0123456789",
+            truncate_to: None,
+        };
+        let options = DownloadOptions {
+            parts: Some(Parts::new(3).with_min_size(0)),
+            ..Default::default()
+        };
+        let output_str = output.to_string_lossy();
+        try_download_with(&provider, "mybucket", "file.txt", &output_str, &options)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"0123456789");
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+
+        // Interrupted while appending the ranges, so the next run continues in one stream
+        fs::remove_file(&output).unwrap();
+        fs::write(&partial, "0123").unwrap();
+        fs::write(multipart::part_path(&partial, (4, 7)), "4567").unwrap();
+        try_download_with(&provider, "mybucket", "file.txt", &output_str, &options)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"0123456789");
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+    }
+
+    /// Serves whole objects without a Content-Length
+    struct Unsized {
+        content: &'static [u8],
+        e_tag: &'static str,
+        md5_etags: bool,
+    }
+
+    impl S3ObjOps for Unsized {
+        fn md5_etags(self: &Self) -> bool {
+            self.md5_etags
+        }
+
+        async fn head_object(self: &Self, _: &str, _: &str) -> Result<HeadObjectOutput> {
+            Ok(HeadObjectOutput::builder().e_tag(self.e_tag).build())
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Ok(GetObjectOutput::builder()
+                .body(ByteStream::from_static(self.content))
+                .build())
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            _: u64,
+            _: u64,
+        ) -> Result<GetObjectOutput> {
+            Err(anyhow!("Ranges need a size"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_try_download_unsized() {
+        let output = "/tmp/slow_stac_unsized/file.txt";
+        let _ = fs::remove_dir_all("/tmp/slow_stac_unsized");
+        fs::create_dir_all("/tmp/slow_stac_unsized").unwrap();
+        fs::write(format!("{}.partial", output), "0123").unwrap();
+        let provider = Unsized {
+            content: b"0123456789",
+            e_tag: "\"781e5e245d69b566979b86e28d23f2c7\"",
+            md5_etags: true,
+        };
+        let options = DownloadOptions {
+            missing_length: MissingLength::Fail,
+            ..Default::default()
+        };
+        assert!(
+            try_download_with(&provider, "mybucket", "file.txt", output, &options)
+                .await
+                .is_err()
+        );
+        try_download(&provider, "mybucket", "file.txt", output)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(output).unwrap(), b"0123456789");
+
+        let output = "/tmp/slow_stac_unsized/changed.txt";
+        let changed = Unsized {
+            content: b"0123456789",
+            e_tag: "\"00000000000000000000000000000000\"",
+            md5_etags: true,
+        };
+        assert!(try_download(&changed, "mybucket", "changed.txt", output)
+            .await
+            .is_err());
+        assert!(!Path::new(output).exists());
+
+        // The ETags of other servers aren't MD5s, however they look
+        let opaque = Unsized {
+            md5_etags: false,
+            ..changed
+        };
+        try_download(&opaque, "mybucket", "changed.txt", output)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(output).unwrap(), b"0123456789");
+    }
+
+    /// Serves "old" as version v1 and "new!" as the current version
+    struct Versioned;
+
+    impl Versioned {
+        fn content(version_id: Option<&str>) -> &'static [u8] {
+            match version_id {
+                Some("v1") => b"old",
+                _ => b"new!",
+            }
+        }
+    }
+
+    impl S3ObjOps for Versioned {
+        async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+            self.head_object_version(bucket, key, None).await
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Err(anyhow!("Only ranges are served"))
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            bucket: &str,
+            key: &str,
+            start_byte: u64,
+            end_byte: u64,
+        ) -> Result<GetObjectOutput> {
+            self.get_object_range_version(bucket, key, None, start_byte, end_byte)
+                .await
+        }
+
+        async fn head_object_version(
+            self: &Self,
+            _: &str,
+            _: &str,
+            version_id: Option<&str>,
+        ) -> Result<HeadObjectOutput> {
+            Ok(HeadObjectOutput::builder()
+                .content_length(Self::content(version_id).len() as i64)
+                .version_id(version_id.unwrap_or("v2"))
+                .build())
+        }
+
+        async fn get_object_range_version(
+            self: &Self,
+            _: &str,
+            _: &str,
+            version_id: Option<&str>,
+            start_byte: u64,
+            end_byte: u64,
+        ) -> Result<GetObjectOutput> {
+            let range = &Self::content(version_id)[start_byte as usize..=end_byte as usize];
+            Ok(GetObjectOutput::builder()
+                .body(ByteStream::from_static(range))
+                .build())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_try_download_version() {
+        let dir = Path::new("/tmp/slow_stac_version");
+        let _ = fs::remove_dir_all(dir);
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("bucket", "B04.tif", "v1/B04.tif")
+                    .with_version_id(Some("v1".to_string())),
+                DownloadTask::new("bucket", "B04.tif", "current/B04.tif"),
+            ],
+        )
+        .with_root(dir);
+        let plan: DownloadPlan =
+            serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+        assert_eq!(plan.tasks()[0].version_id(), Some("v1"));
+        plan.execute(&Versioned).await.unwrap();
+        assert_eq!(fs::read(dir.join("v1/B04.tif")).unwrap(), b"old");
+        assert_eq!(fs::read(dir.join("current/B04.tif")).unwrap(), b"new!");
+
+        let (plan, pinned) = plan.pinned(&Versioned).await.unwrap();
+        assert_eq!(pinned, 1);
+        assert_eq!(plan.tasks()[0].version_id(), Some("v1"));
+        assert_eq!(plan.tasks()[1].version_id(), Some("v2"));
+
+        // Providers without versions refuse a pinned one rather than serving the current object
+        let provider = MockProvider {
+            content: b"new!",
+            truncate_to: None,
+        };
+        let output = dir.join("mock/B04.tif");
+        let error = try_download_version(
+            &provider,
+            "bucket",
+            "B04.tif",
+            Some("v1"),
+            None,
+            &output.to_string_lossy(),
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("does not support object versions"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_with_restarts() {
+        let output = Path::new("/tmp/slow_stac_verify_restarts/file.txt");
+        let _ = fs::remove_dir_all("/tmp/slow_stac_verify_restarts");
+        let provider = MockProvider {
+            content: b"0123456789",
+            truncate_to: None,
+        };
+        let options = DownloadOptions::default();
+
+        // The remote object is smaller than planned on every download
+        let task = DownloadTask::new("mybucket", "file.txt", "file.txt").with_size(Some(11));
+        download_task(&provider, &task, output, &options)
+            .await
+            .unwrap();
+        let error = verify_with_restarts(&provider, &task, output, &options)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("identically on 3 downloads"));
+
+        // A corrupt earlier copy is replaced
+        fs::write(output, "corrupt").unwrap();
+        let task = task.with_size(Some(10));
+        assert!(verify_with_restarts(&provider, &task, output, &options)
+            .await
+            .is_ok());
+        assert_eq!(fs::read(output).unwrap(), b"0123456789");
+    }
+
+    #[test]
+    fn test_pacing_pause() {
+        let pacing = Pacing::default();
+        assert_eq!(pacing.pause(1 << 20, Duration::ZERO), Duration::ZERO);
+
+        let pacing = Pacing {
+            max_bytes_per_sec: Some(1000),
+            chunk_delay: Duration::from_millis(10),
+        };
+        // 2000 bytes at 1000 B/s should take 2s, 0.5s have passed
+        assert_eq!(
+            pacing.pause(2000, Duration::from_millis(500)),
+            Duration::from_millis(1510)
+        );
+        // Already slower than the cap
+        assert_eq!(
+            pacing.pause(2000, Duration::from_secs(3)),
+            Duration::from_millis(10)
+        );
+    }
+
+    #[test]
+    fn test_stall_threshold() {
+        let threshold = StallThreshold {
+            min_bytes_per_sec: 1024,
+            window: Duration::from_secs(60),
+        };
+        // Too early to judge
+        assert!(!threshold.is_stalled(0, Duration::from_secs(30)));
+        assert!(threshold.is_stalled(1024, Duration::from_secs(60)));
+        assert!(!threshold.is_stalled(60 * 1024, Duration::from_secs(60)));
+
+        let error = anyhow::Error::from(threshold.stalled(1024, Duration::from_secs(60)));
+        assert!(is_stalled(&error));
+        assert!(is_stalled(&error.context("Downloading B02_10m")));
+        assert!(!is_stalled(&anyhow!("Access denied")));
+    }
+
+    #[tokio::test]
+    async fn test_presign_and_refresh() {
+        let provider = MockProvider {
+            content: b"0123456789",
+            truncate_to: None,
+        };
+        let plan = mock_download_plan()
+            .presigned(&provider, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(plan.source(), Some(presign::SOURCE));
+        assert!(plan.has_credentials());
+        assert_eq!(plan.presigned_urls().unwrap().len(), 3);
+
+        let mut plan = plan;
+        plan.tasks[1].url_expires = Some(0);
+        assert!(plan.presigned_urls().is_err());
+
+        let (plan, resigned) = plan
+            .refreshed(
+                &provider,
+                Duration::from_secs(3600),
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resigned, 1);
+        assert!(plan.presigned_urls().is_ok());
+
+        let pinned = DownloadPlan::new(
+            "provider.collection",
+            vec![DownloadTask::new("bucket", "B04.tif", "B04.tif")
+                .with_version_id(Some("v1".to_string()))],
+        )
+        .presigned(&provider, Duration::from_secs(3600))
+        .await
+        .unwrap();
+        let urls = pinned.presigned_urls().unwrap();
+        let object = (
+            "bucket".to_string(),
+            "B04.tif".to_string(),
+            Some("v1".to_string()),
+        );
+        assert_eq!(
+            urls[&object],
+            "https://bucket.example.com/B04.tif?versionId=v1&X-Amz-Signature=0"
+        );
+    }
+
+    #[test]
+    fn test_remote_plan_file_name() {
+        let url = Url::parse("https://example.org/plans/cop_plan.json.age?token=1").unwrap();
+        assert_eq!(remote_plan_file_name(&url), "cop_plan.json.age");
+        let url = Url::parse("https://example.org/").unwrap();
+        assert_eq!(remote_plan_file_name(&url), "download_plan.json");
+    }
+
+    #[test]
+    fn test_plan_signature() {
+        let key = b"shared secret";
+        let plan = mock_download_plan()
+            .with_provenance(Provenance::new(b"id = \"provider.collection\""))
+            .with_signature(key)
+            .unwrap();
+        assert!(plan.verify_signature(key).is_ok());
+        assert!(plan.verify_signature(b"other key").is_err());
+
+        // The signature survives a round trip through another format
+        let path = "/tmp/slow_stac_signed_plan.toml";
+        plan.write(path).unwrap();
+        let plan = DownloadPlan::read(path).unwrap();
+        assert!(plan.verify_signature(key).is_ok());
+
+        let mut tampered = plan;
+        tampered.tasks[0].key = "path/to/other.txt".to_string();
+        assert!(tampered.verify_signature(key).is_err());
+    }
 }
@@ -0,0 +1,4 @@
+pub mod mod09ga;
+mod provider;
+
+pub use provider::Provider;
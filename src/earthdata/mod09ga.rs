@@ -0,0 +1,165 @@
+use crate::download_plan::{DownloadPlan, DownloadTask};
+use crate::image_selection::{expand_products, ImageSelection, Product};
+use crate::prepare::PrepareOptions;
+use crate::resolve::{file_checksum, file_size, resolve_asset, Location, Transport};
+use crate::search::search_items;
+use crate::user_agent;
+use anyhow::{anyhow, Result};
+use stac::{Asset, Item};
+use std::path::{Path, PathBuf};
+use toml;
+use tracing::instrument;
+
+pub const STAC_API: &str = "https://cmr.earthdata.nasa.gov/stac/LPCLOUD";
+pub const COLLECTION_ID: &str = "MOD09GA_061";
+
+#[allow(dead_code)]
+pub fn image_selection_toml() -> toml::Table {
+    toml::toml! {
+        id = "earthdata.mod09ga"
+
+        provider = "NASA Earthdata (LP DAAC)"
+
+        name = "MODIS/Terra Surface Reflectance Daily L2G Global 1km and 500m SIN Grid V061"
+
+        description = "MOD09GA provides an estimate of the surface spectral reflectance of Terra MODIS\n\
+        bands 1 through 7 corrected for atmospheric conditions such as gases, aerosols, and\n\
+        Rayleigh scattering. Each granule is a single HDF-EOS file containing 500m reflectance\n\
+        bands and 1km observation and quality layers for one sinusoidal grid tile."
+
+        docs = "https://lpdaac.usgs.gov/products/mod09gav061/"
+
+        ids_to_download = [
+            "MOD09GA.A2024125.h10v04.061.2024127033028",
+            "MOD09GA.A2024125.h10v04.061.2024127033028",
+        ]
+
+        [[products]]
+        id = "data"
+        name = "HDF-EOS Granule"
+        download = true
+
+        [[products]]
+        id = "browse"
+        name = "Browse Image"
+        download = false
+
+        [[products]]
+        id = "metadata"
+        name = "Granule Metadata"
+        download = false
+    }
+}
+
+/// The curated template with products generated from the collection's `item_assets`, falling back
+/// to the curated products when the catalogue can't be reached.
+pub async fn image_selection() -> ImageSelection {
+    ImageSelection::from_template(&image_selection_toml())
+        .with_item_assets(STAC_API, COLLECTION_ID)
+        .await
+}
+
+pub async fn generate_download_plan(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+) -> Result<DownloadPlan> {
+    generate_download_plan_with(selection, output_dir, &PrepareOptions::default()).await
+}
+
+/// Generate a plan, fetching the selection's items as `options` set out
+#[instrument(skip_all, fields(selection = %selection.id))]
+pub async fn generate_download_plan_with(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+    options: &PrepareOptions,
+) -> Result<DownloadPlan> {
+    let stac_api = selection.stac_api(STAC_API);
+    match (selection.ids_to_download(), &selection.search) {
+        (Some(ids_to_download), _) => {
+            let products_to_download = selection
+                .products_to_download()
+                .ok_or(anyhow!("No products selected for download"))?;
+            let products = &products_to_download;
+            let inputs = ids_to_download
+                .into_iter()
+                .map(|id| (id.clone(), id))
+                .collect();
+            let tasks = options
+                .resolve(selection, inputs, |id: String| async move {
+                    let item = fetch_single_item(stac_api, COLLECTION_ID, &id).await?;
+                    item_tasks(&item, products)
+                })
+                .await?;
+            Ok(DownloadPlan::new(&selection.id, tasks).with_root(&output_dir))
+        }
+        (None, Some(search)) => {
+            let items = search_items(stac_api, COLLECTION_ID, search).await?;
+            generate_download_plan_from_items(selection, &items, output_dir)
+        }
+        (None, None) => Err(anyhow!("No ids to download or search defined")),
+    }
+}
+
+/// Generate a plan for STAC Items that have already been retrieved (e.g. an ItemCollection saved
+/// from a search), ignoring the selection's `ids_to_download`.
+#[instrument(skip_all, fields(selection = %selection.id))]
+pub fn generate_download_plan_from_items(
+    selection: &ImageSelection,
+    items: &[Item],
+    output_dir: PathBuf,
+) -> Result<DownloadPlan> {
+    let products_to_download = selection
+        .products_to_download()
+        .ok_or(anyhow!("No products selected for download"))?;
+
+    let mut tasks: Vec<DownloadTask> = vec![];
+    for item in items {
+        tasks.extend(item_tasks(item, &products_to_download)?);
+    }
+    Ok(DownloadPlan::new(&selection.id, tasks).with_root(&output_dir))
+}
+
+/// The tasks downloading an item's selected assets
+fn item_tasks(item: &Item, products_to_download: &[Product]) -> Result<Vec<DownloadTask>> {
+    let mut tasks: Vec<DownloadTask> = vec![];
+    let assets = map_products_to_assets(item, products_to_download)?;
+    for (product, asset) in assets {
+        let (host, path) = match resolve_asset(&asset, Transport::Https)? {
+            Location::Https { host, path } => (host, path),
+            _ => return Err(anyhow!("No https location found for asset: {}", asset.href)),
+        };
+
+        let file_name = Path::new(&path).file_name().unwrap();
+        let output = Path::new(&item.id).join(file_name);
+
+        let task = DownloadTask::new(&host, &path, output.to_str().unwrap())
+            .with_size(file_size(&asset))
+            .with_checksum(file_checksum(&asset))
+            .with_asset_key(&product.id)
+            .with_overviews_only(product.overviews_only)
+            .with_item(item);
+        tasks.push(task)
+    }
+    Ok(tasks)
+}
+
+#[instrument(skip(stac_api))]
+async fn fetch_single_item(stac_api: &str, collection: &str, id: &str) -> Result<Item> {
+    let url = format!("{stac_api}/collections/{collection}/items/{id}");
+    let item = user_agent::get(url).await?.json::<Item>().await?;
+    Ok(item)
+}
+
+fn map_products_to_assets(item: &Item, products: &[Product]) -> Result<Vec<(Product, Asset)>> {
+    let keys = item.assets.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+    let mut assets = vec![];
+    for product in expand_products(&item.id, products, &keys)? {
+        let asset = item.assets.get(&product.id).ok_or(anyhow!(
+            "Did not find matching asset for product {} in {}",
+            product.id,
+            item.id
+        ))?;
+        assets.push((product, asset.clone()));
+    }
+    Ok(assets)
+}
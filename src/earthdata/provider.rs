@@ -0,0 +1,150 @@
+use crate::{http, s3, user_agent};
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use reqwest::redirect::Policy;
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use std::sync::RwLock;
+use tracing::instrument;
+
+const LOGIN_HOST: &str = "urs.earthdata.nasa.gov";
+const TOKEN_URL: &str = "https://urs.earthdata.nasa.gov/api/users/find_or_create_token";
+
+/// Serves HTTPS downloads from NASA Earthdata (e.g. LP DAAC) using an Earthdata Login bearer token.
+///
+/// Tasks for this provider store the host of the data endpoint as the `bucket` and the url path as
+/// the `key`. Data endpoints redirect authenticated requests to short-lived presigned urls, which
+/// the underlying client follows; the token is never forwarded to the redirected host.
+pub struct Provider {
+    client: Client,
+    token: RwLock<String>,
+    /// Username and password to log in again with when the token expires
+    login: Option<(String, String)>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+impl Provider {
+    #[allow(dead_code)]
+    pub fn new(client: Client, token: &str) -> Self {
+        Self {
+            client,
+            token: RwLock::new(token.to_string()),
+            login: None,
+        }
+    }
+
+    pub fn from_token(token: &str) -> Self {
+        Self::new(client(), token)
+    }
+
+    /// Exchange Earthdata Login credentials for a bearer token, reusing an existing token if one
+    /// has already been issued to the user.
+    pub async fn login(username: &str, password: &str) -> Result<Self> {
+        let client = client();
+        let token = fetch_token(&client, username, password).await?;
+        Ok(Self::new(client, &token).with_login(username, password))
+    }
+
+    /// Keep credentials to log in again with when the token expires during a download.
+    pub fn with_login(self, username: &str, password: &str) -> Self {
+        Self {
+            login: Some((username.to_string(), password.to_string())),
+            ..self
+        }
+    }
+
+    /// Authenticate with `EARTHDATA_TOKEN` if it is set, otherwise log in with
+    /// `EARTHDATA_USERNAME` and `EARTHDATA_PASSWORD`. When both are set, the credentials are used
+    /// to replace the token once it expires.
+    pub async fn from_env() -> Result<Self> {
+        if let Ok(token) = std::env::var("EARTHDATA_TOKEN") {
+            let provider = Self::from_token(&token);
+            return match (
+                std::env::var("EARTHDATA_USERNAME"),
+                std::env::var("EARTHDATA_PASSWORD"),
+            ) {
+                (Ok(username), Ok(password)) => Ok(provider.with_login(&username, &password)),
+                _ => Ok(provider),
+            };
+        }
+        let username = std::env::var("EARTHDATA_USERNAME").map_err(|_| {
+            anyhow!("Set EARTHDATA_TOKEN or EARTHDATA_USERNAME and EARTHDATA_PASSWORD")
+        })?;
+        let password = std::env::var("EARTHDATA_PASSWORD")
+            .map_err(|_| anyhow!("EARTHDATA_PASSWORD is not set"))?;
+        Self::login(&username, &password).await
+    }
+
+    fn request(self: &Self, bucket: &str, key: &str) -> RequestBuilder {
+        let url = format!("https://{}/{}", bucket, key);
+        let token = self.token.read().expect("Token lock poisoned").clone();
+        self.client.get(url).bearer_auth(token)
+    }
+}
+
+async fn fetch_token(client: &Client, username: &str, password: &str) -> Result<String> {
+    let response = client
+        .post(TOKEN_URL)
+        .basic_auth(username, Some(password))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TokenResponse>()
+        .await?;
+    Ok(response.access_token)
+}
+
+/// Build a client that refuses to follow redirects to the Earthdata Login page. Data endpoints only
+/// send requests there when the token was rejected, and the login page itself responds 200 OK.
+fn client() -> Client {
+    let policy = Policy::custom(|attempt| {
+        if attempt.url().host_str() == Some(LOGIN_HOST) {
+            attempt.error("Earthdata token was rejected or has expired")
+        } else if attempt.previous().len() > 10 {
+            attempt.error("Too many redirects")
+        } else {
+            attempt.follow()
+        }
+    });
+    user_agent::client_builder()
+        .redirect(policy)
+        .build()
+        .expect("Client configuration should always be valid")
+}
+
+impl s3::S3ObjOps for Provider {
+    #[instrument(skip(self))]
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        http::head_object(self.request(bucket, key)).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        http::get_object(self.request(bucket, key)).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        http::get_object_range(self.request(bucket, key), start_byte, end_byte).await
+    }
+
+    async fn refresh_credentials(self: &Self) -> Result<bool> {
+        let Some((username, password)) = &self.login else {
+            return Ok(false);
+        };
+        let token = fetch_token(&self.client, username, password).await?;
+        *self.token.write().expect("Token lock poisoned") = token;
+        Ok(true)
+    }
+}
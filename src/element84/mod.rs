@@ -2,5 +2,7 @@
 mod provider;
 #[allow(dead_code)]
 pub mod sentinel2collection1level2a;
+pub mod sentinel2level1c;
+pub mod sentinel2precollection1level2a;
 
 pub use provider::Provider;
\ No newline at end of file
@@ -1,51 +1,158 @@
-use aws_sdk_s3::Client;
+use crate::provider_config::{self, ProviderConfig};
+use crate::s3;
+use anyhow::anyhow;
 use aws_sdk_s3::operation::get_object::GetObjectOutput;
 use aws_sdk_s3::operation::head_object::HeadObjectOutput;
-use crate::s3;
+use aws_sdk_s3::types::RequestPayer;
+use aws_sdk_s3::Client;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+/// Providers whose buckets are requester-pays, which sign every request as requester
+const REQUESTER_PAYS: [&str; 1] = [provider_config::ELEMENT84_L1C];
+
+/// Fail unless the provider called `name` can sign requests, when its buckets are requester-pays
+pub(crate) fn check_configured(name: &str) -> anyhow::Result<()> {
+    check_requester_pays(name, &provider_config::load(name)?)
+}
+
+fn check_requester_pays(name: &str, config: &ProviderConfig) -> anyhow::Result<()> {
+    let signed = config.profile.is_some() || config.fallback_profile.is_some();
+    if REQUESTER_PAYS.contains(&name) && !signed {
+        return Err(anyhow!(
+            "The buckets of {} are requester-pays, set a profile or fallback_profile billed for \
+             the transfer under [providers.{}] in the config file",
+            name,
+            name
+        ));
+    }
+    Ok(())
+}
 
 pub struct Provider {
     client: Client,
+    /// Settings of the signed client tried when a bucket refuses anonymous requests
+    fallback: Option<ProviderConfig>,
+    /// Built on first use
+    fallback_client: RwLock<Option<Client>>,
+    /// Buckets that refused anonymous requests, sent only signed requests from then on
+    signed_buckets: Mutex<HashSet<String>>,
+    /// Sign every request as requester, without trying anonymous ones first
+    requester_pays: bool,
 }
 
 impl Provider {
     #[allow(dead_code)]
     pub fn new(client: Client) -> Self {
-        Self { client }
+        Self {
+            client,
+            fallback: None,
+            fallback_client: RwLock::new(None),
+            signed_buckets: Mutex::new(HashSet::new()),
+            requester_pays: false,
+        }
+    }
+
+    pub fn with_requester_pays(self, requester_pays: bool) -> Self {
+        Self {
+            requester_pays,
+            ..self
+        }
     }
 
     pub async fn from_profile(profile_name: &str) -> Self {
         let client = s3::client_from_profile(profile_name).await;
-        Self { client }
+        Self::new(client)
     }
-    
+
     pub async fn as_anon() -> Self {
-        let region = "us-west-2";
-        let client = s3::anon_client(region).await;
-        Self { client }
+        Self::from_config(&ProviderConfig::defaults(provider_config::ELEMENT84)).await
+    }
+
+    /// Anonymous clients fall back to the config's `fallback_profile`, if it has one
+    pub async fn from_config(config: &ProviderConfig) -> Self {
+        let fallback = match (&config.profile, &config.fallback_profile) {
+            (None, Some(profile)) => Some(config.clone().with_profile(Some(profile.clone()))),
+            _ => None,
+        };
+        Self {
+            fallback,
+            ..Self::new(config.client().await)
+        }
+    }
+
+    /// With the settings of the config file (see `provider_config`), anonymous unless a profile
+    /// is set there
+    pub async fn configured() -> anyhow::Result<Self> {
+        Self::configured_as(provider_config::ELEMENT84).await
+    }
+
+    /// With the settings of the provider called `name`, for collections whose buckets need their
+    /// own, e.g. `provider_config::ELEMENT84_L1C`
+    pub async fn configured_as(name: &str) -> anyhow::Result<Self> {
+        let config = provider_config::load(name)?;
+        check_requester_pays(name, &config)?;
+        Ok(Self::from_config(&config)
+            .await
+            .with_requester_pays(REQUESTER_PAYS.contains(&name)))
+    }
+
+    fn is_signed(self: &Self, bucket: &str) -> bool {
+        let signed_buckets = self.signed_buckets.lock().expect("Bucket lock poisoned");
+        signed_buckets.contains(bucket)
+    }
+
+    async fn signed_client(self: &Self, fallback: &ProviderConfig) -> Client {
+        if let Some(client) = self.fallback_client.read().await.as_ref() {
+            return client.clone();
+        }
+        let mut fallback_client = self.fallback_client.write().await;
+        fallback_client
+            .get_or_insert(fallback.client().await)
+            .clone()
+    }
+
+    /// Send a request anonymously, and if the bucket refuses it, signed with the fallback profile
+    /// as requester, which requester-pays buckets need and others ignore. A requester-pays
+    /// provider sends every request signed as requester.
+    async fn send<T, F, Fut>(self: &Self, bucket: &str, request: F) -> anyhow::Result<T>
+    where
+        F: Fn(Client, Option<RequestPayer>) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let Some(fallback) = &self.fallback else {
+            let request_payer = self.requester_pays.then_some(RequestPayer::Requester);
+            return request(self.client.clone(), request_payer).await;
+        };
+        if !self.requester_pays && !self.is_signed(bucket) {
+            match request(self.client.clone(), None).await {
+                Err(e) if s3::is_auth_error(&e) => {
+                    println!(
+                        "Warning: {} refused anonymous access, retrying with profile {}",
+                        bucket,
+                        fallback.profile.as_deref().unwrap_or_default()
+                    );
+                    let mut signed_buckets =
+                        self.signed_buckets.lock().expect("Bucket lock poisoned");
+                    signed_buckets.insert(bucket.to_string());
+                }
+                outcome => return outcome,
+            }
+        }
+        let client = self.signed_client(fallback).await;
+        request(client, Some(RequestPayer::Requester)).await
     }
 }
 impl s3::S3ObjOps for Provider {
     async fn head_object(self: &Self, bucket: &str, key: &str) -> anyhow::Result<HeadObjectOutput> {
-        let head = self
-            .client
-            .head_object()
-            .bucket(bucket)
-            .key(key)
-            .send()
-            .await?;
-        Ok(head)
+        self.head_object_version(bucket, key, None).await
     }
 
     async fn get_object(self: &Self, bucket: &str, key: &str) -> anyhow::Result<GetObjectOutput> {
-        let object = self
-            .client
-            .get_object()
-            .bucket(bucket)
-            .key(key)
-            .customize()
-            .send()
-            .await?;
-        Ok(object)
+        self.get_object_version(bucket, key, None).await
     }
 
     async fn get_object_range(
@@ -54,17 +161,141 @@ impl s3::S3ObjOps for Provider {
         key: &str,
         start_byte: u64,
         end_byte: u64,
+    ) -> anyhow::Result<GetObjectOutput> {
+        self.get_object_range_version(bucket, key, None, start_byte, end_byte)
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn head_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> anyhow::Result<HeadObjectOutput> {
+        self.send(bucket, |client, request_payer| async move {
+            let head = client
+                .head_object()
+                .bucket(bucket)
+                .key(key)
+                .set_version_id(version_id.map(str::to_string))
+                .set_request_payer(request_payer)
+                .send()
+                .await?;
+            Ok(head)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> anyhow::Result<GetObjectOutput> {
+        self.send(bucket, |client, request_payer| async move {
+            let object = client
+                .get_object()
+                .bucket(bucket)
+                .key(key)
+                .set_version_id(version_id.map(str::to_string))
+                .set_request_payer(request_payer)
+                .customize()
+                .send()
+                .await?;
+            Ok(object)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object_range_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        start_byte: u64,
+        end_byte: u64,
     ) -> anyhow::Result<GetObjectOutput> {
         let range = format!("bytes={}-{}", start_byte, end_byte);
-        let object = self
-            .client
-            .get_object()
-            .bucket(bucket)
-            .key(key)
-            .range(range)
-            .customize()
-            .send()
-            .await?;
-        Ok(object)
+        self.send(bucket, |client, request_payer| {
+            let range = range.clone();
+            async move {
+                let object = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_version_id(version_id.map(str::to_string))
+                    .range(range)
+                    .set_request_payer(request_payer)
+                    .customize()
+                    .send()
+                    .await?;
+                Ok(object)
+            }
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_objects(
+        self: &Self,
+        bucket: &str,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<s3::ListedObject>> {
+        self.send(bucket, |client, request_payer| async move {
+            s3::list_objects_v2(&client, bucket, prefix, request_payer).await
+        })
+        .await
+    }
+
+    fn md5_etags(self: &Self) -> bool {
+        true
+    }
+
+    /// Rebuild the fallback client, e.g. after an external tool has renewed the profile's session
+    /// credentials
+    async fn refresh_credentials(self: &Self) -> anyhow::Result<bool> {
+        let Some(fallback) = &self.fallback else {
+            return Ok(false);
+        };
+        *self.fallback_client.write().await = Some(fallback.client().await);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let config = ProviderConfig {
+            fallback_profile: Some("earth-search".to_string()),
+            ..ProviderConfig::defaults(provider_config::ELEMENT84)
+        };
+        let provider = Provider::from_config(&config).await;
+        let fallback = provider.fallback.as_ref().unwrap();
+        assert_eq!(fallback.profile.as_deref(), Some("earth-search"));
+        assert_eq!(fallback.region.as_deref(), Some("us-west-2"));
+        assert!(!provider.is_signed("sentinel-cogs"));
+
+        let signed = config.with_profile(Some("field".to_string()));
+        assert!(Provider::from_config(&signed).await.fallback.is_none());
+    }
+
+    #[test]
+    fn test_check_requester_pays() {
+        let l1c = ProviderConfig::defaults(provider_config::ELEMENT84_L1C);
+        assert!(check_requester_pays(provider_config::ELEMENT84_L1C, &l1c).is_err());
+        assert!(check_requester_pays(provider_config::ELEMENT84, &l1c).is_ok());
+        let signed = l1c.clone().with_profile(Some("field".to_string()));
+        assert!(check_requester_pays(provider_config::ELEMENT84_L1C, &signed).is_ok());
+        let fallback = ProviderConfig {
+            fallback_profile: Some("earth-search".to_string()),
+            ..l1c
+        };
+        assert!(check_requester_pays(provider_config::ELEMENT84_L1C, &fallback).is_ok());
     }
 }
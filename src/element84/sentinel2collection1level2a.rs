@@ -1,12 +1,18 @@
-use crate::download_plan::{DownloadPlan, DownloadTask};
-use crate::image_selection::{ImageSelection, Product};
+use crate::download_plan::{try_download, DownloadPlan, DownloadTask};
+use crate::image_selection::{expand_products, ImageSelection, Product};
+use crate::prepare::PrepareOptions;
+use crate::resolve::{file_checksum, file_size, resolve_asset, Location, Transport};
+use crate::s3::S3ObjOps;
+use crate::search::search_items;
+use crate::user_agent;
 use anyhow::{anyhow, Result};
-use regex::Regex;
 use stac::{Asset, Item};
 use std::path::{Path, PathBuf};
 use toml;
+use tracing::instrument;
 
-const COLLECTION_ID: &str = "sentinel-2-c1-l2a";
+pub const STAC_API: &str = "https://earth-search.aws.element84.com/v1";
+pub const COLLECTION_ID: &str = "sentinel-2-c1-l2a";
 
 #[allow(dead_code)]
 pub fn image_selection_toml() -> toml::Table {
@@ -60,72 +66,148 @@ pub fn image_selection_toml() -> toml::Table {
     }
 }
 
+/// The curated template with products generated from the collection's `item_assets`, falling back
+/// to the curated products when the catalogue can't be reached.
+pub async fn image_selection() -> ImageSelection {
+    ImageSelection::from_template(&image_selection_toml())
+        .with_item_assets(STAC_API, COLLECTION_ID)
+        .await
+}
+
 pub async fn generate_download_plan(
     selection: &ImageSelection,
     output_dir: PathBuf,
 ) -> anyhow::Result<DownloadPlan> {
-    let ids_to_download = selection
-        .ids_to_download()
-        .ok_or(anyhow!("No ids to download"))?;
+    generate_download_plan_with(selection, output_dir, &PrepareOptions::default()).await
+}
+
+/// Generate a plan, fetching the selection's items as `options` set out
+#[instrument(skip_all, fields(selection = %selection.id))]
+pub async fn generate_download_plan_with(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+    options: &PrepareOptions,
+) -> Result<DownloadPlan> {
+    generate_download_plan_in(COLLECTION_ID, selection, output_dir, options).await
+}
+
+/// Generate a plan from another Earth Search collection whose items are laid out the same way,
+/// e.g. `sentinel-2-l1c`
+pub(crate) async fn generate_download_plan_in(
+    collection_id: &str,
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+    options: &PrepareOptions,
+) -> Result<DownloadPlan> {
+    let stac_api = selection.stac_api(STAC_API);
+    match (selection.ids_to_download(), &selection.search) {
+        (Some(ids_to_download), _) => {
+            let products_to_download = selection
+                .products_to_download()
+                .ok_or(anyhow!("No products selected for download"))?;
+            let products = &products_to_download;
+            let inputs = ids_to_download
+                .into_iter()
+                .map(|id| (id.clone(), id))
+                .collect();
+            let tasks = options
+                .resolve(selection, inputs, |id: String| async move {
+                    let item = fetch_single_item(stac_api, collection_id, &id).await?;
+                    item_tasks(&item, products)
+                })
+                .await?;
+            Ok(DownloadPlan::new(&selection.id, tasks).with_root(&output_dir))
+        }
+        (None, Some(search)) => {
+            let items = search_items(stac_api, collection_id, search).await?;
+            generate_download_plan_from_items(selection, &items, output_dir)
+        }
+        (None, None) => Err(anyhow!("No ids to download or search defined")),
+    }
+}
+
+/// Generate a plan for STAC Items that have already been retrieved (e.g. an ItemCollection saved
+/// from a search), ignoring the selection's `ids_to_download`.
+#[instrument(skip_all, fields(selection = %selection.id))]
+pub fn generate_download_plan_from_items(
+    selection: &ImageSelection,
+    items: &[Item],
+    output_dir: PathBuf,
+) -> Result<DownloadPlan> {
     let products_to_download = selection
         .products_to_download()
         .ok_or(anyhow!("No products selected for download"))?;
 
     let mut tasks: Vec<DownloadTask> = vec![];
+    for item in items {
+        tasks.extend(item_tasks(item, &products_to_download)?);
+    }
+    Ok(DownloadPlan::new(&selection.id, tasks).with_root(&output_dir))
+}
 
-    for id in ids_to_download {
-        let item = fetch_single_item(COLLECTION_ID, &id).await?;
-        let assets = map_products_to_assets(&item, &products_to_download).ok_or(anyhow!(
-            "Did not find matching assets for specified products"
-        ))?;
-        for asset in assets {
-            let S3UrlParts { bucket, key, .. } = get_s3_url_parts(&asset.href)?;
-
-            let file_name = Path::new(&key).file_name().unwrap();
-            let output = output_dir.join(&id).join(file_name);
-
-            let task = DownloadTask::new(&bucket, &key, output.to_str().unwrap());
-            tasks.push(task)
-        }
+/// The tasks downloading an item's selected assets
+fn item_tasks(item: &Item, products_to_download: &[Product]) -> Result<Vec<DownloadTask>> {
+    let mut tasks: Vec<DownloadTask> = vec![];
+    let assets = map_products_to_assets(item, products_to_download)?;
+    for (product, asset) in assets {
+        let (bucket, key) = match resolve_asset(&asset, Transport::S3)? {
+            Location::S3 { bucket, key, .. } => (bucket, key),
+            _ => return Err(anyhow!("No S3 location found for asset: {}", asset.href)),
+        };
+
+        let file_name = Path::new(&key).file_name().unwrap();
+        let output = Path::new(&item.id).join(file_name);
+
+        let task = DownloadTask::new(&bucket, &key, output.to_str().unwrap())
+            .with_size(file_size(&asset))
+            .with_checksum(file_checksum(&asset))
+            .with_asset_key(&product.id)
+            .with_overviews_only(product.overviews_only)
+            .with_item(item);
+        tasks.push(task)
     }
-    Ok(DownloadPlan::new(&selection.id, tasks))
+    Ok(tasks)
+}
+
+/// Download one asset of one item, e.g. `download_asset(&provider, "S2A_T08VPH_20240504T195929_L2A", "red", path)`,
+/// without writing a selection or plan. Asset keys are those published in the item (see
+/// `image_selection()` for the collection's list).
+pub async fn download_asset<P: AsRef<Path>>(
+    provider: &impl S3ObjOps,
+    id: &str,
+    asset_key: &str,
+    path: P,
+) -> Result<()> {
+    let item = fetch_single_item(STAC_API, COLLECTION_ID, id).await?;
+    let asset = item
+        .assets
+        .get(asset_key)
+        .ok_or(anyhow!("{} has no asset {}", id, asset_key))?;
+    let (bucket, key) = match resolve_asset(asset, Transport::S3)? {
+        Location::S3 { bucket, key, .. } => (bucket, key),
+        _ => return Err(anyhow!("No S3 location found for asset: {}", asset.href)),
+    };
+    try_download(provider, &bucket, &key, &path.as_ref().to_string_lossy()).await
 }
 
-async fn fetch_single_item(collection: &str, id: &str) -> Result<Item> {
-    let url =
-        format!("https://earth-search.aws.element84.com/v1/collections/{collection}/items/{id}");
+#[instrument(skip(stac_api))]
+async fn fetch_single_item(stac_api: &str, collection: &str, id: &str) -> Result<Item> {
+    let url = format!("{stac_api}/collections/{collection}/items/{id}");
     println!("{url}");
-    let item = reqwest::get(url).await?.json::<Item>().await?;
+    let item = user_agent::get(url).await?.json::<Item>().await?;
     Ok(item)
 }
 
-fn map_products_to_assets(item: &Item, products: &[Product]) -> Option<Vec<Asset>> {
+fn map_products_to_assets(item: &Item, products: &[Product]) -> Result<Vec<(Product, Asset)>> {
+    let keys = item.assets.keys().map(|k| k.as_str()).collect::<Vec<_>>();
     let mut assets = vec![];
-    for product in products {
-        let asset = item.assets.get(&product.id)?.clone();
-        assets.push(asset);
+    for product in expand_products(&item.id, products, &keys)? {
+        let asset = item.assets.get(&product.id).ok_or(anyhow!(
+            "Did not find matching asset for product {} in {}",
+            product.id,
+            item.id
+        ))?;
+        assets.push((product, asset.clone()));
     }
-    Some(assets)
-}
-
-struct S3UrlParts {
-    bucket: String,
-    region: String,
-    key: String,
-}
-
-fn get_s3_url_parts(url: &str) -> Result<S3UrlParts> {
-    let pattern = r"https://(?<bucket>[^.]+)\.s3\.(?<region>[^.]+)\.amazonaws\.com/(?<key>.+)";
-    let re = Regex::new(pattern).expect("Regex pattern should always compile");
-
-    let captures = re
-        .captures(url)
-        .ok_or(anyhow!("No regex matches found for: {}", url))?;
-
-    let (_, [bucket, region, key]) = captures.extract();
-    Ok(S3UrlParts {
-        bucket: bucket.to_string(),
-        region: region.to_string(),
-        key: key.to_string(),
-    })
+    Ok(assets)
 }
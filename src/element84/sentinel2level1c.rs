@@ -0,0 +1,100 @@
+//! Earth Search `sentinel-2-l1c`: top-of-atmosphere reflectance as JPEG 2000 from the
+//! requester-pays `sentinel-s2-l1c` bucket in eu-central-1. Its provider has settings of its own,
+//! `[providers.element84-l1c]` (see `provider_config`), which need a `profile` or
+//! `fallback_profile` whose account is billed for the transfer; every request is signed as
+//! requester.
+use super::provider;
+use super::sentinel2collection1level2a::{self, STAC_API};
+use crate::download_plan::DownloadPlan;
+use crate::image_selection::ImageSelection;
+use crate::prepare::PrepareOptions;
+use crate::provider_config;
+use anyhow::Result;
+use std::path::PathBuf;
+use toml;
+
+pub use sentinel2collection1level2a::generate_download_plan_from_items;
+
+pub const COLLECTION_ID: &str = "sentinel-2-l1c";
+
+pub fn image_selection_toml() -> toml::Table {
+    toml::toml! {
+        id = "element84.sentinel2level1c"
+
+        provider = "Element84"
+
+        name = "Sentinel-2 Level 1C Top of Atmosphere Reflectance"
+
+        description = "Level 1C product provides orthorectified Top-Of-Atmosphere (TOA) reflectance\n\
+        images, with sub-pixel multispectral registration. Cloud and land/water masks are\n\
+        included. Earth Search serves the full Sentinel-2 archive from 2015, before the\n\
+        Collection 1 reprocessing."
+
+        docs = "https://sentinels.copernicus.eu/web/sentinel/sentinel-data-access/sentinel-products/sentinel-2-data-products/collection-1-level-1c"
+
+        ids_to_download = [
+            "S2A_8VPH_20240504_0_L1C",
+        ]
+
+        [[products]]
+        id = "red"
+        name = "Red"
+        download = false
+
+        [[products]]
+        id = "green"
+        name = "Green"
+        download = false
+
+        [[products]]
+        id = "blue"
+        name = "Blue"
+        download = false
+
+        [[products]]
+        id = "nir"
+        name = "NIR"
+        download = false
+
+        [[products]]
+        id = "cirrus"
+        name = "Cirrus"
+        download = false
+
+        [[products]]
+        id = "visual"
+        name = "True Color"
+        download = true
+    }
+}
+
+/// The curated template with products generated from the collection's `item_assets`, falling back
+/// to the curated products when the catalogue can't be reached.
+pub async fn image_selection() -> ImageSelection {
+    ImageSelection::from_template(&image_selection_toml())
+        .with_item_assets(STAC_API, COLLECTION_ID)
+        .await
+}
+
+pub async fn generate_download_plan(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+) -> Result<DownloadPlan> {
+    generate_download_plan_with(selection, output_dir, &PrepareOptions::default()).await
+}
+
+pub async fn generate_download_plan_with(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+    options: &PrepareOptions,
+) -> Result<DownloadPlan> {
+    // Rather than planning downloads that can't start
+    provider::check_configured(provider_config::ELEMENT84_L1C)?;
+    sentinel2collection1level2a::generate_download_plan_in(
+        COLLECTION_ID,
+        selection,
+        output_dir,
+        options,
+    )
+    .await
+}
@@ -0,0 +1,89 @@
+//! Earth Search `sentinel-2-pre-c1-l2a`: surface reflectance COGs processed before the Collection 1
+//! reprocessing, for the history Collection 1 doesn't cover yet. Items have the same assets as
+//! `sentinel2collection1level2a`.
+use super::sentinel2collection1level2a::{self, STAC_API};
+use crate::download_plan::DownloadPlan;
+use crate::image_selection::ImageSelection;
+use crate::prepare::PrepareOptions;
+use anyhow::Result;
+use std::path::PathBuf;
+use toml;
+
+pub use sentinel2collection1level2a::generate_download_plan_from_items;
+
+pub const COLLECTION_ID: &str = "sentinel-2-pre-c1-l2a";
+
+pub fn image_selection_toml() -> toml::Table {
+    toml::toml! {
+        id = "element84.sentinel2precollection1level2a"
+
+        provider = "Element84"
+
+        name = "Sentinel-2 Pre-Collection 1 Level 2A Surface Reflectance"
+
+        description = "Level 2A Surface Reflectance (SR) images as Cloud Optimized GeoTIFFs, processed\n\
+        with the processing baselines in use at the time of acquisition rather than\n\
+        reprocessed for Collection 1. Acquisitions from 2022 on (processing baseline 04.00)\n\
+        carry a reflectance offset (BOA_ADD_OFFSET) that earlier ones lack."
+
+        docs = "https://github.com/Element84/earth-search"
+
+        ids_to_download = [
+            "S2A_8VPH_20210504_0_L2A",
+        ]
+
+        [[products]]
+        id = "red"
+        name = "Red"
+        download = false
+
+        [[products]]
+        id = "green"
+        name = "Green"
+        download = false
+
+        [[products]]
+        id = "blue"
+        name = "Blue"
+        download = false
+
+        [[products]]
+        id = "nir"
+        name = "NIR"
+        download = false
+
+        [[products]]
+        id = "visual"
+        name = "True Color"
+        download = true
+    }
+}
+
+/// The curated template with products generated from the collection's `item_assets`, falling back
+/// to the curated products when the catalogue can't be reached.
+pub async fn image_selection() -> ImageSelection {
+    ImageSelection::from_template(&image_selection_toml())
+        .with_item_assets(STAC_API, COLLECTION_ID)
+        .await
+}
+
+pub async fn generate_download_plan(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+) -> Result<DownloadPlan> {
+    generate_download_plan_with(selection, output_dir, &PrepareOptions::default()).await
+}
+
+pub async fn generate_download_plan_with(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+    options: &PrepareOptions,
+) -> Result<DownloadPlan> {
+    sentinel2collection1level2a::generate_download_plan_in(
+        COLLECTION_ID,
+        selection,
+        output_dir,
+        options,
+    )
+    .await
+}
@@ -0,0 +1,114 @@
+//! Named execution environments in the config file, bundling the settings that change with the
+//! network a machine is on, so moving between them is `--env field` rather than a dozen flags:
+//!
+//! ```toml
+//! [environments.field]
+//! proxy = "http://10.0.0.1:3128"
+//! profile = "flaky"
+//! jobs = 1
+//! max_bytes_per_sec = 65536
+//! max_bytes_per_day = 2000000000
+//! windows = ["22:00-06:00"]
+//! ```
+//!
+//! Options given on the command line take precedence over the environment, and the environment
+//! over the rest of the config file.
+use crate::download_plan::DownloadOptions;
+use crate::profile::Profile;
+use crate::schedule::Window;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Environment {
+    /// Proxy for HTTP requests, e.g. to STAC APIs, Google Cloud Storage and S3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Preset download settings, replacing the profile in the config file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<Profile>,
+    /// Tasks downloaded at the same time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<u64>,
+    /// Throughput cap of each transfer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes_per_sec: Option<u64>,
+    /// Copernicus only, see `copernicus::quota`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_minute: Option<u64>,
+    /// Copernicus only, see `copernicus::quota`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes_per_day: Option<u64>,
+    /// Daily windows to download in, see `schedule`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<Window>,
+}
+
+impl Environment {
+    /// `options` with the environment's download settings replacing theirs
+    pub fn apply(self: &Self, mut options: DownloadOptions) -> DownloadOptions {
+        if let Some(jobs) = self.jobs {
+            options.jobs = jobs;
+        }
+        if self.chunk_size.is_some() {
+            options.chunk_size = self.chunk_size;
+        }
+        if self.max_bytes_per_sec.is_some() {
+            options.pacing.max_bytes_per_sec = self.max_bytes_per_sec;
+        }
+        options
+    }
+
+    /// Send the HTTP requests of this process through the proxy, if there is one. Clients read the
+    /// proxy variables when they are built, so this comes before any request, and before the
+    /// runtime starts threads that could read the environment while it is set.
+    pub fn use_proxy(self: &Self) {
+        if let Some(proxy) = &self.proxy {
+            env::set_var("HTTP_PROXY", proxy);
+            env::set_var("HTTPS_PROXY", proxy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_environment() {
+        let config: Config = toml::from_str(
+            "[environments.field]\n\
+             profile = \"flaky\"\n\
+             jobs = 1\n\
+             max_bytes_per_sec = 65536\n\
+             windows = [\"22:00-06:00\"]\n\
+             [environments.office]\n\
+             jobs = 8\n",
+        )
+        .unwrap();
+        let field = config.environment("field").unwrap();
+        assert_eq!(field.profile, Some(Profile::Flaky));
+        assert_eq!(field.windows, ["22:00-06:00".parse().unwrap()]);
+        let options = field.apply(DownloadOptions {
+            jobs: 4,
+            chunk_size: Some(1024),
+            ..DownloadOptions::default()
+        });
+        assert_eq!(options.jobs, 1);
+        assert_eq!(options.chunk_size, Some(1024));
+        assert_eq!(options.pacing.max_bytes_per_sec, Some(65536));
+
+        let error = config.environment("home").unwrap_err().to_string();
+        assert!(error.contains("field, office"), "{}", error);
+        assert_eq!(
+            toml::to_string(&config)
+                .unwrap()
+                .matches("22:00-06:00")
+                .count(),
+            1
+        );
+    }
+}
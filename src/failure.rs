@@ -0,0 +1,282 @@
+//! Process exit codes and machine-readable failure reasons, so wrapper scripts and schedulers can
+//! branch on the outcome of a command without parsing its output, and the finer reasons tasks fail
+//! for in the execution report
+use crate::backoff::is_throttle_error;
+use crate::download_plan::{is_stalled, Incomplete, TimedOut};
+use crate::pause::Paused;
+use crate::s3::is_auth_error;
+use crate::verify::VerificationFailed;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::ErrorKind;
+use thiserror::Error;
+
+/// Markers of connection failures in S3 SDK errors, which don't expose the underlying I/O error
+const NETWORK_ERROR_MARKERS: [&str; 5] = [
+    "dispatch failure",
+    "DispatchFailure",
+    "TimeoutError",
+    "ResponseTimeout",
+    "connection closed before message completed",
+];
+
+/// Markers of name resolution failures, from hyper's resolver and the system's
+const DNS_ERROR_MARKERS: [&str; 4] = [
+    "dns error",
+    "failed to lookup address",
+    "Name or service not known",
+    "Temporary failure in name resolution",
+];
+
+/// Markers of TLS failures, from rustls and native-tls
+const TLS_ERROR_MARKERS: [&str; 4] = [
+    "InvalidCertificate",
+    "certificate verify failed",
+    "invalid peer certificate",
+    "handshake failure",
+];
+
+/// Markers of missing objects in S3 SDK and HTTP errors. Not a bare `NotFound`, which I/O errors
+/// for missing local files show too.
+const NOT_FOUND_ERROR_MARKERS: [&str; 4] = [
+    "NoSuchKey",
+    "NotFound(NotFound",
+    "StatusCode(404)",
+    "404 Not Found",
+];
+
+/// The command was interrupted, e.g. with Ctrl-C
+#[derive(Debug, Error)]
+#[error("Cancelled by the user")]
+pub struct Cancelled;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// Credentials were missing, rejected or expired
+    Auth,
+    /// The connection failed, timed out or dropped
+    Network,
+    /// Some tasks stalled or the plan timed out, and a later run can resume them
+    Partial,
+    /// A downloaded file did not match its checksum, ETag or size
+    Verification,
+    /// The plan is paused until `slow-stac resume`
+    Paused,
+    Cancelled,
+    Other,
+}
+
+impl FailureKind {
+    pub fn classify(error: &anyhow::Error) -> Self {
+        let is = |check: fn(&(dyn std::error::Error + 'static)) -> bool| error.chain().any(check);
+        if is(|e| e.is::<Cancelled>()) {
+            FailureKind::Cancelled
+        } else if is(|e| e.is::<Paused>()) {
+            FailureKind::Paused
+        } else if is(|e| e.is::<VerificationFailed>()) {
+            FailureKind::Verification
+        } else if is(|e| e.is::<Incomplete>() || e.is::<TimedOut>()) || is_stalled(error) {
+            FailureKind::Partial
+        } else if is_auth_error(error) {
+            FailureKind::Auth
+        } else if is_network_error(error) {
+            FailureKind::Network
+        } else {
+            FailureKind::Other
+        }
+    }
+
+    /// 2 is left to clap for usage errors and 130 follows the shell convention for SIGINT.
+    pub fn exit_code(self: &Self) -> u8 {
+        match self {
+            FailureKind::Other => 1,
+            FailureKind::Auth => 3,
+            FailureKind::Network => 4,
+            FailureKind::Partial => 5,
+            FailureKind::Verification => 6,
+            FailureKind::Paused => 7,
+            FailureKind::Cancelled => 130,
+        }
+    }
+}
+
+/// Why a task failed, finer than `FailureKind` so the report tells e.g. expired credentials from a
+/// bad link
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskFailure {
+    /// The host name did not resolve
+    Dns,
+    /// The connection was refused, timed out or dropped
+    Connect,
+    /// The TLS handshake failed, e.g. on a certificate injected by a captive portal
+    Tls,
+    /// The transfer was too slow or the task timed out, see `download_plan::Stalled`
+    Stalled,
+    /// The provider kept throttling requests past the retries
+    Throttled,
+    /// Credentials were missing, rejected or expired
+    Auth,
+    /// The object is gone from the provider
+    NotFound,
+    /// The downloaded file did not match its checksum, ETag or size
+    Checksum,
+    /// Writing the file failed, e.g. on a full disk
+    Disk,
+    Other,
+}
+
+impl TaskFailure {
+    pub fn classify(error: &anyhow::Error) -> Self {
+        let debug = format!("{:?}", error);
+        let marked = |markers: &[&str]| markers.iter().any(|marker| debug.contains(marker));
+        if error.chain().any(|e| e.is::<VerificationFailed>()) {
+            TaskFailure::Checksum
+        } else if is_stalled(error) {
+            TaskFailure::Stalled
+        } else if is_throttle_error(error) {
+            TaskFailure::Throttled
+        } else if is_auth_error(error) {
+            TaskFailure::Auth
+        } else if marked(&NOT_FOUND_ERROR_MARKERS) {
+            TaskFailure::NotFound
+        } else if marked(&DNS_ERROR_MARKERS) {
+            TaskFailure::Dns
+        } else if marked(&TLS_ERROR_MARKERS) {
+            TaskFailure::Tls
+        } else if is_network_error(error) {
+            TaskFailure::Connect
+        // Other I/O errors of a task come from writing its file
+        } else if error.chain().any(|e| e.is::<std::io::Error>()) {
+            TaskFailure::Disk
+        } else {
+            TaskFailure::Other
+        }
+    }
+}
+
+impl fmt::Display for TaskFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TaskFailure::Dns => "dns",
+            TaskFailure::Connect => "connect",
+            TaskFailure::Tls => "tls",
+            TaskFailure::Stalled => "stalled",
+            TaskFailure::Throttled => "throttled",
+            TaskFailure::Auth => "auth",
+            TaskFailure::NotFound => "not-found",
+            TaskFailure::Checksum => "checksum",
+            TaskFailure::Disk => "disk",
+            TaskFailure::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn is_network_error(error: &anyhow::Error) -> bool {
+    let io_or_http = error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_connect() || e.is_timeout() || e.is_request() || e.is_body();
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::UnexpectedEof
+            );
+        }
+        false
+    });
+    let error = format!("{:?}", error);
+    io_or_http
+        || NETWORK_ERROR_MARKERS
+            .iter()
+            .any(|marker| error.contains(marker))
+}
+
+/// The error as a JSON object for `--json`, e.g.
+/// `{"error": {"kind": "auth", "exit_code": 3, "message": "...", "causes": [...]}}`
+pub fn json_error(error: &anyhow::Error) -> Value {
+    let kind = FailureKind::classify(error);
+    json!({
+        "error": {
+            "kind": kind,
+            "exit_code": kind.exit_code(),
+            "message": error.to_string(),
+            "causes": error.chain().skip(1).map(|cause| cause.to_string()).collect::<Vec<_>>(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_classify() {
+        let classify = |error: anyhow::Error| FailureKind::classify(&error);
+        assert_eq!(classify(Cancelled.into()), FailureKind::Cancelled);
+        assert_eq!(
+            classify(anyhow::Error::from(VerificationFailed("bad".to_string())).context("task")),
+            FailureKind::Verification
+        );
+        let incomplete = Incomplete {
+            completed: 2,
+            stalled: 1,
+        };
+        assert_eq!(classify(incomplete.into()), FailureKind::Partial);
+        assert_eq!(classify(anyhow!("ExpiredToken")), FailureKind::Auth);
+        let reset = std::io::Error::new(ErrorKind::ConnectionReset, "reset");
+        assert_eq!(classify(reset.into()), FailureKind::Network);
+        assert_eq!(classify(anyhow!("No such plan")), FailureKind::Other);
+        let paused = crate::pause::Paused("plan.json".into());
+        assert_eq!(classify(paused.into()), FailureKind::Paused);
+
+        let json = json_error(&anyhow!("ExpiredToken").context("Downloading B02"));
+        assert_eq!(json["error"]["kind"], "auth");
+        assert_eq!(json["error"]["exit_code"], 3);
+        assert_eq!(json["error"]["causes"][0], "ExpiredToken");
+    }
+
+    #[test]
+    fn test_task_failure() {
+        let classify = |error: anyhow::Error| TaskFailure::classify(&error);
+        assert_eq!(
+            classify(anyhow!(
+                "dispatch failure: dns error: failed to lookup address"
+            )),
+            TaskFailure::Dns
+        );
+        assert_eq!(
+            classify(anyhow!(
+                "dispatch failure: invalid peer certificate: UnknownIssuer"
+            )),
+            TaskFailure::Tls
+        );
+        let reset = std::io::Error::new(ErrorKind::ConnectionReset, "reset");
+        assert_eq!(classify(reset.into()), TaskFailure::Connect);
+        assert_eq!(classify(anyhow!("StatusCode(503)")), TaskFailure::Throttled);
+        assert_eq!(
+            classify(anyhow!("service error: NoSuchKey")),
+            TaskFailure::NotFound
+        );
+        assert_eq!(
+            classify(VerificationFailed("md5 mismatch".to_string()).into()),
+            TaskFailure::Checksum
+        );
+        let full = std::io::Error::new(ErrorKind::Other, "No space left on device");
+        assert_eq!(
+            classify(anyhow::Error::from(full).context("B04.tif")),
+            TaskFailure::Disk
+        );
+        assert_eq!(TaskFailure::NotFound.to_string(), "not-found");
+    }
+}
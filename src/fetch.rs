@@ -0,0 +1,537 @@
+//! One-shot downloads for library users who want a single asset without writing a selection or
+//! plan file
+use crate::copernicus::sentinel2level2a;
+use crate::download_plan::{DownloadOptions, DownloadPlan, DownloadTask};
+use crate::earthdata::mod09ga;
+use crate::element84::{
+    sentinel2collection1level2a, sentinel2level1c, sentinel2precollection1level2a,
+};
+use crate::image_selection::ImageSelection;
+use crate::planetary_computer::landsat_c2_l2;
+use crate::prepare::PrepareOptions;
+use crate::provider_config;
+use crate::s3::{ListedObject, S3ObjOps};
+use crate::verify::{StreamDigest, VerificationFailed};
+use crate::{copernicus, earthdata, element84, planetary_computer};
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Consecutive failed requests after which a stream is given up on
+const MAX_STREAM_RETRIES: u32 = 5;
+const STREAM_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Download one asset (product) of one item, e.g.
+/// `fetch_asset("element84.sentinel2collection1level2a", "S2A_T08VPH_20240504T195929_L2A", "red", dir, &DownloadOptions::default())`.
+///
+/// `collection` is a selection id. The item is resolved through the collection's catalogue into
+/// a single-task plan, which is executed with the collection's default provider, resuming any
+/// partial download in `output_dir`. Returns the path of the downloaded file.
+pub async fn fetch_asset<P: AsRef<Path>>(
+    collection: &str,
+    item_id: &str,
+    asset_key: &str,
+    output_dir: P,
+    options: &DownloadOptions,
+) -> Result<PathBuf> {
+    let provider = CollectionProvider::new(collection).await?;
+    let plan = provider
+        .asset_plan(item_id, asset_key, output_dir.as_ref().to_path_buf())
+        .await?;
+    plan.execute_with(&provider, options).await?;
+    match plan.output_paths().as_slice() {
+        [path] => Ok(path.clone()),
+        paths => Err(anyhow!(
+            "Expected {} of {} to be a single file, planned {}",
+            asset_key,
+            item_id,
+            paths.len()
+        )),
+    }
+}
+
+/// The default provider of each collection, for operations on single assets
+pub enum CollectionProvider {
+    Copernicus(copernicus::Provider),
+    /// With the selection id of the Earth Search collection
+    Element84(element84::Provider, String),
+    Earthdata(earthdata::Provider),
+    PlanetaryComputer(planetary_computer::Provider),
+}
+
+impl CollectionProvider {
+    /// The provider of a collection given by its selection id
+    pub async fn new(collection: &str) -> Result<Self> {
+        match collection {
+            "copernicus.sentinel2level2a" => Ok(CollectionProvider::Copernicus(
+                copernicus::Provider::configured().await?,
+            )),
+            "element84.sentinel2collection1level2a"
+            | "element84.sentinel2precollection1level2a" => Ok(CollectionProvider::Element84(
+                element84::Provider::configured().await?,
+                collection.to_string(),
+            )),
+            "element84.sentinel2level1c" => Ok(CollectionProvider::Element84(
+                element84::Provider::configured_as(provider_config::ELEMENT84_L1C).await?,
+                collection.to_string(),
+            )),
+            "earthdata.mod09ga" => Ok(CollectionProvider::Earthdata(
+                earthdata::Provider::from_env().await?,
+            )),
+            "planetarycomputer.landsatc2l2" => Ok(CollectionProvider::PlanetaryComputer(
+                planetary_computer::Provider::from_env(),
+            )),
+            _ => Err(anyhow!("Unknown collection: {}", collection)),
+        }
+    }
+
+    /// The plan of one asset of one item into `output_dir`, resolved through the collection's
+    /// catalogue
+    pub async fn asset_plan(
+        self: &Self,
+        item_id: &str,
+        asset_key: &str,
+        output_dir: PathBuf,
+    ) -> Result<DownloadPlan> {
+        match self {
+            CollectionProvider::Copernicus(provider) => {
+                let selection =
+                    ImageSelection::from_template(&sentinel2level2a::image_selection_toml())
+                        .for_asset(item_id, asset_key)
+                        .with_normalized_ids()?;
+                sentinel2level2a::generate_download_plan(provider, &selection, output_dir).await
+            }
+            CollectionProvider::Element84(_, collection) => {
+                let (collection_id, template) = match collection.as_str() {
+                    "element84.sentinel2level1c" => (
+                        sentinel2level1c::COLLECTION_ID,
+                        sentinel2level1c::image_selection_toml(),
+                    ),
+                    "element84.sentinel2precollection1level2a" => (
+                        sentinel2precollection1level2a::COLLECTION_ID,
+                        sentinel2precollection1level2a::image_selection_toml(),
+                    ),
+                    _ => (
+                        sentinel2collection1level2a::COLLECTION_ID,
+                        sentinel2collection1level2a::image_selection_toml(),
+                    ),
+                };
+                let selection = ImageSelection::from_template(&template)
+                    .for_asset(item_id, asset_key)
+                    .with_normalized_ids()?;
+                sentinel2collection1level2a::generate_download_plan_in(
+                    collection_id,
+                    &selection,
+                    output_dir,
+                    &PrepareOptions::default(),
+                )
+                .await
+            }
+            CollectionProvider::Earthdata(_) => {
+                let selection = ImageSelection::from_template(&mod09ga::image_selection_toml())
+                    .for_asset(item_id, asset_key);
+                mod09ga::generate_download_plan(&selection, output_dir).await
+            }
+            CollectionProvider::PlanetaryComputer(_) => {
+                let selection =
+                    ImageSelection::from_template(&landsat_c2_l2::image_selection_toml())
+                        .for_asset(item_id, asset_key);
+                landsat_c2_l2::generate_download_plan(&selection, output_dir).await
+            }
+        }
+    }
+
+    /// The task planned for one asset of one item, resolved through the collection's catalogue
+    pub async fn asset_task(self: &Self, item_id: &str, asset_key: &str) -> Result<DownloadTask> {
+        // Plans need an output directory, nothing is written to it
+        let plan = self
+            .asset_plan(item_id, asset_key, std::env::temp_dir())
+            .await?;
+        match plan.tasks() {
+            [task] => Ok(task.clone()),
+            tasks => Err(anyhow!(
+                "Expected {} of {} to be a single file, planned {}",
+                asset_key,
+                item_id,
+                tasks.len()
+            )),
+        }
+    }
+}
+
+impl S3ObjOps for CollectionProvider {
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        match self {
+            CollectionProvider::Copernicus(provider) => provider.head_object(bucket, key).await,
+            CollectionProvider::Element84(provider, _) => provider.head_object(bucket, key).await,
+            CollectionProvider::Earthdata(provider) => provider.head_object(bucket, key).await,
+            CollectionProvider::PlanetaryComputer(provider) => {
+                provider.head_object(bucket, key).await
+            }
+        }
+    }
+
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        match self {
+            CollectionProvider::Copernicus(provider) => provider.get_object(bucket, key).await,
+            CollectionProvider::Element84(provider, _) => provider.get_object(bucket, key).await,
+            CollectionProvider::Earthdata(provider) => provider.get_object(bucket, key).await,
+            CollectionProvider::PlanetaryComputer(provider) => {
+                provider.get_object(bucket, key).await
+            }
+        }
+    }
+
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        match self {
+            CollectionProvider::Copernicus(provider) => {
+                provider
+                    .get_object_range(bucket, key, start_byte, end_byte)
+                    .await
+            }
+            CollectionProvider::Element84(provider, _) => {
+                provider
+                    .get_object_range(bucket, key, start_byte, end_byte)
+                    .await
+            }
+            CollectionProvider::Earthdata(provider) => {
+                provider
+                    .get_object_range(bucket, key, start_byte, end_byte)
+                    .await
+            }
+            CollectionProvider::PlanetaryComputer(provider) => {
+                provider
+                    .get_object_range(bucket, key, start_byte, end_byte)
+                    .await
+            }
+        }
+    }
+
+    async fn head_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectOutput> {
+        match self {
+            CollectionProvider::Copernicus(provider) => {
+                provider.head_object_version(bucket, key, version_id).await
+            }
+            CollectionProvider::Element84(provider, _) => {
+                provider.head_object_version(bucket, key, version_id).await
+            }
+            CollectionProvider::Earthdata(provider) => {
+                provider.head_object_version(bucket, key, version_id).await
+            }
+            CollectionProvider::PlanetaryComputer(provider) => {
+                provider.head_object_version(bucket, key, version_id).await
+            }
+        }
+    }
+
+    async fn get_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectOutput> {
+        match self {
+            CollectionProvider::Copernicus(provider) => {
+                provider.get_object_version(bucket, key, version_id).await
+            }
+            CollectionProvider::Element84(provider, _) => {
+                provider.get_object_version(bucket, key, version_id).await
+            }
+            CollectionProvider::Earthdata(provider) => {
+                provider.get_object_version(bucket, key, version_id).await
+            }
+            CollectionProvider::PlanetaryComputer(provider) => {
+                provider.get_object_version(bucket, key, version_id).await
+            }
+        }
+    }
+
+    async fn get_object_range_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        match self {
+            CollectionProvider::Copernicus(provider) => {
+                provider
+                    .get_object_range_version(bucket, key, version_id, start_byte, end_byte)
+                    .await
+            }
+            CollectionProvider::Element84(provider, _) => {
+                provider
+                    .get_object_range_version(bucket, key, version_id, start_byte, end_byte)
+                    .await
+            }
+            CollectionProvider::Earthdata(provider) => {
+                provider
+                    .get_object_range_version(bucket, key, version_id, start_byte, end_byte)
+                    .await
+            }
+            CollectionProvider::PlanetaryComputer(provider) => {
+                provider
+                    .get_object_range_version(bucket, key, version_id, start_byte, end_byte)
+                    .await
+            }
+        }
+    }
+
+    async fn list_objects(self: &Self, bucket: &str, prefix: &str) -> Result<Vec<ListedObject>> {
+        match self {
+            CollectionProvider::Copernicus(provider) => provider.list_objects(bucket, prefix).await,
+            CollectionProvider::Element84(provider, _) => {
+                provider.list_objects(bucket, prefix).await
+            }
+            CollectionProvider::Earthdata(provider) => provider.list_objects(bucket, prefix).await,
+            CollectionProvider::PlanetaryComputer(provider) => {
+                provider.list_objects(bucket, prefix).await
+            }
+        }
+    }
+
+    fn md5_etags(self: &Self) -> bool {
+        match self {
+            CollectionProvider::Copernicus(provider) => provider.md5_etags(),
+            CollectionProvider::Element84(provider, _) => provider.md5_etags(),
+            CollectionProvider::Earthdata(provider) => provider.md5_etags(),
+            CollectionProvider::PlanetaryComputer(provider) => provider.md5_etags(),
+        }
+    }
+
+    async fn refresh_credentials(self: &Self) -> Result<bool> {
+        match self {
+            CollectionProvider::Copernicus(provider) => provider.refresh_credentials().await,
+            CollectionProvider::Element84(provider, _) => provider.refresh_credentials().await,
+            CollectionProvider::Earthdata(provider) => provider.refresh_credentials().await,
+            CollectionProvider::PlanetaryComputer(provider) => provider.refresh_credentials().await,
+        }
+    }
+}
+
+/// Write one asset of one item to `writer` as it downloads, e.g. to stdout for piping into
+/// `gdal_translate /vsistdin/`. Nothing is written to disk. Returns the number of bytes written.
+pub async fn cat_asset<W: AsyncWrite + Unpin>(
+    collection: &str,
+    item_id: &str,
+    asset_key: &str,
+    writer: &mut W,
+) -> Result<u64> {
+    let provider = CollectionProvider::new(collection).await?;
+    let task = provider.asset_task(item_id, asset_key).await?;
+    stream_object(
+        &provider,
+        task.bucket(),
+        task.key(),
+        task.checksum(),
+        writer,
+    )
+    .await
+}
+
+/// Write an object to `writer` as it arrives. A dropped connection is resumed from the next byte
+/// with a range request, so the writer sees each byte once. The object is checked against
+/// `checksum` (`<algorithm>:<hex digest>`) at the end, when the bytes have already been written,
+/// so a mismatch is reported by the error alone.
+pub async fn stream_object<W: AsyncWrite + Unpin>(
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+    checksum: Option<&str>,
+    writer: &mut W,
+) -> Result<u64> {
+    stream_object_with_delay(provider, bucket, key, checksum, writer, STREAM_RETRY_DELAY).await
+}
+
+async fn stream_object_with_delay<W: AsyncWrite + Unpin>(
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+    checksum: Option<&str>,
+    writer: &mut W,
+    retry_delay: Duration,
+) -> Result<u64> {
+    let total_size = provider
+        .head_object(bucket, key)
+        .await?
+        .content_length()
+        .ok_or(anyhow!("Error reading size of remote object"))? as u64;
+    let expected = checksum.and_then(|checksum| checksum.split_once(':'));
+    let mut digest = expected.and_then(|(algorithm, _)| StreamDigest::new(algorithm));
+    let mut written = 0;
+    let mut failures = 0;
+    while written < total_size {
+        let failure = match provider
+            .get_object_range(bucket, key, written, total_size - 1)
+            .await
+        {
+            Ok(mut response) => {
+                let resumed_at = format!("bytes {}-", written);
+                if written > 0
+                    && !response
+                        .content_range()
+                        .is_some_and(|range| range.starts_with(&resumed_at))
+                {
+                    return Err(anyhow!(
+                        "{} ignored the range request, cannot resume",
+                        bucket
+                    ));
+                }
+                let started_at = written;
+                let failure = loop {
+                    match response.body.try_next().await {
+                        Ok(Some(bytes)) => {
+                            // A reader that went away isn't worth retrying for
+                            writer.write_all(&bytes).await?;
+                            if let Some(digest) = &mut digest {
+                                digest.update(&bytes);
+                            }
+                            written += bytes.len() as u64;
+                        }
+                        // A body ending short of the object is a dropped connection too
+                        Ok(None) if written < total_size => {
+                            break Some(anyhow!(
+                                "The response ended at byte {} of {}",
+                                written,
+                                total_size
+                            ))
+                        }
+                        Ok(None) => break None,
+                        Err(e) => break Some(anyhow::Error::from(e)),
+                    }
+                };
+                if written > started_at {
+                    failures = 0;
+                }
+                failure
+            }
+            Err(e) => Some(e),
+        };
+        let Some(e) = failure else { continue };
+        failures += 1;
+        if failures > MAX_STREAM_RETRIES {
+            return Err(e);
+        }
+        eprintln!(
+            "Warning: {}, resuming from byte {} of {}",
+            e, written, total_size
+        );
+        tokio::time::sleep(retry_delay).await;
+    }
+    writer.flush().await?;
+    if let (Some(digest), Some((algorithm, expected))) = (digest, expected) {
+        let actual = digest.hex();
+        if actual != expected.to_lowercase() {
+            return Err(VerificationFailed(format!(
+                "{} has {} {} but {} was expected",
+                key, algorithm, actual, expected
+            ))
+            .into());
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::primitives::ByteStream;
+
+    /// Drops every connection after `truncate_to` bytes
+    struct Flaky {
+        content: &'static [u8],
+        truncate_to: usize,
+    }
+
+    impl S3ObjOps for Flaky {
+        async fn head_object(self: &Self, _: &str, _: &str) -> Result<HeadObjectOutput> {
+            Ok(HeadObjectOutput::builder()
+                .content_length(self.content.len() as i64)
+                .build())
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Err(anyhow!("Only ranges are served"))
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            start_byte: u64,
+            end_byte: u64,
+        ) -> Result<GetObjectOutput> {
+            let start = start_byte as usize;
+            let end = (end_byte as usize + 1).min(start + self.truncate_to);
+            Ok(GetObjectOutput::builder()
+                .content_range(format!(
+                    "bytes {}-{}/{}",
+                    start_byte,
+                    end_byte,
+                    self.content.len()
+                ))
+                .body(ByteStream::from_static(&self.content[start..end]))
+                .build())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_object() {
+        let provider = Flaky {
+            content: b"0123456789",
+            truncate_to: 4,
+        };
+        let checksum = "md5:781e5e245d69b566979b86e28d23f2c7";
+        let mut output = vec![];
+        let written = stream_object_with_delay(
+            &provider,
+            "b",
+            "k",
+            Some(checksum),
+            &mut output,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, 10);
+        assert_eq!(output, b"0123456789");
+
+        let mut output = vec![];
+        let e = stream_object_with_delay(
+            &provider,
+            "b",
+            "k",
+            Some("md5:00"),
+            &mut output,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap_err();
+        assert!(e.is::<VerificationFailed>());
+
+        // Empty bodies are given up on rather than requested forever
+        let provider = Flaky {
+            content: b"0123456789",
+            truncate_to: 0,
+        };
+        let e = stream_object_with_delay(&provider, "b", "k", None, &mut vec![], Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("ended at byte 0 of 10"));
+    }
+}
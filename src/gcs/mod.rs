@@ -0,0 +1,36 @@
+mod provider;
+
+pub use provider::Provider;
+
+use anyhow::{anyhow, Result};
+
+/// Split a `gs://bucket/key` url into its bucket and key, so collections can build download tasks
+/// from GCS-hosted alternate hrefs.
+pub fn split_gs_url(url: &str) -> Result<(String, String)> {
+    let path = url
+        .strip_prefix("gs://")
+        .ok_or(anyhow!("Expected a gs:// url, found: {}", url))?;
+    let (bucket, key) = path
+        .split_once('/')
+        .ok_or(anyhow!("No object key found in url: {}", url))?;
+    Ok((bucket.to_string(), key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_gs_url() {
+        let url = "gs://gcp-public-data-sentinel-2/tiles/08/V/PH/granule.SAFE/manifest.safe";
+        let (bucket, key) = split_gs_url(url).unwrap();
+        assert_eq!(bucket, "gcp-public-data-sentinel-2");
+        assert_eq!(key, "tiles/08/V/PH/granule.SAFE/manifest.safe");
+    }
+
+    #[test]
+    fn test_split_gs_url_without_key() {
+        assert!(split_gs_url("gs://gcp-public-data-sentinel-2").is_err());
+        assert!(split_gs_url("s3://bucket/key").is_err());
+    }
+}
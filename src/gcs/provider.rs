@@ -0,0 +1,244 @@
+use crate::{http, s3, user_agent};
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tracing::instrument;
+
+const STORAGE_URL: &str = "https://storage.googleapis.com";
+const READ_ONLY_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_only";
+const TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+/// Refresh access tokens this long before they expire so in-flight requests don't race the expiry
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Serves downloads from Google Cloud Storage over the XML API, either anonymously for public
+/// buckets or with a service account.
+pub struct Provider {
+    client: Client,
+    service_account: Option<ServiceAccount>,
+}
+
+/// The subset of a service account json key file needed to request access tokens
+#[derive(Deserialize, Clone, Debug)]
+pub struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+struct ServiceAccount {
+    key: ServiceAccountKey,
+    token: Mutex<Option<AccessToken>>,
+}
+
+struct AccessToken {
+    value: String,
+    expires_at: Instant,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+/// A page of the JSON API's object listing, which unlike the XML API needs no XML parsing
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectList {
+    #[serde(default)]
+    items: Vec<ListedItem>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListedItem {
+    name: String,
+    /// Sizes are given as strings, being unsigned 64 bit
+    size: String,
+    etag: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl Provider {
+    pub fn as_anon() -> Self {
+        Self {
+            client: user_agent::client(),
+            service_account: None,
+        }
+    }
+
+    pub fn from_service_account(key: ServiceAccountKey) -> Self {
+        Self {
+            client: user_agent::client(),
+            service_account: Some(ServiceAccount {
+                key,
+                token: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn from_service_account_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let key: ServiceAccountKey = serde_json::from_str(&content)?;
+        Ok(Self::from_service_account(key))
+    }
+
+    /// Use the service account key referenced by `GOOGLE_APPLICATION_CREDENTIALS` if it is set,
+    /// otherwise fall back to anonymous access.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+            Ok(path) => Self::from_service_account_file(path),
+            Err(_) => Ok(Self::as_anon()),
+        }
+    }
+
+    async fn request(self: &Self, bucket: &str, key: &str) -> Result<RequestBuilder> {
+        self.authorized(
+            self.client
+                .get(format!("{}/{}/{}", STORAGE_URL, bucket, key)),
+        )
+        .await
+    }
+
+    async fn authorized(self: &Self, request: RequestBuilder) -> Result<RequestBuilder> {
+        match &self.service_account {
+            Some(account) => Ok(request.bearer_auth(account.access_token(&self.client).await?)),
+            None => Ok(request),
+        }
+    }
+}
+
+impl ServiceAccount {
+    /// Return the cached access token, requesting a new one if it is missing or about to expire.
+    async fn access_token(self: &Self, client: &Client) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some(t) = token.as_ref() {
+            if t.expires_at > Instant::now() + TOKEN_REFRESH_MARGIN {
+                return Ok(t.value.clone());
+            }
+        }
+        let fresh = self.fetch_token(client).await?;
+        let value = fresh.value.clone();
+        *token = Some(fresh);
+        Ok(value)
+    }
+
+    async fn fetch_token(self: &Self, client: &Client) -> Result<AccessToken> {
+        let iat = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let claims = Claims {
+            iss: &self.key.client_email,
+            scope: READ_ONLY_SCOPE,
+            aud: &self.key.token_uri,
+            iat,
+            exp: iat + TOKEN_LIFETIME.as_secs(),
+        };
+        let encoding_key = EncodingKey::from_rsa_pem(self.key.private_key.as_bytes())?;
+        let assertion =
+            jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &encoding_key)?;
+
+        let response = client
+            .post(&self.key.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(|e| anyhow!("Service account token request failed: {}", e))?
+            .json::<TokenResponse>()
+            .await?;
+
+        let lifetime = response
+            .expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(TOKEN_LIFETIME);
+        Ok(AccessToken {
+            value: response.access_token,
+            expires_at: Instant::now() + lifetime,
+        })
+    }
+}
+
+impl s3::S3ObjOps for Provider {
+    #[instrument(skip(self))]
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        http::head_object(self.request(bucket, key).await?).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        http::get_object(self.request(bucket, key).await?).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        http::get_object_range(self.request(bucket, key).await?, start_byte, end_byte).await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_objects(
+        self: &Self,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<Vec<s3::ListedObject>> {
+        let url = format!("{}/storage/v1/b/{}/o", STORAGE_URL, bucket);
+        let mut objects = vec![];
+        let mut page_token = None;
+        loop {
+            let mut query = vec![("prefix", prefix.to_string())];
+            if let Some(token) = page_token {
+                query.push(("pageToken", token));
+            }
+            let request = self.authorized(self.client.get(&url).query(&query)).await?;
+            let page = request
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<ObjectList>()
+                .await?;
+            for item in page.items {
+                objects.push(s3::ListedObject {
+                    size: item.size.parse()?,
+                    key: item.name,
+                    e_tag: item.etag,
+                });
+            }
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(objects),
+            }
+        }
+    }
+
+    /// Drop the cached access token so the next request fetches a new one.
+    async fn refresh_credentials(self: &Self) -> Result<bool> {
+        match &self.service_account {
+            Some(account) => {
+                account.token.lock().await.take();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
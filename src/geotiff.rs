@@ -0,0 +1,1200 @@
+//! Reading a remote GeoTIFF's metadata from the first kilobytes of the file, to check an asset's
+//! projection, resolution and layout before downloading it. A Cloud Optimized GeoTIFF keeps its
+//! IFDs (image file directories) at the start of the file, so one ranged request is usually
+//! enough; values stored beyond it are fetched with further ranged requests.
+//!
+//! Both classic TIFF and BigTIFF are read, in either byte order. IFD entries are kept as raw bytes
+//! alongside their interpretation, so `download_overviews` can write the overviews of a COG as a
+//! GeoTIFF of their own.
+use crate::control::{self, Skipped, Stopped};
+use crate::download_plan::DownloadOptions;
+use crate::s3::S3ObjOps;
+use crate::{porcelain, units};
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+/// Bytes read from the start of the file, by default. GDAL writes the IFDs of a COG, with their
+/// tile offsets, before any image data.
+pub const DEFAULT_HEADER_BYTES: u64 = 64 * 1024;
+/// A COG has a handful of IFDs, more are taken to be a loop in a corrupt file
+const MAX_IFDS: usize = 64;
+/// Values larger than this aren't metadata
+const MAX_VALUE_BYTES: u64 = 16 * 1024 * 1024;
+/// Blocks closer than this are requested together, GDAL leaves a few bytes between tiles
+const MAX_BLOCK_GAP: u64 = 64 * 1024;
+/// Longest run of blocks requested at once
+const MAX_BLOCK_RUN: u64 = 16 * 1024 * 1024;
+
+/// Tags of the TIFF and GeoTIFF specifications that are interpreted
+pub mod tag {
+    pub const NEW_SUBFILE_TYPE: u16 = 254;
+    pub const IMAGE_WIDTH: u16 = 256;
+    pub const IMAGE_LENGTH: u16 = 257;
+    pub const BITS_PER_SAMPLE: u16 = 258;
+    pub const COMPRESSION: u16 = 259;
+    pub const STRIP_OFFSETS: u16 = 273;
+    pub const SAMPLES_PER_PIXEL: u16 = 277;
+    pub const ROWS_PER_STRIP: u16 = 278;
+    pub const STRIP_BYTE_COUNTS: u16 = 279;
+    pub const TILE_WIDTH: u16 = 322;
+    pub const TILE_LENGTH: u16 = 323;
+    pub const TILE_OFFSETS: u16 = 324;
+    pub const TILE_BYTE_COUNTS: u16 = 325;
+    pub const SAMPLE_FORMAT: u16 = 339;
+    pub const MODEL_PIXEL_SCALE: u16 = 33550;
+    pub const MODEL_TIEPOINT: u16 = 33922;
+    pub const MODEL_TRANSFORMATION: u16 = 34264;
+    pub const GEO_KEY_DIRECTORY: u16 = 34735;
+    pub const GEO_DOUBLE_PARAMS: u16 = 34736;
+    pub const GEO_ASCII_PARAMS: u16 = 34737;
+    pub const GDAL_METADATA: u16 = 42112;
+    pub const GDAL_NODATA: u16 = 42113;
+}
+
+/// Tags of the full-resolution image that georeference it and describe its values
+const GEO_TAGS: [u16; 8] = [
+    tag::MODEL_PIXEL_SCALE,
+    tag::MODEL_TIEPOINT,
+    tag::MODEL_TRANSFORMATION,
+    tag::GEO_KEY_DIRECTORY,
+    tag::GEO_DOUBLE_PARAMS,
+    tag::GEO_ASCII_PARAMS,
+    tag::GDAL_METADATA,
+    tag::GDAL_NODATA,
+];
+
+const GEOGRAPHIC_TYPE_KEY: u16 = 2048;
+const PROJECTED_CS_TYPE_KEY: u16 = 3072;
+/// GeoKey value of a coordinate system defined by parameters rather than an EPSG code
+const USER_DEFINED: u64 = 32767;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    /// An unsigned integer of 1, 2, 4 or 8 bytes
+    fn uint(self, bytes: &[u8]) -> u64 {
+        let mut buf = [0; 8];
+        match self {
+            ByteOrder::Little => {
+                buf[..bytes.len()].copy_from_slice(bytes);
+                u64::from_le_bytes(buf)
+            }
+            ByteOrder::Big => {
+                buf[8 - bytes.len()..].copy_from_slice(bytes);
+                u64::from_be_bytes(buf)
+            }
+        }
+    }
+
+    /// The `size` bytes of an unsigned integer
+    fn uint_bytes(self, value: u64, size: usize) -> Vec<u8> {
+        match self {
+            ByteOrder::Little => value.to_le_bytes()[..size].to_vec(),
+            ByteOrder::Big => value.to_be_bytes()[8 - size..].to_vec(),
+        }
+    }
+}
+
+/// Sizes of the entry count, an entry, and an offset
+fn field_sizes(big_tiff: bool) -> (u64, u64, u64) {
+    match big_tiff {
+        false => (2, 12, 4),
+        true => (8, 20, 8),
+    }
+}
+
+/// Bytes per value of a TIFF field type, None for types this reader doesn't know
+fn type_size(field_type: u16) -> Option<u64> {
+    match field_type {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 | 16 | 17 | 18 => Some(8),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u64,
+    /// The values, in the file's byte order
+    pub value: Vec<u8>,
+}
+
+/// An image file directory: the full-resolution image, an overview or a mask
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ifd {
+    pub order: ByteOrder,
+    pub entries: Vec<Entry>,
+}
+
+impl Ifd {
+    pub fn entry(self: &Self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    /// Add an entry or replace the one with its tag, keeping entries in tag order as TIFF requires
+    fn set(self: &mut Self, entry: Entry) {
+        self.entries.retain(|existing| existing.tag != entry.tag);
+        let position = self
+            .entries
+            .partition_point(|existing| existing.tag < entry.tag);
+        self.entries.insert(position, entry);
+    }
+
+    fn set_integers(self: &mut Self, tag: u16, field_type: u16, values: &[u64]) {
+        let size = type_size(field_type).expect("Integer types have a size") as usize;
+        let value = values
+            .iter()
+            .flat_map(|value| self.order.uint_bytes(*value, size))
+            .collect();
+        self.set(Entry {
+            tag,
+            field_type,
+            count: values.len() as u64,
+            value,
+        });
+    }
+
+    fn set_doubles(self: &mut Self, tag: u16, values: &[f64]) {
+        let value = values
+            .iter()
+            .flat_map(|value| self.order.uint_bytes(value.to_bits(), 8))
+            .collect();
+        self.set(Entry {
+            tag,
+            field_type: 12,
+            count: values.len() as u64,
+            value,
+        });
+    }
+
+    /// Values of an unsigned integer tag
+    pub fn integers(self: &Self, tag: u16) -> Option<Vec<u64>> {
+        let entry = self.entry(tag)?;
+        if !matches!(entry.field_type, 1 | 3 | 4 | 16) {
+            return None;
+        }
+        let size = type_size(entry.field_type)? as usize;
+        Some(
+            entry
+                .value
+                .chunks_exact(size)
+                .map(|value| self.order.uint(value))
+                .collect(),
+        )
+    }
+
+    pub fn integer(self: &Self, tag: u16) -> Option<u64> {
+        self.integers(tag)?.first().copied()
+    }
+
+    /// Values of a floating point tag
+    pub fn doubles(self: &Self, tag: u16) -> Option<Vec<f64>> {
+        let entry = self.entry(tag)?;
+        let values = match entry.field_type {
+            11 => entry
+                .value
+                .chunks_exact(4)
+                .map(|value| f32::from_bits(self.order.uint(value) as u32) as f64)
+                .collect(),
+            12 => entry
+                .value
+                .chunks_exact(8)
+                .map(|value| f64::from_bits(self.order.uint(value)))
+                .collect(),
+            _ => return None,
+        };
+        Some(values)
+    }
+
+    pub fn ascii(self: &Self, tag: u16) -> Option<String> {
+        let entry = self.entry(tag).filter(|entry| entry.field_type == 2)?;
+        let text = String::from_utf8_lossy(&entry.value);
+        Some(text.trim_end_matches('\0').to_string())
+    }
+
+    pub fn width(self: &Self) -> Option<u64> {
+        self.integer(tag::IMAGE_WIDTH)
+    }
+
+    pub fn height(self: &Self) -> Option<u64> {
+        self.integer(tag::IMAGE_LENGTH)
+    }
+
+    fn subfile_type(self: &Self) -> u64 {
+        self.integer(tag::NEW_SUBFILE_TYPE).unwrap_or(0)
+    }
+
+    /// A reduced-resolution copy of the image, not a mask
+    pub fn is_overview(self: &Self) -> bool {
+        self.subfile_type() & 1 != 0 && self.subfile_type() & 4 == 0
+    }
+
+    pub fn is_mask(self: &Self) -> bool {
+        self.subfile_type() & 4 != 0
+    }
+
+    pub fn is_tiled(self: &Self) -> bool {
+        self.entry(tag::TILE_WIDTH).is_some()
+    }
+
+    /// Width and height of the tiles, or of the strips of an untiled image
+    pub fn block_size(self: &Self) -> Option<(u64, u64)> {
+        match self.is_tiled() {
+            true => Some((
+                self.integer(tag::TILE_WIDTH)?,
+                self.integer(tag::TILE_LENGTH)?,
+            )),
+            false => Some((
+                self.width()?,
+                self.integer(tag::ROWS_PER_STRIP)
+                    .unwrap_or(self.height()?)
+                    .min(self.height()?),
+            )),
+        }
+    }
+
+    /// Offsets and lengths of the tiles or strips
+    pub fn blocks(self: &Self) -> Option<Vec<(u64, u64)>> {
+        let (offsets, byte_counts) = match self.is_tiled() {
+            true => (tag::TILE_OFFSETS, tag::TILE_BYTE_COUNTS),
+            false => (tag::STRIP_OFFSETS, tag::STRIP_BYTE_COUNTS),
+        };
+        let offsets = self.integers(offsets)?;
+        let byte_counts = self.integers(byte_counts)?;
+        (offsets.len() == byte_counts.len()).then(|| offsets.into_iter().zip(byte_counts).collect())
+    }
+}
+
+/// The structure of a GeoTIFF, without its image data
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoTiff {
+    /// Size of the whole file, if the source reported it
+    pub size: Option<u64>,
+    pub order: ByteOrder,
+    pub big_tiff: bool,
+    pub ifds: Vec<Ifd>,
+}
+
+impl GeoTiff {
+    /// The full-resolution image
+    pub fn image(self: &Self) -> &Ifd {
+        &self.ifds[0]
+    }
+
+    /// The overviews, largest first as GDAL writes them
+    pub fn overviews(self: &Self) -> Vec<&Ifd> {
+        self.ifds.iter().filter(|ifd| ifd.is_overview()).collect()
+    }
+
+    /// EPSG code of the projected or geographic coordinate system
+    pub fn epsg(self: &Self) -> Option<u64> {
+        let keys = self.image().integers(tag::GEO_KEY_DIRECTORY)?;
+        let key_count = *keys.get(3)? as usize;
+        let epsg = |wanted: u16| {
+            keys[4..]
+                .chunks_exact(4)
+                .take(key_count)
+                // A location of 0 means the value is held in the key itself
+                .find(|key| key[0] == wanted as u64 && key[1] == 0)
+                .map(|key| key[3])
+                .filter(|code| *code != USER_DEFINED)
+        };
+        epsg(PROJECTED_CS_TYPE_KEY).or_else(|| epsg(GEOGRAPHIC_TYPE_KEY))
+    }
+
+    /// Width and height of a pixel in the units of the coordinate system
+    pub fn resolution(self: &Self) -> Option<(f64, f64)> {
+        let scale = self.image().doubles(tag::MODEL_PIXEL_SCALE)?;
+        Some((*scale.first()?, *scale.get(1)?))
+    }
+
+    /// Coordinates of the top left corner of the top left pixel
+    pub fn origin(self: &Self) -> Option<(f64, f64)> {
+        let tiepoint = self.image().doubles(tag::MODEL_TIEPOINT)?;
+        let (x_scale, y_scale) = self.resolution()?;
+        let &[i, j, _, x, y, ..] = &tiepoint[..] else {
+            return None;
+        };
+        Some((x - i * x_scale, y + j * y_scale))
+    }
+
+    pub fn nodata(self: &Self) -> Option<String> {
+        self.image().ascii(tag::GDAL_NODATA)
+    }
+
+    pub fn to_text(self: &Self) -> String {
+        let image = self.image();
+        let bands = image.integer(tag::SAMPLES_PER_PIXEL).unwrap_or(1);
+        let bits = image.integer(tag::BITS_PER_SAMPLE).unwrap_or(1);
+        let sample_format = match image.integer(tag::SAMPLE_FORMAT).unwrap_or(1) {
+            2 => "signed integers",
+            3 => "floating point",
+            _ => "unsigned integers",
+        };
+        let mut lines = vec![format!(
+            "{} x {} pixels, {} band{} of {}-bit {}, {} compression",
+            image.width().unwrap_or(0),
+            image.height().unwrap_or(0),
+            bands,
+            if bands == 1 { "" } else { "s" },
+            bits,
+            sample_format,
+            compression_name(image.integer(tag::COMPRESSION).unwrap_or(1))
+        )];
+        if let Some((width, height)) = image.block_size() {
+            let kind = if image.is_tiled() { "tiles" } else { "strips" };
+            lines.push(format!("Blocks: {} x {} {}", width, height, kind));
+        }
+        lines.push(match self.epsg() {
+            Some(epsg) => format!("Projection: EPSG:{}", epsg),
+            None => "Projection: unknown".to_string(),
+        });
+        if let Some((x_scale, y_scale)) = self.resolution() {
+            let mut line = format!("Resolution: {} x {}", x_scale, y_scale);
+            if let Some((x, y)) = self.origin() {
+                line.push_str(&format!(", origin {}, {}", x, y));
+            }
+            lines.push(line);
+        }
+        let overviews: Vec<_> = self
+            .overviews()
+            .iter()
+            .map(|ifd| {
+                format!(
+                    "{} x {}",
+                    ifd.width().unwrap_or(0),
+                    ifd.height().unwrap_or(0)
+                )
+            })
+            .collect();
+        lines.push(match overviews.is_empty() {
+            true => "Overviews: none".to_string(),
+            false => format!("Overviews: {}", overviews.join(", ")),
+        });
+        lines.push(format!(
+            "Nodata: {}",
+            self.nodata().unwrap_or_else(|| "none".to_string())
+        ));
+        let mut layout = if self.big_tiff { "BigTIFF" } else { "TIFF" }.to_string();
+        if let Some(size) = self.size {
+            layout.push_str(&format!(", {} bytes", size));
+        }
+        lines.push(layout);
+        lines.join("\n")
+    }
+
+    /// The metadata as `--porcelain` records, with TIFF codes rather than names: `image` with the
+    /// width, height, bands, bits per sample, sample format and compression, `blocks` with their
+    /// width, height and `tiles` or `strips`, `projection` with the EPSG code, `resolution` with
+    /// the pixel size and origin, `overview` per overview, `nodata` and `layout` with `tiff` or
+    /// `bigtiff` and the file size
+    pub fn to_porcelain(self: &Self) -> String {
+        let image = self.image();
+        let mut lines = vec![porcelain::record(
+            "image",
+            &[
+                &image.width().unwrap_or(0),
+                &image.height().unwrap_or(0),
+                &image.integer(tag::SAMPLES_PER_PIXEL).unwrap_or(1),
+                &image.integer(tag::BITS_PER_SAMPLE).unwrap_or(1),
+                &image.integer(tag::SAMPLE_FORMAT).unwrap_or(1),
+                &image.integer(tag::COMPRESSION).unwrap_or(1),
+            ],
+        )];
+        if let Some((width, height)) = image.block_size() {
+            let kind = if image.is_tiled() { "tiles" } else { "strips" };
+            lines.push(porcelain::record("blocks", &[&width, &height, &kind]));
+        }
+        lines.push(porcelain::record(
+            "projection",
+            &[&porcelain::optional(self.epsg())],
+        ));
+        if let Some((x_scale, y_scale)) = self.resolution() {
+            let origin = self.origin();
+            lines.push(porcelain::record(
+                "resolution",
+                &[
+                    &x_scale,
+                    &y_scale,
+                    &porcelain::optional(origin.map(|(x, _)| x)),
+                    &porcelain::optional(origin.map(|(_, y)| y)),
+                ],
+            ));
+        }
+        for ifd in self.overviews() {
+            lines.push(porcelain::record(
+                "overview",
+                &[&ifd.width().unwrap_or(0), &ifd.height().unwrap_or(0)],
+            ));
+        }
+        lines.push(porcelain::record(
+            "nodata",
+            &[&porcelain::optional(self.nodata())],
+        ));
+        let layout = if self.big_tiff { "bigtiff" } else { "tiff" };
+        lines.push(porcelain::record(
+            "layout",
+            &[&layout, &porcelain::optional(self.size)],
+        ));
+        lines.join("\n")
+    }
+}
+
+fn compression_name(compression: u64) -> String {
+    match compression {
+        1 => "no".to_string(),
+        5 => "LZW".to_string(),
+        7 => "JPEG".to_string(),
+        8 | 32946 => "DEFLATE".to_string(),
+        32773 => "PackBits".to_string(),
+        34712 => "JPEG 2000".to_string(),
+        34887 => "LERC".to_string(),
+        50000 => "ZSTD".to_string(),
+        50001 => "WEBP".to_string(),
+        other => format!("code {}", other),
+    }
+}
+
+/// A remote object, of which the first bytes are held
+struct Remote<'a, P> {
+    provider: &'a P,
+    bucket: &'a str,
+    key: &'a str,
+    prefix: Vec<u8>,
+    /// Size of the whole object, if the source reported it
+    size: Option<u64>,
+}
+
+impl<'a, P: S3ObjOps> Remote<'a, P> {
+    async fn new(
+        provider: &'a P,
+        bucket: &'a str,
+        key: &'a str,
+        header_bytes: u64,
+    ) -> Result<Self> {
+        let response = provider
+            .get_object_range(bucket, key, 0, header_bytes.max(16) - 1)
+            .await?;
+        let size = response
+            .content_range()
+            .and_then(|range| range.rsplit('/').next()?.parse().ok());
+        let prefix = response.body.collect().await?.to_vec();
+        Ok(Self {
+            provider,
+            bucket,
+            key,
+            prefix,
+            size,
+        })
+    }
+
+    /// A value of an IFD
+    async fn bytes(self: &Self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        if length > MAX_VALUE_BYTES {
+            return Err(anyhow!(
+                "{} holds a {} byte value, which isn't metadata",
+                self.key,
+                length
+            ));
+        }
+        self.range(offset, length).await
+    }
+
+    async fn range(self: &Self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let end = offset
+            .checked_add(length)
+            .ok_or(anyhow!("Invalid offset in {}", self.key))?;
+        if end <= self.prefix.len() as u64 {
+            return Ok(self.prefix[offset as usize..end as usize].to_vec());
+        }
+        let response = self
+            .provider
+            .get_object_range(self.bucket, self.key, offset, end - 1)
+            .await?;
+        let bytes = response.body.collect().await?.to_vec();
+        if bytes.len() as u64 != length {
+            return Err(anyhow!("{} ends before byte {}", self.key, end));
+        }
+        Ok(bytes)
+    }
+}
+
+/// Read the metadata of a remote GeoTIFF, requesting its first `header_bytes` and then any values
+/// stored beyond them.
+pub async fn read_remote(
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+    header_bytes: u64,
+) -> Result<GeoTiff> {
+    let remote = Remote::new(provider, bucket, key, header_bytes).await?;
+    read(&remote).await
+}
+
+async fn read<P: S3ObjOps>(remote: &Remote<'_, P>) -> Result<GeoTiff> {
+    let key = remote.key;
+    let header = &remote.prefix;
+    let order = match header.get(..2) {
+        Some(b"II") => ByteOrder::Little,
+        Some(b"MM") => ByteOrder::Big,
+        _ => return Err(anyhow!("{} is not a TIFF", key)),
+    };
+    let (big_tiff, first_ifd) = match header.get(2..4).map(|magic| order.uint(magic)) {
+        Some(42) if header.len() >= 8 => (false, order.uint(&header[4..8])),
+        Some(43) if header.len() >= 16 => (true, order.uint(&header[8..16])),
+        _ => return Err(anyhow!("{} is not a TIFF", key)),
+    };
+    let (count_size, entry_size, offset_size) = field_sizes(big_tiff);
+
+    let mut ifds = vec![];
+    let mut next = first_ifd;
+    while next != 0 {
+        if ifds.len() >= MAX_IFDS {
+            return Err(anyhow!("{} has more than {} IFDs", key, MAX_IFDS));
+        }
+        let count = order.uint(&remote.bytes(next, count_size).await?);
+        let table_size = count
+            .checked_mul(entry_size)
+            .ok_or(anyhow!("Invalid IFD in {}", key))?;
+        let table = remote
+            .bytes(next + count_size, table_size + offset_size)
+            .await?;
+        let mut entries = vec![];
+        for field in table[..table_size as usize].chunks_exact(entry_size as usize) {
+            let field_type = order.uint(&field[2..4]) as u16;
+            let Some(size) = type_size(field_type) else {
+                continue;
+            };
+            let (count, value) = match big_tiff {
+                false => (order.uint(&field[4..8]), &field[8..12]),
+                true => (order.uint(&field[4..12]), &field[12..20]),
+            };
+            let length = count
+                .checked_mul(size)
+                .ok_or(anyhow!("Invalid count in {}", key))?;
+            let value = match length <= offset_size {
+                true => value[..length as usize].to_vec(),
+                false => remote.bytes(order.uint(value), length).await?,
+            };
+            entries.push(Entry {
+                tag: order.uint(&field[0..2]) as u16,
+                field_type,
+                count,
+                value,
+            });
+        }
+        ifds.push(Ifd { order, entries });
+        next = order.uint(&table[table_size as usize..]);
+    }
+    if ifds.is_empty() {
+        return Err(anyhow!("{} has no images", key));
+    }
+    Ok(GeoTiff {
+        size: remote.size,
+        order,
+        big_tiff,
+        ifds,
+    })
+}
+
+/// Download just the overviews of a remote COG into a GeoTIFF at `output`, with the largest as its
+/// full-resolution image and the georeferencing of the original scaled to it. Overviews are
+/// typically a few percent of the file, a preview for planning before fetching full resolution.
+/// The metadata is read as by `read_remote`. The blocks are paced, checked for stalls and follow
+/// `options.control` like any other download. Returns the number of bytes written.
+pub async fn download_overviews(
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+    header_bytes: u64,
+    output: &Path,
+    options: &DownloadOptions,
+) -> Result<u64> {
+    let remote = Remote::new(provider, bucket, key, header_bytes).await?;
+    let geotiff = read(&remote).await?;
+    let image = geotiff.image();
+    let mut ifds: Vec<Ifd> = geotiff.overviews().into_iter().cloned().collect();
+    let Some(largest) = ifds.first_mut() else {
+        return Err(anyhow!("{} has no overviews", key));
+    };
+    let (Some(width), Some(height), Some(overview_width), Some(overview_height)) = (
+        image.width(),
+        image.height(),
+        largest.width(),
+        largest.height(),
+    ) else {
+        return Err(anyhow!("{} has images without a size", key));
+    };
+    largest.set_integers(tag::NEW_SUBFILE_TYPE, 4, &[0]);
+    for entry in image
+        .entries
+        .iter()
+        .filter(|entry| GEO_TAGS.contains(&entry.tag))
+    {
+        largest.set(entry.clone());
+    }
+    let x_ratio = width as f64 / overview_width as f64;
+    let y_ratio = height as f64 / overview_height as f64;
+    if let Some(mut scale) = image.doubles(tag::MODEL_PIXEL_SCALE) {
+        scale
+            .iter_mut()
+            .zip([x_ratio, y_ratio])
+            .for_each(|(value, ratio)| *value *= ratio);
+        largest.set_doubles(tag::MODEL_PIXEL_SCALE, &scale);
+    }
+    if let Some(mut matrix) = image.doubles(tag::MODEL_TRANSFORMATION) {
+        // Coefficients of the pixel column and row in the rows for x and y
+        for (index, ratio) in [(0, x_ratio), (1, y_ratio), (4, x_ratio), (5, y_ratio)] {
+            if let Some(value) = matrix.get_mut(index) {
+                *value *= ratio;
+            }
+        }
+        largest.set_doubles(tag::MODEL_TRANSFORMATION, &matrix);
+    }
+
+    let mut blocks = vec![];
+    for ifd in &ifds {
+        let ifd_blocks = ifd
+            .blocks()
+            .ok_or(anyhow!("{} has an overview without blocks", key))?;
+        blocks.push(ifd_blocks);
+    }
+    let offsets_tag = match ifds[0].is_tiled() {
+        true => tag::TILE_OFFSETS,
+        false => tag::STRIP_OFFSETS,
+    };
+    // LONG, or LONG8 in a BigTIFF
+    let offset_type = if geotiff.big_tiff { 16 } else { 4 };
+    // Sized with placeholder offsets, which are filled in once the layout is known
+    for (ifd, ifd_blocks) in ifds.iter_mut().zip(&blocks) {
+        ifd.set_integers(offsets_tag, offset_type, &vec![0; ifd_blocks.len()]);
+    }
+    let header_size = if geotiff.big_tiff { 16 } else { 8 };
+    let mut position = header_size;
+    let mut ifd_positions = vec![];
+    for ifd in &ifds {
+        ifd_positions.push(position);
+        position += ifd_size(ifd, geotiff.big_tiff);
+    }
+    for (ifd, ifd_blocks) in ifds.iter_mut().zip(&blocks) {
+        let mut offsets = vec![];
+        for (offset, length) in ifd_blocks {
+            // Blocks that were never written stay empty
+            match *offset == 0 || *length == 0 {
+                true => offsets.push(0),
+                false => {
+                    offsets.push(position);
+                    position += length;
+                }
+            }
+        }
+        ifd.set_integers(offsets_tag, offset_type, &offsets);
+    }
+    if !geotiff.big_tiff && position > u32::MAX as u64 {
+        return Err(anyhow!("The overviews of {} don't fit in a TIFF", key));
+    }
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = format!("{}.partial", output.to_string_lossy());
+    let mut file = std::io::BufWriter::new(fs::File::create(&partial)?);
+    let order = geotiff.order;
+    file.write_all(match order {
+        ByteOrder::Little => b"II",
+        ByteOrder::Big => b"MM",
+    })?;
+    match geotiff.big_tiff {
+        false => {
+            file.write_all(&order.uint_bytes(42, 2))?;
+            file.write_all(&order.uint_bytes(ifd_positions[0], 4))?;
+        }
+        true => {
+            // Followed by the size of offsets and a reserved zero
+            file.write_all(&order.uint_bytes(43, 2))?;
+            file.write_all(&order.uint_bytes(8, 2))?;
+            file.write_all(&order.uint_bytes(0, 2))?;
+            file.write_all(&order.uint_bytes(ifd_positions[0], 8))?;
+        }
+    }
+    for (index, ifd) in ifds.iter().enumerate() {
+        let next = ifd_positions.get(index + 1).copied().unwrap_or(0);
+        file.write_all(&ifd_bytes(
+            ifd,
+            ifd_positions[index],
+            next,
+            geotiff.big_tiff,
+        ))?;
+    }
+    let total: u64 = blocks.iter().flatten().map(|(_, length)| length).sum();
+    println!(
+        "Downloading {} overviews of {} ({} of {})",
+        ifds.len(),
+        key,
+        units::size(total),
+        remote.size.map_or("?".to_string(), units::size)
+    );
+    // In the order their offsets were assigned
+    let blocks: Vec<_> = blocks.into_iter().flatten().collect();
+    let mut transfer = Transfer::new(&remote, total, options);
+    copy_blocks(&remote, &blocks, &mut file, &mut transfer).await?;
+    file.flush()?;
+    drop(file);
+    fs::rename(&partial, output)?;
+    Ok(position)
+}
+
+/// Bytes taken by an IFD and the values that don't fit in its entries
+fn ifd_size(ifd: &Ifd, big_tiff: bool) -> u64 {
+    let (count_size, entry_size, offset_size) = field_sizes(big_tiff);
+    let values: u64 = ifd
+        .entries
+        .iter()
+        .map(|entry| entry.value.len() as u64)
+        .filter(|length| *length > offset_size)
+        // Values start on a word boundary
+        .map(|length| length + length % 2)
+        .sum();
+    count_size + ifd.entries.len() as u64 * entry_size + offset_size + values
+}
+
+/// An IFD written at `position`, followed by the values that don't fit in its entries
+fn ifd_bytes(ifd: &Ifd, position: u64, next: u64, big_tiff: bool) -> Vec<u8> {
+    let (count_size, entry_size, offset_size) = field_sizes(big_tiff);
+    let order = ifd.order;
+    let mut table = order.uint_bytes(ifd.entries.len() as u64, count_size as usize);
+    let mut values = vec![];
+    let value_position =
+        position + count_size + ifd.entries.len() as u64 * entry_size + offset_size;
+    for entry in &ifd.entries {
+        table.extend(order.uint_bytes(entry.tag as u64, 2));
+        table.extend(order.uint_bytes(entry.field_type as u64, 2));
+        table.extend(order.uint_bytes(entry.count, offset_size as usize));
+        if entry.value.len() as u64 <= offset_size {
+            let mut value = entry.value.clone();
+            value.resize(offset_size as usize, 0);
+            table.extend(value);
+        } else {
+            table.extend(
+                order.uint_bytes(value_position + values.len() as u64, offset_size as usize),
+            );
+            values.extend(&entry.value);
+            if entry.value.len() % 2 == 1 {
+                values.push(0);
+            }
+        }
+    }
+    table.extend(order.uint_bytes(next, offset_size as usize));
+    table.extend(values);
+    table
+}
+
+/// The block requests of `download_overviews`, with the stall threshold, pacing and control of
+/// the plan's other transfers
+struct Transfer<'a> {
+    options: &'a DownloadOptions,
+    /// See `control::task_id`
+    id: String,
+    total: u64,
+    received: u64,
+    started: Instant,
+    window_started: Instant,
+    window_bytes: u64,
+}
+
+impl<'a> Transfer<'a> {
+    fn new<P>(remote: &Remote<'_, P>, total: u64, options: &'a DownloadOptions) -> Self {
+        let id = control::task_id(remote.bucket, remote.key);
+        if let Some(control) = &options.control {
+            control.attempt(&id);
+        }
+        Self {
+            options,
+            id,
+            total,
+            received: 0,
+            started: Instant::now(),
+            window_started: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// `length` bytes of the remote object from `offset`
+    async fn range<P: S3ObjOps>(
+        self: &mut Self,
+        remote: &Remote<'_, P>,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        if offset + length <= remote.prefix.len() as u64 {
+            return remote.range(offset, length).await;
+        }
+        if let Some(control) = &self.options.control {
+            if control.wait_while_paused().await {
+                self.window_started = Instant::now();
+                self.window_bytes = 0;
+            }
+            if control.is_stopped() {
+                return Err(Stopped.into());
+            }
+            if control.is_skipped(&self.id) {
+                return Err(Skipped.into());
+            }
+        }
+        let mut response = remote
+            .provider
+            .get_object_range(remote.bucket, remote.key, offset, offset + length - 1)
+            .await?;
+        let mut bytes = vec![];
+        loop {
+            let next = response.body.try_next();
+            let chunk = match &self.options.stall {
+                Some(threshold) => tokio::time::timeout(threshold.window, next)
+                    .await
+                    .map_err(|_| {
+                        threshold.stalled(self.window_bytes, self.window_started.elapsed())
+                    })??,
+                None => next.await?,
+            };
+            let Some(chunk) = chunk else { break };
+            let chunk_len = chunk.len() as u64;
+            bytes.extend_from_slice(&chunk);
+            self.received += chunk_len;
+            if let Some(control) = &self.options.control {
+                control.progress(&self.id, chunk_len, self.received, Some(self.total));
+            }
+
+            self.window_bytes += chunk_len;
+            if let Some(threshold) = &self.options.stall {
+                let elapsed = self.window_started.elapsed();
+                if threshold.is_stalled(self.window_bytes, elapsed) {
+                    return Err(threshold.stalled(self.window_bytes, elapsed).into());
+                }
+                if elapsed >= threshold.window {
+                    self.window_started = Instant::now();
+                    self.window_bytes = 0;
+                }
+            }
+
+            let mut pause = self
+                .options
+                .pacing
+                .pause(self.received, self.started.elapsed());
+            if let Some(control) = &self.options.control {
+                pause = pause.max(control.throttle(chunk_len));
+            }
+            if !pause.is_zero() {
+                tokio::time::sleep(pause).await;
+            }
+        }
+        if bytes.len() as u64 != length {
+            return Err(anyhow!(
+                "{} ends before byte {}",
+                remote.key,
+                offset + length
+            ));
+        }
+        Ok(bytes)
+    }
+}
+
+/// Append blocks to `file` in order, requesting runs of nearby blocks together
+async fn copy_blocks<P: S3ObjOps>(
+    remote: &Remote<'_, P>,
+    blocks: &[(u64, u64)],
+    file: &mut impl Write,
+    transfer: &mut Transfer<'_>,
+) -> Result<()> {
+    let blocks: Vec<_> = blocks
+        .iter()
+        .filter(|(offset, length)| *offset != 0 && *length != 0)
+        .collect();
+    let mut index = 0;
+    while index < blocks.len() {
+        let start = blocks[index].0;
+        let mut end = start + blocks[index].1;
+        let mut run = index + 1;
+        while let Some((offset, length)) = blocks.get(run) {
+            let contiguous = *offset >= end && *offset - end <= MAX_BLOCK_GAP;
+            if !contiguous || offset + length - start > MAX_BLOCK_RUN {
+                break;
+            }
+            end = offset + length;
+            run += 1;
+        }
+        let bytes = transfer.range(remote, start, end - start).await?;
+        for (offset, length) in &blocks[index..run] {
+            let from = (offset - start) as usize;
+            file.write_all(&bytes[from..from + *length as usize])?;
+        }
+        index = run;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::operation::get_object::GetObjectOutput;
+    use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+    use aws_sdk_s3::primitives::ByteStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Object {
+        content: Vec<u8>,
+        ranges: AtomicUsize,
+    }
+
+    impl S3ObjOps for Object {
+        async fn head_object(self: &Self, _: &str, _: &str) -> Result<HeadObjectOutput> {
+            Ok(HeadObjectOutput::builder()
+                .content_length(self.content.len() as i64)
+                .build())
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Err(anyhow!("Only ranges are served"))
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            start_byte: u64,
+            end_byte: u64,
+        ) -> Result<GetObjectOutput> {
+            self.ranges.fetch_add(1, Ordering::SeqCst);
+            let end = end_byte.min(self.content.len() as u64 - 1);
+            let range = self.content[start_byte as usize..=end as usize].to_vec();
+            Ok(GetObjectOutput::builder()
+                .content_range(format!(
+                    "bytes {}-{}/{}",
+                    start_byte,
+                    end,
+                    self.content.len()
+                ))
+                .body(ByteStream::from(range))
+                .build())
+        }
+    }
+
+    fn shorts(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn longs(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn doubles(values: &[f64]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    /// A little-endian classic TIFF of IFDs given as (tag, field type, values), with values that
+    /// don't fit in their entry stored after each IFD
+    fn tiff(ifds: &[Vec<(u16, u16, Vec<u8>)>], padding: usize) -> Vec<u8> {
+        let mut file = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        for (index, entries) in ifds.iter().enumerate() {
+            let data_start = file.len() + 2 + entries.len() * 12 + 4;
+            let mut data: Vec<u8> = vec![];
+            file.extend((entries.len() as u16).to_le_bytes());
+            for (tag, field_type, value) in entries {
+                file.extend(tag.to_le_bytes());
+                file.extend(field_type.to_le_bytes());
+                let count = value.len() as u64 / type_size(*field_type).unwrap();
+                file.extend((count as u32).to_le_bytes());
+                if value.len() <= 4 {
+                    let mut value = value.clone();
+                    value.resize(4, 0);
+                    file.extend(value);
+                } else {
+                    file.extend(((data_start + data.len()) as u32).to_le_bytes());
+                    data.extend(value);
+                }
+            }
+            // Image data between the IFDs puts later ones beyond the header
+            let next = match index + 1 < ifds.len() {
+                true => data_start + data.len() + padding,
+                false => 0,
+            };
+            file.extend((next as u32).to_le_bytes());
+            file.extend(data);
+            file.resize(file.len() + padding, 0);
+        }
+        file
+    }
+
+    /// A 512 pixel COG with two overviews and a mask
+    fn cog() -> Object {
+        let image = vec![
+            (tag::IMAGE_WIDTH, 3, shorts(&[512])),
+            (tag::IMAGE_LENGTH, 3, shorts(&[512])),
+            (tag::BITS_PER_SAMPLE, 3, shorts(&[16])),
+            (tag::COMPRESSION, 3, shorts(&[8])),
+            (tag::TILE_WIDTH, 3, shorts(&[256])),
+            (tag::TILE_LENGTH, 3, shorts(&[256])),
+            (tag::TILE_OFFSETS, 4, longs(&[1000, 2000, 3000, 4000])),
+            (tag::TILE_BYTE_COUNTS, 4, longs(&[10, 20, 30, 40])),
+            (tag::MODEL_PIXEL_SCALE, 12, doubles(&[10.0, 10.0, 0.0])),
+            (
+                tag::MODEL_TIEPOINT,
+                12,
+                doubles(&[0.0, 0.0, 0.0, 600000.0, 6700020.0, 0.0]),
+            ),
+            (
+                tag::GEO_KEY_DIRECTORY,
+                3,
+                shorts(&[1, 1, 0, 2, 1024, 0, 1, 1, 3072, 0, 1, 32608]),
+            ),
+            (tag::GDAL_NODATA, 2, b"0\0".to_vec()),
+        ];
+        let overview = vec![
+            (tag::NEW_SUBFILE_TYPE, 4, longs(&[1])),
+            (tag::IMAGE_WIDTH, 3, shorts(&[256])),
+            (tag::IMAGE_LENGTH, 3, shorts(&[256])),
+            (tag::TILE_WIDTH, 3, shorts(&[256])),
+            (tag::TILE_LENGTH, 3, shorts(&[256])),
+            (tag::TILE_OFFSETS, 4, longs(&[5000])),
+            (tag::TILE_BYTE_COUNTS, 4, longs(&[50])),
+        ];
+        let smaller_overview = vec![
+            (tag::NEW_SUBFILE_TYPE, 4, longs(&[1])),
+            (tag::IMAGE_WIDTH, 3, shorts(&[128])),
+            (tag::IMAGE_LENGTH, 3, shorts(&[128])),
+            (tag::TILE_WIDTH, 3, shorts(&[256])),
+            (tag::TILE_LENGTH, 3, shorts(&[256])),
+            (tag::TILE_OFFSETS, 4, longs(&[5060])),
+            (tag::TILE_BYTE_COUNTS, 4, longs(&[30])),
+        ];
+        let mask = vec![
+            (tag::NEW_SUBFILE_TYPE, 4, longs(&[5])),
+            (tag::IMAGE_WIDTH, 3, shorts(&[256])),
+            (tag::IMAGE_LENGTH, 3, shorts(&[256])),
+        ];
+        let mut content = tiff(&[image, overview, smaller_overview, mask], 2048);
+        // The overview tiles, in the padding after their IFDs
+        for (i, byte) in content[5000..5090].iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        Object {
+            content,
+            ranges: AtomicUsize::new(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_remote() {
+        let object = cog();
+        let geotiff = read_remote(&object, "bucket", "B04.tif", 1024)
+            .await
+            .unwrap();
+        assert_eq!(geotiff.ifds.len(), 4);
+        assert_eq!(geotiff.image().block_size(), Some((256, 256)));
+        assert_eq!(geotiff.image().blocks().unwrap()[3], (4000, 40));
+        assert_eq!(geotiff.overviews().len(), 2);
+        assert_eq!(geotiff.epsg(), Some(32608));
+        assert_eq!(geotiff.origin(), Some((600000.0, 6700020.0)));
+        assert_eq!(geotiff.nodata(), Some("0".to_string()));
+        assert_eq!(geotiff.size, Some(object.content.len() as u64));
+        // The header, then the entry count and the entries of each IFD beyond it
+        assert_eq!(object.ranges.load(Ordering::SeqCst), 7);
+        assert_eq!(
+            geotiff.to_text(),
+            [
+                "512 x 512 pixels, 1 band of 16-bit unsigned integers, DEFLATE compression",
+                "Blocks: 256 x 256 tiles",
+                "Projection: EPSG:32608",
+                "Resolution: 10 x 10, origin 600000, 6700020",
+                "Overviews: 256 x 256, 128 x 128",
+                "Nodata: 0",
+                &format!("TIFF, {} bytes", object.content.len()),
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            geotiff.to_porcelain(),
+            [
+                "image\t512\t512\t1\t16\t1\t8",
+                "blocks\t256\t256\ttiles",
+                "projection\t32608",
+                "resolution\t10\t10\t600000\t6700020",
+                "overview\t256\t256",
+                "overview\t128\t128",
+                "nodata\t0",
+                &format!("layout\ttiff\t{}", object.content.len()),
+            ]
+            .join("\n")
+        );
+
+        let object = Object {
+            content: b"\xff\xd8\xff\xe0 not a tiff".to_vec(),
+            ranges: AtomicUsize::new(0),
+        };
+        assert!(read_remote(&object, "bucket", "B04.jp2", 1024)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_overviews() {
+        let output = Path::new("/tmp/slow_stac_geotiff/B04_overviews.tif");
+        let _ = fs::remove_dir_all("/tmp/slow_stac_geotiff");
+        let object = cog();
+        let options = DownloadOptions::default();
+        let written = download_overviews(&object, "bucket", "B04.tif", 1024, output, &options)
+            .await
+            .unwrap();
+        // The header and IFDs, then both overviews' tiles in one request
+        assert_eq!(object.ranges.load(Ordering::SeqCst), 8);
+
+        let written_object = Object {
+            content: fs::read(output).unwrap(),
+            ranges: AtomicUsize::new(0),
+        };
+        assert_eq!(written, written_object.content.len() as u64);
+        let geotiff = read_remote(&written_object, "bucket", "B04_overviews.tif", 1024)
+            .await
+            .unwrap();
+        assert_eq!(geotiff.ifds.len(), 2);
+        assert!(!geotiff.image().is_overview());
+        assert_eq!(geotiff.image().width(), Some(256));
+        assert_eq!(geotiff.overviews().len(), 1);
+        assert_eq!(geotiff.epsg(), Some(32608));
+        assert_eq!(geotiff.resolution(), Some((20.0, 20.0)));
+        assert_eq!(geotiff.origin(), Some((600000.0, 6700020.0)));
+        let tiles: Vec<u8> = geotiff
+            .ifds
+            .iter()
+            .flat_map(|ifd| ifd.blocks().unwrap())
+            .flat_map(|(offset, length)| {
+                written_object.content[offset as usize..(offset + length) as usize].to_vec()
+            })
+            .collect();
+        let expected: Vec<u8> = object.content[5000..5050]
+            .iter()
+            .chain(&object.content[5060..5090])
+            .copied()
+            .collect();
+        assert_eq!(tiles, expected);
+    }
+}
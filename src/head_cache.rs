@@ -0,0 +1,214 @@
+//! HEAD responses remembered while a plan executes. Each attempt at a task starts with a HEAD for
+//! the object's size and ETag, and verification sends another, so a task retried after a dropped
+//! connection would repeat the round trip each time. Over a satellite link with seconds of latency
+//! that adds up across thousands of tasks. Responses are forgotten after a TTL so an object
+//! replaced upstream is noticed within a few minutes, and at once by a task restarted after
+//! failing verification (see `S3ObjOps::invalidate`).
+use crate::s3::{ListedObject, S3ObjOps};
+use anyhow::Result;
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long HEAD responses are reused by default
+pub const HEAD_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Wraps a provider for one execution, answering repeated HEAD requests for an object from the
+/// first response until it is `ttl` old. Failed requests aren't cached.
+pub struct HeadCache<'a, P> {
+    inner: &'a P,
+    ttl: Duration,
+    /// By bucket, key and pinned version
+    heads: Mutex<HashMap<(String, String, Option<String>), (Instant, HeadObjectOutput)>>,
+}
+
+impl<'a, P: S3ObjOps> HeadCache<'a, P> {
+    pub fn new(inner: &'a P, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            heads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cached(self: &Self, object: &(String, String, Option<String>)) -> Option<HeadObjectOutput> {
+        let heads = self.heads.lock().expect("Head cache lock poisoned");
+        heads
+            .get(object)
+            .filter(|(fetched, _)| fetched.elapsed() < self.ttl)
+            .map(|(_, head)| head.clone())
+    }
+}
+
+impl<'a, P: S3ObjOps> S3ObjOps for HeadCache<'a, P> {
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        self.head_object_version(bucket, key, None).await
+    }
+
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        self.inner.get_object(bucket, key).await
+    }
+
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        self.inner
+            .get_object_range(bucket, key, start_byte, end_byte)
+            .await
+    }
+
+    async fn head_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectOutput> {
+        let object = (
+            bucket.to_string(),
+            key.to_string(),
+            version_id.map(str::to_string),
+        );
+        if let Some(head) = self.cached(&object) {
+            return Ok(head);
+        }
+        let head = self
+            .inner
+            .head_object_version(bucket, key, version_id)
+            .await?;
+        if !self.ttl.is_zero() {
+            let mut heads = self.heads.lock().expect("Head cache lock poisoned");
+            heads.insert(object, (Instant::now(), head.clone()));
+        }
+        Ok(head)
+    }
+
+    async fn get_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectOutput> {
+        self.inner.get_object_version(bucket, key, version_id).await
+    }
+
+    async fn get_object_range_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        self.inner
+            .get_object_range_version(bucket, key, version_id, start_byte, end_byte)
+            .await
+    }
+
+    fn invalidate(self: &Self, bucket: &str, key: &str, version_id: Option<&str>) {
+        let object = (
+            bucket.to_string(),
+            key.to_string(),
+            version_id.map(str::to_string),
+        );
+        let mut heads = self.heads.lock().expect("Head cache lock poisoned");
+        heads.remove(&object);
+        self.inner.invalidate(bucket, key, version_id);
+    }
+
+    fn md5_etags(self: &Self) -> bool {
+        self.inner.md5_etags()
+    }
+
+    async fn refresh_credentials(self: &Self) -> Result<bool> {
+        self.inner.refresh_credentials().await
+    }
+
+    async fn list_objects(self: &Self, bucket: &str, prefix: &str) -> Result<Vec<ListedObject>> {
+        self.inner.list_objects(bucket, prefix).await
+    }
+
+    async fn presign_get(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String> {
+        self.inner.presign_get(bucket, key, expires_in).await
+    }
+
+    async fn presign_get_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String> {
+        self.inner
+            .presign_get_version(bucket, key, version_id, expires_in)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counting {
+        heads: AtomicUsize,
+    }
+
+    impl S3ObjOps for Counting {
+        async fn head_object(self: &Self, _: &str, key: &str) -> Result<HeadObjectOutput> {
+            self.heads.fetch_add(1, Ordering::SeqCst);
+            Ok(HeadObjectOutput::builder()
+                .content_length(key.len() as i64)
+                .e_tag(format!("\"{}\"", key))
+                .build())
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Err(anyhow!("Only heads are served"))
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            _: u64,
+            _: u64,
+        ) -> Result<GetObjectOutput> {
+            Err(anyhow!("Only heads are served"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head_cache() {
+        let provider = Counting::default();
+        let cache = HeadCache::new(&provider, HEAD_CACHE_TTL);
+        for _ in 0..3 {
+            let head = cache.head_object("bucket", "B04.tif").await.unwrap();
+            assert_eq!(head.content_length(), Some(7));
+            assert_eq!(head.e_tag(), Some("\"B04.tif\""));
+        }
+        cache.head_object("bucket", "B8A.tif").await.unwrap();
+        assert_eq!(provider.heads.load(Ordering::SeqCst), 2);
+        cache.invalidate("bucket", "B04.tif", None);
+        cache.head_object("bucket", "B04.tif").await.unwrap();
+        cache.head_object("bucket", "B8A.tif").await.unwrap();
+        assert_eq!(provider.heads.load(Ordering::SeqCst), 3);
+
+        let uncached = HeadCache::new(&provider, Duration::ZERO);
+        uncached.head_object("bucket", "B04.tif").await.unwrap();
+        uncached.head_object("bucket", "B04.tif").await.unwrap();
+        assert_eq!(provider.heads.load(Ordering::SeqCst), 5);
+    }
+}
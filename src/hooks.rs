@@ -0,0 +1,239 @@
+//! Hooks fired when every task of an item has downloaded and verified, so per-scene processing
+//! (e.g. computing NDVI or loading into a database) can start while other scenes download.
+use crate::rclone::{self, RcloneOptions};
+use crate::stack::{self, StackOptions};
+use crate::upload::{self, UploadOptions};
+use crate::user_agent;
+use anyhow::{anyhow, Error, Result};
+use serde_json::json;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Lists the hooks that succeeded for an item, one per line, so resuming a plan doesn't fire
+/// them again for items completed by an earlier run (but does fire hooks added since)
+pub const DONE_MARKER: &str = ".slow-stac-item-hooked";
+
+/// An item whose tasks have all completed
+#[derive(Debug, Clone)]
+pub struct CompletedItem {
+    pub selection_id: String,
+    pub item_id: String,
+    pub dir: PathBuf,
+    /// Asset key (or file name, for tasks without one) and path of each downloaded file
+    pub files: Vec<(String, PathBuf)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemHook {
+    /// Shell command run with the item directory appended as its last argument, and
+    /// SLOW_STAC_ITEM_ID, SLOW_STAC_ITEM_DIR and SLOW_STAC_SELECTION_ID set
+    Command(String),
+    /// URL POSTed `{"selection_id", "item_id", "item_dir"}`
+    Webhook(String),
+    /// Render a small PNG of the item's bands, requires the `quicklook` feature
+    Quicklook(Quicklook),
+    /// Stack the item's bands into one multiband GeoTIFF with GDAL
+    Stack(StackOptions),
+    /// Upload the item's files to an S3 bucket, optionally removing them locally
+    Upload(UploadOptions),
+    /// Copy the item's files to an rclone remote, optionally removing them locally
+    Rclone(RcloneOptions),
+}
+
+/// What a quicklook shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quicklook {
+    /// NDVI from the red and near infrared bands, brown through yellow to green
+    Ndvi,
+    /// Near infrared, red and green as RGB, vegetation showing red
+    FalseColor,
+}
+
+impl Quicklook {
+    /// Written to the item directory, e.g. `quicklook_ndvi.png`
+    pub fn file_name(self: &Self) -> String {
+        format!("quicklook_{}.png", self.to_string().replace('-', "_"))
+    }
+}
+
+impl FromStr for Quicklook {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "ndvi" => Ok(Quicklook::Ndvi),
+            "false-color" => Ok(Quicklook::FalseColor),
+            _ => Err(anyhow!(
+                "Unknown quicklook {}, expected ndvi or false-color",
+                name
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Quicklook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quicklook::Ndvi => write!(f, "ndvi"),
+            Quicklook::FalseColor => write!(f, "false-color"),
+        }
+    }
+}
+
+impl fmt::Display for ItemHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemHook::Command(command) => write!(f, "command: {}", command),
+            ItemHook::Webhook(url) => write!(f, "webhook: {}", url),
+            ItemHook::Quicklook(quicklook) => write!(f, "quicklook: {}", quicklook),
+            ItemHook::Stack(_) => write!(f, "stack"),
+            ItemHook::Upload(options) => write!(f, "upload: {}", options.destination()),
+            ItemHook::Rclone(options) => write!(f, "rclone: {}", options.destination),
+        }
+    }
+}
+
+impl ItemHook {
+    /// Where the hook copies a file of an item to, for the hooks that upload
+    pub fn uploaded_to(self: &Self, item_id: &str, file_name: &str) -> Option<String> {
+        match self {
+            ItemHook::Upload(options) => Some(options.url(item_id, file_name)),
+            ItemHook::Rclone(options) => Some(options.target(item_id, file_name)),
+            _ => None,
+        }
+    }
+
+    pub async fn fire(self: &Self, item: &CompletedItem) -> Result<()> {
+        match self {
+            ItemHook::Command(command) => {
+                // "$@" passes the directory through without the shell splitting it
+                let status = tokio::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(format!("{} \"$@\"", command))
+                    .arg("sh")
+                    .arg(&item.dir)
+                    .env("SLOW_STAC_SELECTION_ID", &item.selection_id)
+                    .env("SLOW_STAC_ITEM_ID", &item.item_id)
+                    .env("SLOW_STAC_ITEM_DIR", &item.dir)
+                    .status()
+                    .await?;
+                if !status.success() {
+                    return Err(anyhow!("Hook `{}` exited with {}", command, status));
+                }
+            }
+            ItemHook::Webhook(url) => {
+                let body = json!({
+                    "selection_id": item.selection_id,
+                    "item_id": item.item_id,
+                    "item_dir": item.dir,
+                });
+                user_agent::client()
+                    .post(url)
+                    .json(&body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            ItemHook::Quicklook(quicklook) => {
+                let quicklook = *quicklook;
+                let item = item.clone();
+                // Decoding rasters is CPU bound, keep it off the download tasks' threads
+                tokio::task::spawn_blocking(move || write_quicklook(quicklook, &item)).await??;
+            }
+            ItemHook::Stack(options) => {
+                stack::stack_item(item, options).await?;
+            }
+            ItemHook::Upload(options) => {
+                upload::upload_item(item, options).await?;
+            }
+            ItemHook::Rclone(options) => {
+                rclone::copy_item(item, options).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "quicklook")]
+fn write_quicklook(quicklook: Quicklook, item: &CompletedItem) -> Result<()> {
+    let output = item.dir.join(quicklook.file_name());
+    crate::quicklook::write_quicklook(quicklook, &item.files, &output)?;
+    println!("Wrote quicklook {:?}", output);
+    Ok(())
+}
+
+#[cfg(not(feature = "quicklook"))]
+fn write_quicklook(quicklook: Quicklook, _: &CompletedItem) -> Result<()> {
+    Err(anyhow!(
+        "Cannot render the {} quicklook: slow-stac was built without the quicklook feature",
+        quicklook
+    ))
+}
+
+/// Fire the hooks an earlier run hasn't already fired for a completed item, recording each that
+/// succeeds.
+pub async fn fire_all(hooks: &[ItemHook], item: &CompletedItem) -> Result<()> {
+    let marker = item.dir.join(DONE_MARKER);
+    let fired = match marker.exists() {
+        true => std::fs::read_to_string(&marker)?,
+        false => String::new(),
+    };
+    for hook in hooks {
+        let line = hook.to_string();
+        if fired.lines().any(|fired| fired == line) {
+            continue;
+        }
+        hook.fire(item).await?;
+        append_line(&marker, &line)?;
+    }
+    Ok(())
+}
+
+fn append_line(path: &Path, line: &str) -> Result<()> {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fire_all() {
+        let item = CompletedItem {
+            selection_id: "provider.collection".to_string(),
+            item_id: "S2A".to_string(),
+            dir: PathBuf::from("/tmp/slow_stac_hooks/S2A item"),
+            files: vec![],
+        };
+        let _ = std::fs::remove_dir_all("/tmp/slow_stac_hooks");
+        std::fs::create_dir_all(&item.dir).unwrap();
+        let hooks = [ItemHook::Command(
+            "echo \"$SLOW_STAC_ITEM_ID\" >> /tmp/slow_stac_hooks/log; echo".to_string(),
+        )];
+        fire_all(&hooks, &item).await.unwrap();
+        // Already fired for this item
+        fire_all(&hooks, &item).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string("/tmp/slow_stac_hooks/log").unwrap(),
+            "S2A\n"
+        );
+
+        let failing = [ItemHook::Command("test -d".to_string())];
+        let missing = CompletedItem {
+            dir: PathBuf::from("/tmp/slow_stac_hooks/missing"),
+            ..item
+        };
+        assert!(fire_all(&failing, &missing).await.is_err());
+        assert_eq!(
+            Quicklook::FalseColor.file_name(),
+            "quicklook_false_color.png"
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Utility functions for serving `S3ObjOps` requests from plain HTTPS endpoints
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::primitives::ByteStream;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, ETAG, RANGE};
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+/// Many HTTPS data endpoints redirect to presigned urls that are only valid for GET requests, so
+/// the object size is read from the `Content-Range` header of a single byte GET instead of a HEAD.
+pub async fn head_object(request: RequestBuilder) -> Result<HeadObjectOutput> {
+    let response = request.header(RANGE, "bytes=0-0").send().await?;
+    let response = check_status(response)?;
+
+    let content_length = match response.status() {
+        StatusCode::PARTIAL_CONTENT => header_str(&response, CONTENT_RANGE)
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|total| total.parse::<i64>().ok()),
+        _ => header_str(&response, CONTENT_LENGTH).and_then(|len| len.parse::<i64>().ok()),
+    };
+    let e_tag = header_str(&response, ETAG).map(|etag| etag.to_string());
+    // A ranged GET answered with 200 shows the server ignores ranges, whatever it advertises
+    let accept_ranges = match response.status() {
+        StatusCode::PARTIAL_CONTENT => Some("bytes".to_string()),
+        _ => Some("none".to_string()),
+    };
+
+    Ok(HeadObjectOutput::builder()
+        .set_content_length(content_length)
+        .set_e_tag(e_tag)
+        .set_accept_ranges(accept_ranges)
+        .build())
+}
+
+pub async fn get_object(request: RequestBuilder) -> Result<GetObjectOutput> {
+    let response = check_status(request.send().await?)?;
+    Ok(into_get_object_output(response))
+}
+
+pub async fn get_object_range(
+    request: RequestBuilder,
+    start_byte: u64,
+    end_byte: u64,
+) -> Result<GetObjectOutput> {
+    let range = format!("bytes={}-{}", start_byte, end_byte);
+    let response = check_status(request.header(RANGE, range).send().await?)?;
+    Ok(into_get_object_output(response))
+}
+
+fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    // The query of presigned and SAS urls holds the credentials
+    let mut url = response.url().clone();
+    url.set_query(None);
+    Err(anyhow!("Request to {} failed with status {}", url, status))
+}
+
+fn header_str(response: &Response, name: reqwest::header::HeaderName) -> Option<&str> {
+    response.headers().get(name)?.to_str().ok()
+}
+
+fn into_get_object_output(response: Response) -> GetObjectOutput {
+    let content_length = header_str(&response, CONTENT_LENGTH).and_then(|len| len.parse().ok());
+    let content_range = header_str(&response, CONTENT_RANGE).map(|range| range.to_string());
+    let e_tag = header_str(&response, ETAG).map(|etag| etag.to_string());
+    let body = ByteStream::from_body_1_x(reqwest::Body::from(response));
+
+    GetObjectOutput::builder()
+        .set_content_length(content_length)
+        .set_content_range(content_range)
+        .set_e_tag(e_tag)
+        .body(body)
+        .build()
+}
@@ -0,0 +1,278 @@
+//! Recognising and normalising the item id formats used by each catalogue
+use crate::copernicus::sentinel2level2a;
+use crate::element84::{
+    sentinel2collection1level2a, sentinel2level1c, sentinel2precollection1level2a,
+};
+use crate::planetary_computer::landsat_c2_l2;
+use crate::search::{search_items, Search};
+use crate::user_agent;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde_json::Value;
+use stac::Item;
+
+/// Copernicus SAFE product name, with or without the `.SAFE` suffix
+const SAFE_NAME: &str = r"^S2[A-D]_MSI(?<level>L1C|L2A)_\d{8}T\d{6}_N\d{4}_R\d{3}_T\d{2}[A-Z]{3}_\d{8}T\d{6}(?<suffix>\.SAFE)?$";
+/// Earth Search `sentinel-2-c1-l2a` item id
+const EARTH_SEARCH_C1_ID: &str = r"^S2[A-D]_T\d{2}[A-Z]{3}_\d{8}T\d{6}_L2A$";
+/// Earth Search `sentinel-2-l2a`, `sentinel-2-pre-c1-l2a` or `sentinel-2-l1c` (pre Collection 1)
+/// item id
+const EARTH_SEARCH_LEGACY_ID: &str = r"^S2[A-D]_\d{1,2}[A-Z]{3}_\d{8}_\d+_(?<level>L1C|L2A)$";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IdFormat {
+    Safe,
+    EarthSearchC1,
+    EarthSearchLegacy,
+    Unknown,
+}
+
+impl IdFormat {
+    pub fn detect(id: &str) -> Self {
+        if matches(SAFE_NAME, id) {
+            IdFormat::Safe
+        } else if matches(EARTH_SEARCH_C1_ID, id) {
+            IdFormat::EarthSearchC1
+        } else if matches(EARTH_SEARCH_LEGACY_ID, id) {
+            IdFormat::EarthSearchLegacy
+        } else {
+            IdFormat::Unknown
+        }
+    }
+}
+
+fn matches(pattern: &str, id: &str) -> bool {
+    Regex::new(pattern)
+        .expect("Regex pattern should always compile")
+        .is_match(id)
+}
+
+/// Validate an id against the format used by the selection's catalogue, correcting what can be
+/// corrected (e.g. a missing `.SAFE` suffix) and rejecting ids meant for another catalogue.
+/// Selections without a known id format are passed through unchanged.
+pub fn normalize_id(selection_id: &str, id: &str) -> Result<String> {
+    let id = id.trim();
+    match selection_id {
+        "copernicus.sentinel2level2a" => normalize_safe_name(id),
+        "element84.sentinel2collection1level2a" => match IdFormat::detect(id) {
+            IdFormat::EarthSearchC1 => Ok(id.to_string()),
+            IdFormat::Safe => Err(anyhow!(
+                "{} is a Copernicus product name, use the copernicus.sentinel2level2a selection or an Earth Search id such as S2A_T08VPH_20240504T195929_L2A",
+                id
+            )),
+            IdFormat::EarthSearchLegacy => Err(anyhow!(
+                "{} is from an older Earth Search collection, not sentinel-2-c1-l2a, use the element84.sentinel2precollection1level2a or element84.sentinel2level1c selection",
+                id
+            )),
+            IdFormat::Unknown => Err(anyhow!(
+                "{} is not an Earth Search Sentinel-2 C1 L2A id (expected e.g. S2A_T08VPH_20240504T195929_L2A)",
+                id
+            )),
+        },
+        "element84.sentinel2level1c" => normalize_legacy_id(id, "L1C"),
+        "element84.sentinel2precollection1level2a" => normalize_legacy_id(id, "L2A"),
+        _ => Ok(id.to_string()),
+    }
+}
+
+/// Earth Search pre Collection 1 ids of the given processing level
+fn normalize_legacy_id(id: &str, level: &str) -> Result<String> {
+    let re = Regex::new(EARTH_SEARCH_LEGACY_ID).expect("Regex pattern should always compile");
+    match re.captures(id) {
+        Some(captures) if &captures["level"] == level => Ok(id.to_string()),
+        Some(captures) => Err(anyhow!(
+            "{} is a {} item, not {}",
+            id,
+            &captures["level"],
+            level
+        )),
+        None => Err(anyhow!(
+            "{} is not an Earth Search pre Collection 1 id (expected e.g. S2A_8VPH_20240504_0_{})",
+            id,
+            level
+        )),
+    }
+}
+
+fn normalize_safe_name(id: &str) -> Result<String> {
+    let re = Regex::new(SAFE_NAME).expect("Regex pattern should always compile");
+    match re.captures(id) {
+        Some(captures) if &captures["level"] == "L1C" => Err(anyhow!(
+            "{} is a Level-1C product, the copernicus.sentinel2level2a selection only downloads Level-2A",
+            id
+        )),
+        Some(captures) if captures.name("suffix").is_some() => Ok(id.to_string()),
+        Some(_) => Ok(format!("{}.SAFE", id)),
+        None => match IdFormat::detect(id) {
+            IdFormat::EarthSearchC1 | IdFormat::EarthSearchLegacy => Err(anyhow!(
+                "{} is an Earth Search item id, use the element84.sentinel2collection1level2a selection or the SAFE product name",
+                id
+            )),
+            _ => Err(anyhow!(
+                "{} is not a Sentinel-2 SAFE product name (expected e.g. S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE)",
+                id
+            )),
+        },
+    }
+}
+
+/// The platform, MGRS tile and sensing date shared by a Copernicus product and the Earth Search
+/// item generated from it. The sensing times in the two ids differ (datatake vs tile), so the date
+/// is the finest common key.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Acquisition {
+    pub platform: String,
+    pub tile: String,
+    pub date: String,
+}
+
+impl Acquisition {
+    pub fn from_id(id: &str) -> Option<Self> {
+        let pattern = match IdFormat::detect(id) {
+            IdFormat::Safe => {
+                r"^(?<platform>S2[A-D])_MSI\w{3}_(?<date>\d{8})T\d{6}_N\d{4}_R\d{3}_T(?<tile>\w{5})_"
+            }
+            IdFormat::EarthSearchC1 => r"^(?<platform>S2[A-D])_T(?<tile>\w{5})_(?<date>\d{8})T",
+            IdFormat::EarthSearchLegacy => {
+                r"^(?<platform>S2[A-D])_(?<tile>\w{4,5})_(?<date>\d{8})_"
+            }
+            IdFormat::Unknown => return None,
+        };
+        let re = Regex::new(pattern).expect("Regex pattern should always compile");
+        let captures = re.captures(id)?;
+        Some(Acquisition {
+            platform: captures["platform"].to_string(),
+            // Legacy Earth Search ids drop the leading zero of the UTM zone
+            tile: format!("{:0>5}", &captures["tile"]),
+            date: captures["date"].to_string(),
+        })
+    }
+
+    /// The whole UTC day of the acquisition as a STAC datetime interval
+    fn datetime_interval(self: &Self) -> String {
+        let (y, rest) = self.date.split_at(4);
+        let (m, d) = rest.split_at(2);
+        format!("{y}-{m}-{d}T00:00:00Z/{y}-{m}-{d}T23:59:59Z")
+    }
+}
+
+/// STAC API and collection holding the items of a selection
+pub fn catalogue(selection_id: &str) -> Result<(&'static str, &'static str)> {
+    match selection_id {
+        "copernicus.sentinel2level2a" => {
+            Ok((sentinel2level2a::STAC_API, sentinel2level2a::COLLECTION_ID))
+        }
+        "element84.sentinel2collection1level2a" => Ok((
+            sentinel2collection1level2a::STAC_API,
+            sentinel2collection1level2a::COLLECTION_ID,
+        )),
+        "planetarycomputer.landsatc2l2" => {
+            Ok((landsat_c2_l2::STAC_API, landsat_c2_l2::COLLECTION_ID))
+        }
+        "element84.sentinel2level1c" => Ok((
+            sentinel2collection1level2a::STAC_API,
+            sentinel2level1c::COLLECTION_ID,
+        )),
+        "element84.sentinel2precollection1level2a" => Ok((
+            sentinel2collection1level2a::STAC_API,
+            sentinel2precollection1level2a::COLLECTION_ID,
+        )),
+        _ => Err(anyhow!("Ids of {} cannot be translated", selection_id)),
+    }
+}
+
+/// Find the id of the same acquisition in another selection's catalogue, e.g. the Earth Search
+/// item for a Copernicus SAFE product. The source item's footprint and sensing day are searched in
+/// the target catalogue and the candidate with the same platform and tile is returned.
+pub async fn translate_id(from_selection: &str, to_selection: &str, id: &str) -> Result<String> {
+    let acquisition =
+        Acquisition::from_id(id).ok_or(anyhow!("Cannot determine the acquisition of {}", id))?;
+    let (from_api, from_collection) = catalogue(from_selection)?;
+    let (to_api, to_collection) = catalogue(to_selection)?;
+
+    let url = format!("{from_api}/collections/{from_collection}/items/{id}");
+    let item: Value = user_agent::get(url)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let bbox = item
+        .get("bbox")
+        .and_then(|b| serde_json::from_value::<[f64; 4]>(b.clone()).ok())
+        .ok_or(anyhow!("{} has no 2D bbox to search with", id))?;
+
+    let search = Search {
+        datetime: Some(acquisition.datetime_interval()),
+        bbox: Some(bbox),
+        ..Default::default()
+    };
+    let candidates = search_items(to_api, to_collection, &search).await?;
+    matching_id(to_selection, &acquisition, &candidates).ok_or(anyhow!(
+        "No item matching {} found in {}",
+        id,
+        to_collection
+    ))
+}
+
+/// The id of the first candidate that is valid for the selection and from the same acquisition
+fn matching_id(
+    selection_id: &str,
+    acquisition: &Acquisition,
+    candidates: &[Item],
+) -> Option<String> {
+    candidates
+        .iter()
+        .filter_map(|item| normalize_id(selection_id, &item.id).ok())
+        .find(|id| Acquisition::from_id(id).as_ref() == Some(acquisition))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAFE: &str = "S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE";
+    const C1: &str = "S2A_T08VPH_20240504T195929_L2A";
+
+    #[test]
+    fn test_normalize_copernicus_ids() {
+        let cop = "copernicus.sentinel2level2a";
+        assert_eq!(normalize_id(cop, SAFE).unwrap(), SAFE);
+        assert_eq!(
+            normalize_id(cop, SAFE.trim_end_matches(".SAFE")).unwrap(),
+            SAFE
+        );
+        assert!(normalize_id(cop, C1).is_err());
+        assert!(normalize_id(cop, &SAFE.replace("MSIL2A", "MSIL1C")).is_err());
+    }
+
+    #[test]
+    fn test_normalize_earth_search_ids() {
+        let e84 = "element84.sentinel2collection1level2a";
+        assert_eq!(normalize_id(e84, C1).unwrap(), C1);
+        assert!(normalize_id(e84, SAFE).is_err());
+        assert!(normalize_id(e84, "S2A_8VPH_20240504_0_L2A").is_err());
+        let l1c = "element84.sentinel2level1c";
+        assert_eq!(
+            normalize_id(l1c, "S2A_8VPH_20240504_0_L1C").unwrap(),
+            "S2A_8VPH_20240504_0_L1C"
+        );
+        assert!(normalize_id(l1c, "S2A_8VPH_20240504_0_L2A").is_err());
+    }
+
+    #[test]
+    fn test_acquisition_from_id() {
+        let expected = Some(Acquisition {
+            platform: "S2A".to_string(),
+            tile: "08VPH".to_string(),
+            date: "20240504".to_string(),
+        });
+        assert_eq!(Acquisition::from_id(SAFE), expected);
+        assert_eq!(Acquisition::from_id(C1), expected);
+        assert_eq!(Acquisition::from_id("S2A_8VPH_20240504_0_L2A"), expected);
+        assert_eq!(Acquisition::from_id("not-an-id"), None);
+        assert_eq!(
+            expected.unwrap().datetime_interval(),
+            "2024-05-04T00:00:00Z/2024-05-04T23:59:59Z"
+        );
+    }
+}
@@ -1,5 +1,10 @@
-use anyhow::Result;
+use crate::ids::normalize_id;
+use crate::search::Search;
+use crate::user_agent;
+use anyhow::{anyhow, Result};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
@@ -12,7 +17,19 @@ pub struct ImageSelection {
     name: String,
     description: String,
     docs: String,
+    /// Overrides the collection's default STAC API root, e.g. to use an internal mirror or proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stac_api: Option<String>,
+    /// Refuse to generate plans larger than this many bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_bytes: Option<u64>,
+    /// Substitute bands missing at the requested resolution with the finest one available
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub resolution_fallback: bool,
     ids_to_download: Vec<String>,
+    /// Find items by area and time instead of listing `ids_to_download`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search: Option<Search>,
     products: Vec<Product>,
 }
 
@@ -20,14 +37,187 @@ pub struct ImageSelection {
 pub struct Product {
     pub id: String,
     name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
     download: bool,
+    /// Treat `id` as a regular expression matched against the item's asset keys
+    #[serde(default, skip_serializing_if = "is_false")]
+    regex: bool,
+    /// Treat `id` as a glob pattern (`*`, `?` and `[...]`) matched against the item's asset keys
+    #[serde(default, skip_serializing_if = "is_false")]
+    glob: bool,
+    /// For COG assets, download only the overviews into a reduced-resolution GeoTIFF
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub overviews_only: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Product {
+    pub fn is_pattern(self: &Self) -> bool {
+        self.regex || self.glob
+    }
+
+    /// Whether the product selects `candidate`. Patterns must match the whole candidate, plain ids
+    /// must be equal to it.
+    pub fn matches(self: &Self, candidate: &str) -> Result<bool> {
+        if !self.is_pattern() {
+            return Ok(self.id == candidate);
+        }
+        let re = Regex::new(&format!("^(?:{})$", self.pattern()))?;
+        Ok(re.is_match(candidate))
+    }
+
+    /// Whether the product's id occurs somewhere within `candidate`, for catalogues whose file
+    /// identifiers embed the product id (e.g. Copernicus manifests).
+    pub fn matches_within(self: &Self, candidate: &str) -> Result<bool> {
+        if !self.is_pattern() {
+            return Ok(candidate.contains(&self.id));
+        }
+        let re = Regex::new(&self.pattern())?;
+        Ok(re.is_match(candidate))
+    }
+
+    /// Copy of this product selecting a single concrete key matched by its pattern
+    pub fn expanded(self: &Self, key: &str) -> Self {
+        Self {
+            id: key.to_string(),
+            regex: false,
+            glob: false,
+            ..self.clone()
+        }
+    }
+
+    fn pattern(self: &Self) -> String {
+        match self.glob {
+            true => glob_to_regex(&self.id),
+            false => self.id.clone(),
+        }
+    }
+}
+
+/// Fetch a collection and generate a product for each of its `item_assets`.
+pub async fn fetch_item_asset_products(stac_api: &str, collection: &str) -> Result<Vec<Product>> {
+    let url = format!("{stac_api}/collections/{collection}");
+    let collection_json: Value = user_agent::get(url)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    products_from_item_assets(&collection_json)
+        .ok_or(anyhow!("{} publishes no item_assets", collection))
+}
+
+fn products_from_item_assets(collection: &Value) -> Option<Vec<Product>> {
+    let item_assets = collection.get("item_assets")?.as_object()?;
+    if item_assets.is_empty() {
+        return None;
+    }
+    let products = item_assets
+        .iter()
+        .map(|(key, asset)| {
+            let field = |name: &str| asset.get(name).and_then(|v| v.as_str());
+            Product {
+                id: key.clone(),
+                name: field("title").unwrap_or(key).to_string(),
+                description: field("description").map(|d| d.to_string()),
+                download: false,
+                regex: false,
+                glob: false,
+                overviews_only: false,
+            }
+        })
+        .collect();
+    Some(products)
+}
+
+/// Resolve product patterns against the keys available for an item, printing what each pattern
+/// expanded to. Plain products are passed through unchanged.
+pub fn expand_products(item_id: &str, products: &[Product], keys: &[&str]) -> Result<Vec<Product>> {
+    let mut expanded = vec![];
+    for product in products {
+        if !product.is_pattern() {
+            expanded.push(product.clone());
+            continue;
+        }
+        let mut matched = vec![];
+        for key in keys {
+            if product.matches(key)? {
+                matched.push(*key);
+            }
+        }
+        if matched.is_empty() {
+            return Err(anyhow!(
+                "Pattern '{}' did not match any assets of {}",
+                product.id,
+                item_id
+            ));
+        }
+        println!(
+            "{}: pattern '{}' matched {}",
+            item_id,
+            product.id,
+            matched.join(", ")
+        );
+        expanded.extend(matched.into_iter().map(|key| product.expanded(key)));
+    }
+    Ok(expanded)
+}
+
+/// Translate a glob pattern into an unanchored regular expression.
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::new();
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                let mut class = chars.by_ref().take_while(|&c| c != ']').peekable();
+                if class.peek() == Some(&'!') {
+                    class.next();
+                    re.push('^');
+                }
+                re.extend(class);
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re
 }
 
 impl ImageSelection {
+    /// Read a selection file, whose relative `aoi_file` is relative to the file's directory
     pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
         let content = fs::read_to_string(path)?;
-        let selection: Self = toml::from_str(&content)?;
-        Ok(selection)
+        let mut selection: Self = toml::from_str(&content)?;
+        let aoi_file = selection
+            .search
+            .as_mut()
+            .and_then(|search| search.aoi_file.as_mut());
+        if let (Some(aoi_file), Some(dir)) = (aoi_file, path.parent()) {
+            *aoi_file = dir.join(&*aoi_file);
+        }
+        selection.with_normalized_ids()
+    }
+
+    /// Check `ids_to_download` against the id format of the selection's catalogue, correcting ids
+    /// such as Copernicus product names missing their `.SAFE` suffix.
+    pub fn with_normalized_ids(self) -> Result<Self> {
+        let ids_to_download = self
+            .ids_to_download
+            .iter()
+            .map(|id| normalize_id(&self.id, id))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            ids_to_download,
+            ..self
+        })
     }
 
     #[allow(dead_code)]
@@ -44,6 +234,81 @@ impl ImageSelection {
         selection
     }
 
+    /// Replace the curated products with those generated from the collection's `item_assets`,
+    /// keeping the curated `download` flags. The curated products are kept if the catalogue can't
+    /// be reached or publishes no `item_assets`.
+    pub async fn with_item_assets(self, stac_api: &str, collection: &str) -> Self {
+        let stac_api = self.stac_api(stac_api).to_string();
+        match fetch_item_asset_products(&stac_api, collection).await {
+            Ok(products) => {
+                let products = products
+                    .into_iter()
+                    .map(|p| {
+                        let download = self
+                            .products
+                            .iter()
+                            .any(|curated| curated.id == p.id && curated.download);
+                        Product { download, ..p }
+                    })
+                    .collect();
+                Self { products, ..self }
+            }
+            Err(e) => {
+                println!("Using the built-in product list ({})", e);
+                self
+            }
+        }
+    }
+
+    /// Narrow the selection to a single asset of a single item, e.g. for one-off downloads.
+    pub fn for_asset(self, item_id: &str, asset_key: &str) -> Self {
+        let product = Product {
+            id: asset_key.to_string(),
+            name: asset_key.to_string(),
+            description: None,
+            download: true,
+            regex: false,
+            glob: false,
+            overviews_only: false,
+        };
+        Self {
+            ids_to_download: vec![item_id.to_string()],
+            search: None,
+            products: vec![product],
+            ..self
+        }
+    }
+
+    /// Download only the overviews of every selected COG asset, e.g. to preview items over a
+    /// constrained link before fetching them at full resolution
+    pub fn with_overviews_only(self) -> Self {
+        let products = self
+            .products
+            .into_iter()
+            .map(|product| Product {
+                overviews_only: true,
+                ..product
+            })
+            .collect();
+        Self { products, ..self }
+    }
+
+    /// Replace the ids to download, e.g. with ids translated from another catalogue
+    pub fn with_ids_to_download(self, ids_to_download: Vec<String>) -> Self {
+        Self {
+            ids_to_download,
+            ..self
+        }
+    }
+
+    /// The STAC API root to use, falling back to `default` when the selection does not override it.
+    pub fn stac_api<'a>(self: &'a Self, default: &'a str) -> &'a str {
+        self.stac_api
+            .as_deref()
+            .unwrap_or(default)
+            .trim_end_matches('/')
+    }
+
     pub fn products_to_download(self: &Self) -> Option<Vec<Product>> {
         let products = self.products.clone();
         let to_download = products
@@ -86,6 +351,84 @@ mod tests {
         assert_eq!(selection.products.len(), 5);
     }
 
+    #[test]
+    fn test_stac_api_override() {
+        let mut selection =
+            ImageSelection::from_template(&sentinel2level2a::image_selection_toml());
+        assert_eq!(
+            selection.stac_api("https://default/stac"),
+            "https://default/stac"
+        );
+
+        selection.stac_api = Some("https://mirror.example.org/stac/".to_string());
+        assert_eq!(
+            selection.stac_api("https://default/stac"),
+            "https://mirror.example.org/stac"
+        );
+    }
+
+    fn mock_product(id: &str, regex: bool, glob: bool) -> Product {
+        Product {
+            id: id.to_string(),
+            name: "Band".to_string(),
+            description: None,
+            download: true,
+            regex,
+            glob,
+            overviews_only: false,
+        }
+    }
+
+    #[test]
+    fn test_product_patterns() {
+        let regex = mock_product("B0[234]_10m", true, false);
+        assert!(regex.matches("B03_10m").unwrap());
+        assert!(!regex.matches("B05_10m").unwrap());
+        assert!(regex
+            .matches_within("IMG_DATA_Band_B02_10m_Tile1_Data")
+            .unwrap());
+
+        let glob = mock_product("B0?_*", false, true);
+        assert!(glob.matches("B08_10m").unwrap());
+        assert!(!glob.matches("TCI_10m").unwrap());
+
+        let plain = mock_product("B02_10m", false, false);
+        assert!(!plain.matches("B02_10m_extra").unwrap());
+        assert!(plain.matches_within("B02_10m_extra").unwrap());
+    }
+
+    #[test]
+    fn test_expand_products() {
+        let products = vec![
+            mock_product("[!v]*", false, true),
+            mock_product("visual", false, false),
+        ];
+        let keys = vec!["red", "green", "visual"];
+        let expanded = expand_products("item", &products, &keys).unwrap();
+        let ids = expanded.iter().map(|p| p.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["red", "green", "visual"]);
+
+        let products = vec![mock_product("nir.*", true, false)];
+        assert!(expand_products("item", &products, &keys).is_err());
+    }
+
+    #[test]
+    fn test_products_from_item_assets() {
+        let collection = serde_json::json!({
+            "item_assets": {
+                "red": { "title": "Red (band 4) - 10m", "description": "Red band" },
+                "scl": {}
+            }
+        });
+        let products = products_from_item_assets(&collection).unwrap();
+        assert_eq!(products.len(), 2);
+        assert_eq!(products[0].id, "red");
+        assert_eq!(products[0].name, "Red (band 4) - 10m");
+        assert_eq!(products[1].name, "scl");
+
+        assert!(products_from_item_assets(&serde_json::json!({})).is_none());
+    }
+
     #[test]
     fn test_write_toml() {
         let path = Path::new(TEMPLATE_PATH);
@@ -102,5 +445,31 @@ mod tests {
         let selection = ImageSelection::read(path).unwrap();
         assert_eq!(selection.id, "copernicus.sentinel2level2a");
         assert_eq!(selection.products.len(), 5);
+
+        let mut selection = selection;
+        selection.search = Some(Search {
+            aoi_file: Some("aoi/field.shp".into()),
+            ..Default::default()
+        });
+        let content = toml::to_string(&selection).unwrap();
+        fs::write("/tmp/slow_stac_selection.toml", content).unwrap();
+        let selection = ImageSelection::read("/tmp/slow_stac_selection.toml").unwrap();
+        let aoi_file = selection.search.unwrap().aoi_file.unwrap();
+        assert_eq!(aoi_file, Path::new("/tmp/aoi/field.shp"));
+    }
+
+    #[test]
+    fn test_normalized_ids() {
+        let mut selection =
+            ImageSelection::from_template(&sentinel2level2a::image_selection_toml());
+        selection.ids_to_download =
+            vec!["S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750".to_string()];
+        let selection = selection.with_normalized_ids().unwrap();
+        assert!(selection.ids_to_download[0].ends_with(".SAFE"));
+
+        let mut selection =
+            ImageSelection::from_template(&sentinel2level2a::image_selection_toml());
+        selection.ids_to_download = vec!["S2A_T08VPH_20240504T195929_L2A".to_string()];
+        assert!(selection.with_normalized_ids().is_err());
     }
 }
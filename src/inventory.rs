@@ -0,0 +1,309 @@
+//! Catalogue of the files in a local archive, for bookkeeping and for sharing what has been
+//! downloaded with collaborators. Plans write each item to `<output dir>/<item id>/<file>`, so item
+//! directories are recognised by the id formats of the known collections.
+use crate::ids::{Acquisition, IdFormat};
+use crate::verify;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// MODIS product name, e.g. `MOD09GA.A2024125.h10v04.061.2024127033028`
+const MODIS_ID: &str = r"^MOD09GA\.A(?<year>\d{4})(?<day>\d{3})\.h\d{2}v\d{2}\.\d{3}\.\d{13}$";
+/// Left behind by downloads and conversions in progress, or by delta plans, and not part of the
+/// archive
+const IN_PROGRESS_SUFFIXES: [&str; 6] = [
+    ".partial",
+    ".partial.state",
+    ".converting.tif",
+    ".aoi.vrt",
+    ".stack.vrt",
+    crate::delta::OUTDATED_SUFFIX,
+];
+const CSV_HEADER: &str = "item_id,collection,product,path,size,checksum,acquisition_date";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InventoryEntry {
+    pub item_id: String,
+    /// Selection id of the collection the item's id belongs to
+    pub collection: String,
+    /// Band or asset, from the file name, e.g. `B04_10m`
+    pub product: String,
+    /// Relative to the archive directory
+    pub path: String,
+    pub size: u64,
+    /// `sha256:<hex digest>`, as checked by `verify::check_checksum`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// `YYYY-MM-DD`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acquisition_date: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InventoryFormat {
+    Csv,
+    Json,
+}
+
+impl InventoryFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => InventoryFormat::Json,
+            _ => InventoryFormat::Csv,
+        }
+    }
+}
+
+/// Selection id of the collection an item id belongs to, if it is a known one
+pub fn collection(item_id: &str) -> Option<&'static str> {
+    match IdFormat::detect(item_id) {
+        IdFormat::Safe => Some("copernicus.sentinel2level2a"),
+        IdFormat::EarthSearchC1 => Some("element84.sentinel2collection1level2a"),
+        IdFormat::EarthSearchLegacy if item_id.ends_with("_L1C") => {
+            Some("element84.sentinel2level1c")
+        }
+        IdFormat::EarthSearchLegacy => Some("element84.sentinel2precollection1level2a"),
+        _ if modis_date(item_id).is_some() => Some("earthdata.mod09ga"),
+        _ => None,
+    }
+}
+
+/// Acquisition date of an item as `YYYY-MM-DD`
+pub fn acquisition_date(item_id: &str) -> Option<String> {
+    if let Some(date) = modis_date(item_id) {
+        return Some(date);
+    }
+    let date = Acquisition::from_id(item_id)?.date;
+    Some(format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]))
+}
+
+/// MODIS ids carry the year and day of the year, e.g. `A2024125` is 2024-05-04
+fn modis_date(item_id: &str) -> Option<String> {
+    let re = Regex::new(MODIS_ID).expect("Regex pattern should always compile");
+    let captures = re.captures(item_id)?;
+    let year: u32 = captures["year"].parse().ok()?;
+    let mut day: u32 = captures["day"].parse().ok()?;
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let february = if leap { 29 } else { 28 };
+    let month_days = [31, february, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    for (month, days) in month_days.iter().enumerate() {
+        if day <= *days {
+            return Some(format!("{}-{:02}-{:02}", year, month + 1, day));
+        }
+        day -= days;
+    }
+    None
+}
+
+/// Band or asset a file holds, e.g. `B04_10m` for Copernicus'
+/// `T08VPH_20240504T195901_B04_10m.jp2`, `B04` for Earth Search's `B04.tif` and `stack` for
+/// `<item id>_stack.tif`
+pub fn product(item_id: &str, file_name: &str) -> String {
+    let stem = match file_name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => file_name,
+    };
+    let tile_prefix =
+        Regex::new(r"^T\d{2}[A-Z]{3}_\d{8}T\d{6}_").expect("Regex pattern should always compile");
+    let product = tile_prefix.replace(stem, "");
+    let product = product.replace(item_id, "");
+    let product = product.trim_matches(|c| c == '.' || c == '_');
+    match product.is_empty() {
+        // The item's own file, e.g. the HDF of a MODIS granule
+        true => Path::new(file_name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or(file_name.to_string()),
+        false => product.to_string(),
+    }
+}
+
+pub(crate) fn is_archived(file_name: &str) -> bool {
+    !file_name.starts_with('.')
+        && !IN_PROGRESS_SUFFIXES
+            .iter()
+            .any(|suffix| file_name.ends_with(suffix))
+}
+
+/// List the files of every recognised item directory in `dir`, hashing them unless `checksums`
+/// is false. Returns the entries and the directories that weren't recognised.
+pub fn inventory(dir: &Path, checksums: bool) -> Result<(Vec<InventoryEntry>, Vec<String>)> {
+    let mut entries = vec![];
+    let mut unrecognised = vec![];
+    let mut item_dirs: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .collect();
+    item_dirs.sort_by_key(|entry| entry.file_name());
+    for item_dir in item_dirs {
+        let item_id = item_dir.file_name().to_string_lossy().to_string();
+        let Some(collection) = collection(&item_id) else {
+            unrecognised.push(item_id);
+            continue;
+        };
+        let mut files: Vec<_> = fs::read_dir(item_dir.path())?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .collect();
+        files.sort_by_key(|entry| entry.file_name());
+        for file in files {
+            let file_name = file.file_name().to_string_lossy().to_string();
+            if !is_archived(&file_name) {
+                continue;
+            }
+            let checksum = match checksums {
+                true => Some(format!(
+                    "sha256:{}",
+                    verify::file_digest(&file.path(), "sha256")?
+                )),
+                false => None,
+            };
+            entries.push(InventoryEntry {
+                item_id: item_id.clone(),
+                collection: collection.to_string(),
+                product: product(&item_id, &file_name),
+                path: format!("{}/{}", item_id, file_name),
+                size: file.metadata()?.len(),
+                checksum,
+                acquisition_date: acquisition_date(&item_id),
+            });
+        }
+    }
+    Ok((entries, unrecognised))
+}
+
+pub fn to_string(entries: &[InventoryEntry], format: InventoryFormat) -> Result<String> {
+    match format {
+        InventoryFormat::Json => Ok(serde_json::to_string_pretty(entries)? + "\n"),
+        InventoryFormat::Csv => {
+            let mut lines = vec![CSV_HEADER.to_string()];
+            for entry in entries {
+                let fields = [
+                    entry.item_id.clone(),
+                    entry.collection.clone(),
+                    entry.product.clone(),
+                    entry.path.clone(),
+                    entry.size.to_string(),
+                    entry.checksum.clone().unwrap_or_default(),
+                    entry.acquisition_date.clone().unwrap_or_default(),
+                ];
+                lines.push(fields.map(|field| csv_field(&field)).join(","));
+            }
+            Ok(lines.join("\n") + "\n")
+        }
+    }
+}
+
+/// Read an inventory written by `to_string`, choosing the format from the file extension
+pub fn read(path: &Path) -> Result<Vec<InventoryEntry>> {
+    let content = fs::read_to_string(path)?;
+    if InventoryFormat::from_path(path) == InventoryFormat::Json {
+        return Ok(serde_json::from_str(&content)?);
+    }
+    let mut lines = content.lines();
+    if lines.next() != Some(CSV_HEADER) {
+        return Err(anyhow!(
+            "{:?} is not an inventory, expected {}",
+            path,
+            CSV_HEADER
+        ));
+    }
+    let mut entries = vec![];
+    for line in lines.filter(|line| !line.is_empty()) {
+        let fields = split_csv_line(line);
+        let [item_id, collection, product, path, size, checksum, acquisition_date] =
+            <[String; 7]>::try_from(fields).map_err(|_| anyhow!("Malformed line: {}", line))?;
+        entries.push(InventoryEntry {
+            item_id,
+            collection,
+            product,
+            path,
+            size: size.parse()?,
+            checksum: Some(checksum).filter(|c| !c.is_empty()),
+            acquisition_date: Some(acquisition_date).filter(|d| !d.is_empty()),
+        });
+    }
+    Ok(entries)
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inventory() {
+        let dir = Path::new("/tmp/slow_stac_inventory");
+        let _ = fs::remove_dir_all(dir);
+        let safe = "S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE";
+        let modis = "MOD09GA.A2024125.h10v04.061.2024127033028";
+        for item in [safe, modis, "notes"] {
+            fs::create_dir_all(dir.join(item)).unwrap();
+        }
+        fs::write(
+            dir.join(safe).join("T08VPH_20240504T195901_B04_10m.jp2"),
+            "b04",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(safe)
+                .join("T08VPH_20240504T195901_B08_10m.jp2.partial"),
+            "",
+        )
+        .unwrap();
+        fs::write(dir.join(safe).join(".slow-stac-item-hooked"), "").unwrap();
+        fs::write(dir.join(modis).join(format!("{}.hdf", modis)), "hdf").unwrap();
+
+        let (entries, unrecognised) = inventory(dir, true).unwrap();
+        assert_eq!(unrecognised, ["notes"]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].collection, "earthdata.mod09ga");
+        assert_eq!(entries[0].product, "hdf");
+        assert_eq!(entries[0].acquisition_date.as_deref(), Some("2024-05-04"));
+        assert_eq!(entries[1].product, "B04_10m");
+        assert_eq!(entries[1].size, 3);
+        assert_eq!(entries[1].acquisition_date.as_deref(), Some("2024-05-04"));
+        let checksum = entries[1].checksum.as_deref().unwrap();
+        verify::check_checksum(&dir.join(&entries[1].path), checksum).unwrap();
+
+        let path = dir.join("inventory.csv");
+        fs::write(&path, to_string(&entries, InventoryFormat::Csv).unwrap()).unwrap();
+        assert_eq!(read(&path).unwrap(), entries);
+        assert_eq!(
+            product(
+                "S2A_T08VPH_20240504T195929_L2A",
+                "S2A_T08VPH_20240504T195929_L2A_stack.tif"
+            ),
+            "stack"
+        );
+        assert_eq!(split_csv_line("a,\"b,\"\"c\"\"\",d"), ["a", "b,\"c\"", "d"]);
+    }
+}
@@ -0,0 +1,162 @@
+//! Exporting the items of a downloaded plan with `plan export-items`, their assets pointing at the
+//! local files. The remote href of each downloaded asset is kept as its `remote` alternate (see the
+//! STAC alternate-assets extension). Items are written as an ItemCollection, as newline-delimited
+//! JSON for `.ndjson` files, or as stac-geoparquet for `.parquet` files (requires the geoparquet
+//! feature), which is far smaller than JSON for large archives.
+use crate::download_plan::DownloadPlan;
+use crate::ids;
+use crate::user_agent;
+use anyhow::Result;
+use serde_json::json;
+use stac::Item;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// The alternate-assets extension, which the remote hrefs are kept under
+const ALTERNATE_ASSETS: &str =
+    "https://stac-extensions.github.io/alternate-assets/v1.2.0/schema.json";
+
+/// Point the assets of `item` downloaded by `plan` at their files, returning how many were
+pub fn localize(plan: &DownloadPlan, item: &mut Item) -> usize {
+    let mut localized = 0;
+    for task in plan.tasks() {
+        let (Some(asset_key), true) = (task.asset_key(), task.item() == item.id) else {
+            continue;
+        };
+        let Some(asset) = item.assets.get_mut(asset_key) else {
+            continue;
+        };
+        let output = plan.output_path(task);
+        if !output.exists() {
+            continue;
+        }
+        let local = fs::canonicalize(&output).unwrap_or(output);
+        let remote = std::mem::replace(&mut asset.href, local.to_string_lossy().to_string());
+        // Alongside the catalogue's own alternates
+        let alternate = asset
+            .additional_fields
+            .entry("alternate")
+            .or_insert_with(|| json!({}));
+        if !alternate.is_object() {
+            *alternate = json!({});
+        }
+        alternate["remote"] = json!({"href": remote});
+        localized += 1;
+    }
+    if localized > 0 && !item.extensions.iter().any(|e| e == ALTERNATE_ASSETS) {
+        item.extensions.push(ALTERNATE_ASSETS.to_string());
+    }
+    localized
+}
+
+/// The items of the plan, from its selection's catalogue
+pub async fn fetch_items(plan: &DownloadPlan) -> Result<Vec<Item>> {
+    let (stac_api, collection) = ids::catalogue(&plan.selection_id)?;
+    let ids: BTreeSet<_> = plan.tasks().iter().map(|task| task.item()).collect();
+    let mut items = vec![];
+    for id in ids {
+        let url = format!("{stac_api}/collections/{collection}/items/{id}");
+        items.push(
+            user_agent::get(url)
+                .await?
+                .error_for_status()?
+                .json::<Item>()
+                .await?,
+        );
+    }
+    Ok(items)
+}
+
+/// Write `items` to `path` in the format its extension calls for
+pub fn write(items: Vec<Item>, path: &Path) -> Result<()> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("parquet") => write_geoparquet(items, path),
+        Some("ndjson") => {
+            let mut lines = vec![];
+            for item in &items {
+                lines.push(serde_json::to_string(item)?);
+            }
+            fs::write(path, lines.join("\n") + "\n")?;
+            Ok(())
+        }
+        _ => {
+            let collection = json!({"type": "FeatureCollection", "features": items});
+            fs::write(path, serde_json::to_string_pretty(&collection)?)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "geoparquet")]
+fn write_geoparquet(items: Vec<Item>, path: &Path) -> Result<()> {
+    let file = fs::File::create(path)?;
+    stac::geoparquet::to_writer(file, stac::ItemCollection::from(items))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "geoparquet"))]
+fn write_geoparquet(_: Vec<Item>, path: &Path) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "Cannot write {:?}: slow-stac was built without the geoparquet feature",
+        path
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_plan::DownloadTask;
+    use crate::items::read_items;
+
+    #[test]
+    fn test_localize() {
+        let root = Path::new("/tmp/slow_stac_item_export");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("S2A")).unwrap();
+        fs::write(root.join("S2A/B04.tif"), "red").unwrap();
+        let plan = DownloadPlan::new(
+            "element84.sentinel2collection1level2a",
+            vec![
+                DownloadTask::new("bucket", "S2A/B04.tif", "S2A/B04.tif").with_asset_key("red"),
+                DownloadTask::new("bucket", "S2A/B08.tif", "S2A/B08.tif").with_asset_key("nir"),
+            ],
+        )
+        .with_root(root);
+        let mut item: Item = serde_json::from_value(json!({
+            "type": "Feature",
+            "stac_version": "1.0.0",
+            "id": "S2A",
+            "geometry": null,
+            "properties": {"datetime": "2024-05-04T19:59:29Z"},
+            "links": [],
+            "assets": {
+                "red": {
+                    "href": "s3://bucket/S2A/B04.tif",
+                    "alternate": {"https": {"href": "https://bucket.s3.amazonaws.com/S2A/B04.tif"}}
+                },
+                "nir": {"href": "s3://bucket/S2A/B08.tif"}
+            }
+        }))
+        .unwrap();
+        assert_eq!(localize(&plan, &mut item), 1);
+        assert_eq!(
+            item.assets["red"].href,
+            "/tmp/slow_stac_item_export/S2A/B04.tif"
+        );
+        assert_eq!(
+            item.assets["red"].additional_fields["alternate"]["remote"]["href"],
+            "s3://bucket/S2A/B04.tif"
+        );
+        assert_eq!(
+            item.assets["red"].additional_fields["alternate"]["https"]["href"],
+            "https://bucket.s3.amazonaws.com/S2A/B04.tif"
+        );
+        assert_eq!(item.extensions, [ALTERNATE_ASSETS]);
+        assert_eq!(item.assets["nir"].href, "s3://bucket/S2A/B08.tif");
+
+        let path = root.join("items.json");
+        write(vec![item], &path).unwrap();
+        assert_eq!(read_items(&path).unwrap()[0].id, "S2A");
+    }
+}
@@ -0,0 +1,59 @@
+//! Read STAC Items saved to disk, e.g. the results of a search
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use stac::Item;
+use std::fs;
+use std::path::Path;
+
+/// Read the items of a GeoJSON FeatureCollection (a STAC ItemCollection) or a single Item.
+pub fn read_items<P: AsRef<Path>>(path: P) -> Result<Vec<Item>> {
+    let content = fs::read_to_string(path)?;
+    let value: Value = serde_json::from_str(&content)?;
+    match value.get("type").and_then(|t| t.as_str()) {
+        Some("FeatureCollection") => {
+            let features = value
+                .get("features")
+                .and_then(|f| f.as_array())
+                .ok_or(anyhow!("ItemCollection has no 'features' array"))?;
+            features
+                .iter()
+                .map(|feature| Ok(serde_json::from_value::<Item>(feature.clone())?))
+                .collect()
+        }
+        Some("Feature") => Ok(vec![serde_json::from_value::<Item>(value)?]),
+        _ => Err(anyhow!("Expected a STAC ItemCollection or Item")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TEST_OUTPUT_PATH: &str = "/tmp/item_collection.json";
+
+    fn mock_item(id: &str) -> Value {
+        json!({
+            "type": "Feature",
+            "stac_version": "1.0.0",
+            "id": id,
+            "geometry": null,
+            "properties": { "datetime": "2024-05-04T19:59:29Z" },
+            "links": [],
+            "assets": {}
+        })
+    }
+
+    #[test]
+    fn test_read_item_collection() {
+        let collection = json!({
+            "type": "FeatureCollection",
+            "features": [mock_item("a"), mock_item("b")]
+        });
+        fs::write(TEST_OUTPUT_PATH, collection.to_string()).unwrap();
+
+        let items = read_items(TEST_OUTPUT_PATH).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].id, "b");
+    }
+}
@@ -1,7 +1,80 @@
 #![allow(async_fn_in_trait)]
 #![allow(dead_code)]
+#[cfg(feature = "aoi-files")]
+pub mod aoi;
+pub mod audit;
+pub mod backoff;
+pub mod cache;
+pub mod checkpoint;
+pub mod cog;
+pub mod debug_bundle;
+pub mod config;
+pub mod control;
+pub mod control_socket;
+pub mod datatake;
+pub mod delta;
 pub mod copernicus;
+pub mod coverage;
 pub mod download_plan;
+pub mod environment;
+mod fetch;
+pub mod geotiff;
+pub mod head_cache;
+pub mod hooks;
+pub mod ids;
+pub mod logging;
 pub mod image_selection;
+pub mod inventory;
+pub mod item_export;
+pub mod items;
+pub mod mirror;
+#[cfg(feature = "fuse")]
+pub mod mount;
+pub mod multipart;
+pub mod multisource;
+pub mod notify;
+pub mod partial;
+pub mod pause;
+pub mod peer;
+pub mod plan_check;
+pub mod planetary_computer;
+pub mod plan_crypt;
+pub mod plan_diff;
+pub mod porcelain;
+pub mod presign;
+pub mod probe;
+pub mod profile;
+pub mod provenance;
+pub mod prepare;
+pub mod prune;
+#[cfg(feature = "quicklook")]
+pub mod quicklook;
+pub mod provider;
+pub mod provider_config;
+pub mod readthrough;
+pub mod rclone;
 mod s3;
+pub mod search;
 pub mod element84;
+pub mod earthdata;
+pub mod failure;
+pub mod gcs;
+mod http;
+pub mod resolve;
+pub mod schedule;
+pub mod scl;
+pub mod service;
+pub mod skip_list;
+pub mod stack;
+pub mod task_list;
+pub mod timeseries;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod units;
+pub mod upload;
+pub mod user_agent;
+pub mod verify;
+#[cfg(target_os = "linux")]
+pub mod watch;
+
+pub use fetch::{cat_asset, fetch_asset, stream_object};
@@ -0,0 +1,80 @@
+//! Log output for a terminal or, when running as a systemd service, the journal
+use crate::debug_bundle;
+use std::fs::File;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Install the global subscriber. Under journald, events are sent with their span fields as
+/// journal fields (e.g. `PLAN=`, `OUTPUT=`) at `info` and above; otherwise they are written to
+/// stderr, without ANSI colours unless it is a terminal, only when RUST_LOG asks for them.
+///
+/// With `debug_log`, debug events and the time spent in each span are also written there for a
+/// debug bundle (see `debug_bundle`), whatever RUST_LOG asks for.
+pub fn init(debug_log: Option<&Path>) {
+    let default_level = match is_journald() {
+        true => "info",
+        false => "off",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new(default_level));
+    let journald = match is_journald() {
+        true => match tracing_journald::layer() {
+            Ok(journald) => Some(journald.with_syslog_identifier("slow-stac".to_string())),
+            Err(e) => {
+                println!("Warning: could not connect to the journal: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    let stderr = journald.is_none().then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+    });
+    let debug = debug_log.and_then(|path| match File::create(path) {
+        Ok(file) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_writer(Mutex::new(file))
+                .with_ansi(false)
+                .with_span_events(FmtSpan::CLOSE)
+                .with_filter(EnvFilter::new(debug_bundle::LOG_FILTER)),
+        ),
+        Err(e) => {
+            println!("Warning: could not write the debug log {:?}: {}", path, e);
+            None
+        }
+    });
+    tracing_subscriber::registry()
+        .with(Layer::and_then(journald, stderr).with_filter(filter))
+        .with(debug)
+        .init();
+}
+
+/// Whether stderr is connected to the journal, which systemd advertises by setting JOURNAL_STREAM
+/// to the device and inode of the stream.
+#[cfg(unix)]
+pub fn is_journald() -> bool {
+    use std::os::fd::AsFd;
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(stream) = std::env::var("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Ok(stderr) = std::io::stderr().as_fd().try_clone_to_owned() else {
+        return false;
+    };
+    match std::fs::File::from(stderr).metadata() {
+        Ok(metadata) => stream == format!("{}:{}", metadata.dev(), metadata.ino()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn is_journald() -> bool {
+    false
+}
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Context, Result};
 use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 /// A tool for downloading satellite imagery from S3 on slow or unstable connections
 #[derive(Parser)]
@@ -8,6 +9,19 @@ use std::path::PathBuf;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Report errors as a JSON object on stderr
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Print tables and sizes as tab-separated records with unformatted values, for scripts
+    #[arg(long, global = true)]
+    porcelain: bool,
+
+    /// Use the proxy, download settings and windows of this environment in the config file, e.g.
+    /// field or office. Options given on the command line take precedence
+    #[arg(long, global = true)]
+    env: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -19,6 +33,10 @@ enum Commands {
 
         /// Directory to save image selection toml
         output_dir: PathBuf,
+
+        /// Use the built-in product list instead of fetching it from the collection
+        #[arg(long)]
+        offline: bool,
     },
     /// Prepare the download plan
     Prepare {
@@ -27,10 +45,612 @@ enum Commands {
 
         /// Directory to save downloaded images
         output_dir: PathBuf,
+
+        /// Print the per-item download sizes without writing the plan
+        #[arg(long)]
+        preview: bool,
+
+        /// STAC ItemCollection json to plan from instead of fetching `ids_to_download`
+        #[arg(long)]
+        items: Option<PathBuf>,
+
+        /// Encrypt the plan with a passphrase (from SLOW_STAC_PLAN_PASSPHRASE or prompted for)
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Sign the plan with the key in SLOW_STAC_PLAN_KEY so recipients can verify it
+        #[arg(long)]
+        sign: bool,
+
+        /// Plan only the files of archived items in the output directory that changed upstream,
+        /// and newer processings of archived items
+        #[arg(long)]
+        delta: bool,
+
+        /// Plan only the overviews of COG assets, as reduced-resolution GeoTIFFs of a few percent
+        /// of the full size
+        #[arg(long)]
+        overviews_only: bool,
+
+        /// Number of items (and manifests) to look up at the same time
+        #[arg(long, default_value_t = slow_stac::prepare::DEFAULT_JOBS)]
+        jobs: usize,
+
+        /// Plan the items that resolve and list those that don't, with the reasons, in
+        /// unresolved.json in the output directory instead of failing
+        #[arg(long)]
+        skip_unresolved: bool,
     },
     /// Execute the download plan
     Download {
-        /// Json file defining images to download
+        /// Plan file (json, toml, yaml or ndjson) defining images to download, or an http(s) URL
+        /// to fetch it from
+        download_plan: PathBuf,
+
+        /// Directory to save downloaded images, replacing the one the plan was prepared with
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+
+        /// Preset download settings for the connection: slow, flaky or fast. Defaults to the
+        /// profile in the config file
+        #[arg(long)]
+        profile: Option<slow_stac::profile::Profile>,
+
+        /// Save --profile as the default in the config file
+        #[arg(long, requires = "profile")]
+        save_profile: bool,
+
+        /// Number of tasks to download at the same time
+        #[arg(long)]
+        jobs: Option<usize>,
+
+        /// Request objects in ranges of at most this many bytes
+        #[arg(long)]
+        chunk_size: Option<u64>,
+
+        /// Download each object of at least --parts-min-size bytes in this many ranges at the same
+        /// time, for high-latency links where a single stream never fills the connection
+        #[arg(long)]
+        parts: Option<usize>,
+
+        /// Objects smaller than this many bytes are downloaded in a single stream with --parts
+        #[arg(long, requires = "parts", default_value_t = slow_stac::multipart::DEFAULT_MIN_SIZE)]
+        parts_min_size: u64,
+
+        /// Download gently in the background: cap throughput and pause between chunks
+        #[arg(long)]
+        nice: bool,
+
+        /// Throughput cap in bytes per second for --nice
+        #[arg(long, default_value_t = 256 * 1024)]
+        nice_rate: u64,
+
+        /// Abort a transfer slower than this many bytes per second over --stall-window, 0 to never
+        /// [default: 1024]
+        #[arg(long)]
+        min_rate: Option<u64>,
+
+        /// Seconds over which --min-rate is measured [default: 60]
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        stall_window: Option<u64>,
+
+        /// Abort a task that takes longer than this many seconds
+        #[arg(long)]
+        task_timeout: Option<u64>,
+
+        /// Stop downloading after this many seconds
+        #[arg(long)]
+        timeout: Option<u64>,
+
+        /// Order to download tasks in: plan, or item-smallest-first to complete one item at a time
+        /// starting with its smallest files [default: plan]
+        #[arg(long)]
+        order: Option<slow_stac::download_plan::TaskOrder>,
+
+        /// Shell command to run when every file of an item has downloaded and verified, with the
+        /// item directory appended as its last argument. Can be given more than once
+        #[arg(long)]
+        on_item_complete: Vec<String>,
+
+        /// URL to POST `{"selection_id", "item_id", "item_dir"}` to when every file of an item
+        /// has downloaded and verified. Can be given more than once
+        #[arg(long)]
+        item_webhook: Vec<String>,
+
+        /// Render an ndvi or false-color PNG into each item's directory once its bands have
+        /// downloaded (GeoTIFF bands only, requires the quicklook feature)
+        #[arg(long)]
+        quicklook: Vec<slow_stac::hooks::Quicklook>,
+
+        /// Convert JPEG 2000 bands to Cloud Optimized GeoTIFFs alongside them with GDAL's
+        /// gdal_translate as they complete
+        #[arg(long)]
+        cog: bool,
+
+        /// Compression of the COGs written by --cog, e.g. DEFLATE, ZSTD, LZW or JPEG
+        #[arg(long, default_value = slow_stac::cog::DEFAULT_COMPRESSION, requires = "cog")]
+        cog_compression: String,
+
+        /// Stack each item's bands, in selection order, into one multiband GeoTIFF with GDAL once
+        /// they have all downloaded, as configured by [stack] in the config file
+        #[arg(long)]
+        stack: bool,
+
+        /// Remove the individual band files after stacking them
+        #[arg(long, requires = "stack")]
+        stack_remove_bands: bool,
+
+        /// Upload each item to this S3 destination, e.g. s3://bucket/prefix, once its files have
+        /// downloaded and verified (and any --cog or --stack outputs are written)
+        #[arg(long)]
+        upload: Option<String>,
+
+        /// AWS profile with credentials for --upload
+        #[arg(long, default_value = "default", requires = "upload")]
+        upload_profile: String,
+
+        /// Remove each file locally once it has uploaded
+        #[arg(long, requires = "upload")]
+        upload_remove_local: bool,
+
+        /// Copy each item to this rclone remote, e.g. gdrive:sentinel-2, once its files have
+        /// downloaded and verified, with the rclone binary and its configuration
+        #[arg(long)]
+        rclone: Option<String>,
+
+        /// Attempts at copying each file with --rclone
+        #[arg(long, default_value_t = slow_stac::rclone::DEFAULT_ATTEMPTS, requires = "rclone")]
+        rclone_attempts: u32,
+
+        /// Remove each file locally once rclone has copied it
+        #[arg(long, requires = "rclone")]
+        rclone_remove_local: bool,
+
+        /// Cache shared with other users to download each object into once, linking it into the
+        /// output directory [default: cache_dir from the config file]
+        #[arg(long)]
+        cache: Option<PathBuf>,
+
+        /// Another slow-stac instance on the local network to download objects from before the
+        /// internet, as started with `serve`, e.g. 192.168.1.20:8470, sharing the token in
+        /// SLOW_STAC_PEER_TOKEN or peer_token in the config file. Can be given more than once
+        /// [default: peers from the config file]
+        #[arg(long)]
+        peer: Vec<String>,
+
+        /// Copernicus only: fetch each chunk partly from Copernicus and partly from the Google
+        /// Cloud mirror at once, split by how fast each has been
+        #[arg(long)]
+        multi_source: bool,
+
+        /// Only download during this daily window in UTC, e.g. 22:00-06:00, waiting for it to
+        /// open and resuming in the next one. Can be given more than once
+        #[arg(long)]
+        window: Vec<slow_stac::schedule::Window>,
+
+        /// Copernicus only: pause to make at most this many requests per minute
+        #[arg(long)]
+        max_requests_per_minute: Option<u64>,
+
+        /// Copernicus only: pause to transfer at most this many bytes in any 24 hours
+        #[arg(long)]
+        max_bytes_per_day: Option<u64>,
+
+        /// Image selection the plan was prepared from: prepare it again whenever it is edited and
+        /// download the updated plan, waiting for edits once the plan is complete (Linux only)
+        #[arg(long)]
+        watch_selection: Option<PathBuf>,
+
+        /// Download without asking, however much of the plan is left (see confirm_above_bytes in
+        /// the config file)
+        #[arg(long, short)]
+        yes: bool,
+
+        /// Show a live dashboard of the running tasks, with keys to pause, skip tasks and change
+        /// how many run at once. Messages go to a log file meanwhile (requires the tui feature)
+        #[arg(long)]
+        tui: bool,
+
+        /// Listen on <plan>.sock for commands from `slow-stac control` while downloading
+        #[arg(long = "control")]
+        control_socket: bool,
+
+        /// Push the plan's state to s3://bucket/key or an http(s) URL accepting PUT as tasks
+        /// complete, to continue on another machine with `plan pull-state` if this one is lost
+        #[arg(long)]
+        push_state: Option<String>,
+
+        /// AWS profile with credentials for an s3:// --push-state
+        #[arg(long, default_value = "default", requires = "push_state")]
+        push_state_profile: String,
+
+        /// Seconds to reuse an object's size and ETag before asking for them again, 0 to ask on
+        /// every attempt [default: 300]
+        #[arg(long)]
+        head_cache_ttl: Option<u64>,
+
+        /// For objects served without a size: stream them until the response ends and check them
+        /// against their ETag, or fail them [default: stream]
+        #[arg(long)]
+        missing_length: Option<slow_stac::download_plan::MissingLength>,
+
+        /// Write the debug log, timings, failed tasks and environment, sanitized, to
+        /// slow-stac-debug-<time>.tar.gz in the working directory afterwards, to attach to a bug
+        /// report
+        #[arg(long)]
+        debug_bundle: bool,
+
+        /// Write timeseries.csv and timeseries.json into the output directory afterwards, listing
+        /// each downloaded item's date, tile and band files in date order for building data cubes
+        #[arg(long)]
+        timeseries: bool,
+
+        /// Mosaic each band of acquisitions split across tiles of the same datatake into a VRT
+        /// per UTM zone under <output dir>/datatakes afterwards, with gdalbuildvrt
+        #[arg(long)]
+        merge_datatakes: bool,
+    },
+    /// Rewrite a selection's ids for the same acquisitions in another collection's catalogue
+    Translate {
+        /// Toml file defining image ids and product types to download
+        image_selection: PathBuf,
+
+        /// Collection to translate the ids to
+        collection: Collection,
+
+        /// Toml file to write the translated image selection to
+        output: PathBuf,
+    },
+    /// List the properties a collection's STAC API can filter on
+    Queryables {
+        /// Collection to list queryables for
+        collection: Collection,
+    },
+    /// Download every object under an S3 prefix that matches the globs, resuming an interrupted
+    /// mirror, e.g. for buckets shared by collaborators without a STAC catalogue
+    Mirror {
+        /// Bucket and prefix to mirror, e.g. s3://bucket/prefix
+        source: String,
+
+        /// Directory to mirror into, each object written to its key below the prefix
+        output_dir: PathBuf,
+
+        /// Only mirror keys below the prefix matching this glob, e.g. '*.tif'. Can be given more
+        /// than once
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// Leave out keys below the prefix matching this glob. Can be given more than once
+        #[arg(long)]
+        exclude: Vec<String>,
+
+        /// AWS profile with credentials for the bucket [default: anonymous access]
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Region of the bucket for anonymous access
+        #[arg(long, default_value = "us-west-2")]
+        region: String,
+
+        /// Endpoint of S3-compatible storage other than AWS, whose buckets are addressed by path
+        #[arg(long)]
+        endpoint_url: Option<String>,
+
+        /// Number of objects to download at once
+        #[arg(long)]
+        jobs: Option<usize>,
+    },
+    /// Measure the connection to a collection's provider and save recommended download settings
+    Speedtest {
+        /// Collection to download sample ranges from
+        collection: Collection,
+
+        /// Number of ranges to read from the sample object
+        #[arg(long, default_value_t = 3)]
+        samples: u64,
+    },
+    /// Inspect a download plan
+    Plan {
+        #[command(subcommand)]
+        command: PlanCommands,
+    },
+    /// Catalogue the items downloaded to a directory, with each file's product, size, checksum
+    /// and acquisition date
+    Inventory {
+        /// Output directory of one or more plans
+        dir: PathBuf,
+
+        /// File to write the inventory to, CSV or JSON by extension [default: CSV on stdout]
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Skip hashing the files, which reads the whole archive
+        #[arg(long)]
+        no_checksums: bool,
+    },
+    /// Verify an archive against the checksums recorded in an inventory or plan, e.g. from a
+    /// timer to catch corruption on external drives
+    Audit {
+        /// Output directory of one or more plans
+        dir: PathBuf,
+
+        /// Inventory with the checksums [default: inventory.csv or inventory.json in the
+        /// directory]
+        #[arg(long, conflicts_with = "plan")]
+        inventory: Option<PathBuf>,
+
+        /// Plan the files were downloaded with, using the checksums and sizes published by the
+        /// catalogue
+        #[arg(long)]
+        plan: Option<PathBuf>,
+
+        /// Skip files an earlier audit verified within this many days
+        #[arg(long, default_value_t = 0)]
+        recheck_after_days: u64,
+
+        /// Delete corrupt files so downloading the plan again replaces them
+        #[arg(long, requires = "plan")]
+        requeue: bool,
+    },
+    /// List items superseded by a reprocessed version of the same acquisition, deleting them
+    /// with --delete
+    Prune {
+        /// Output directory of one or more plans
+        dir: PathBuf,
+
+        /// Plan downloading into the directory, whose items only supersede others once every
+        /// file it lists is there. Can be given more than once
+        #[arg(long)]
+        plan: Vec<PathBuf>,
+
+        /// Delete the superseded items instead of only listing them
+        #[arg(long)]
+        delete: bool,
+    },
+    /// Share downloaded files with other slow-stac instances on the local network, which ask for
+    /// them with `download --peer`
+    Serve {
+        /// Plan whose completed files to share. Can be given more than once
+        #[arg(long)]
+        plan: Vec<PathBuf>,
+
+        /// Download cache to share [default: cache_dir from the config file]
+        #[arg(long)]
+        cache: Option<PathBuf>,
+
+        /// Address to listen on, e.g. 0.0.0.0:8470 to share with the local network. Requests must
+        /// carry the token in SLOW_STAC_PEER_TOKEN or peer_token in the config file
+        #[arg(long, default_value = slow_stac::peer::DEFAULT_BIND)]
+        bind: String,
+    },
+    /// Mount a plan's output tree read-only, downloading the parts of files that are read before
+    /// the plan has downloaded them. Unmount with `fusermount -u` (`umount` on macOS)
+    Mount {
+        /// Plan file (json, toml, yaml or ndjson) defining images to download
+        download_plan: PathBuf,
+
+        /// Empty directory to mount the tree on
+        mountpoint: PathBuf,
+
+        /// Directory for the blocks read [default: <plan>.blocks]
+        #[arg(long)]
+        cache: Option<PathBuf>,
+    },
+    /// Stop a download started with --control and keep the plan from downloading until resumed
+    Pause {
+        /// Plan to pause
+        download_plan: PathBuf,
+    },
+    /// Run a paused plan's download again, after any backoff a host asked for
+    Resume {
+        /// Plan to resume
+        download_plan: PathBuf,
+    },
+    /// Steer a download started with --control without restarting it
+    Control {
+        /// Plan being downloaded
+        download_plan: PathBuf,
+
+        /// pause, resume, rate <bytes per second|off>, jobs <n>, skip <bucket>/<key>, status or
+        /// stop
+        #[arg(required = true)]
+        command: Vec<String>,
+    },
+    /// Write one asset of one item to stdout as it downloads, e.g. to pipe into
+    /// `gdal_translate /vsistdin/`. Messages go to stderr
+    Cat {
+        /// Collection the item belongs to
+        collection: Collection,
+
+        /// Item id
+        item_id: String,
+
+        /// Asset key, e.g. red or B04_10m
+        asset: String,
+    },
+    /// Report a remote GeoTIFF asset's projection, resolution, blocks, overviews and nodata from
+    /// the first kilobytes of the file
+    Inspect {
+        /// Collection the item belongs to
+        collection: Collection,
+
+        /// Item id
+        item_id: String,
+
+        /// Asset key, e.g. red or B04_10m
+        asset: String,
+
+        /// Kilobytes requested from the start of the file
+        #[arg(long, default_value_t = slow_stac::geotiff::DEFAULT_HEADER_BYTES / 1024)]
+        header_kb: u64,
+    },
+    /// Install a systemd service that downloads a plan unattended, restarting it on failure
+    InstallService {
+        /// Plan file to download, or image selection to prepare a plan from first
+        plan_or_selection: PathBuf,
+
+        /// Directory to prepare the plan in and save images to (required for a selection)
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+
+        /// Only download during this daily window in UTC, e.g. 22:00-06:00. Can be given more
+        /// than once
+        #[arg(long)]
+        window: Vec<slow_stac::schedule::Window>,
+
+        /// Download settings preset passed on to `download`
+        #[arg(long)]
+        profile: Option<slow_stac::profile::Profile>,
+
+        /// Download gently in the background, see `download --nice`
+        #[arg(long)]
+        nice: bool,
+
+        /// Name of the unit [default: slow-stac-<plan file name>]
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Install a system unit in /etc/systemd/system instead of a user unit
+        #[arg(long)]
+        system: bool,
+
+        /// Print the unit instead of installing it
+        #[arg(long)]
+        print: bool,
+
+        /// Keep the service running once the plan is complete, preparing the selection again
+        /// and downloading what was added whenever it is edited (Linux only)
+        #[arg(long)]
+        watch: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum PlanCommands {
+    /// Print task counts and sizes per item, product and bucket
+    Stats {
+        /// Plan file (json, toml, yaml or ndjson) defining images to download
+        download_plan: PathBuf,
+    },
+    /// List the tasks of a plan with their item, product, size and status
+    List {
+        /// Plan file (json, toml, yaml or ndjson) defining images to download
+        download_plan: PathBuf,
+
+        /// Directory the images are saved to, replacing the one the plan was prepared with
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+
+        /// Only list tasks whose download stopped part way
+        #[arg(long)]
+        failed: bool,
+
+        /// Only list tasks that are not done, including failed ones
+        #[arg(long)]
+        pending: bool,
+    },
+    /// Write a plan and its progress from the state a download pushed with --push-state, to
+    /// continue it on this machine
+    PullState {
+        /// s3://bucket/key or http(s) URL the state was pushed to
+        source: String,
+
+        /// Directory to download the plan to, where the plan file is written
+        output_dir: PathBuf,
+
+        /// AWS profile with credentials for an s3:// source
+        #[arg(long, default_value = "default")]
+        profile: String,
+    },
+    /// Skip items of a plan, from its next task on if it is downloading
+    Skip {
+        /// Plan file (json, toml, yaml or ndjson) defining images to download
+        download_plan: PathBuf,
+
+        /// Id of an item to skip, can be given more than once
+        #[arg(long = "item", required = true)]
+        items: Vec<String>,
+    },
+    /// Print the tasks added, removed and changed from one plan to another, e.g. after re-preparing
+    /// an edited selection
+    Diff {
+        /// Plan file (json, toml, yaml or ndjson) to compare from
+        before: PathBuf,
+        /// Plan file (json, toml, yaml or ndjson) to compare to
+        after: PathBuf,
+    },
+    /// Check the partial downloads of a plan against their checkpoints, e.g. after copying the
+    /// output directory to another machine, before resuming it
+    Partials {
+        /// Plan file (json, toml, yaml or ndjson) defining images to download
+        download_plan: PathBuf,
+
+        /// Directory the images were copied to, replacing the one the plan was prepared with
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+    },
+    /// Check that the objects of a plan exist by listing their directories, and list the other
+    /// files found next to them
+    Check {
+        /// Plan file (json, toml, yaml or ndjson) to check
+        download_plan: PathBuf,
+    },
+    /// Write the items of a plan with their downloaded assets pointing at the local files, as an
+    /// ItemCollection, newline-delimited JSON (.ndjson) or stac-geoparquet (.parquet, requires the
+    /// geoparquet feature)
+    ExportItems {
+        /// Plan file (json, toml, yaml or ndjson) whose items to export
+        download_plan: PathBuf,
+
+        /// File to write the items to
+        output: PathBuf,
+
+        /// STAC ItemCollection json the plan was prepared from, instead of fetching the items
+        #[arg(long)]
+        items: Option<PathBuf>,
+    },
+    /// Print who prepared a plan and check its signature with the key in SLOW_STAC_PLAN_KEY
+    Verify {
+        /// Plan file (json, toml, yaml or ndjson) to verify
+        download_plan: PathBuf,
+    },
+    /// Encrypt a plan with a passphrase, writing it alongside with a .age extension
+    Encrypt {
+        /// Plan file (json, toml, yaml or ndjson) to encrypt
+        download_plan: PathBuf,
+    },
+    /// Decrypt a .age plan, writing it alongside without the extension
+    Decrypt {
+        /// Encrypted plan file, e.g. plan.json.age
+        download_plan: PathBuf,
+    },
+    /// Sign a URL for every task so the plan can be downloaded without credentials
+    Presign {
+        /// Plan file (json, toml, yaml or ndjson) to presign and rewrite
+        download_plan: PathBuf,
+
+        /// Hours the URLs stay valid, at most 168
+        #[arg(long, default_value_t = 168)]
+        expires_in_hours: u64,
+    },
+    /// Re-sign the URLs of a presigned plan that have expired or expire within the hour
+    Refresh {
+        /// Presigned plan file to refresh and rewrite
+        download_plan: PathBuf,
+
+        /// Hours the new URLs stay valid, at most 168
+        #[arg(long, default_value_t = 168)]
+        expires_in_hours: u64,
+    },
+    /// Pin each task to the current version of its object, so the plan downloads the same objects
+    /// even if they are republished before it runs
+    PinVersions {
+        /// Plan file (json, toml, yaml or ndjson) to pin and rewrite
+        download_plan: PathBuf,
+    },
+    /// Measure each source of the plan's objects and route the tasks to the fastest
+    Probe {
+        /// Plan file (json, toml, yaml or ndjson) to probe and rewrite
         download_plan: PathBuf,
     },
 }
@@ -41,47 +661,964 @@ enum Collection {
     CopSentinel2,
     /// Sentinel 2 Level 2A via Element84 Earth Search
     E84Sentinel2,
+    /// Sentinel 2 Level 1C via Element84 Earth Search (requester pays)
+    E84Sentinel2L1c,
+    /// Sentinel 2 Level 2A processed before Collection 1 via Element84 Earth Search
+    E84Sentinel2PreC1,
+    /// MODIS Terra Surface Reflectance (MOD09GA) via NASA Earthdata
+    EdMod09ga,
+    /// Landsat Collection 2 Level 2 via Microsoft Planetary Computer
+    PcLandsat,
+}
+
+impl Collection {
+    fn selection_id(self: &Self) -> &'static str {
+        match self {
+            Collection::CopSentinel2 => "copernicus.sentinel2level2a",
+            Collection::E84Sentinel2 => "element84.sentinel2collection1level2a",
+            Collection::E84Sentinel2L1c => "element84.sentinel2level1c",
+            Collection::E84Sentinel2PreC1 => "element84.sentinel2precollection1level2a",
+            Collection::EdMod09ga => "earthdata.mod09ga",
+            Collection::PcLandsat => "planetarycomputer.landsatc2l2",
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Exits with a code per failure kind (see `slow_stac::failure::FailureKind::exit_code`)
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    // Setting the proxy variables is only sound while this is the only thread
+    let environment = match &cli.env {
+        Some(name) => slow_stac::config::Config::path()
+            .and_then(slow_stac::config::Config::read)
+            .and_then(|config| config.environment(name)),
+        None => Ok(slow_stac::environment::Environment::default()),
+    };
+    if let Ok(environment) = &environment {
+        environment.use_proxy();
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("The runtime should always start")
+        .block_on(async_main(cli, environment))
+}
+
+async fn async_main(
+    cli: Cli,
+    environment: Result<slow_stac::environment::Environment>,
+) -> ExitCode {
+    let debug_log = match &cli.command {
+        Commands::Download {
+            debug_bundle: true, ..
+        } => match slow_stac::debug_bundle::start() {
+            Ok(path) => Some(path),
+            Err(e) => {
+                println!("Warning: could not collect a debug bundle: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+    slow_stac::logging::init(debug_log.as_deref());
+    slow_stac::porcelain::set_enabled(cli.porcelain);
+    // A config file that can't be read is reported by the commands that rely on it
+    let config = slow_stac::config::Config::path().and_then(slow_stac::config::Config::read);
+    if let Some(rate_unit) = config.ok().and_then(|config| config.rate_unit) {
+        slow_stac::units::set_rate_unit(rate_unit);
+    }
+
+    let result = tokio::select! {
+        result = run(&cli, environment) => result,
+        Ok(()) = tokio::signal::ctrl_c() => Err(slow_stac::failure::Cancelled.into()),
+    };
+    slow_stac::debug_bundle::clean_up();
+    let Err(error) = result else {
+        return ExitCode::SUCCESS;
+    };
+    if cli.json {
+        eprintln!("{}", slow_stac::failure::json_error(&error));
+    } else {
+        eprintln!("Error: {:?}", error);
+    }
+    ExitCode::from(slow_stac::failure::FailureKind::classify(&error).exit_code())
+}
 
+async fn run(cli: &Cli, environment: Result<slow_stac::environment::Environment>) -> Result<()> {
+    let environment = environment?;
     match &cli.command {
         Commands::Select {
             collection,
             output_dir,
+            offline,
         } => {
-            handle_select(collection, output_dir)?;
+            handle_select(collection, output_dir, *offline).await?;
         }
         Commands::Prepare {
             image_selection,
             output_dir,
+            preview,
+            items,
+            encrypt,
+            sign,
+            delta,
+            overviews_only,
+            jobs,
+            skip_unresolved,
+        } => {
+            handle_prepare(
+                image_selection,
+                output_dir,
+                *preview,
+                items.as_ref(),
+                *encrypt,
+                *sign,
+                *delta,
+                *overviews_only,
+                *jobs,
+                *skip_unresolved,
+            )
+            .await?;
+        }
+        Commands::Download {
+            download_plan,
+            output_dir,
+            profile,
+            save_profile,
+            jobs,
+            chunk_size,
+            parts,
+            parts_min_size,
+            nice,
+            nice_rate,
+            min_rate,
+            stall_window,
+            task_timeout,
+            timeout,
+            order,
+            on_item_complete,
+            item_webhook,
+            quicklook,
+            cog,
+            cog_compression,
+            stack,
+            stack_remove_bands,
+            upload,
+            upload_profile,
+            upload_remove_local,
+            rclone,
+            rclone_attempts,
+            rclone_remove_local,
+            cache,
+            peer,
+            multi_source,
+            window,
+            max_requests_per_minute,
+            max_bytes_per_day,
+            watch_selection,
+            yes,
+            tui,
+            control_socket,
+            push_state,
+            push_state_profile,
+            head_cache_ttl,
+            missing_length,
+            debug_bundle: _,
+            timeseries,
+            merge_datatakes,
+        } => {
+            if *tui && !cfg!(feature = "tui") {
+                return Err(anyhow!(
+                    "Cannot show the dashboard: slow-stac was built without the tui feature"
+                ));
+            }
+            if slow_stac::pause::PauseState::read(download_plan)?.is_some() {
+                return Err(slow_stac::pause::Paused(download_plan.clone()).into());
+            }
+            let config_path = slow_stac::config::Config::path()?;
+            let config = slow_stac::config::Config::read(&config_path)?;
+            if *save_profile {
+                let config = slow_stac::config::Config {
+                    profile: *profile,
+                    ..config.clone()
+                };
+                config.write(&config_path)?;
+                println!("Saved the default profile to {:?}", config_path);
+            }
+            // Options given on the command line take precedence over the environment, and the
+            // environment over the profile
+            let mut options = match profile.or(environment.profile).or(config.profile) {
+                Some(profile) => profile.options(),
+                None => slow_stac::download_plan::DownloadOptions::default(),
+            };
+            options = environment.apply(options);
+            if let Some(jobs) = jobs {
+                options.jobs = *jobs;
+            }
+            if chunk_size.is_some() {
+                options.chunk_size = *chunk_size;
+            }
+            options.parts = parts.map(|parts| {
+                slow_stac::multipart::Parts::new(parts).with_min_size(*parts_min_size)
+            });
+            if *nice {
+                options.pacing = slow_stac::download_plan::Pacing::nice(*nice_rate);
+                options.jobs = 1;
+            }
+            if min_rate.is_some() || stall_window.is_some() {
+                let threshold = options.stall.clone().unwrap_or_default();
+                options.stall = match min_rate.unwrap_or(threshold.min_bytes_per_sec) {
+                    0 => None,
+                    min_bytes_per_sec => Some(slow_stac::download_plan::StallThreshold {
+                        min_bytes_per_sec,
+                        window: stall_window
+                            .map(std::time::Duration::from_secs)
+                            .unwrap_or(threshold.window),
+                    }),
+                };
+            }
+            options.task_timeout = task_timeout.map(std::time::Duration::from_secs);
+            if let Some(ttl) = head_cache_ttl {
+                options.head_cache_ttl = std::time::Duration::from_secs(*ttl);
+            }
+            if let Some(missing_length) = missing_length {
+                options.missing_length = *missing_length;
+            }
+            options.timeseries = *timeseries;
+            options.merge_datatakes = *merge_datatakes;
+            if *merge_datatakes {
+                slow_stac::stack::check(&slow_stac::datatake::PROGRAMS).await?;
+            }
+            options.timeout = timeout.map(std::time::Duration::from_secs);
+            if let Some(order) = order {
+                options.order = *order;
+            }
+            options.item_hooks = on_item_complete
+                .iter()
+                .map(|command| slow_stac::hooks::ItemHook::Command(command.clone()))
+                .chain(
+                    item_webhook
+                        .iter()
+                        .map(|url| slow_stac::hooks::ItemHook::Webhook(url.clone())),
+                )
+                .chain(
+                    quicklook
+                        .iter()
+                        .map(|quicklook| slow_stac::hooks::ItemHook::Quicklook(*quicklook)),
+                )
+                .collect();
+            // Last, since it may remove the bands the other hooks read
+            if *stack {
+                slow_stac::stack::check(&slow_stac::stack::PROGRAMS).await?;
+                let mut stack = config.stack.clone().unwrap_or_default();
+                if *stack_remove_bands {
+                    stack.keep_bands = false;
+                }
+                options
+                    .item_hooks
+                    .push(slow_stac::hooks::ItemHook::Stack(stack));
+            }
+            // After the stack, so it is uploaded too
+            if let Some(destination) = upload {
+                let upload = slow_stac::upload::UploadOptions::new(destination, upload_profile)?
+                    .with_remove_local(*upload_remove_local);
+                options
+                    .item_hooks
+                    .push(slow_stac::hooks::ItemHook::Upload(upload));
+            }
+            if let Some(destination) = rclone {
+                let rclone = slow_stac::rclone::RcloneOptions::new(destination)?
+                    .with_attempts(*rclone_attempts)
+                    .with_remove_local(*rclone_remove_local);
+                rclone.check().await?;
+                options
+                    .item_hooks
+                    .push(slow_stac::hooks::ItemHook::Rclone(rclone));
+            }
+            if *cog {
+                let cog = slow_stac::cog::CogOptions {
+                    compression: cog_compression.clone(),
+                    ..Default::default()
+                };
+                cog.check().await?;
+                options.cog = Some(cog);
+            }
+            options.cache = cache.clone().or(config.cache_dir.clone());
+            let peers = match peer.is_empty() {
+                true => &config.peers,
+                false => peer,
+            };
+            let peer_token = slow_stac::peer::token(config.peer_token.as_deref());
+            options.peers =
+                std::sync::Arc::new(slow_stac::peer::Peers::new(peers, peer_token.as_deref()));
+            if let Some(destination) = push_state {
+                let plan_file = download_plan
+                    .file_name()
+                    .ok_or(anyhow!("No plan file name in {:?}", download_plan))?;
+                options.push_state = Some(slow_stac::checkpoint::PushState {
+                    remote: slow_stac::checkpoint::StateRemote::new(
+                        destination,
+                        push_state_profile,
+                    )?,
+                    plan_file: plan_file.to_string_lossy().to_string(),
+                    passphrase: match slow_stac::plan_crypt::is_encrypted(download_plan) {
+                        true => Some(slow_stac::plan_crypt::passphrase()?),
+                        false => None,
+                    },
+                });
+            }
+            if *multi_source && options.chunk_size.is_none() {
+                options.chunk_size = Some(slow_stac::multisource::DEFAULT_CHUNK_SIZE);
+            }
+            let quota = slow_stac::copernicus::quota::Quota {
+                requests_per_minute: max_requests_per_minute
+                    .or(environment.max_requests_per_minute),
+                bytes_per_day: max_bytes_per_day.or(environment.max_bytes_per_day),
+            };
+            let window = match window.is_empty() {
+                true => &environment.windows,
+                false => window,
+            };
+            // Rather than finding out once the download is over
+            if let Some(email) = &config.email {
+                email.check()?;
+            }
+            if !*yes {
+                confirm_download(download_plan, output_dir.as_ref(), &config)?;
+            }
+            let control =
+                (*tui || *control_socket).then(|| slow_stac::control::Control::new(options.jobs));
+            options.control = control.clone();
+            let socket = match (&control, *control_socket) {
+                (Some(control), true) => {
+                    if !download_plan.exists() {
+                        return Err(anyhow!("--control needs a local plan"));
+                    }
+                    let path = slow_stac::control_socket::socket_path(download_plan);
+                    let (listener, socket_file) = slow_stac::control_socket::bind(&path)?;
+                    println!("Listening for control commands on {:?}", path);
+                    let server = slow_stac::control_socket::serve(listener, control.clone());
+                    Some((socket_file, tokio::spawn(server)))
+                }
+                _ => None,
+            };
+            let download = async {
+                match watch_selection {
+                    Some(selection) => {
+                        handle_watched_download(
+                            selection,
+                            download_plan,
+                            output_dir.as_ref(),
+                            &quota,
+                            *multi_source,
+                            &options,
+                            window,
+                            config.email.as_ref(),
+                        )
+                        .await
+                    }
+                    None => {
+                        handle_scheduled_download(
+                            download_plan,
+                            output_dir.as_ref(),
+                            &quota,
+                            *multi_source,
+                            &options,
+                            window,
+                            config.email.as_ref(),
+                        )
+                        .await
+                    }
+                }
+            };
+            let stopping = control.clone();
+            let downloaded = match control {
+                Some(control) if *tui => {
+                    let remaining = remaining_bytes(download_plan, output_dir.as_ref())?;
+                    with_dashboard(download, control, remaining).await
+                }
+                _ => download.await,
+            };
+            if let Some((socket_file, server)) = socket {
+                server.abort();
+                drop(socket_file);
+            }
+            match downloaded {
+                Err(e) if e.is::<slow_stac::control::Stopped>() => {
+                    let not_before = stopping.and_then(|control| control.held_until());
+                    slow_stac::pause::PauseState::new(
+                        std::env::args().skip(1).collect(),
+                        not_before,
+                    )
+                    .with_working_directory(std::env::current_dir()?)
+                    .write(download_plan)?;
+                    println!(
+                        "Paused {:?}, continue with `slow-stac resume`",
+                        download_plan
+                    );
+                }
+                downloaded => downloaded?,
+            }
+        }
+        Commands::Pause { download_plan } => {
+            handle_pause(download_plan).await?;
+        }
+        Commands::Resume { download_plan } => {
+            handle_resume(download_plan).await?;
+        }
+        Commands::Control {
+            download_plan,
+            command,
+        } => {
+            let command = match command.join(" ").parse()? {
+                slow_stac::control_socket::Command::Status if slow_stac::porcelain::enabled() => {
+                    slow_stac::control_socket::Command::PorcelainStatus
+                }
+                command => command,
+            };
+            let path = slow_stac::control_socket::socket_path(download_plan);
+            println!(
+                "{}",
+                slow_stac::control_socket::send(&path, &command).await?
+            );
+        }
+        Commands::Translate {
+            image_selection,
+            collection,
+            output,
+        } => {
+            handle_translate(image_selection, collection, output).await?;
+        }
+        Commands::Queryables { collection } => {
+            handle_queryables(collection).await?;
+        }
+        Commands::Mirror {
+            source,
+            output_dir,
+            include,
+            exclude,
+            profile,
+            region,
+            endpoint_url,
+            jobs,
+        } => {
+            let config = match profile {
+                Some(profile) => slow_stac::provider_config::ProviderConfig::from_profile(profile),
+                None => slow_stac::provider_config::ProviderConfig::anonymous(region),
+            };
+            let config = slow_stac::provider_config::ProviderConfig {
+                path_style: config.path_style.or(endpoint_url.as_ref().map(|_| true)),
+                endpoint_url: endpoint_url.clone(),
+                ..config
+            };
+            let source = slow_stac::mirror::MirrorSource::new(source)?;
+            let filter = slow_stac::mirror::Filter::new(include, exclude)?;
+            let mut options = slow_stac::download_plan::DownloadOptions::default();
+            if let Some(jobs) = jobs {
+                options.jobs = *jobs;
+            }
+            let summary =
+                slow_stac::mirror::mirror(&source, &filter, output_dir, &config, &options).await?;
+            summary.print();
+            summary.into_result()?;
+        }
+        Commands::Speedtest {
+            collection,
+            samples,
+        } => {
+            handle_speedtest(collection, *samples).await?;
+        }
+        Commands::Inventory {
+            dir,
+            output,
+            no_checksums,
+        } => {
+            handle_inventory(dir, output.as_ref(), !*no_checksums)?;
+        }
+        Commands::Audit {
+            dir,
+            inventory,
+            plan,
+            recheck_after_days,
+            requeue,
+        } => {
+            handle_audit(
+                dir,
+                inventory.as_ref(),
+                plan.as_ref(),
+                *recheck_after_days,
+                *requeue,
+            )?;
+        }
+        Commands::Prune { dir, plan, delete } => {
+            let plans = plan
+                .iter()
+                .map(slow_stac::download_plan::DownloadPlan::read)
+                .collect::<Result<Vec<_>>>()?;
+            let superseded = slow_stac::prune::find_superseded(dir, &plans)?;
+            for item in &superseded {
+                match slow_stac::porcelain::enabled() {
+                    true => println!(
+                        "{}",
+                        slow_stac::porcelain::record(
+                            "superseded",
+                            &[&item.item_id, &item.bytes, &item.superseded_by],
+                        )
+                    ),
+                    false => println!(
+                        "{} ({}) is superseded by {}",
+                        item.item_id,
+                        slow_stac::units::size(item.bytes),
+                        item.superseded_by
+                    ),
+                }
+            }
+            match (superseded.is_empty(), *delete) {
+                (true, _) => println!("No superseded items"),
+                (false, true) => slow_stac::prune::prune(&superseded)?,
+                (false, false) => println!("Run again with --delete to delete them"),
+            }
+        }
+        Commands::Serve { plan, cache, bind } => {
+            let config = slow_stac::config::Config::read(&slow_stac::config::Config::path()?)?;
+            let mut shared = slow_stac::peer::Shared::default();
+            for plan in plan {
+                shared = shared.with_plan(&slow_stac::download_plan::DownloadPlan::read(plan)?);
+            }
+            let cache = cache.clone().or(config.cache_dir);
+            if let Some(cache) = cache {
+                shared = shared.with_cache(cache);
+            } else if plan.is_empty() {
+                return Err(anyhow!("Nothing to share, give --plan or --cache"));
+            }
+            if let Some(token) = slow_stac::peer::token(config.peer_token.as_deref()) {
+                shared = shared.with_token(&token);
+            }
+            let listener = tokio::net::TcpListener::bind(bind).await?;
+            println!("Sharing downloaded files on {}", listener.local_addr()?);
+            slow_stac::peer::serve(listener, shared).await?;
+        }
+        Commands::Mount {
+            download_plan,
+            mountpoint,
+            cache,
+        } => {
+            handle_mount(download_plan, mountpoint, cache.as_ref()).await?;
+        }
+        Commands::Cat {
+            collection,
+            item_id,
+            asset,
+        } => {
+            let stdout = redirect_stdout_to_stderr()?;
+            let mut writer = tokio::fs::File::from_std(std::fs::File::from(stdout));
+            let written =
+                slow_stac::cat_asset(collection.selection_id(), item_id, asset, &mut writer)
+                    .await?;
+            eprintln!("Wrote {} bytes of {} {}", written, item_id, asset);
+        }
+        Commands::Inspect {
+            collection,
+            item_id,
+            asset,
+            header_kb,
+        } => {
+            handle_inspect(collection, item_id, asset, *header_kb).await?;
+        }
+        Commands::InstallService {
+            plan_or_selection,
+            output_dir,
+            window,
+            profile,
+            nice,
+            name,
+            system,
+            print,
+            watch,
         } => {
-            handle_prepare(image_selection, output_dir).await?;
+            handle_install_service(
+                plan_or_selection,
+                output_dir.as_ref(),
+                window,
+                cli.env.as_ref(),
+                *profile,
+                *nice,
+                name.as_ref(),
+                *system,
+                *print,
+                *watch,
+            )
+            .await?;
         }
-        Commands::Download { download_plan } => {
-            handle_download(download_plan).await?;
+        Commands::Plan { command } => match command {
+            PlanCommands::Stats { download_plan } => {
+                handle_plan_stats(download_plan)?;
+            }
+            PlanCommands::List {
+                download_plan,
+                output_dir,
+                failed,
+                pending,
+            } => {
+                let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+                let plan = match output_dir {
+                    Some(output_dir) => plan.with_root(output_dir),
+                    None => plan,
+                };
+                let filter = slow_stac::task_list::TaskFilter {
+                    failed: *failed,
+                    pending: *pending,
+                };
+                match slow_stac::porcelain::enabled() {
+                    true => println!("{}", slow_stac::task_list::to_porcelain(&plan, filter)),
+                    false => {
+                        use std::io::IsTerminal;
+                        let color = std::io::stdout().is_terminal();
+                        println!("{}", slow_stac::task_list::to_table(&plan, filter, color));
+                    }
+                }
+            }
+            PlanCommands::PullState {
+                source,
+                output_dir,
+                profile,
+            } => {
+                let remote = slow_stac::checkpoint::StateRemote::new(source, profile)?;
+                let state = slow_stac::checkpoint::pull(&remote).await?;
+                let lost = state.done.len();
+                println!(
+                    "Pulled the state saved at {} (Unix time): {} files uploaded, {} skipped items",
+                    state.saved_at,
+                    state.uploaded.len(),
+                    state.skipped_items.len()
+                );
+                std::fs::create_dir_all(output_dir)?;
+                let path = state.restore(output_dir)?;
+                if lost > 0 {
+                    println!(
+                        "Warning: {} files were only on the old machine, they download again",
+                        lost
+                    );
+                }
+                println!("Wrote {:?}, download it to continue", path);
+            }
+            PlanCommands::Skip {
+                download_plan,
+                items,
+            } => {
+                let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+                for item in items {
+                    if !plan.tasks().iter().any(|task| &task.item() == item) {
+                        println!("Warning: {} is not an item of the plan", item);
+                    }
+                }
+                let skip_list = slow_stac::skip_list::SkipList::of_plan(download_plan);
+                let added = skip_list.add(items)?;
+                println!("Added {} items to {:?}", added, skip_list.path());
+            }
+            PlanCommands::Diff { before, after } => {
+                let before = slow_stac::download_plan::DownloadPlan::read(before)?;
+                let after = slow_stac::download_plan::DownloadPlan::read(after)?;
+                slow_stac::plan_diff::diff(&before, &after).print();
+            }
+            PlanCommands::Partials {
+                download_plan,
+                output_dir,
+            } => {
+                let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+                let plan = match output_dir {
+                    Some(output_dir) => plan.with_root(output_dir),
+                    None => plan,
+                };
+                plan.check_partials()?;
+            }
+            PlanCommands::Probe { download_plan } => {
+                handle_plan_probe(download_plan).await?;
+            }
+            PlanCommands::PinVersions { download_plan } => {
+                let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+                let provider =
+                    slow_stac::provider::CollectionProvider::new(&plan.selection_id).await?;
+                let (plan, pinned) = plan.pinned(&provider).await?;
+                println!("Pinned {} of {} tasks", pinned, plan.task_count());
+                plan.write(download_plan)?;
+            }
+            PlanCommands::Presign {
+                download_plan,
+                expires_in_hours,
+            } => {
+                handle_plan_presign(download_plan, *expires_in_hours, false).await?;
+            }
+            PlanCommands::Refresh {
+                download_plan,
+                expires_in_hours,
+            } => {
+                handle_plan_presign(download_plan, *expires_in_hours, true).await?;
+            }
+            PlanCommands::Check { download_plan } => {
+                handle_plan_check(download_plan).await?;
+            }
+            PlanCommands::ExportItems {
+                download_plan,
+                output,
+                items,
+            } => {
+                handle_export_items(download_plan, output, items.as_ref()).await?;
+            }
+            PlanCommands::Verify { download_plan } => {
+                let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+                if let Some(provenance) = plan.provenance() {
+                    provenance.print();
+                }
+                let key = slow_stac::provenance::signing_key()
+                    .ok_or(anyhow!("Set SLOW_STAC_PLAN_KEY to verify the plan"))?;
+                plan.verify_signature(&key)?;
+                println!("Signature is valid");
+            }
+            PlanCommands::Encrypt { download_plan } => {
+                let output = slow_stac::plan_crypt::encrypted_path(download_plan);
+                handle_plan_rewrite(download_plan, &output)?;
+            }
+            PlanCommands::Decrypt { download_plan } => {
+                if !slow_stac::plan_crypt::is_encrypted(download_plan) {
+                    return Err(anyhow!("{:?} is not an encrypted plan", download_plan));
+                }
+                let output = slow_stac::plan_crypt::inner_path(download_plan);
+                handle_plan_rewrite(download_plan, &output)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+async fn handle_pause(download_plan: &PathBuf) -> Result<()> {
+    if slow_stac::pause::PauseState::read(download_plan)?.is_some() {
+        println!("{:?} is already paused", download_plan);
+        return Ok(());
+    }
+    let socket = slow_stac::control_socket::socket_path(download_plan);
+    let stop = slow_stac::control_socket::Command::Stop;
+    match slow_stac::control_socket::send(&socket, &stop).await {
+        // The download records the pause once its tasks have stopped
+        Ok(reply) => println!("{}", reply),
+        Err(_) => {
+            slow_stac::pause::PauseState::new(vec![], None).write(download_plan)?;
+            println!(
+                "No download of {:?} with --control is running, it won't start until resumed",
+                download_plan
+            );
         }
     }
     Ok(())
 }
 
-fn handle_select(collection: &Collection, output_dir: &PathBuf) -> Result<()> {
-    let (template, filename) = match collection {
+async fn handle_resume(download_plan: &PathBuf) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let state = slow_stac::pause::PauseState::read(download_plan)?
+        .ok_or(anyhow!("{:?} is not paused", download_plan))?;
+    let wait = state.wait();
+    if !wait.is_zero() {
+        println!(
+            "Waiting {} for the backoff a host asked for",
+            slow_stac::units::duration(wait)
+        );
+        tokio::time::sleep(wait).await;
+    }
+    slow_stac::pause::PauseState::remove(download_plan)?;
+    if state.args.is_empty() {
+        println!("Resumed {:?}, download it to continue", download_plan);
+        return Ok(());
+    }
+    println!("Resuming: slow-stac {}", state.args.join(" "));
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command.args(&state.args);
+    if let Some(working_directory) = &state.working_directory {
+        command.current_dir(working_directory);
+    }
+    // Replaces this process, so only returns if the download could not be started
+    let error = command.exec();
+    Err(error.into())
+}
+
+/// Bytes left to download in a local plan
+fn remaining_bytes(download_plan: &PathBuf, output_dir: Option<&PathBuf>) -> Result<Option<u64>> {
+    if !download_plan.exists() {
+        return Ok(None);
+    }
+    let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+    let plan = match output_dir {
+        Some(output_dir) => plan.with_root(output_dir),
+        None => plan,
+    };
+    Ok(Some(plan.remaining().bytes))
+}
+
+/// Run the download with its dashboard on the terminal, printing to a log file meanwhile. Quitting
+/// the dashboard cancels the download.
+#[cfg(feature = "tui")]
+async fn with_dashboard(
+    download: impl std::future::Future<Output = Result<()>>,
+    control: std::sync::Arc<slow_stac::control::Control>,
+    remaining: Option<u64>,
+) -> Result<()> {
+    use std::io::Write;
+    use std::os::fd::{AsFd, AsRawFd};
+
+    let log_path = std::env::temp_dir().join(format!("slow-stac-{}.log", std::process::id()));
+    let log = std::fs::File::create(&log_path)?;
+    std::io::stdout().flush()?;
+    let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+    let stderr = std::io::stderr().as_fd().try_clone_to_owned()?;
+    let redirect = |from: i32, to: i32| -> Result<()> {
+        // SAFETY: dup2 only replaces the process's own descriptor with a copy of another
+        match unsafe { libc::dup2(from, to) } {
+            result if result < 0 => Err(std::io::Error::last_os_error().into()),
+            _ => Ok(()),
+        }
+    };
+    redirect(log.as_raw_fd(), libc::STDOUT_FILENO)?;
+    redirect(log.as_raw_fd(), libc::STDERR_FILENO)?;
+
+    let terminal = std::fs::File::from(stdout.try_clone()?);
+    let downloaded = match slow_stac::tui::Tui::start(control, terminal, remaining) {
+        Ok(mut dashboard) => {
+            let downloaded = tokio::select! {
+                downloaded = download => downloaded,
+                closed = dashboard.closed() => {
+                    closed.and(Err(slow_stac::failure::Cancelled.into()))
+                }
+            };
+            dashboard.stop().await.and(downloaded)
+        }
+        Err(e) => Err(e),
+    };
+
+    std::io::stdout().flush()?;
+    redirect(stdout.as_raw_fd(), libc::STDOUT_FILENO)?;
+    redirect(stderr.as_raw_fd(), libc::STDERR_FILENO)?;
+    println!(
+        "Messages printed while downloading were written to {:?}",
+        log_path
+    );
+    downloaded
+}
+
+#[cfg(not(feature = "tui"))]
+async fn with_dashboard(
+    download: impl std::future::Future<Output = Result<()>>,
+    _: std::sync::Arc<slow_stac::control::Control>,
+    _: Option<u64>,
+) -> Result<()> {
+    // --tui is refused before getting here
+    download.await
+}
+
+#[cfg(feature = "fuse")]
+async fn handle_mount(
+    download_plan: &PathBuf,
+    mountpoint: &PathBuf,
+    cache: Option<&PathBuf>,
+) -> Result<()> {
+    let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+    let cache = cache.cloned().unwrap_or(PathBuf::from(format!(
+        "{}.blocks",
+        download_plan.to_string_lossy()
+    )));
+    println!(
+        "Mounting {} files of {:?} on {:?}, caching blocks read in {:?}",
+        plan.tasks().len(),
+        download_plan,
+        mountpoint,
+        cache
+    );
+    slow_stac::mount::mount(&plan, &cache, mountpoint).await
+}
+
+#[cfg(not(feature = "fuse"))]
+async fn handle_mount(_: &PathBuf, _: &PathBuf, _: Option<&PathBuf>) -> Result<()> {
+    Err(anyhow!(
+        "Cannot mount the plan: slow-stac was built without the fuse feature"
+    ))
+}
+
+/// Point stdout at stderr, so messages printed while resolving and downloading an asset don't mix
+/// with it, and return the original stdout to write the asset to.
+fn redirect_stdout_to_stderr() -> Result<std::os::fd::OwnedFd> {
+    use std::io::Write;
+    use std::os::fd::AsFd;
+    std::io::stdout().flush()?;
+    let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+    // SAFETY: dup2 only replaces the process's own stdout descriptor with a copy of stderr
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(stdout)
+}
+
+async fn handle_select(collection: &Collection, output_dir: &PathBuf, offline: bool) -> Result<()> {
+    let (selection, filename) = match collection {
         Collection::CopSentinel2 => {
             let template = slow_stac::copernicus::sentinel2level2a::image_selection_toml();
             let filename = "cop_sentinel2_selection.toml";
-            (template, filename)
+            (
+                slow_stac::image_selection::ImageSelection::from_template(&template),
+                filename,
+            )
         }
         Collection::E84Sentinel2 => {
-            let template =
-                slow_stac::element84::sentinel2collection1level2a::image_selection_toml();
+            let selection = match offline {
+                true => slow_stac::image_selection::ImageSelection::from_template(
+                    &slow_stac::element84::sentinel2collection1level2a::image_selection_toml(),
+                ),
+                false => slow_stac::element84::sentinel2collection1level2a::image_selection().await,
+            };
             let filename = "cop_sentinel2_selection.toml";
-            (template, filename)
+            (selection, filename)
+        }
+        Collection::E84Sentinel2L1c => {
+            let selection = match offline {
+                true => slow_stac::image_selection::ImageSelection::from_template(
+                    &slow_stac::element84::sentinel2level1c::image_selection_toml(),
+                ),
+                false => slow_stac::element84::sentinel2level1c::image_selection().await,
+            };
+            let filename = "e84_sentinel2_l1c_selection.toml";
+            (selection, filename)
+        }
+        Collection::E84Sentinel2PreC1 => {
+            let selection = match offline {
+                true => slow_stac::image_selection::ImageSelection::from_template(
+                    &slow_stac::element84::sentinel2precollection1level2a::image_selection_toml(),
+                ),
+                false => {
+                    slow_stac::element84::sentinel2precollection1level2a::image_selection().await
+                }
+            };
+            let filename = "e84_sentinel2_pre_c1_selection.toml";
+            (selection, filename)
+        }
+        Collection::EdMod09ga => {
+            let selection = match offline {
+                true => slow_stac::image_selection::ImageSelection::from_template(
+                    &slow_stac::earthdata::mod09ga::image_selection_toml(),
+                ),
+                false => slow_stac::earthdata::mod09ga::image_selection().await,
+            };
+            let filename = "ed_mod09ga_selection.toml";
+            (selection, filename)
+        }
+        Collection::PcLandsat => {
+            let selection = match offline {
+                true => slow_stac::image_selection::ImageSelection::from_template(
+                    &slow_stac::planetary_computer::landsat_c2_l2::image_selection_toml(),
+                ),
+                false => slow_stac::planetary_computer::landsat_c2_l2::image_selection().await,
+            };
+            let filename = "pc_landsat_selection.toml";
+            (selection, filename)
         }
     };
-    let selection = slow_stac::image_selection::ImageSelection::from_template(&template);
     let path = output_dir.join(filename);
     if path.exists() {
         return Err(anyhow!("File already exists {:?}", path));
@@ -91,56 +1628,1138 @@ fn handle_select(collection: &Collection, output_dir: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-async fn handle_prepare(image_selection: &PathBuf, output_dir: &PathBuf) -> Result<()> {
+async fn handle_prepare(
+    image_selection: &PathBuf,
+    output_dir: &PathBuf,
+    preview: bool,
+    items: Option<&PathBuf>,
+    encrypt: bool,
+    sign: bool,
+    delta: bool,
+    overviews_only: bool,
+    jobs: usize,
+    skip_unresolved: bool,
+) -> Result<Option<PathBuf>> {
+    let signing_key = match sign {
+        true => Some(
+            slow_stac::provenance::signing_key()
+                .ok_or(anyhow!("Set SLOW_STAC_PLAN_KEY to sign the plan"))?,
+        ),
+        false => None,
+    };
     if !output_dir.exists() {
         return Err(anyhow!("Directory does not exist {:?}", output_dir));
     }
-    let selection = slow_stac::image_selection::ImageSelection::read(image_selection)
+    let mut selection = slow_stac::image_selection::ImageSelection::read(image_selection)
         .with_context(|| anyhow!("Could not parse the provided file"))?;
+    if overviews_only {
+        selection = selection.with_overviews_only();
+    }
+    let items = match items {
+        Some(path) => Some(slow_stac::items::read_items(path)?),
+        None => None,
+    };
+    // Items resolved so far are kept here until the plan is written
+    let journal = slow_stac::prepare::journal_path(output_dir, &selection.id);
+    let mut prepare_options = slow_stac::prepare::PrepareOptions::default()
+        .with_jobs(jobs)
+        .with_journal(journal.clone());
+    if skip_unresolved {
+        prepare_options =
+            prepare_options.with_unresolved(output_dir.join(slow_stac::prepare::UNRESOLVED_FILE));
+    }
     let (plan, filename) = match selection.id.as_str() {
         "copernicus.sentinel2level2a" => {
-            let provider = slow_stac::copernicus::Provider::from_profile("copernicus").await;
-            let plan = slow_stac::copernicus::sentinel2level2a::generate_download_plan(
-                &provider,
-                &selection,
-                output_dir.clone(),
-            )
-            .await?;
+            let provider = slow_stac::copernicus::Provider::configured().await?;
+            let plan = match items {
+                Some(items) => {
+                    slow_stac::copernicus::sentinel2level2a::generate_download_plan_from_items(
+                        &provider,
+                        &selection,
+                        items,
+                        output_dir.clone(),
+                        &prepare_options,
+                    )
+                    .await?
+                }
+                None => {
+                    slow_stac::copernicus::sentinel2level2a::generate_download_plan_with(
+                        &provider,
+                        &selection,
+                        output_dir.clone(),
+                        &prepare_options,
+                    )
+                    .await?
+                }
+            };
             let filename = "cop_sentinel2_download_plan.json";
             (plan, filename)
         }
         "element84.sentinel2collection1level2a" => {
-            let plan = slow_stac::element84::sentinel2collection1level2a::generate_download_plan(
-                &selection,
-                output_dir.clone(),
-            )
-            .await?;
+            let plan = match items {
+                Some(items) => {
+                    slow_stac::element84::sentinel2collection1level2a::generate_download_plan_from_items(
+                        &selection,
+                        &items,
+                        output_dir.clone(),
+                    )?
+                }
+                None => {
+                    slow_stac::element84::sentinel2collection1level2a::generate_download_plan_with(
+                        &selection,
+                        output_dir.clone(),
+                        &prepare_options,
+                    )
+                    .await?
+                }
+            };
             let filename = "e84_sentinel2_download_plan.json";
             (plan, filename)
         }
+        "element84.sentinel2level1c" => {
+            let plan = match items {
+                Some(items) => {
+                    slow_stac::element84::sentinel2level1c::generate_download_plan_from_items(
+                        &selection,
+                        &items,
+                        output_dir.clone(),
+                    )?
+                }
+                None => {
+                    slow_stac::element84::sentinel2level1c::generate_download_plan_with(
+                        &selection,
+                        output_dir.clone(),
+                        &prepare_options,
+                    )
+                    .await?
+                }
+            };
+            let filename = "e84_sentinel2_l1c_download_plan.json";
+            (plan, filename)
+        }
+        "element84.sentinel2precollection1level2a" => {
+            let plan = match items {
+                Some(items) => {
+                    slow_stac::element84::sentinel2precollection1level2a::generate_download_plan_from_items(
+                        &selection,
+                        &items,
+                        output_dir.clone(),
+                    )?
+                }
+                None => {
+                    slow_stac::element84::sentinel2precollection1level2a::generate_download_plan_with(
+                        &selection,
+                        output_dir.clone(),
+                        &prepare_options,
+                    )
+                    .await?
+                }
+            };
+            let filename = "e84_sentinel2_pre_c1_download_plan.json";
+            (plan, filename)
+        }
+        "earthdata.mod09ga" => {
+            let plan = match items {
+                Some(items) => slow_stac::earthdata::mod09ga::generate_download_plan_from_items(
+                    &selection,
+                    &items,
+                    output_dir.clone(),
+                )?,
+                None => {
+                    slow_stac::earthdata::mod09ga::generate_download_plan_with(
+                        &selection,
+                        output_dir.clone(),
+                        &prepare_options,
+                    )
+                    .await?
+                }
+            };
+            let filename = "ed_mod09ga_download_plan.json";
+            (plan, filename)
+        }
+        "planetarycomputer.landsatc2l2" => {
+            let plan = match items {
+                Some(items) => {
+                    slow_stac::planetary_computer::landsat_c2_l2::generate_download_plan_from_items(
+                        &selection,
+                        &items,
+                        output_dir.clone(),
+                    )?
+                }
+                None => {
+                    slow_stac::planetary_computer::landsat_c2_l2::generate_download_plan_with(
+                        &selection,
+                        output_dir.clone(),
+                        &prepare_options,
+                    )
+                    .await?
+                }
+            };
+            let filename = "pc_landsat_download_plan.json";
+            (plan, filename)
+        }
         _ => return Err(anyhow!("Unknown id: {}", selection.id)),
     };
-    let path = output_dir.join(filename);
-    if path.exists() {
-        return Err(anyhow!("File already exists {:?}", path));
+    let (plan, filename, outdated) = match delta {
+        true => {
+            let (plan, outdated) = slow_stac::delta::delta(plan)?;
+            (plan, format!("delta_{}", filename), outdated)
+        }
+        false => (plan, filename.to_string(), vec![]),
+    };
+    let path = match encrypt {
+        true => slow_stac::plan_crypt::encrypted_path(&output_dir.join(&filename)),
+        false => output_dir.join(&filename),
+    };
+    // Re-preparing updates the plan already there, keeping the tasks that have downloaded
+    let plan = match path.exists() {
+        true => {
+            let existing = slow_stac::download_plan::DownloadPlan::read(&path)?;
+            let (plan, diff) = slow_stac::plan_diff::reconcile(&existing, plan)?;
+            // Removed tasks are listed too, for scripts to clean up after
+            match slow_stac::porcelain::enabled() {
+                true => println!("{}", diff.to_porcelain()),
+                false => println!("Updating {:?}\n{}\n", path, diff.to_text()),
+            }
+            if !diff.removed.is_empty() && !slow_stac::porcelain::enabled() {
+                println!(
+                    "Warning: {} tasks are no longer selected and were removed from the plan, \
+                     files they already downloaded are kept",
+                    diff.removed.len()
+                );
+            }
+            plan
+        }
+        false => plan,
+    };
+    plan.print_preview();
+    if preview {
+        return Ok(None);
+    }
+    if let Some(max_total_bytes) = selection.max_total_bytes {
+        plan.check_size_limit(max_total_bytes)?;
+    }
+    let plan = plan.with_provenance(slow_stac::provenance::Provenance::new(&std::fs::read(
+        image_selection,
+    )?));
+    let aoi_bbox = match &selection.search {
+        Some(search) => search.search_bbox()?,
+        None => None,
+    };
+    let plan = plan.with_aoi_bbox(aoi_bbox);
+    let plan = match signing_key {
+        Some(key) => plan.with_signature(&key)?,
+        None => plan,
+    };
+    if !encrypt && plan.has_credentials() {
+        println!("Warning: the plan contains signed URLs, consider writing it with --encrypt");
     }
     plan.write(&path)?;
     println!("Wrote download plan file to {:?}", &path);
-    Ok(())
+    slow_stac::prepare::remove_journal(&journal)?;
+    slow_stac::delta::set_aside(&outdated)?;
+    Ok(Some(path))
 }
 
-async fn handle_download(download_plan: &PathBuf) -> Result<()> {
+/// Ask before downloading a plan with more than `confirm_above_bytes` left, with the number of
+/// files and an estimate at the throughput `speedtest` last measured. Remote plans, and runs
+/// without a terminal to answer from such as services, aren't asked about.
+fn confirm_download(
+    download_plan: &PathBuf,
+    output_dir: Option<&PathBuf>,
+    config: &slow_stac::config::Config,
+) -> Result<()> {
+    use std::io::{IsTerminal, Write};
+
+    let threshold = config
+        .confirm_above_bytes
+        .unwrap_or(slow_stac::config::DEFAULT_CONFIRM_ABOVE_BYTES);
+    if threshold == 0 || !download_plan.exists() {
+        return Ok(());
+    }
     let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
-    match plan.selection_id.as_str() {
+    let plan = match output_dir {
+        Some(output_dir) => plan.with_root(output_dir),
+        None => plan,
+    };
+    let remaining = plan.remaining();
+    if remaining.bytes <= threshold {
+        return Ok(());
+    }
+    let mut summary = format!(
+        "{} files ({}) are left to download",
+        remaining.files,
+        slow_stac::units::size(remaining.bytes)
+    );
+    if remaining.unknown > 0 {
+        summary += &format!(", {} more of unknown size", remaining.unknown);
+    }
+    if let Some(recommendation) = config.recommendation(&plan.selection_id) {
+        let bytes_per_sec = recommendation.bytes_per_sec as f64;
+        summary += &format!(
+            ", about {} at the last measured {}",
+            slow_stac::units::eta(remaining.bytes, bytes_per_sec),
+            slow_stac::units::rate(bytes_per_sec)
+        );
+    }
+    if !std::io::stdin().is_terminal() {
+        println!("{}", summary);
+        return Ok(());
+    }
+    print!("{}. Continue? [y/N] ", summary);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(slow_stac::failure::Cancelled.into()),
+    }
+}
+
+/// Download a plan, only during `windows` if any are given: waiting for the next to open and
+/// stopping when it closes.
+async fn handle_scheduled_download(
+    download_plan: &PathBuf,
+    output_dir: Option<&PathBuf>,
+    quota: &slow_stac::copernicus::quota::Quota,
+    multi_source: bool,
+    options: &slow_stac::download_plan::DownloadOptions,
+    windows: &[slow_stac::schedule::Window],
+    email: Option<&slow_stac::notify::EmailConfig>,
+) -> Result<()> {
+    if windows.is_empty() {
+        return handle_download(
+            download_plan,
+            output_dir,
+            quota,
+            multi_source,
+            options,
+            email,
+        )
+        .await;
+    }
+    loop {
+        let now = slow_stac::schedule::time_of_day();
+        let (wait, open_for) =
+            slow_stac::schedule::next_window(windows, now).ok_or(anyhow!("No download window"))?;
+        if !wait.is_zero() {
+            println!(
+                "Waiting {} for the next download window",
+                slow_stac::units::duration(wait)
+            );
+            tokio::time::sleep(wait).await;
+        }
+        // The plan stops when the window closes, unless --timeout stops it first
+        let closes = options.timeout.map_or(true, |timeout| open_for < timeout);
+        let mut window_options = options.clone();
+        window_options.timeout = Some(options.timeout.unwrap_or(open_for).min(open_for));
+        match handle_download(
+            download_plan,
+            output_dir,
+            quota,
+            multi_source,
+            &window_options,
+            email,
+        )
+        .await
+        {
+            Err(e) if closes && e.is::<slow_stac::download_plan::TimedOut>() => {
+                println!("Download window closed, resuming in the next one");
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Download a plan, preparing it again from `selection` whenever the selection is edited and
+/// carrying on with the updated plan. Partial downloads interrupted by an edit are resumed. Once
+/// the plan is complete it waits for the next edit, so it runs until stopped.
+async fn handle_watched_download(
+    selection: &PathBuf,
+    download_plan: &PathBuf,
+    output_dir: Option<&PathBuf>,
+    quota: &slow_stac::copernicus::quota::Quota,
+    multi_source: bool,
+    options: &slow_stac::download_plan::DownloadOptions,
+    windows: &[slow_stac::schedule::Window],
+    email: Option<&slow_stac::notify::EmailConfig>,
+) -> Result<()> {
+    #[cfg(not(target_os = "linux"))]
+    return Err(anyhow!("--watch-selection is only supported on Linux"));
+
+    #[cfg(target_os = "linux")]
+    {
+        // The plan is prepared again where it was prepared, re-rooted by --output-dir if given
+        let plan_dir = std::fs::canonicalize(download_plan)?
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .ok_or(anyhow!("No directory for {:?}", download_plan))?;
+        let mut watcher = slow_stac::watch::SelectionWatcher::new(selection)?;
+        let mut download_plan = download_plan.clone();
+        let mut complete = false;
+        loop {
+            let changed = match complete {
+                true => {
+                    println!("Plan complete, waiting for {:?} to be edited", selection);
+                    watcher.changed().await
+                }
+                false => tokio::select! {
+                    downloaded = handle_scheduled_download(
+                        &download_plan,
+                        output_dir,
+                        quota,
+                        multi_source,
+                        options,
+                        windows,
+                        email,
+                    ) => {
+                        downloaded?;
+                        complete = true;
+                        continue;
+                    }
+                    changed = watcher.changed() => changed,
+                },
+            };
+            changed?;
+            println!("{:?} was edited, preparing it again", selection);
+            let prepared = handle_prepare(
+                selection,
+                &plan_dir,
+                false,
+                None,
+                slow_stac::plan_crypt::is_encrypted(&download_plan),
+                slow_stac::provenance::signing_key().is_some(),
+                false,
+                false,
+                slow_stac::prepare::DEFAULT_JOBS,
+                false,
+            )
+            .await;
+            match prepared {
+                Ok(Some(path)) => {
+                    download_plan = path;
+                    complete = false;
+                }
+                Ok(None) => {}
+                // Likely a mistake in the edit, which the next edit fixes
+                Err(e) => println!(
+                    "Warning: could not prepare {:?}, carrying on with the current plan: {:#}",
+                    selection, e
+                ),
+            }
+        }
+    }
+}
+
+async fn handle_download(
+    download_plan: &PathBuf,
+    output_dir: Option<&PathBuf>,
+    quota: &slow_stac::copernicus::quota::Quota,
+    multi_source: bool,
+    options: &slow_stac::download_plan::DownloadOptions,
+    email: Option<&slow_stac::notify::EmailConfig>,
+) -> Result<()> {
+    // Remote plans are saved to the output directory, or the working directory without one
+    let download_plan = match download_plan
+        .to_str()
+        .filter(|p| p.starts_with("https://") || p.starts_with("http://"))
+    {
+        Some(url) => {
+            let dir = output_dir.cloned().unwrap_or(PathBuf::from("."));
+            slow_stac::download_plan::fetch_plan(url, &dir).await?
+        }
+        None => download_plan.clone(),
+    };
+    let mut plan = slow_stac::download_plan::DownloadPlan::read(&download_plan)?;
+    // Signed plans are checked before re-rooting, which is a local change
+    match (slow_stac::provenance::signing_key(), plan.is_signed()) {
+        (Some(key), true) => plan.verify_signature(&key)?,
+        (Some(_), false) => {
+            return Err(anyhow!(
+                "{:?} is not signed, unset SLOW_STAC_PLAN_KEY to download it",
+                download_plan
+            ))
+        }
+        (None, true) => {
+            println!("Warning: set SLOW_STAC_PLAN_KEY to verify the plan's signature")
+        }
+        (None, false) => {}
+    }
+    if let Some(output_dir) = output_dir {
+        if plan.root().is_none() {
+            println!("Warning: plan has no output root; absolute task outputs are not re-rooted");
+        }
+        plan = plan.with_root(output_dir);
+    }
+    let options = &slow_stac::download_plan::DownloadOptions {
+        skip_list: Some(slow_stac::skip_list::SkipList::of_plan(&download_plan)),
+        ..options.clone()
+    };
+    let mirrored = plan.selection_id == "copernicus.sentinel2level2a" && plan.source().is_none();
+    if multi_source && !mirrored {
+        println!("Warning: only unrouted Copernicus plans have a mirror, ignoring --multi-source");
+    }
+    let summary = match plan.selection_id.as_str() {
+        _ if plan.source() == Some(slow_stac::presign::SOURCE) => {
+            let provider = slow_stac::presign::Provider::from_plan(&plan)?;
+            let provider = slow_stac::backoff::Throttled::new(provider)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            plan.execute_summarized(&provider, options).await
+        }
+        "copernicus.sentinel2level2a"
+            if plan.source() == Some(slow_stac::copernicus::gcs_mirror::SOURCE) =>
+        {
+            let provider = slow_stac::gcs::Provider::from_env()?;
+            let provider = slow_stac::backoff::Throttled::new(provider)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            plan.execute_summarized(&provider, options).await
+        }
+        "copernicus.sentinel2level2a" if multi_source => {
+            let provider = slow_stac::copernicus::Provider::configured()
+                .await?
+                .with_quota(quota);
+            let provider = slow_stac::backoff::Throttled::new(provider)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            let mirror = slow_stac::backoff::Throttled::new(slow_stac::gcs::Provider::from_env()?)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            let provider = slow_stac::multisource::MultiSource::new(
+                provider,
+                mirror,
+                slow_stac::copernicus::gcs_mirror::locate,
+            )
+            .with_stall(options.stall.clone());
+            plan.execute_summarized(&provider, options).await
+        }
         "copernicus.sentinel2level2a" => {
-            let provider = slow_stac::copernicus::Provider::from_profile("copernicus").await;
-            plan.execute(&provider).await?;
+            let provider = slow_stac::copernicus::Provider::configured()
+                .await?
+                .with_quota(quota);
+            let provider = slow_stac::backoff::Throttled::new(provider)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            plan.execute_summarized(&provider, options).await
         }
-        "element84.sentinel2collection1level2a" => {
-            let provider = slow_stac::element84::Provider::as_anon().await;
-            plan.execute(&provider).await?;
+        "element84.sentinel2collection1level2a" | "element84.sentinel2precollection1level2a" => {
+            let provider = slow_stac::element84::Provider::configured().await?;
+            let provider = slow_stac::backoff::Throttled::new(provider)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            plan.execute_summarized(&provider, options).await
+        }
+        "element84.sentinel2level1c" => {
+            let provider = slow_stac::element84::Provider::configured_as(
+                slow_stac::provider_config::ELEMENT84_L1C,
+            )
+            .await?;
+            let provider = slow_stac::backoff::Throttled::new(provider)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            plan.execute_summarized(&provider, options).await
+        }
+        "earthdata.mod09ga" => {
+            let provider = slow_stac::earthdata::Provider::from_env().await?;
+            let provider = slow_stac::backoff::Throttled::new(provider)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            plan.execute_summarized(&provider, options).await
+        }
+        "planetarycomputer.landsatc2l2" => {
+            let provider = slow_stac::planetary_computer::Provider::from_env();
+            let provider = slow_stac::backoff::Throttled::new(provider)
+                .with_max_retries(options.max_retries)
+                .with_control(options.control.clone());
+            plan.execute_summarized(&provider, options).await
         }
         _ => return Err(anyhow!("Unknown id: {}", plan.selection_id)),
     };
+    summary.print();
+    let unexplained = summary
+        .failures
+        .iter()
+        .any(|(_, failure)| *failure == slow_stac::failure::TaskFailure::Other);
+    if slow_stac::debug_bundle::started() {
+        match slow_stac::debug_bundle::write(&plan, &summary).await {
+            Ok(path) => println!(
+                "Wrote a debug bundle to attach to a bug report to {:?}",
+                path
+            ),
+            Err(e) => println!("Warning: could not write the debug bundle: {}", e),
+        }
+    } else if unexplained {
+        println!("Run the download again with --debug-bundle to collect logs for a bug report");
+    }
+    if let Some(email) = email {
+        let plan_name = download_plan
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or(plan.selection_id.clone());
+        if let Err(e) = slow_stac::notify::send_summary(email, &plan_name, &summary).await {
+            println!("Warning: could not email the summary: {}", e);
+        }
+    }
+    summary.into_result()?;
+    Ok(())
+}
+
+async fn handle_export_items(
+    download_plan: &PathBuf,
+    output: &PathBuf,
+    items: Option<&PathBuf>,
+) -> Result<()> {
+    let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+    let mut items = match items {
+        Some(path) => {
+            let planned: std::collections::HashSet<_> =
+                plan.tasks().iter().map(|task| task.item()).collect();
+            let mut items = slow_stac::items::read_items(path)?;
+            items.retain(|item| planned.contains(&item.id));
+            items
+        }
+        None => slow_stac::item_export::fetch_items(&plan).await?,
+    };
+    let mut localized = 0;
+    for item in &mut items {
+        localized += slow_stac::item_export::localize(&plan, item);
+    }
+    let count = items.len();
+    slow_stac::item_export::write(items, output)?;
+    println!(
+        "Wrote {} items to {:?}, {} assets pointing at downloaded files",
+        count, output, localized
+    );
+    Ok(())
+}
+
+async fn handle_translate(
+    image_selection: &PathBuf,
+    collection: &Collection,
+    output: &PathBuf,
+) -> Result<()> {
+    if output.exists() {
+        return Err(anyhow!("File already exists {:?}", output));
+    }
+    let selection = slow_stac::image_selection::ImageSelection::read(image_selection)
+        .with_context(|| anyhow!("Could not parse the provided file"))?;
+    let template = match collection {
+        Collection::CopSentinel2 => slow_stac::copernicus::sentinel2level2a::image_selection_toml(),
+        Collection::E84Sentinel2 => {
+            slow_stac::element84::sentinel2collection1level2a::image_selection_toml()
+        }
+        Collection::E84Sentinel2L1c => {
+            slow_stac::element84::sentinel2level1c::image_selection_toml()
+        }
+        Collection::E84Sentinel2PreC1 => {
+            slow_stac::element84::sentinel2precollection1level2a::image_selection_toml()
+        }
+        Collection::EdMod09ga => slow_stac::earthdata::mod09ga::image_selection_toml(),
+        Collection::PcLandsat => {
+            slow_stac::planetary_computer::landsat_c2_l2::image_selection_toml()
+        }
+    };
+    let target = slow_stac::image_selection::ImageSelection::from_template(&template);
+    let ids = selection
+        .ids_to_download()
+        .ok_or(anyhow!("No ids to translate in {:?}", image_selection))?;
+
+    let mut translated = vec![];
+    for id in ids {
+        let target_id = slow_stac::ids::translate_id(&selection.id, &target.id, &id).await?;
+        println!("{} -> {}", id, target_id);
+        translated.push(target_id);
+    }
+    target.with_ids_to_download(translated).write(output)?;
+    println!("Wrote translated image selection file to {:?}", output);
+    Ok(())
+}
+
+async fn handle_queryables(collection: &Collection) -> Result<()> {
+    let (stac_api, collection_id) = match collection {
+        Collection::CopSentinel2 => (
+            slow_stac::copernicus::sentinel2level2a::STAC_API,
+            slow_stac::copernicus::sentinel2level2a::COLLECTION_ID,
+        ),
+        Collection::E84Sentinel2 => (
+            slow_stac::element84::sentinel2collection1level2a::STAC_API,
+            slow_stac::element84::sentinel2collection1level2a::COLLECTION_ID,
+        ),
+        Collection::E84Sentinel2L1c => (
+            slow_stac::element84::sentinel2collection1level2a::STAC_API,
+            slow_stac::element84::sentinel2level1c::COLLECTION_ID,
+        ),
+        Collection::E84Sentinel2PreC1 => (
+            slow_stac::element84::sentinel2collection1level2a::STAC_API,
+            slow_stac::element84::sentinel2precollection1level2a::COLLECTION_ID,
+        ),
+        Collection::EdMod09ga => (
+            slow_stac::earthdata::mod09ga::STAC_API,
+            slow_stac::earthdata::mod09ga::COLLECTION_ID,
+        ),
+        Collection::PcLandsat => (
+            slow_stac::planetary_computer::landsat_c2_l2::STAC_API,
+            slow_stac::planetary_computer::landsat_c2_l2::COLLECTION_ID,
+        ),
+    };
+    let queryables = slow_stac::search::fetch_queryables(stac_api, collection_id).await?;
+    slow_stac::search::print_queryables(&queryables);
+    Ok(())
+}
+
+/// Write (or print) a unit running `download` for a plan, preparing the plan first when given an
+/// image selection.
+async fn handle_install_service(
+    plan_or_selection: &PathBuf,
+    output_dir: Option<&PathBuf>,
+    windows: &[slow_stac::schedule::Window],
+    environment: Option<&String>,
+    profile: Option<slow_stac::profile::Profile>,
+    nice: bool,
+    name: Option<&String>,
+    system: bool,
+    print: bool,
+    watch: bool,
+) -> Result<()> {
+    let plan_or_selection = std::fs::canonicalize(plan_or_selection)
+        .with_context(|| anyhow!("Could not find {:?}", plan_or_selection))?;
+    let output_dir = match output_dir {
+        Some(dir) => Some(
+            std::fs::canonicalize(dir)
+                .with_context(|| anyhow!("Directory does not exist {:?}", dir))?,
+        ),
+        None => None,
+    };
+    let mut args = vec![];
+    let download_plan = match slow_stac::image_selection::ImageSelection::read(&plan_or_selection) {
+        Ok(_) => {
+            let output_dir = output_dir
+                .as_ref()
+                .ok_or(anyhow!("--output-dir is required to prepare a selection"))?;
+            let download_plan = handle_prepare(
+                &plan_or_selection,
+                output_dir,
+                false,
+                None,
+                false,
+                false,
+                false,
+                false,
+                slow_stac::prepare::DEFAULT_JOBS,
+                false,
+            )
+            .await?
+            .ok_or(anyhow!("No plan was prepared"))?;
+            if watch {
+                args.push("--watch-selection".to_string());
+                args.push(plan_or_selection.to_string_lossy().to_string());
+            }
+            download_plan
+        }
+        Err(_) if watch => return Err(anyhow!("--watch requires an image selection")),
+        Err(_) => {
+            if let Some(output_dir) = &output_dir {
+                args.push("--output-dir".to_string());
+                args.push(output_dir.to_string_lossy().to_string());
+            }
+            plan_or_selection.clone()
+        }
+    };
+    if slow_stac::plan_crypt::is_encrypted(&download_plan) {
+        println!("Warning: the service needs SLOW_STAC_PLAN_PASSPHRASE set in its environment");
+    }
+    if let Some(profile) = profile {
+        args.push("--profile".to_string());
+        args.push(format!("{:?}", profile).to_lowercase());
+    }
+    if nice {
+        args.push("--nice".to_string());
+    }
+    for window in windows {
+        args.push("--window".to_string());
+        args.push(window.to_string());
+    }
+    // Read from this user's config file, whichever user the service runs as
+    let mut variables = vec![];
+    if let Some(environment) = environment {
+        args.push("--env".to_string());
+        args.push(environment.clone());
+        let config = slow_stac::config::Config::path()?;
+        let config = std::fs::canonicalize(&config)
+            .with_context(|| anyhow!("Could not find the config file {:?}", config))?;
+        variables.push((
+            slow_stac::config::CONFIG_ENV.to_string(),
+            config.to_string_lossy().to_string(),
+        ));
+    }
+    let program = std::env::current_exe()?;
+    let plan_name = download_plan
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let working_directory = output_dir.unwrap_or(
+        download_plan
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or(PathBuf::from("/")),
+    );
+    let unit = slow_stac::service::ServiceUnit {
+        name: name
+            .cloned()
+            .unwrap_or(slow_stac::service::unit_name(&plan_name)),
+        description: format!("slow-stac download of {}", plan_name),
+        exec_start: [
+            vec![
+                program.to_string_lossy().to_string(),
+                "download".to_string(),
+                download_plan.to_string_lossy().to_string(),
+            ],
+            args,
+        ]
+        .concat(),
+        working_directory,
+        environment: variables,
+        user: !system,
+    };
+    if print {
+        print!("{}", unit.to_unit());
+        return Ok(());
+    }
+    let path = unit.write()?;
+    println!("Wrote service unit to {:?}", path);
+    let systemctl = match system {
+        true => "systemctl",
+        false => "systemctl --user",
+    };
+    println!(
+        "Start it with: {} daemon-reload && {} enable --now {}",
+        systemctl, systemctl, unit.name
+    );
+    Ok(())
+}
+
+/// Print the metadata of a GeoTIFF asset, read with ranged requests.
+async fn handle_inspect(
+    collection: &Collection,
+    item_id: &str,
+    asset: &str,
+    header_kb: u64,
+) -> Result<()> {
+    let provider = slow_stac::provider::CollectionProvider::new(collection.selection_id()).await?;
+    let task = provider.asset_task(item_id, asset).await?;
+    let geotiff =
+        slow_stac::geotiff::read_remote(&provider, task.bucket(), task.key(), header_kb * 1024)
+            .await?;
+    match slow_stac::porcelain::enabled() {
+        true => println!("{}", geotiff.to_porcelain()),
+        false => println!("{}", geotiff.to_text()),
+    }
+    Ok(())
+}
+
+/// Time ranged reads of the first object planned for the collection's built-in selection.
+async fn handle_speedtest(collection: &Collection, samples: u64) -> Result<()> {
+    let output_dir = PathBuf::from(".");
+    let result = match collection {
+        Collection::CopSentinel2 => {
+            let selection = slow_stac::image_selection::ImageSelection::from_template(
+                &slow_stac::copernicus::sentinel2level2a::image_selection_toml(),
+            );
+            let provider = slow_stac::copernicus::Provider::configured().await?;
+            let plan = slow_stac::copernicus::sentinel2level2a::generate_download_plan(
+                &provider, &selection, output_dir,
+            )
+            .await?;
+            let (bucket, key) = plan
+                .sample_object()
+                .ok_or(anyhow!("No sample object to test with"))?;
+            slow_stac::probe::speed_test("copernicus", &provider, bucket, key, samples).await?
+        }
+        Collection::E84Sentinel2 => {
+            let selection = slow_stac::image_selection::ImageSelection::from_template(
+                &slow_stac::element84::sentinel2collection1level2a::image_selection_toml(),
+            );
+            let plan = slow_stac::element84::sentinel2collection1level2a::generate_download_plan(
+                &selection, output_dir,
+            )
+            .await?;
+            let (bucket, key) = plan
+                .sample_object()
+                .ok_or(anyhow!("No sample object to test with"))?;
+            let provider = slow_stac::element84::Provider::configured().await?;
+            slow_stac::probe::speed_test("element84", &provider, bucket, key, samples).await?
+        }
+        Collection::E84Sentinel2L1c => {
+            let selection = slow_stac::image_selection::ImageSelection::from_template(
+                &slow_stac::element84::sentinel2level1c::image_selection_toml(),
+            );
+            let plan = slow_stac::element84::sentinel2level1c::generate_download_plan(
+                &selection, output_dir,
+            )
+            .await?;
+            let (bucket, key) = plan
+                .sample_object()
+                .ok_or(anyhow!("No sample object to test with"))?;
+            let provider = slow_stac::element84::Provider::configured_as(
+                slow_stac::provider_config::ELEMENT84_L1C,
+            )
+            .await?;
+            slow_stac::probe::speed_test(
+                slow_stac::provider_config::ELEMENT84_L1C,
+                &provider,
+                bucket,
+                key,
+                samples,
+            )
+            .await?
+        }
+        Collection::E84Sentinel2PreC1 => {
+            let selection = slow_stac::image_selection::ImageSelection::from_template(
+                &slow_stac::element84::sentinel2precollection1level2a::image_selection_toml(),
+            );
+            let plan =
+                slow_stac::element84::sentinel2precollection1level2a::generate_download_plan(
+                    &selection, output_dir,
+                )
+                .await?;
+            let (bucket, key) = plan
+                .sample_object()
+                .ok_or(anyhow!("No sample object to test with"))?;
+            let provider = slow_stac::element84::Provider::configured().await?;
+            slow_stac::probe::speed_test("element84", &provider, bucket, key, samples).await?
+        }
+        Collection::EdMod09ga => {
+            let selection = slow_stac::image_selection::ImageSelection::from_template(
+                &slow_stac::earthdata::mod09ga::image_selection_toml(),
+            );
+            let plan =
+                slow_stac::earthdata::mod09ga::generate_download_plan(&selection, output_dir)
+                    .await?;
+            let (bucket, key) = plan
+                .sample_object()
+                .ok_or(anyhow!("No sample object to test with"))?;
+            let provider = slow_stac::earthdata::Provider::from_env().await?;
+            slow_stac::probe::speed_test("earthdata", &provider, bucket, key, samples).await?
+        }
+        Collection::PcLandsat => {
+            let selection = slow_stac::image_selection::ImageSelection::from_template(
+                &slow_stac::planetary_computer::landsat_c2_l2::image_selection_toml(),
+            );
+            let plan = slow_stac::planetary_computer::landsat_c2_l2::generate_download_plan(
+                &selection, output_dir,
+            )
+            .await?;
+            let (bucket, key) = plan
+                .sample_object()
+                .ok_or(anyhow!("No sample object to test with"))?;
+            let provider = slow_stac::planetary_computer::Provider::from_env();
+            slow_stac::probe::speed_test("planetarycomputer", &provider, bucket, key, samples)
+                .await?
+        }
+    };
+    let recommendation = slow_stac::probe::Recommendation::from_result(&result);
+    recommendation.print();
+
+    let path = slow_stac::config::Config::path()?;
+    slow_stac::config::Config::read(&path)?
+        .with_recommendation(collection.selection_id(), recommendation)
+        .write(&path)?;
+    println!("Saved the recommendation to {:?}", path);
+    Ok(())
+}
+
+fn handle_inventory(dir: &PathBuf, output: Option<&PathBuf>, checksums: bool) -> Result<()> {
+    let (entries, unrecognised) = slow_stac::inventory::inventory(dir, checksums)?;
+    for name in unrecognised {
+        eprintln!(
+            "Warning: {:?} is not an item of a known collection, skipped",
+            name
+        );
+    }
+    match output {
+        Some(output) => {
+            if output.exists() {
+                return Err(anyhow!("File already exists {:?}", output));
+            }
+            let format = slow_stac::inventory::InventoryFormat::from_path(output);
+            std::fs::write(output, slow_stac::inventory::to_string(&entries, format)?)?;
+            println!("Wrote {} files to {:?}", entries.len(), output);
+        }
+        None => {
+            let format = slow_stac::inventory::InventoryFormat::Csv;
+            print!("{}", slow_stac::inventory::to_string(&entries, format)?);
+        }
+    }
+    Ok(())
+}
+
+fn handle_audit(
+    dir: &PathBuf,
+    inventory: Option<&PathBuf>,
+    plan: Option<&PathBuf>,
+    recheck_after_days: u64,
+    requeue: bool,
+) -> Result<()> {
+    let records = match (inventory, plan) {
+        (_, Some(plan)) => {
+            // The archive may have moved since, e.g. to an external drive
+            let plan = slow_stac::download_plan::DownloadPlan::read(plan)?.with_root(dir);
+            slow_stac::audit::Recorded::from_plan(&plan)
+        }
+        (Some(inventory), None) => {
+            let entries = slow_stac::inventory::read(inventory)?;
+            slow_stac::audit::Recorded::from_inventory(dir, &entries)
+        }
+        (None, None) => {
+            let inventory = ["inventory.csv", "inventory.json"]
+                .map(|name| dir.join(name))
+                .into_iter()
+                .find(|path| path.exists())
+                .ok_or(anyhow!(
+                    "No inventory found in {:?}, give --inventory or --plan",
+                    dir
+                ))?;
+            let entries = slow_stac::inventory::read(&inventory)?;
+            slow_stac::audit::Recorded::from_inventory(dir, &entries)
+        }
+    };
+    let recheck_after = std::time::Duration::from_secs(recheck_after_days * 24 * 60 * 60);
+    let report = slow_stac::audit::audit(dir, &records, recheck_after)?;
+    println!("{}", report.to_text());
+    if requeue {
+        slow_stac::audit::requeue(&report)?;
+    }
+    report.into_result()?;
+    Ok(())
+}
+
+fn handle_plan_stats(download_plan: &PathBuf) -> Result<()> {
+    let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+    plan.print_stats();
+    Ok(())
+}
+
+/// Presign every task of a plan, or with `refresh` only those expiring within the hour, and
+/// rewrite it. Only Copernicus requires credentials that can be presigned; Earth Search objects
+/// are public and Earthdata uses bearer tokens.
+async fn handle_plan_presign(
+    download_plan: &PathBuf,
+    expires_in_hours: u64,
+    refresh: bool,
+) -> Result<()> {
+    let expires_in = std::time::Duration::from_secs(expires_in_hours * 60 * 60);
+    if expires_in > slow_stac::presign::MAX_EXPIRES_IN {
+        return Err(anyhow!("Presigned URLs are valid for at most 168 hours"));
+    }
+    let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+    if plan.selection_id != "copernicus.sentinel2level2a" {
+        return Err(anyhow!("{} plans cannot be presigned", plan.selection_id));
+    }
+    let provider = slow_stac::copernicus::Provider::configured().await?;
+    let plan = match refresh {
+        true => {
+            let margin = std::time::Duration::from_secs(60 * 60);
+            let (plan, resigned) = plan.refreshed(&provider, expires_in, margin).await?;
+            println!("Re-signed {} of {} tasks", resigned, plan.task_count());
+            plan
+        }
+        false => {
+            let plan = plan.presigned(&provider, expires_in).await?;
+            println!("Presigned {} tasks", plan.task_count());
+            plan
+        }
+    };
+    plan.write(download_plan)?;
+    if !slow_stac::plan_crypt::is_encrypted(download_plan) {
+        println!("Warning: the plan now contains signed URLs, consider `plan encrypt`");
+    }
+    Ok(())
+}
+
+/// List the directories of a plan's objects at the source it downloads from
+async fn handle_plan_check(download_plan: &PathBuf) -> Result<()> {
+    let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+    let check = match plan.source() {
+        Some(slow_stac::copernicus::gcs_mirror::SOURCE) => {
+            let provider = slow_stac::gcs::Provider::from_env()?;
+            slow_stac::plan_check::check_keys(&plan, &provider).await?
+        }
+        Some(source) => return Err(anyhow!("Plans routed to {} cannot be checked", source)),
+        None => {
+            let provider = slow_stac::provider::CollectionProvider::new(&plan.selection_id).await?;
+            slow_stac::plan_check::check_keys(&plan, &provider).await?
+        }
+    };
+    check.print();
+    if !check.missing.is_empty() {
+        return Err(anyhow!(
+            "{} objects of the plan are missing",
+            check.missing.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Read a plan and write it to another path, encrypting or decrypting it according to the paths.
+fn handle_plan_rewrite(download_plan: &PathBuf, output: &PathBuf) -> Result<()> {
+    if output.exists() {
+        return Err(anyhow!("File already exists {:?}", output));
+    }
+    let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+    plan.write(output)?;
+    println!("Wrote download plan file to {:?}", output);
+    Ok(())
+}
+
+/// Probe the sources a plan's objects are available from. Only Copernicus products are mirrored
+/// elsewhere (the Google Cloud public Sentinel-2 bucket); other plans have a single source.
+async fn handle_plan_probe(download_plan: &PathBuf) -> Result<()> {
+    let plan = slow_stac::download_plan::DownloadPlan::read(download_plan)?;
+    if plan.selection_id != "copernicus.sentinel2level2a" {
+        println!(
+            "{} has a single source, nothing to probe",
+            plan.selection_id
+        );
+        return Ok(());
+    }
+    if plan.source().is_some() {
+        return Err(anyhow!(
+            "Plan has already been routed to {:?}",
+            plan.source()
+        ));
+    }
+    let (bucket, key) = plan
+        .sample_object()
+        .ok_or(anyhow!("Plan has no tasks to probe"))?;
+
+    let mut results = vec![];
+    let copernicus = slow_stac::copernicus::Provider::configured().await?;
+    match slow_stac::probe::probe("copernicus", &copernicus, bucket, key).await {
+        Ok(result) => results.push(result),
+        Err(e) => println!("Warning: could not probe copernicus: {}", e),
+    }
+    if let Some((mirror_bucket, mirror_key)) =
+        slow_stac::copernicus::gcs_mirror::locate(bucket, key)
+    {
+        let gcs = slow_stac::gcs::Provider::from_env()?;
+        let source = slow_stac::copernicus::gcs_mirror::SOURCE;
+        match slow_stac::probe::probe(source, &gcs, &mirror_bucket, &mirror_key).await {
+            Ok(result) => results.push(result),
+            Err(e) => println!("Warning: could not probe {}: {}", source, e),
+        }
+    }
+
+    let (files, bytes) = (plan.task_count(), plan.total_bytes());
+    slow_stac::probe::print_results(&results, files, bytes);
+    let fastest = slow_stac::probe::fastest(&results, files, bytes)
+        .ok_or(anyhow!("No source could be reached"))?;
+    if fastest.source == "copernicus" {
+        println!("Keeping the copernicus source");
+        return Ok(());
+    }
+    let plan = plan.routed_to(&fastest.source, slow_stac::copernicus::gcs_mirror::locate)?;
+    plan.write(download_plan)?;
+    println!("Routed the plan to {}", fastest.source);
     Ok(())
 }
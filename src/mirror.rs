@@ -0,0 +1,241 @@
+//! Mirroring every object under a bucket prefix, for buckets shared by collaborators that have no
+//! STAC catalogue. The objects are listed (see `S3ObjOps::list_objects`), filtered by include and
+//! exclude globs matched against the key below the prefix, and downloaded as a plan writing each to
+//! `<dir>/<key below the prefix>`, so an interrupted mirror resumes like any plan.
+//!
+//! Files already in the directory are kept, even when the object has since changed size; those
+//! are reported so they can be removed and mirrored again.
+use crate::backoff::Throttled;
+use crate::download_plan::{DownloadOptions, DownloadPlan, DownloadTask, ExecutionSummary};
+use crate::image_selection::glob_to_regex;
+use crate::provider_config::ProviderConfig;
+use crate::s3::{ListedObject, S3ObjOps};
+use crate::{element84, porcelain, units};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::fs;
+use std::path::{Component, Path};
+
+/// Selection id of mirror plans
+pub const SELECTION_ID: &str = "mirror";
+
+/// A bucket and prefix given as `s3://bucket/prefix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorSource {
+    pub bucket: String,
+    /// Empty for the whole bucket
+    pub prefix: String,
+}
+
+impl MirrorSource {
+    pub fn new(source: &str) -> Result<Self> {
+        let path = source
+            .strip_prefix("s3://")
+            .ok_or(anyhow!("Expected an s3://bucket/prefix source"))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(anyhow!("No bucket in {}", source));
+        }
+        Ok(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    /// The key below the prefix, without a leading slash. None for keys that would be written
+    /// outside the mirror directory, such as `a/../../x`.
+    fn relative<'a>(self: &Self, key: &'a str) -> Option<&'a str> {
+        let relative = key
+            .strip_prefix(&self.prefix)
+            .unwrap_or(key)
+            .trim_start_matches('/');
+        let escapes = Path::new(relative).components().any(|component| {
+            matches!(
+                component,
+                Component::ParentDir | Component::RootDir | Component::Prefix(_)
+            )
+        });
+        match escapes {
+            true => None,
+            false => Some(relative),
+        }
+    }
+}
+
+/// Include and exclude globs, `*` matching across `/`. With no include globs every key is
+/// included, and excludes win over includes.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let compile = |globs: &[String]| -> Result<Vec<Regex>> {
+            globs
+                .iter()
+                .map(|glob| Ok(Regex::new(&format!("^{}$", glob_to_regex(glob)))?))
+                .collect()
+        };
+        Ok(Self {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn matches(self: &Self, key: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|re| re.is_match(key));
+        included && !self.exclude.iter().any(|re| re.is_match(key))
+    }
+}
+
+/// A plan downloading the listed objects that pass the filter into `dir`, the keys of those
+/// already there with a different size, and the keys skipped for escaping `dir`
+pub fn plan(
+    source: &MirrorSource,
+    objects: &[ListedObject],
+    filter: &Filter,
+    dir: &Path,
+) -> (DownloadPlan, Vec<String>, Vec<String>) {
+    let mut tasks = vec![];
+    let mut changed = vec![];
+    let mut skipped = vec![];
+    for object in objects {
+        let Some(relative) = source.relative(&object.key) else {
+            skipped.push(object.key.clone());
+            continue;
+        };
+        // Directory placeholders some tools create
+        if relative.is_empty() || object.key.ends_with('/') || !filter.matches(relative) {
+            continue;
+        }
+        if let Ok(metadata) = fs::metadata(dir.join(relative)) {
+            if metadata.len() != object.size {
+                changed.push(object.key.clone());
+            }
+        }
+        tasks.push(
+            DownloadTask::new(&source.bucket, &object.key, relative).with_size(Some(object.size)),
+        );
+    }
+    (
+        DownloadPlan::new(SELECTION_ID, tasks).with_root(dir),
+        changed,
+        skipped,
+    )
+}
+
+/// `mirror` with the number of matching and listed objects and the files and bytes left to
+/// download, then `skipped` and `changed` per key left alone
+fn porcelain_summary(
+    plan: &DownloadPlan,
+    listed: usize,
+    changed: &[String],
+    skipped: &[String],
+) -> String {
+    let remaining = plan.remaining();
+    let mut lines = vec![porcelain::record(
+        "mirror",
+        &[
+            &plan.tasks().len(),
+            &listed,
+            &remaining.files,
+            &remaining.bytes,
+        ],
+    )];
+    for key in skipped {
+        lines.push(porcelain::record("skipped", &[key]));
+    }
+    for key in changed {
+        lines.push(porcelain::record("changed", &[key]));
+    }
+    lines.join("\n")
+}
+
+/// Mirror `source` into `dir` through a client configured by `config`
+pub async fn mirror(
+    source: &MirrorSource,
+    filter: &Filter,
+    dir: &Path,
+    config: &ProviderConfig,
+    options: &DownloadOptions,
+) -> Result<ExecutionSummary> {
+    let provider = Throttled::new(element84::Provider::from_config(config).await)
+        .with_max_retries(options.max_retries)
+        .with_control(options.control.clone());
+    let objects = provider
+        .list_objects(&source.bucket, &source.prefix)
+        .await?;
+    let (plan, changed, skipped) = plan(source, &objects, filter, dir);
+    match porcelain::enabled() {
+        true => println!(
+            "{}",
+            porcelain_summary(&plan, objects.len(), &changed, &skipped)
+        ),
+        false => {
+            for key in &skipped {
+                println!(
+                    "Warning: skipped {}, its path leaves the mirror directory",
+                    key
+                );
+            }
+            for key in &changed {
+                println!(
+                    "Warning: {} changed size since it was mirrored, remove it to mirror it again",
+                    key
+                );
+            }
+            let remaining = plan.remaining();
+            println!(
+                "{} of {} objects match, {} files ({}) left to download",
+                plan.tasks().len(),
+                objects.len(),
+                remaining.files,
+                units::size(remaining.bytes)
+            );
+        }
+    }
+    Ok(plan.execute_summarized(&provider, options).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let dir = Path::new("/tmp/slow_stac_mirror");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("2024")).unwrap();
+        fs::write(dir.join("2024/a.tif"), "old").unwrap();
+        let source = MirrorSource::new("s3://shared/field/").unwrap();
+        assert_eq!(source.prefix, "field/");
+        assert!(MirrorSource::new("https://shared/field").is_err());
+
+        let object = |key: &str| ListedObject {
+            key: key.to_string(),
+            size: 10,
+            e_tag: None,
+        };
+        let objects = [
+            object("field/"),
+            object("field/2024/a.tif"),
+            object("field/2024/a.tif.aux.xml"),
+            object("field/2024/notes.txt"),
+            object("field/2024/tmp/b.tif"),
+            object("field/../../etc/c.tif"),
+        ];
+        let filter = Filter::new(&["*.tif".to_string()], &["*/tmp/*".to_string()]).unwrap();
+        let (plan, changed, skipped) = plan(&source, &objects, &filter, dir);
+        let outputs: Vec<_> = plan.tasks().iter().map(|task| task.output()).collect();
+        assert_eq!(outputs, ["2024/a.tif"]);
+        assert_eq!(changed, ["field/2024/a.tif"]);
+        assert_eq!(skipped, ["field/../../etc/c.tif"]);
+        assert_eq!(plan.root(), Some("/tmp/slow_stac_mirror"));
+        assert_eq!(
+            porcelain_summary(&plan, objects.len(), &changed, &skipped),
+            "mirror\t1\t6\t0\t0\nskipped\tfield/../../etc/c.tif\nchanged\tfield/2024/a.tif"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! `slow-stac mount`, a plan's output tree as a read-only FUSE filesystem (Linux and macOS, with
+//! the `fuse` feature). Files are served through `ReadThrough`: downloaded outputs from disk, the
+//! rest with ranged requests for the blocks read, so GIS software can open remote scenes lazily.
+//! Sizes of tasks planned without one are looked up with a HEAD request when first listed.
+use crate::download_plan::DownloadPlan;
+use crate::fetch::CollectionProvider;
+use crate::readthrough::{ReadThrough, BLOCK_SIZE};
+use crate::s3::S3ObjOps;
+use anyhow::Result;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request, FUSE_ROOT_ID,
+};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
+
+/// How long the kernel may keep attributes and entries, which don't change while mounted
+const TTL: Duration = Duration::from_secs(60);
+
+struct Node {
+    /// Path relative to the plan root, None for directories
+    file: Option<PathBuf>,
+    parent: u64,
+    children: BTreeMap<OsString, u64>,
+}
+
+/// The filesystem of a plan's outputs. Inode `n` is `nodes[n - 1]`, the root being 1.
+pub struct PlanFs<P> {
+    view: ReadThrough<P>,
+    /// Runs the view's requests from the filesystem's thread
+    runtime: Handle,
+    nodes: Vec<Node>,
+    mounted: SystemTime,
+}
+
+impl<P: S3ObjOps> PlanFs<P> {
+    pub fn new(view: ReadThrough<P>, runtime: Handle) -> Self {
+        let mut nodes = vec![Node {
+            file: None,
+            parent: FUSE_ROOT_ID,
+            children: BTreeMap::new(),
+        }];
+        let paths: Vec<PathBuf> = view.paths().iter().map(|path| path.to_path_buf()).collect();
+        for path in paths {
+            let names: Vec<&OsStr> = path.iter().collect();
+            let mut ino = FUSE_ROOT_ID;
+            for (i, name) in names.iter().enumerate() {
+                if let Some(child) = nodes[ino as usize - 1].children.get(*name) {
+                    ino = *child;
+                    continue;
+                }
+                nodes.push(Node {
+                    file: (i + 1 == names.len()).then(|| path.clone()),
+                    parent: ino,
+                    children: BTreeMap::new(),
+                });
+                let child = nodes.len() as u64;
+                nodes[ino as usize - 1]
+                    .children
+                    .insert(name.to_os_string(), child);
+                ino = child;
+            }
+        }
+        Self {
+            view,
+            runtime,
+            nodes,
+            mounted: SystemTime::now(),
+        }
+    }
+
+    fn node(self: &Self, ino: u64) -> Result<&Node, c_int> {
+        match ino {
+            0 => Err(libc::ENOENT),
+            _ => self.nodes.get(ino as usize - 1).ok_or(libc::ENOENT),
+        }
+    }
+
+    fn failed(path: &Path, e: anyhow::Error) -> c_int {
+        println!("Warning: error reading {:?}: {}", path, e);
+        libc::EIO
+    }
+
+    fn attr(self: &Self, req: &Request<'_>, ino: u64) -> Result<FileAttr, c_int> {
+        let (kind, perm, size) = match &self.node(ino)?.file {
+            Some(path) => {
+                let size = self
+                    .runtime
+                    .block_on(self.view.size(path))
+                    .map_err(|e| Self::failed(path, e))?;
+                (FileType::RegularFile, 0o444, size)
+            }
+            None => (FileType::Directory, 0o555, 0),
+        };
+        Ok(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mounted,
+            mtime: self.mounted,
+            ctime: self.mounted,
+            crtime: self.mounted,
+            kind,
+            perm,
+            nlink: match kind {
+                FileType::Directory => 2,
+                _ => 1,
+            },
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        })
+    }
+
+    fn kind(self: &Self, ino: u64) -> FileType {
+        match self.node(ino).map(|node| node.file.is_some()) {
+            Ok(true) => FileType::RegularFile,
+            _ => FileType::Directory,
+        }
+    }
+}
+
+impl<P: S3ObjOps> Filesystem for PlanFs<P> {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let ino = match self.node(parent) {
+            Ok(node) => node.children.get(name).copied(),
+            Err(e) => return reply.error(e),
+        };
+        match ino.ok_or(libc::ENOENT).and_then(|ino| self.attr(req, ino)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(req, ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let path = match self.node(ino).map(|node| node.file.as_ref()) {
+            Ok(Some(path)) if offset >= 0 => path,
+            Ok(Some(_)) => return reply.error(libc::EINVAL),
+            Ok(None) => return reply.error(libc::EISDIR),
+            Err(e) => return reply.error(e),
+        };
+        let read = self.view.read(path, offset as u64, size as u64);
+        match self.runtime.block_on(read) {
+            Ok(bytes) => reply.data(&bytes),
+            Err(e) => reply.error(Self::failed(path, e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let node = match self.node(ino) {
+            Ok(node) if node.file.is_none() => node,
+            Ok(_) => return reply.error(libc::ENOTDIR),
+            Err(e) => return reply.error(e),
+        };
+        let mut entries = vec![(ino, OsStr::new(".")), (node.parent, OsStr::new(".."))];
+        entries.extend(
+            node.children
+                .iter()
+                .map(|(name, child)| (*child, name.as_os_str())),
+        );
+        // An entry's offset is that of the next one, where a listing continues from
+        for (i, (child, name)) in entries.into_iter().enumerate().skip(offset.max(0) as usize) {
+            if reply.add(child, i as i64 + 1, self.kind(child), name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Serve the plan's outputs read-only at `mountpoint` through its collection's provider, caching
+/// blocks in `cache_dir`, until it is unmounted (`fusermount -u` or `umount`). Blocks a thread of
+/// the multi-threaded runtime meanwhile.
+pub async fn mount(plan: &DownloadPlan, cache_dir: &Path, mountpoint: &Path) -> Result<()> {
+    let provider = CollectionProvider::new(&plan.selection_id).await?;
+    let view = ReadThrough::new(provider, plan, cache_dir);
+    let filesystem = PlanFs::new(view, Handle::current());
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("slow-stac".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    tokio::task::block_in_place(|| fuser::mount2(filesystem, mountpoint, &options))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_plan::DownloadTask;
+    use anyhow::anyhow;
+    use aws_sdk_s3::operation::get_object::GetObjectOutput;
+    use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+
+    struct Offline;
+
+    impl S3ObjOps for Offline {
+        async fn head_object(self: &Self, _: &str, _: &str) -> Result<HeadObjectOutput> {
+            Err(anyhow!("Offline"))
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Err(anyhow!("Offline"))
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            _: u64,
+            _: u64,
+        ) -> Result<GetObjectOutput> {
+            Err(anyhow!("Offline"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tree() {
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("bucket", "a/B04.tif", "S2A/B04.tif"),
+                DownloadTask::new("bucket", "a/B08.tif", "S2A/B08.tif"),
+                DownloadTask::new("bucket", "b/B04.tif", "S2B/B04.tif"),
+            ],
+        );
+        let view = ReadThrough::new(Offline, &plan, Path::new("/tmp/slow_stac_mount"));
+        let filesystem = PlanFs::new(view, Handle::current());
+        let root = filesystem.node(FUSE_ROOT_ID).unwrap();
+        let names: Vec<_> = root.children.keys().collect();
+        assert_eq!(names, ["S2A", "S2B"]);
+
+        let s2a = filesystem.node(root.children[OsStr::new("S2A")]).unwrap();
+        assert_eq!(s2a.parent, FUSE_ROOT_ID);
+        assert!(s2a.file.is_none());
+        let b08 = filesystem
+            .node(s2a.children[OsStr::new("B08.tif")])
+            .unwrap();
+        assert_eq!(b08.file.as_deref(), Some(Path::new("S2A/B08.tif")));
+        assert_eq!(
+            filesystem.kind(s2a.children[OsStr::new("B08.tif")]),
+            FileType::RegularFile
+        );
+        assert_eq!(filesystem.node(0).err(), Some(libc::ENOENT));
+        assert_eq!(filesystem.node(9).err(), Some(libc::ENOENT));
+    }
+}
@@ -0,0 +1,310 @@
+//! Parallel downloads of large objects, with `download --parts`. A single TCP stream over a
+//! high-latency link rarely fills it, so objects of at least `min_size` bytes are split into byte
+//! ranges requested at the same time. Each range is written to its own
+//! `<output>.partial.<start>-<end>` file, and the files are appended to the `.partial` file in
+//! order once every range has arrived. An interrupted range resumes from the length of its file;
+//! the ranges of an object that has changed since are discarded. A run that died while appending
+//! the ranges leaves a `.partial` file the next run continues in one stream, removing the ranges.
+use crate::control::{self, Skipped, Stopped};
+use crate::download_plan::{is_requested_range, DownloadOptions};
+use crate::partial::PartialState;
+use crate::s3::S3ObjOps;
+use crate::units;
+use anyhow::{anyhow, Result};
+use futures_util::future::try_join_all;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Objects smaller than this are downloaded in one stream
+pub const DEFAULT_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parts {
+    /// Ranges requested at the same time
+    pub count: usize,
+    pub min_size: u64,
+}
+
+impl Parts {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+
+    pub fn with_min_size(self, min_size: u64) -> Self {
+        Self { min_size, ..self }
+    }
+
+    /// Inclusive byte ranges of an object of `size` bytes, a single one below `min_size`
+    pub fn ranges(self: &Self, size: u64) -> Vec<(u64, u64)> {
+        let count = match size >= self.min_size {
+            true => (self.count.max(1) as u64).min(size.max(1)),
+            false => 1,
+        };
+        let part_size = size.div_ceil(count).max(1);
+        (0..count)
+            .map(|part| part * part_size)
+            .take_while(|start| *start < size)
+            .map(|start| (start, (start + part_size).min(size) - 1))
+            .collect()
+    }
+}
+
+/// The object being downloaded, as its HEAD response described it
+pub struct RemoteObject<'a> {
+    pub bucket: &'a str,
+    pub key: &'a str,
+    pub version_id: Option<&'a str>,
+    pub e_tag: Option<&'a str>,
+    pub size: u64,
+}
+
+/// Bytes received by all the ranges of an object
+struct Transfer {
+    received: AtomicU64,
+    /// Received by an earlier run, which pacing doesn't count
+    resumed: u64,
+    started: Instant,
+}
+
+pub fn part_path(partial: &Path, (start, end): (u64, u64)) -> PathBuf {
+    PathBuf::from(format!("{}.{}-{}", partial.to_string_lossy(), start, end))
+}
+
+/// The range files next to `partial`, whichever ranges they hold
+fn part_files(partial: &Path) -> Result<Vec<PathBuf>> {
+    let (Some(dir), Some(name)) = (partial.parent(), partial.file_name()) else {
+        return Ok(vec![]);
+    };
+    let prefix = format!("{}.", name.to_string_lossy());
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let Some(range) = file_name.strip_prefix(&prefix) else {
+            continue;
+        };
+        if range
+            .split_once('-')
+            .is_some_and(|(start, end)| start.parse::<u64>().is_ok() && end.parse::<u64>().is_ok())
+        {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Remove the range files next to `partial`, once the `.partial` file is downloaded in one stream
+pub fn remove_parts(partial: &Path) -> Result<()> {
+    for path in part_files(partial)? {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Download `object` in `options.parts` ranges at the same time into the empty `partial` file
+pub async fn download(
+    provider: &impl S3ObjOps,
+    object: &RemoteObject<'_>,
+    partial: &Path,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let ranges = options.parts.unwrap_or(Parts::new(1)).ranges(object.size);
+
+    // Ranges of another version of the object, or split differently, are of no use
+    let mismatch = PartialState::read(partial)?.and_then(|state| {
+        state.object_mismatch(object.bucket, object.key, object.e_tag, object.size)
+    });
+    if let Some(reason) = &mismatch {
+        println!("Warning: {}, restarting download", reason);
+    }
+    for path in part_files(partial)? {
+        let current = ranges
+            .iter()
+            .any(|range| part_path(partial, *range) == path);
+        if mismatch.is_some() || !current {
+            fs::remove_file(path)?;
+        }
+    }
+    PartialState::new(
+        object.bucket,
+        object.key,
+        object.e_tag,
+        object.size,
+        0,
+        &Sha256::new(),
+    )
+    .write(partial)?;
+
+    let resumed = ranges
+        .iter()
+        .filter_map(|range| fs::metadata(part_path(partial, *range)).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    match resumed {
+        0 => println!("Downloading in {} parts...", ranges.len()),
+        _ => println!(
+            "Resuming download from {} of {} in {} parts",
+            units::size(resumed),
+            units::size(object.size),
+            ranges.len()
+        ),
+    }
+    if let Some(control) = &options.control {
+        let id = control::task_id(object.bucket, object.key);
+        control.attempt(&id);
+        control.progress(&id, 0, resumed, Some(object.size));
+    }
+    let transfer = Transfer {
+        received: AtomicU64::new(resumed),
+        resumed,
+        started: Instant::now(),
+    };
+    try_join_all(
+        ranges
+            .iter()
+            .map(|range| download_range(provider, object, partial, *range, &transfer, options)),
+    )
+    .await?;
+
+    let mut file = OpenOptions::new().append(true).open(partial)?;
+    for range in &ranges {
+        io::copy(&mut File::open(part_path(partial, *range))?, &mut file)?;
+    }
+    file.flush()?;
+    for range in &ranges {
+        fs::remove_file(part_path(partial, *range))?;
+    }
+    Ok(())
+}
+
+/// Download one range into its file, resuming from the file's length. Each range must sustain the
+/// stall threshold on its own.
+async fn download_range(
+    provider: &impl S3ObjOps,
+    object: &RemoteObject<'_>,
+    partial: &Path,
+    (start, end): (u64, u64),
+    transfer: &Transfer,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let path = part_path(partial, (start, end));
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut offset = start + file.metadata()?.len();
+    let id = control::task_id(object.bucket, object.key);
+    let mut window_started = Instant::now();
+    let mut window_bytes = 0;
+    while offset <= end {
+        if let Some(control) = &options.control {
+            if control.wait_while_paused().await {
+                window_started = Instant::now();
+                window_bytes = 0;
+            }
+            if control.is_stopped() {
+                return Err(Stopped.into());
+            }
+            if control.is_skipped(&id) {
+                return Err(Skipped.into());
+            }
+        }
+        let end_byte = match options.chunk_size {
+            Some(chunk_size) => (offset + chunk_size.max(1) - 1).min(end),
+            None => end,
+        };
+        let mut response = provider
+            .get_object_range_version(
+                object.bucket,
+                object.key,
+                object.version_id,
+                offset,
+                end_byte,
+            )
+            .await?;
+        if !is_requested_range(&response, offset, object.size) {
+            return Err(anyhow!(
+                "The server ignored the range request for {}/{}, download it without --parts",
+                object.bucket,
+                object.key
+            ));
+        }
+
+        loop {
+            let next = response.body.try_next();
+            let chunk = match &options.stall {
+                Some(threshold) => tokio::time::timeout(threshold.window, next)
+                    .await
+                    .map_err(|_| threshold.stalled(window_bytes, window_started.elapsed()))??,
+                None => next.await?,
+            };
+            let Some(bytes) = chunk else { break };
+            let bytes_len = bytes.len() as u64;
+            file.write_all(&bytes)?;
+            offset += bytes_len;
+            let received = transfer.received.fetch_add(bytes_len, Ordering::Relaxed) + bytes_len;
+            if let Some(control) = &options.control {
+                control.progress(&id, bytes_len, received, Some(object.size));
+            }
+
+            window_bytes += bytes_len;
+            if let Some(threshold) = &options.stall {
+                let elapsed = window_started.elapsed();
+                if threshold.is_stalled(window_bytes, elapsed) {
+                    return Err(threshold.stalled(window_bytes, elapsed).into());
+                }
+                if elapsed >= threshold.window {
+                    window_started = Instant::now();
+                    window_bytes = 0;
+                }
+            }
+
+            // Paced on the bytes of all the ranges, so the cap holds for the whole object
+            let mut pause = options.pacing.pause(
+                received.saturating_sub(transfer.resumed),
+                transfer.started.elapsed(),
+            );
+            if let Some(control) = &options.control {
+                pause = pause.max(control.throttle(bytes_len));
+            }
+            if !pause.is_zero() {
+                tokio::time::sleep(pause).await;
+            }
+        }
+
+        // Only a body that ended where requested continues with the next chunk
+        if offset != end_byte + 1 {
+            break;
+        }
+    }
+    if offset != end + 1 {
+        return Err(anyhow!(
+            "Received {} of {} bytes of {}/{} from byte {}, keeping {:?} to resume",
+            offset - start,
+            end + 1 - start,
+            object.bucket,
+            object.key,
+            start,
+            path
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        let parts = Parts::new(3).with_min_size(10);
+        assert_eq!(parts.ranges(10), [(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(parts.ranges(2), [(0, 1)]);
+        assert_eq!(parts.ranges(9), [(0, 8)]);
+        assert_eq!(Parts::new(4).with_min_size(0).ranges(2), [(0, 0), (1, 1)]);
+    }
+}
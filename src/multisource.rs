@@ -0,0 +1,409 @@
+//! Fetching every range of an object from two sources at once, e.g. Copernicus and its Google
+//! Cloud mirror, to add up the throughput of routes that are each mediocre. Each range is split
+//! between the sources in proportion to the throughput they last delivered and reassembled in
+//! order, so the download, its resumption and its verification against the catalogue's checksum
+//! work as with a single source.
+//!
+//! Both pieces of a range are held in memory until the slower one arrives, so downloads should
+//! request objects in chunks (see `DEFAULT_CHUNK_SIZE`). A source sending nothing for the stall
+//! window is given up on like a failing one.
+use crate::download_plan::StallThreshold;
+use crate::s3::{ListedObject, S3ObjOps};
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::primitives::ByteStream;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Chunk size for multi-source downloads that don't set one
+pub const DEFAULT_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+/// Ranges smaller than this aren't worth a second request
+const MIN_SPLIT: u64 = 256 * 1024;
+
+/// Locates an object of the primary source at the mirror, see `copernicus::gcs_mirror::locate`
+pub type Locate = fn(&str, &str) -> Option<(String, String)>;
+
+/// Wraps a provider and a mirror of its objects. Metadata comes from the primary alone, and a
+/// mirror that fails or holds a different copy is stood in for by the primary.
+pub struct MultiSource<P, M> {
+    primary: P,
+    mirror: M,
+    locate: Locate,
+    /// Bytes per second the primary and the mirror last delivered
+    rates: Mutex<(f64, f64)>,
+    /// Its window bounds the wait for each part of a piece, which is read whole
+    stall: Option<StallThreshold>,
+}
+
+impl<P: S3ObjOps, M: S3ObjOps> MultiSource<P, M> {
+    pub fn new(primary: P, mirror: M, locate: Locate) -> Self {
+        Self {
+            primary,
+            mirror,
+            locate,
+            rates: Mutex::new((1.0, 1.0)),
+            stall: None,
+        }
+    }
+
+    pub fn with_stall(self, stall: Option<StallThreshold>) -> Self {
+        Self { stall, ..self }
+    }
+
+    /// Last byte of the primary's share of a range of at least two bytes
+    fn split(self: &Self, start_byte: u64, end_byte: u64) -> u64 {
+        let (primary, mirror) = *self.rates.lock().expect("Rates lock poisoned");
+        let len = end_byte + 1 - start_byte;
+        let share = (len as f64 * primary / (primary + mirror)) as u64;
+        start_byte + share.clamp(1, len - 1) - 1
+    }
+
+    fn record_rates(self: &Self, primary: Option<f64>, mirror: Option<f64>) {
+        let mut rates = self.rates.lock().expect("Rates lock poisoned");
+        if let Some(primary) = primary {
+            rates.0 = primary.max(1.0);
+        }
+        match mirror {
+            Some(mirror) => rates.1 = mirror.max(1.0),
+            // A failing mirror is given less to do until it recovers
+            None => rates.1 = (rates.1 / 2.0).max(1.0),
+        }
+    }
+}
+
+/// A fetched range, with the object's total size if the source gave it
+struct Piece {
+    bytes: Vec<u8>,
+    total: Option<u64>,
+    rate: f64,
+}
+
+/// Wait for `next` for at most the stall window, failing as stalled after `bytes` in `elapsed`
+async fn within<T>(
+    stall: Option<&StallThreshold>,
+    bytes: usize,
+    elapsed: Duration,
+    next: impl Future<Output = T>,
+) -> Result<T> {
+    match stall {
+        Some(threshold) => tokio::time::timeout(threshold.window, next)
+            .await
+            .map_err(|_| threshold.stalled(bytes as u64, elapsed).into()),
+        None => Ok(next.await),
+    }
+}
+
+async fn fetch(
+    source: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+    start_byte: u64,
+    end_byte: u64,
+    stall: Option<&StallThreshold>,
+) -> Result<Piece> {
+    let started = Instant::now();
+    let request = source.get_object_range(bucket, key, start_byte, end_byte);
+    let mut response = within(stall, 0, started.elapsed(), request).await??;
+    let content_range = response.content_range().map(|range| range.to_string());
+    if let Some(range) = &content_range {
+        if !range.starts_with(&format!("bytes {}-", start_byte)) {
+            return Err(anyhow!("{} answered {} for a range request", bucket, range));
+        }
+    }
+    let mut bytes = vec![];
+    loop {
+        let next = response.body.try_next();
+        let Some(chunk) = within(stall, bytes.len(), started.elapsed(), next).await?? else {
+            break;
+        };
+        bytes.extend_from_slice(&chunk);
+    }
+    if bytes.len() as u64 != end_byte + 1 - start_byte {
+        return Err(anyhow!(
+            "{} sent {} bytes for bytes {}-{}",
+            bucket,
+            bytes.len(),
+            start_byte,
+            end_byte
+        ));
+    }
+    let elapsed = started.elapsed().max(Duration::from_millis(1));
+    Ok(Piece {
+        rate: bytes.len() as f64 / elapsed.as_secs_f64(),
+        total: content_range.and_then(|range| {
+            range
+                .rsplit('/')
+                .next()
+                .and_then(|total| total.parse().ok())
+        }),
+        bytes,
+    })
+}
+
+impl<P: S3ObjOps, M: S3ObjOps> S3ObjOps for MultiSource<P, M> {
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        self.primary.head_object(bucket, key).await
+    }
+
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        self.primary.get_object(bucket, key).await
+    }
+
+    async fn list_objects(self: &Self, bucket: &str, prefix: &str) -> Result<Vec<ListedObject>> {
+        self.primary.list_objects(bucket, prefix).await
+    }
+
+    async fn head_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectOutput> {
+        self.primary
+            .head_object_version(bucket, key, version_id)
+            .await
+    }
+
+    async fn get_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectOutput> {
+        self.primary
+            .get_object_version(bucket, key, version_id)
+            .await
+    }
+
+    /// The mirror's copy may be another version, so pinned versions come from the primary alone
+    async fn get_object_range_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        match version_id {
+            None => {
+                self.get_object_range(bucket, key, start_byte, end_byte)
+                    .await
+            }
+            Some(_) => {
+                self.primary
+                    .get_object_range_version(bucket, key, version_id, start_byte, end_byte)
+                    .await
+            }
+        }
+    }
+
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        let located = (self.locate)(bucket, key);
+        let Some((mirror_bucket, mirror_key)) =
+            located.filter(|_| end_byte.saturating_sub(start_byte) >= MIN_SPLIT)
+        else {
+            return self
+                .primary
+                .get_object_range(bucket, key, start_byte, end_byte)
+                .await;
+        };
+        let split = self.split(start_byte, end_byte);
+        let stall = self.stall.as_ref();
+        let (first, second) = tokio::join!(
+            fetch(&self.primary, bucket, key, start_byte, split, stall),
+            fetch(
+                &self.mirror,
+                &mirror_bucket,
+                &mirror_key,
+                split + 1,
+                end_byte,
+                stall
+            )
+        );
+        let first = first?;
+        let second = match second {
+            Ok(second) if first.total.is_some() && second.total != first.total => {
+                println!(
+                    "Warning: the mirror's copy of {} differs in size, using the primary only",
+                    key
+                );
+                None
+            }
+            Ok(second) => Some(second),
+            Err(e) => {
+                println!("Warning: mirror failed, using the primary only: {}", e);
+                None
+            }
+        };
+        self.record_rates(Some(first.rate), second.as_ref().map(|second| second.rate));
+        let second = match second {
+            Some(second) => second,
+            None => fetch(&self.primary, bucket, key, split + 1, end_byte, stall).await?,
+        };
+
+        let mut bytes = first.bytes;
+        bytes.extend_from_slice(&second.bytes);
+        let content_range = first
+            .total
+            .map(|total| format!("bytes {}-{}/{}", start_byte, end_byte, total));
+        Ok(GetObjectOutput::builder()
+            .content_length(bytes.len() as i64)
+            .set_content_range(content_range)
+            .body(ByteStream::from(bytes))
+            .build())
+    }
+
+    fn md5_etags(self: &Self) -> bool {
+        self.primary.md5_etags()
+    }
+
+    async fn refresh_credentials(self: &Self) -> Result<bool> {
+        let primary = self.primary.refresh_credentials().await?;
+        let mirror = self.mirror.refresh_credentials().await?;
+        Ok(primary || mirror)
+    }
+
+    async fn presign_get(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String> {
+        self.primary.presign_get(bucket, key, expires_in).await
+    }
+
+    async fn presign_get_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String> {
+        self.primary
+            .presign_get_version(bucket, key, version_id, expires_in)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Source {
+        content: Vec<u8>,
+        fail: bool,
+        /// Never answer, like a source that went silent
+        hang: bool,
+        requests: AtomicUsize,
+    }
+
+    impl Source {
+        fn new(content: &[u8], fail: bool) -> Self {
+            Self {
+                content: content.to_vec(),
+                fail,
+                hang: false,
+                requests: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl S3ObjOps for Source {
+        async fn head_object(self: &Self, _: &str, _: &str) -> Result<HeadObjectOutput> {
+            Ok(HeadObjectOutput::builder()
+                .content_length(self.content.len() as i64)
+                .build())
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Ok(GetObjectOutput::builder()
+                .body(ByteStream::from(self.content.clone()))
+                .build())
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            start_byte: u64,
+            end_byte: u64,
+        ) -> Result<GetObjectOutput> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            if self.fail {
+                return Err(anyhow!("connection reset"));
+            }
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+            let range = self.content[start_byte as usize..=end_byte as usize].to_vec();
+            Ok(GetObjectOutput::builder()
+                .content_range(format!(
+                    "bytes {}-{}/{}",
+                    start_byte,
+                    end_byte,
+                    self.content.len()
+                ))
+                .body(ByteStream::from(range))
+                .build())
+        }
+    }
+
+    fn mirror(bucket: &str, key: &str) -> Option<(String, String)> {
+        Some((format!("{}-mirror", bucket), key.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_get_object_range() {
+        let content: Vec<u8> = (0..MIN_SPLIT * 4).map(|i| (i % 251) as u8).collect();
+        let last = content.len() as u64 - 1;
+        let sources = MultiSource::new(
+            Source::new(&content, false),
+            Source::new(&content, false),
+            mirror,
+        );
+        let response = sources.get_object_range("b", "k", 1, last).await.unwrap();
+        assert_eq!(
+            response.content_range(),
+            Some(format!("bytes 1-{}/{}", last, content.len()).as_str())
+        );
+        let bytes = response.body.collect().await.unwrap().to_vec();
+        assert_eq!(bytes, content[1..]);
+        assert_eq!(sources.mirror.requests.load(Ordering::SeqCst), 1);
+
+        // The primary stands in for a failing mirror
+        let sources = MultiSource::new(
+            Source::new(&content, false),
+            Source::new(&content, true),
+            mirror,
+        );
+        let response = sources.get_object_range("b", "k", 0, last).await.unwrap();
+        assert_eq!(response.body.collect().await.unwrap().to_vec(), content);
+        assert_eq!(sources.primary.requests.load(Ordering::SeqCst), 2);
+        let split = sources.split(0, last);
+        assert!(split > last / 2 && split < last);
+
+        // And for a silent one, once the stall window passes
+        let silent = Source {
+            hang: true,
+            ..Source::new(&content, false)
+        };
+        let stall = StallThreshold {
+            min_bytes_per_sec: 1024,
+            window: Duration::from_millis(50),
+        };
+        let sources =
+            MultiSource::new(Source::new(&content, false), silent, mirror).with_stall(Some(stall));
+        let response = sources.get_object_range("b", "k", 0, last).await.unwrap();
+        assert_eq!(response.body.collect().await.unwrap().to_vec(), content);
+        assert_eq!(sources.primary.requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,130 @@
+//! Notifying someone when an unattended download finishes, by email through an SMTP relay.
+//! Remote stations often only have email, via their satellite provider's store-and-forward link.
+use crate::download_plan::ExecutionSummary;
+use anyhow::{anyhow, Result};
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use std::env;
+
+/// Password for `EmailConfig::username`, kept out of the config file
+const SMTP_PASSWORD_ENV: &str = "SLOW_STAC_SMTP_PASSWORD";
+
+/// The `[email]` section of the config file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp_port: Option<u16>,
+    /// Upgrade the connection with STARTTLS. Local store-and-forward relays often don't offer it,
+    /// but a `username` is only sent with it.
+    #[serde(default)]
+    pub starttls: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+impl EmailConfig {
+    /// Fail for settings that would send the SMTP password in the clear
+    pub fn check(self: &Self) -> Result<()> {
+        if self.username.is_some() && !self.starttls {
+            return Err(anyhow!(
+                "Set starttls = true in [email] to log in to {}, the password would be sent in \
+                 the clear",
+                self.smtp_host
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Email the outcome of executing a plan, with the summary attached as a text file.
+pub async fn send_summary(
+    config: &EmailConfig,
+    plan_name: &str,
+    summary: &ExecutionSummary,
+) -> Result<()> {
+    config.check()?;
+    let email = summary_message(config, plan_name, summary)?;
+    let mut transport = match config.starttls {
+        true => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)?,
+        false => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host),
+    };
+    if let Some(port) = config.smtp_port {
+        transport = transport.port(port);
+    }
+    if let Some(username) = &config.username {
+        let password = env::var(SMTP_PASSWORD_ENV).unwrap_or_default();
+        transport = transport.credentials(Credentials::new(username.clone(), password));
+    }
+    transport.build().send(email).await?;
+    Ok(())
+}
+
+fn summary_message(
+    config: &EmailConfig,
+    plan_name: &str,
+    summary: &ExecutionSummary,
+) -> Result<Message> {
+    let outcome = match (&summary.error, summary.stalled.is_empty()) {
+        (Some(_), _) => "failed",
+        (None, false) => "stalled",
+        (None, true) => "completed",
+    };
+    let mut builder = Message::builder()
+        .from(config.from.parse()?)
+        .subject(format!("slow-stac: {} {}", plan_name, outcome));
+    for to in &config.to {
+        builder = builder.to(to.parse()?);
+    }
+    let text = summary.to_text();
+    let body = format!("Downloading {} {}.\n\n{}\n", plan_name, outcome, text);
+    let attachment = Attachment::new("summary.txt".to_string()).body(text, ContentType::TEXT_PLAIN);
+    Ok(builder.multipart(
+        MultiPart::mixed()
+            .singlepart(SinglePart::plain(body))
+            .singlepart(attachment),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_message() {
+        let config = EmailConfig {
+            smtp_host: "localhost".to_string(),
+            smtp_port: Some(2525),
+            starttls: false,
+            username: None,
+            from: "station@example.org".to_string(),
+            to: vec!["ops@example.org".to_string()],
+        };
+        let summary = ExecutionSummary {
+            completed: 3,
+            error: Some(anyhow!("Access denied")),
+            ..Default::default()
+        };
+        let message = summary_message(&config, "plan.json", &summary).unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("Subject: slow-stac: plan.json failed"));
+        assert!(formatted.contains("filename=\"summary.txt\""));
+
+        assert!(config.check().is_ok());
+        let plaintext = EmailConfig {
+            username: Some("station".to_string()),
+            ..config
+        };
+        assert!(plaintext.check().is_err());
+        let starttls = EmailConfig {
+            starttls: true,
+            ..plaintext
+        };
+        assert!(starttls.check().is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+//! Checkpoints of `.partial` downloads, so a download directory copied to another machine (or
+//! resumed after the object changed) is checked before more bytes are appended to it.
+//!
+//! Next to each `<output>.partial` a `<output>.partial.state` records the object being downloaded
+//! and a SHA-256 of the bytes received so far. Before resuming, the partial file must still start
+//! with those bytes and the object must still have the same size and ETag, otherwise the download
+//! restarts from the first byte rather than splicing two files together.
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartialState {
+    pub bucket: String,
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e_tag: Option<String>,
+    /// Size of the whole object
+    pub size: u64,
+    /// Bytes of the partial file covered by `sha256`
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// Whether a partial download can be resumed
+#[derive(Debug)]
+pub enum PartialCheck {
+    /// The checkpointed bytes are intact (or there is no checkpoint to check against). Carries
+    /// the hash of the whole partial file to continue from.
+    Resume(Sha256),
+    /// The partial file or the object no longer matches the checkpoint
+    Restart(String),
+}
+
+pub fn state_path(partial: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.state", partial.to_string_lossy()))
+}
+
+impl PartialState {
+    /// A checkpoint of the first `bytes` of an object, hashed by `hasher`
+    pub fn new(
+        bucket: &str,
+        key: &str,
+        e_tag: Option<&str>,
+        size: u64,
+        bytes: u64,
+        hasher: &Sha256,
+    ) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            e_tag: e_tag.map(|e_tag| e_tag.to_string()),
+            size,
+            bytes,
+            sha256: digest(hasher),
+        }
+    }
+
+    pub fn read(partial: &Path) -> Result<Option<Self>> {
+        let path = state_path(partial);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+    }
+
+    pub fn write(self: &Self, partial: &Path) -> Result<()> {
+        fs::write(state_path(partial), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Why the object described by the arguments differs from the checkpointed one, if it does
+    pub fn object_mismatch(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        e_tag: Option<&str>,
+        size: u64,
+    ) -> Option<String> {
+        if self.bucket != bucket || self.key != key {
+            return Some(format!(
+                "the partial file is of {}/{}, not {}/{}",
+                self.bucket, self.key, bucket, key
+            ));
+        }
+        if self.size != size {
+            return Some(format!(
+                "the object is now {} bytes, was {}",
+                size, self.size
+            ));
+        }
+        match (&self.e_tag, e_tag) {
+            (Some(was), Some(now)) if was != now => {
+                Some(format!("the object's ETag changed from {} to {}", was, now))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Check a partial file against its checkpoint, if it has one. Nothing is checked against the
+/// remote object; see `PartialState::object_mismatch`.
+pub fn check(partial: &Path) -> Result<PartialCheck> {
+    let state = PartialState::read(partial)?;
+    let len = fs::metadata(partial)?.len();
+    let mut hasher = Sha256::new();
+    let mut reader = File::open(partial)?;
+    if let Some(state) = &state {
+        if len < state.bytes {
+            return Ok(PartialCheck::Restart(format!(
+                "{} bytes were checkpointed but the partial file has {}, it was truncated",
+                state.bytes, len
+            )));
+        }
+        hash_bytes(&mut reader, state.bytes, &mut hasher)?;
+        if digest(&hasher) != state.sha256 {
+            return Ok(PartialCheck::Restart(
+                "the partial file does not match its checkpoint".to_string(),
+            ));
+        }
+    }
+    // Bytes written after the last checkpoint are kept, verification of the whole file catches
+    // any damage to them
+    let checked = state.map(|state| state.bytes).unwrap_or(0);
+    hash_bytes(&mut reader, len - checked, &mut hasher)?;
+    Ok(PartialCheck::Resume(hasher))
+}
+
+fn hash_bytes(reader: &mut impl Read, len: u64, hasher: &mut Sha256) -> Result<()> {
+    let mut buffer = vec![0; 1 << 16];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = reader.read(&mut buffer[..remaining.min(1 << 16) as usize])?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// Hex digest of everything hashed so far, leaving the hasher to continue
+pub fn digest(hasher: &Sha256) -> String {
+    hasher
+        .clone()
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Delete the checkpoint of a partial file, e.g. once the download completes
+pub fn remove_state(partial: &Path) -> Result<()> {
+    let path = state_path(partial);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let partial = Path::new("/tmp/partial_check.tif.partial");
+        fs::write(partial, b"0123").unwrap();
+        let mut hasher = Sha256::new();
+        hasher.update(b"0123");
+        let state = PartialState {
+            bucket: "bucket".to_string(),
+            key: "key".to_string(),
+            e_tag: Some("\"abc\"".to_string()),
+            size: 10,
+            bytes: 4,
+            sha256: digest(&hasher),
+        };
+        state.write(partial).unwrap();
+        assert_eq!(PartialState::read(partial).unwrap(), Some(state.clone()));
+
+        // Bytes appended after the checkpoint are kept
+        fs::write(partial, b"012345").unwrap();
+        let PartialCheck::Resume(resumed) = check(partial).unwrap() else {
+            panic!("expected the partial file to resume");
+        };
+        hasher.update(b"45");
+        assert_eq!(digest(&resumed), digest(&hasher));
+
+        fs::write(partial, b"0x2345").unwrap();
+        assert!(matches!(check(partial).unwrap(), PartialCheck::Restart(_)));
+        fs::write(partial, b"01").unwrap();
+        assert!(matches!(check(partial).unwrap(), PartialCheck::Restart(_)));
+
+        assert_eq!(
+            state.object_mismatch("bucket", "key", Some("\"abc\""), 10),
+            None
+        );
+        assert!(state
+            .object_mismatch("bucket", "key", Some("\"def\""), 10)
+            .is_some());
+        assert!(state.object_mismatch("bucket", "key", None, 11).is_some());
+
+        remove_state(partial).unwrap();
+        fs::remove_file(partial).unwrap();
+        assert!(!state_path(partial).exists());
+    }
+}
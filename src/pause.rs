@@ -0,0 +1,107 @@
+//! Pausing a plan between runs. `slow-stac pause` stops a download started with `--control` and
+//! records `<plan>.paused` with the command line it ran with and any backoff a host asked for;
+//! `slow-stac resume` waits out the backoff and runs the same download again, which resumes the
+//! partial files where they stopped (see `partial`). A paused plan isn't downloaded until it is
+//! resumed, so a scheduled service doesn't start it behind the operator's back: the download
+//! exits with `Paused`'s own exit code, which the service's unit doesn't restart on.
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// A download of a paused plan was refused
+#[derive(Debug, Error)]
+#[error("{0:?} is paused, continue it with `slow-stac resume`")]
+pub struct Paused(pub PathBuf);
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PauseState {
+    /// Unix time the plan was paused
+    pub paused_at: u64,
+    /// Arguments of the download that was stopped, empty if none was running
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Directory the download ran in, which relative paths in `args` are relative to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_directory: Option<PathBuf>,
+    /// Unix time before which a host asked for no more requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+}
+
+pub fn state_path(plan: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.paused", plan.to_string_lossy()))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl PauseState {
+    pub fn new(args: Vec<String>, not_before: Option<SystemTime>) -> Self {
+        Self {
+            paused_at: unix_time(SystemTime::now()),
+            args,
+            working_directory: None,
+            not_before: not_before.map(unix_time),
+        }
+    }
+
+    pub fn with_working_directory(self, working_directory: PathBuf) -> Self {
+        Self {
+            working_directory: Some(working_directory),
+            ..self
+        }
+    }
+
+    pub fn read(plan: &Path) -> Result<Option<Self>> {
+        let path = state_path(plan);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+    }
+
+    pub fn write(self: &Self, plan: &Path) -> Result<()> {
+        fs::write(state_path(plan), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn remove(plan: &Path) -> Result<()> {
+        fs::remove_file(state_path(plan))?;
+        Ok(())
+    }
+
+    /// Time left before the plan should be resumed
+    pub fn wait(self: &Self) -> Duration {
+        let now = unix_time(SystemTime::now());
+        Duration::from_secs(self.not_before.unwrap_or(now).saturating_sub(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_state() {
+        let plan = Path::new("/tmp/slow_stac_pause.json");
+        let _ = fs::remove_file(state_path(plan));
+        assert_eq!(PauseState::read(plan).unwrap(), None);
+
+        let args = vec!["download".to_string(), "plan.json".to_string()];
+        let backoff = SystemTime::now() + Duration::from_secs(120);
+        let state = PauseState::new(args, Some(backoff)).with_working_directory("/data".into());
+        state.write(plan).unwrap();
+        let read = PauseState::read(plan).unwrap().unwrap();
+        assert_eq!(read, state);
+        assert!(read.wait() > Duration::from_secs(110));
+        assert_eq!(PauseState::new(vec![], None).wait(), Duration::ZERO);
+        PauseState::remove(plan).unwrap();
+        assert!(!state_path(plan).exists());
+    }
+}
@@ -0,0 +1,433 @@
+//! Sharing downloaded files with other slow-stac instances on the local network, so a site behind
+//! one slow satellite link downloads each object over it once. `serve` answers plain HTTP requests
+//! for `/objects/<bucket>/<key>` from the completed outputs of some plans and from a download
+//! cache (see `cache`); downloads given peers ask each of them before the internet.
+//!
+//! The server speaks just enough HTTP/1.1 for the `http` helpers: GET and HEAD, single byte ranges,
+//! one request per connection. It listens on loopback unless given another address, and answers
+//! only requests bearing the token shared by the instances (`SLOW_STAC_PEER_TOKEN` or
+//! `peer_token` in the config file). The token travels in plain HTTP, so this is still for trusted
+//! networks.
+use crate::cache;
+use crate::download_plan::DownloadPlan;
+use crate::failure::TaskFailure;
+use crate::http;
+use crate::s3::S3ObjOps;
+use crate::user_agent;
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use reqwest::RequestBuilder;
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::instrument;
+
+/// Loopback only, `serve --bind 0.0.0.0:8470` shares with the local network
+pub const DEFAULT_BIND: &str = "127.0.0.1:8470";
+pub const TOKEN_VAR: &str = "SLOW_STAC_PEER_TOKEN";
+/// Peers are on the local network, so one that doesn't answer quickly is taken to be away
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// The files an instance shares, by bucket and key
+#[derive(Debug, Clone, Default)]
+pub struct Shared {
+    files: HashMap<(String, String), PathBuf>,
+    cache: Option<PathBuf>,
+    /// Bearer token requests must carry
+    token: Option<String>,
+}
+
+/// The shared token, from `SLOW_STAC_PEER_TOKEN` or else the config file
+pub fn token(configured: Option<&str>) -> Option<String> {
+    std::env::var(TOKEN_VAR)
+        .ok()
+        .or(configured.map(str::to_string))
+        .filter(|token| !token.is_empty())
+}
+
+impl Shared {
+    pub fn with_plan(mut self, plan: &DownloadPlan) -> Self {
+        for task in plan.tasks() {
+            let object = (task.bucket().to_string(), task.key().to_string());
+            self.files.insert(object, plan.output_path(task));
+        }
+        self
+    }
+
+    pub fn with_cache(mut self, cache: PathBuf) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Whether a request's `Authorization` header carries the token, compared in constant time
+    fn is_authorized(self: &Self, authorization: Option<&str>) -> bool {
+        let (Some(token), Some(authorization)) = (&self.token, authorization) else {
+            return false;
+        };
+        let Some(given) = authorization.strip_prefix("Bearer ") else {
+            return false;
+        };
+        given.len() == token.len()
+            && given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |differ, (a, b)| differ | (a ^ b))
+                == 0
+    }
+
+    /// The completed file holding an object, if there is one. Downloads in progress are written
+    /// to `.partial` files, so an output that exists is complete.
+    fn path(self: &Self, bucket: &str, key: &str) -> Option<PathBuf> {
+        let object = (bucket.to_string(), key.to_string());
+        if let Some(path) = self.files.get(&object).filter(|path| path.is_file()) {
+            return Some(path.clone());
+        }
+        // The bucket and key come from the request, which mustn't reach outside the cache
+        let climbs = bucket == ".." || key.split('/').any(|part| part == "..");
+        if bucket.is_empty() || bucket.contains('/') || climbs {
+            return None;
+        }
+        let cached = cache::cached_path(self.cache.as_ref()?, bucket, key);
+        cached.is_file().then_some(cached)
+    }
+}
+
+/// Serve the shared files until the listener fails. Fails at once without a token.
+pub async fn serve(listener: TcpListener, shared: Shared) -> Result<()> {
+    if shared.token.is_none() {
+        return Err(anyhow!(
+            "Set {} or peer_token in the config file to share files",
+            TOKEN_VAR
+        ));
+    }
+    let shared = Arc::new(shared);
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &shared).await {
+                println!("Warning: answering {} failed: {}", address, e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, shared: &Shared) -> Result<()> {
+    let head = read_head(&mut stream).await?;
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    let headers: Vec<_> = lines.filter_map(|line| line.split_once(':')).collect();
+    let header = |wanted: &str| {
+        headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.trim())
+    };
+    let range = header("range");
+
+    if !shared.is_authorized(header("authorization")) {
+        return write_status(&mut stream, "401 Unauthorized").await;
+    }
+    if method != "GET" && method != "HEAD" {
+        return write_status(&mut stream, "405 Method Not Allowed").await;
+    }
+    let path = target
+        .strip_prefix("/objects/")
+        .map(percent_decode)
+        .and_then(|path| {
+            let (bucket, key) = path.split_once('/')?;
+            shared.path(bucket, key)
+        });
+    let Some(path) = path else {
+        return write_status(&mut stream, "404 Not Found").await;
+    };
+    let mut file = tokio::fs::File::open(&path).await?;
+    let size = file.metadata().await?.len();
+    let (status, start, length, content_range) = match range {
+        None => ("200 OK", 0, size, None),
+        Some(range) => match byte_range(range, size) {
+            Some((start, end)) => (
+                "206 Partial Content",
+                start,
+                end + 1 - start,
+                Some(format!("bytes {}-{}/{}", start, end, size)),
+            ),
+            None => return write_status(&mut stream, "416 Range Not Satisfiable").await,
+        },
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n",
+        status, length
+    );
+    if let Some(content_range) = content_range {
+        response.push_str(&format!("Content-Range: {}\r\n", content_range));
+    }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    if method == "GET" {
+        file.seek(SeekFrom::Start(start)).await?;
+        tokio::io::copy(&mut file.take(length), &mut stream).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Err(anyhow!(
+                "Request head longer than {} bytes",
+                MAX_REQUEST_HEAD
+            ));
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(anyhow!("Connection closed before the end of the request"));
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+async fn write_status(stream: &mut TcpStream, status: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// First and last byte of a single range such as `bytes=0-1023`, `bytes=1024-` or `bytes=-512`,
+/// or None when it can't be satisfied
+fn byte_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let last = size.checked_sub(1)?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (size.checked_sub(suffix.parse().ok()?)?, last),
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(last)),
+    };
+    (start <= end).then_some((start, end))
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok());
+        match (
+            bytes[i],
+            hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+        ) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Another instance's shared files, e.g. `http://192.168.1.20:8470`
+#[derive(Debug)]
+pub struct Peer {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl Peer {
+    pub fn new(url: &str, token: Option<&str>) -> Self {
+        let url = match url.contains("://") {
+            true => url.trim_end_matches('/').to_string(),
+            false => format!("http://{}", url.trim_end_matches('/')),
+        };
+        Self {
+            client: user_agent::client_builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()
+                .expect("Client configuration should always be valid"),
+            url,
+            token: token.map(str::to_string),
+        }
+    }
+
+    pub fn url(self: &Self) -> &str {
+        &self.url
+    }
+
+    fn request(self: &Self, bucket: &str, key: &str) -> RequestBuilder {
+        let url = format!(
+            "{}/objects/{}/{}",
+            self.url,
+            bucket,
+            key.trim_start_matches('/')
+        );
+        match &self.token {
+            Some(token) => self.client.get(url).bearer_auth(token),
+            None => self.client.get(url),
+        }
+    }
+}
+
+/// The peers of a download. One that doesn't answer, or refuses the token, is left out for the rest
+/// of the run rather than waited for again before every object.
+#[derive(Debug, Default)]
+pub struct Peers {
+    peers: Vec<Peer>,
+    /// Indexes of the peers left out
+    away: Mutex<HashSet<usize>>,
+}
+
+impl Peers {
+    pub fn new(urls: &[String], token: Option<&str>) -> Self {
+        Self {
+            peers: urls.iter().map(|url| Peer::new(url, token)).collect(),
+            away: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn is_away(self: &Self, index: usize) -> bool {
+        self.away
+            .lock()
+            .expect("Peers lock poisoned")
+            .contains(&index)
+    }
+
+    /// The peers holding an object, in order
+    pub async fn holding(self: &Self, bucket: &str, key: &str) -> Vec<&Peer> {
+        let mut holding = vec![];
+        for (index, peer) in self.peers.iter().enumerate() {
+            if self.is_away(index) {
+                continue;
+            }
+            match peer.head_object(bucket, key).await {
+                Ok(_) => holding.push(peer),
+                Err(e) if TaskFailure::classify(&e) == TaskFailure::NotFound => {}
+                Err(e) => {
+                    println!(
+                        "Warning: leaving out peer {} for the rest of the run: {}",
+                        peer.url(),
+                        e
+                    );
+                    self.away.lock().expect("Peers lock poisoned").insert(index);
+                }
+            }
+        }
+        holding
+    }
+}
+
+impl S3ObjOps for Peer {
+    #[instrument(skip(self))]
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        http::head_object(self.request(bucket, key)).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        http::get_object(self.request(bucket, key)).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        http::get_object_range(self.request(bucket, key), start_byte, end_byte).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn shared_path(dir: &Path, bucket: &str, key: &str) -> Option<PathBuf> {
+        Shared::default()
+            .with_cache(dir.join("cache"))
+            .path(bucket, key)
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = Path::new("/tmp/slow_stac_peer");
+        let _ = fs::remove_dir_all(dir);
+        let cached = cache::cached_path(&dir.join("cache"), "bucket", "tiles/B04 10m.tif");
+        fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        fs::write(&cached, "0123456789").unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let peer = Peer::new(&address, Some("token"));
+        let shared = Shared::default()
+            .with_cache(dir.join("cache"))
+            .with_token("token");
+        tokio::spawn(serve(listener, shared));
+
+        let head = peer
+            .head_object("bucket", "tiles/B04 10m.tif")
+            .await
+            .unwrap();
+        assert_eq!(head.content_length(), Some(10));
+        let range = peer
+            .get_object_range("bucket", "tiles/B04 10m.tif", 2, 5)
+            .await
+            .unwrap();
+        let body = range.body.collect().await.unwrap().into_bytes();
+        assert_eq!(&body[..], b"2345");
+        assert!(peer.head_object("bucket", "tiles/B02.tif").await.is_err());
+        let intruder = Peer::new(&address, Some("guess"));
+        let refused = intruder.head_object("bucket", "tiles/B04 10m.tif").await;
+        assert!(format!("{:?}", refused.unwrap_err()).contains("401"));
+
+        let unreachable = "127.0.0.1:1".to_string();
+        let peers = Peers::new(&[unreachable, address], Some("token"));
+        for _ in 0..2 {
+            let holding = peers.holding("bucket", "tiles/B04 10m.tif").await;
+            assert_eq!(holding.len(), 1);
+            assert!(peers.is_away(0));
+        }
+        assert!(peers.holding("bucket", "tiles/B02.tif").await.is_empty());
+        assert!(!peers.is_away(1));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        assert!(serve(listener, Shared::default()).await.is_err());
+        assert_eq!(shared_path(dir, "..", "secret"), None);
+        assert_eq!(shared_path(dir, "bucket", "../../secret"), None);
+        assert_eq!(byte_range("bytes=-3", 10), Some((7, 9)));
+        assert_eq!(byte_range("bytes=10-", 10), None);
+    }
+}
@@ -0,0 +1,273 @@
+//! Checking a plan's keys before a long download, with `plan check`. Instead of a HEAD per object
+//! the directories of the planned keys are listed (see `S3ObjOps::list_objects`), a request per
+//! product rather than per band. The listing also turns up the auxiliary files next to the planned
+//! assets, e.g. metadata and quality masks the selection left out. Directories that cannot be
+//! listed, e.g. of HTTP providers, fall back to a HEAD per planned object.
+use crate::download_plan::DownloadPlan;
+use crate::failure::TaskFailure;
+use crate::s3::{ListedObject, S3ObjOps};
+use crate::{porcelain, units};
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct KeyCheck {
+    pub checked: usize,
+    /// Planned objects that aren't listed, as `bucket/key`
+    pub missing: Vec<String>,
+    /// Planned objects listed with another size than planned, as `bucket/key`
+    pub resized: Vec<String>,
+    /// Objects in the directories of planned ones that aren't planned, as `bucket/key` and size
+    pub auxiliary: Vec<(String, u64)>,
+}
+
+impl KeyCheck {
+    pub fn to_text(self: &Self) -> String {
+        let mut lines = vec![format!(
+            "{} objects checked, {} missing, {} changed size",
+            self.checked,
+            self.missing.len(),
+            self.resized.len()
+        )];
+        for object in &self.missing {
+            lines.push(format!("  missing: {}", object));
+        }
+        for object in &self.resized {
+            lines.push(format!("  changed size: {}", object));
+        }
+        if !self.auxiliary.is_empty() {
+            lines.push(format!(
+                "{} other objects next to the planned ones",
+                self.auxiliary.len()
+            ));
+        }
+        for (object, size) in &self.auxiliary {
+            lines.push(format!("  {} ({})", object, units::size(*size)));
+        }
+        lines.join("\n")
+    }
+
+    /// The check as `--porcelain` records: `checked` with the number of objects, then `missing`,
+    /// `resized` and `auxiliary` (with its size) per object
+    pub fn to_porcelain(self: &Self) -> String {
+        let mut lines = vec![porcelain::record("checked", &[&self.checked])];
+        for object in &self.missing {
+            lines.push(porcelain::record("missing", &[object]));
+        }
+        for object in &self.resized {
+            lines.push(porcelain::record("resized", &[object]));
+        }
+        for (object, size) in &self.auxiliary {
+            lines.push(porcelain::record("auxiliary", &[object, size]));
+        }
+        lines.join("\n")
+    }
+
+    pub fn print(self: &Self) {
+        match porcelain::enabled() {
+            true => println!("{}", self.to_porcelain()),
+            false => println!("{}", self.to_text()),
+        }
+    }
+}
+
+/// The directory of `key` with its trailing slash, empty for keys at the top of the bucket
+fn directory(key: &str) -> &str {
+    key.rfind('/').map_or("", |slash| &key[..=slash])
+}
+
+/// Each bucket and directory holding planned objects
+pub fn directories(plan: &DownloadPlan) -> BTreeSet<(String, String)> {
+    plan.tasks()
+        .iter()
+        .map(|task| (task.bucket().to_string(), directory(task.key()).to_string()))
+        .collect()
+}
+
+/// Compare the plan with the listings of its directories, keyed by bucket and directory
+pub fn check(
+    plan: &DownloadPlan,
+    listings: &HashMap<(String, String), Vec<ListedObject>>,
+) -> KeyCheck {
+    let mut listed = HashMap::new();
+    for ((bucket, _), objects) in listings {
+        for object in objects {
+            listed.insert((bucket.as_str(), object.key.as_str()), object.size);
+        }
+    }
+    let mut check = KeyCheck {
+        checked: plan.tasks().len(),
+        ..KeyCheck::default()
+    };
+    for task in plan.tasks() {
+        let object = format!("{}/{}", task.bucket(), task.key());
+        match (listed.get(&(task.bucket(), task.key())), task.size()) {
+            (None, _) => check.missing.push(object),
+            (Some(listed), Some(size)) if *listed != size => check.resized.push(object),
+            _ => {}
+        }
+    }
+    let planned: BTreeSet<_> = plan
+        .tasks()
+        .iter()
+        .map(|task| (task.bucket(), task.key()))
+        .collect();
+    let mut auxiliary = BTreeSet::new();
+    for ((bucket, dir), objects) in listings {
+        for object in objects {
+            // Listings include subdirectories, and the placeholders some tools create for them
+            if directory(&object.key) != dir
+                || object.key.ends_with('/')
+                || planned.contains(&(bucket.as_str(), object.key.as_str()))
+            {
+                continue;
+            }
+            auxiliary.insert((format!("{}/{}", bucket, object.key), object.size));
+        }
+    }
+    check.auxiliary = auxiliary.into_iter().collect();
+    check
+}
+
+/// The planned objects of a directory that a HEAD request finds, standing in for its listing
+async fn head_directory(
+    plan: &DownloadPlan,
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    dir: &str,
+) -> Result<Vec<ListedObject>> {
+    let mut objects = vec![];
+    for task in plan.tasks() {
+        if task.bucket() != bucket || directory(task.key()) != dir {
+            continue;
+        }
+        match provider.head_object(bucket, task.key()).await {
+            Ok(head) => objects.push(ListedObject {
+                key: task.key().to_string(),
+                size: head.content_length().unwrap_or(0).max(0) as u64,
+                e_tag: head.e_tag().map(|e_tag| e_tag.to_string()),
+            }),
+            Err(e) if TaskFailure::classify(&e) == TaskFailure::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(objects)
+}
+
+/// List the directories of the plan's objects with `provider` and check the plan against them
+pub async fn check_keys(plan: &DownloadPlan, provider: &impl S3ObjOps) -> Result<KeyCheck> {
+    let mut listings = HashMap::new();
+    for (bucket, dir) in directories(plan) {
+        let objects = match provider.list_objects(&bucket, &dir).await {
+            Ok(objects) => objects,
+            Err(e) => {
+                println!("Warning: {}, checking its objects one at a time", e);
+                head_directory(plan, provider, &bucket, &dir).await?
+            }
+        };
+        listings.insert((bucket, dir), objects);
+    }
+    Ok(check(plan, &listings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_plan::DownloadTask;
+    use anyhow::anyhow;
+    use aws_sdk_s3::operation::get_object::GetObjectOutput;
+    use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+
+    /// Serves HEAD requests for keys of B04 bands only, and cannot list
+    struct Heads;
+
+    impl S3ObjOps for Heads {
+        async fn head_object(self: &Self, _: &str, key: &str) -> Result<HeadObjectOutput> {
+            match key.ends_with("B04.tif") {
+                true => Ok(HeadObjectOutput::builder().content_length(4).build()),
+                false => Err(anyhow!("404 Not Found")),
+            }
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Err(anyhow!("Only heads are served"))
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            _: u64,
+            _: u64,
+        ) -> Result<GetObjectOutput> {
+            Err(anyhow!("Only heads are served"))
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("bucket", "S2A/B04.tif", "S2A/B04.tif").with_size(Some(4)),
+                DownloadTask::new("bucket", "S2A/B08.tif", "S2A/B08.tif").with_size(Some(8)),
+                DownloadTask::new("bucket", "S2B/B04.tif", "S2B/B04.tif"),
+            ],
+        );
+        let dirs: Vec<_> = directories(&plan).into_iter().collect();
+        assert_eq!(
+            dirs,
+            [
+                ("bucket".to_string(), "S2A/".to_string()),
+                ("bucket".to_string(), "S2B/".to_string())
+            ]
+        );
+
+        let object = |key: &str, size: u64| ListedObject {
+            key: key.to_string(),
+            size,
+            e_tag: None,
+        };
+        let listings = HashMap::from([
+            (
+                dirs[0].clone(),
+                vec![
+                    object("S2A/B04.tif", 4),
+                    object("S2A/B08.tif", 9),
+                    object("S2A/metadata.xml", 2048),
+                    object("S2A/qi/cloud_mask.tif", 10),
+                ],
+            ),
+            (dirs[1].clone(), vec![object("S2B/", 0)]),
+        ]);
+        let check = check(&plan, &listings);
+        assert_eq!(check.missing, ["bucket/S2B/B04.tif"]);
+        assert_eq!(check.resized, ["bucket/S2A/B08.tif"]);
+        assert_eq!(
+            check.auxiliary,
+            [("bucket/S2A/metadata.xml".to_string(), 2048)]
+        );
+        assert_eq!(
+            check.to_porcelain(),
+            "checked\t3\nmissing\tbucket/S2B/B04.tif\nresized\tbucket/S2A/B08.tif\n\
+             auxiliary\tbucket/S2A/metadata.xml\t2048"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_keys_without_listing() {
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("host", "S2A/B04.tif", "S2A/B04.tif").with_size(Some(4)),
+                DownloadTask::new("host", "S2A/B08.tif", "S2A/B08.tif"),
+                DownloadTask::new("host", "S2B/B04.tif", "S2B/B04.tif").with_size(Some(5)),
+            ],
+        );
+        let check = check_keys(&plan, &Heads).await.unwrap();
+        assert_eq!(check.checked, 3);
+        assert_eq!(check.missing, ["host/S2A/B08.tif"]);
+        assert_eq!(check.resized, ["host/S2B/B04.tif"]);
+        assert!(check.auxiliary.is_empty());
+    }
+}
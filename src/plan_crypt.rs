@@ -0,0 +1,128 @@
+//! Passphrase encryption of plan files with age, so plans carrying presigned URLs or other
+//! temporary credentials can be copied between machines and shared safely. A plan is encrypted
+//! when its file name ends in `.age`, e.g. `plan.json.age`; the extension before it selects the
+//! plan format as usual.
+use age::secrecy::SecretString;
+use anyhow::{anyhow, Result};
+use std::env;
+use std::io::{Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+/// Passphrase used instead of prompting, e.g. for unattended downloads
+const PASSPHRASE_ENV: &str = "SLOW_STAC_PLAN_PASSPHRASE";
+const EXTENSION: &str = "age";
+/// Query parameters of presigned S3 and GCS URLs and Azure SAS tokens
+const SIGNED_URL_MARKERS: [&str; 4] = [
+    "X-Amz-Signature=",
+    "X-Goog-Signature=",
+    "sig=",
+    "Signature=",
+];
+
+pub fn is_encrypted(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some(EXTENSION)
+}
+
+/// The path without its `.age` extension, which gives the format of the encrypted plan
+pub fn inner_path(path: &Path) -> PathBuf {
+    match is_encrypted(path) {
+        true => path.with_extension(""),
+        false => path.to_path_buf(),
+    }
+}
+
+/// Whether `content` is encrypted, by the header of age files
+pub fn is_ciphertext(content: &[u8]) -> bool {
+    content.starts_with(b"age-encryption.org/")
+}
+
+/// `path` with `.age` appended, e.g. `plan.json` to `plan.json.age`
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", EXTENSION));
+    PathBuf::from(name)
+}
+
+/// Whether a URL carries a signature granting temporary access
+pub fn has_credentials(url: &str) -> bool {
+    let Some((_, query)) = url.split_once('?') else {
+        return false;
+    };
+    SIGNED_URL_MARKERS
+        .iter()
+        .any(|marker| query.split('&').any(|param| param.starts_with(marker)))
+}
+
+/// The plan passphrase from `SLOW_STAC_PLAN_PASSPHRASE`, otherwise prompted for on the terminal.
+pub fn passphrase() -> Result<SecretString> {
+    let passphrase = match env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => rpassword::prompt_password("Plan passphrase: ")?,
+    };
+    if passphrase.is_empty() {
+        return Err(anyhow!("The plan passphrase is empty"));
+    }
+    Ok(SecretString::from(passphrase))
+}
+
+pub fn encrypt(plaintext: &[u8], passphrase: SecretString) -> Result<Vec<u8>> {
+    let encryptor = age::Encryptor::with_user_passphrase(passphrase);
+    let mut ciphertext = vec![];
+    let mut writer = encryptor.wrap_output(&mut ciphertext)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?;
+    Ok(ciphertext)
+}
+
+pub fn decrypt(ciphertext: &[u8], passphrase: SecretString) -> Result<Vec<u8>> {
+    let decryptor = age::Decryptor::new_buffered(ciphertext)?;
+    if !decryptor.is_scrypt() {
+        return Err(anyhow!("The plan is not encrypted with a passphrase"));
+    }
+    let identity = age::scrypt::Identity::new(passphrase);
+    let mut reader = decryptor
+        .decrypt(iter::once(&identity as &dyn age::Identity))
+        .map_err(|e| anyhow!("Could not decrypt the plan: {}", e))?;
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let plan = b"{\"selection_id\": \"provider.collection\", \"tasks\": []}";
+        let ciphertext = encrypt(plan, SecretString::from("correct horse".to_string())).unwrap();
+        assert_ne!(ciphertext.as_slice(), plan.as_slice());
+        assert!(is_ciphertext(&ciphertext));
+        assert!(!is_ciphertext(plan));
+
+        let wrong = decrypt(
+            &ciphertext,
+            SecretString::from("battery staple".to_string()),
+        );
+        assert!(wrong.is_err());
+        let plaintext = decrypt(&ciphertext, SecretString::from("correct horse".to_string()));
+        assert_eq!(plaintext.unwrap(), plan);
+    }
+
+    #[test]
+    fn test_paths_and_credentials() {
+        let path = Path::new("/tmp/plan.json.age");
+        assert!(is_encrypted(path));
+        assert_eq!(inner_path(path), Path::new("/tmp/plan.json"));
+        assert_eq!(encrypted_path(Path::new("/tmp/plan.json")), path);
+
+        assert!(has_credentials(
+            "https://bucket.s3.amazonaws.com/key.tif?X-Amz-Expires=3600&X-Amz-Signature=abc"
+        ));
+        assert!(has_credentials(
+            "https://account.blob.core.windows.net/c/b.tif?sv=2021&sig=abc"
+        ));
+        assert!(!has_credentials("https://example.com/b.tif?design=1"));
+    }
+}
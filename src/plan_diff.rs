@@ -0,0 +1,315 @@
+//! Comparing two plans of the same selection, e.g. before and after re-preparing an edited
+//! selection, to see exactly which files the new plan fetches that the old one didn't. Tasks are
+//! matched by their output, since that's where a file lands whichever bucket or key it comes from.
+//!
+//! Re-preparing into an output directory that already has a plan reconciles the two, so the
+//! selection file stays the single source of truth as it's edited.
+use crate::download_plan::{DownloadPlan, DownloadTask};
+use crate::{porcelain, presign, units};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
+/// A task whose source or size differs between the plans
+#[derive(Debug, Clone)]
+pub struct Changed {
+    pub before: DownloadTask,
+    pub after: DownloadTask,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PlanDiff {
+    /// Tasks only in the second plan
+    pub added: Vec<DownloadTask>,
+    /// Tasks only in the first plan
+    pub removed: Vec<DownloadTask>,
+    pub changed: Vec<Changed>,
+}
+
+fn by_output(plan: &DownloadPlan) -> BTreeMap<&str, &DownloadTask> {
+    plan.tasks()
+        .iter()
+        .map(|task| (task.output(), task))
+        .collect()
+}
+
+fn size(task: &DownloadTask) -> String {
+    match task.size() {
+        Some(size) => units::size(size),
+        None => "unknown size".to_string(),
+    }
+}
+
+/// The tasks added, removed and changed going from plan `a` to plan `b`, sorted by output
+pub fn diff(a: &DownloadPlan, b: &DownloadPlan) -> PlanDiff {
+    let (before, after) = (by_output(a), by_output(b));
+    let mut diff = PlanDiff::default();
+    for (output, task) in before.iter() {
+        match after.get(output) {
+            None => diff.removed.push((*task).clone()),
+            Some(other) => {
+                if task.bucket() != other.bucket()
+                    || task.key() != other.key()
+                    || task.size() != other.size()
+                {
+                    diff.changed.push(Changed {
+                        before: (*task).clone(),
+                        after: (*other).clone(),
+                    });
+                }
+            }
+        }
+    }
+    diff.added = after
+        .iter()
+        .filter(|(output, _)| !before.contains_key(*output))
+        .map(|(_, task)| (*task).clone())
+        .collect();
+    diff
+}
+
+/// The `prepared` plan, keeping the tasks of the `existing` plan at the same outputs that have
+/// already downloaded even if their source has changed since, and its diff from the existing
+/// plan. Tasks of the existing plan that are no longer prepared are left out of the plan and
+/// listed as `removed` in the diff. An existing plan routed to another source or presigned is
+/// refused, as its buckets and keys don't mean what the prepared plan's do.
+pub fn reconcile(
+    existing: &DownloadPlan,
+    prepared: DownloadPlan,
+) -> Result<(DownloadPlan, PlanDiff)> {
+    if existing.selection_id != prepared.selection_id {
+        return Err(anyhow!(
+            "The existing plan is for {}, not {}",
+            existing.selection_id,
+            prepared.selection_id
+        ));
+    }
+    if let Some(source) = existing.source() {
+        let state = match source {
+            presign::SOURCE => "presigned".to_string(),
+            _ => format!("routed to {}", source),
+        };
+        return Err(anyhow!(
+            "The existing plan is {}, move it aside to prepare the selection again",
+            state
+        ));
+    }
+    let done: BTreeMap<&str, &DownloadTask> = by_output(existing)
+        .into_iter()
+        .filter(|(_, task)| existing.is_done(task))
+        .collect();
+    let tasks = prepared
+        .tasks()
+        .iter()
+        .map(|task| match done.get(task.output()) {
+            Some(existing) => (*existing).clone(),
+            None => task.clone(),
+        })
+        .collect();
+    let plan = DownloadPlan::new(&prepared.selection_id, tasks);
+    let plan = match prepared.root() {
+        Some(root) => plan.with_root(root),
+        None => plan,
+    };
+    let diff = diff(existing, &plan);
+    Ok((plan, diff))
+}
+
+impl PlanDiff {
+    pub fn is_empty(self: &Self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Bytes the second plan fetches that the first didn't: its added tasks and the new versions
+    /// of changed ones. Tasks planned without a size aren't counted.
+    pub fn extra_bytes(self: &Self) -> u64 {
+        let added = self
+            .added
+            .iter()
+            .filter_map(|task| task.size())
+            .sum::<u64>();
+        let changed = self
+            .changed
+            .iter()
+            .filter_map(|changed| changed.after.size())
+            .sum::<u64>();
+        added + changed
+    }
+
+    pub fn to_text(self: &Self) -> String {
+        if self.is_empty() {
+            return "The plans have the same tasks".to_string();
+        }
+        let mut lines = vec![];
+        for task in self.added.iter() {
+            lines.push(format!(
+                "+ {}  s3://{}/{}  {}",
+                task.output(),
+                task.bucket(),
+                task.key(),
+                size(task)
+            ));
+        }
+        for task in self.removed.iter() {
+            lines.push(format!(
+                "- {}  s3://{}/{}  {}",
+                task.output(),
+                task.bucket(),
+                task.key(),
+                size(task)
+            ));
+        }
+        for Changed { before, after } in self.changed.iter() {
+            lines.push(format!("~ {}", after.output()));
+            if (before.bucket(), before.key()) != (after.bucket(), after.key()) {
+                lines.push(format!(
+                    "    s3://{}/{} -> s3://{}/{}",
+                    before.bucket(),
+                    before.key(),
+                    after.bucket(),
+                    after.key()
+                ));
+            }
+            if before.size() != after.size() {
+                lines.push(format!("    {} -> {}", size(before), size(after)));
+            }
+        }
+        lines.push(String::new());
+        lines.push(format!(
+            "{} added, {} removed, {} changed, {} to fetch",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            units::size(self.extra_bytes())
+        ));
+        lines.join("\n")
+    }
+
+    /// The diff as `--porcelain` records: `added` and `removed` with the task's output, bucket,
+    /// key and size, `changed` with its output followed by the bucket, key and size before and
+    /// after
+    pub fn to_porcelain(self: &Self) -> String {
+        let fields = |task: &DownloadTask| {
+            let size = porcelain::optional(task.size());
+            [task.bucket().to_string(), task.key().to_string(), size]
+        };
+        let mut lines = vec![];
+        for (kind, tasks) in [("added", &self.added), ("removed", &self.removed)] {
+            for task in tasks.iter() {
+                let [bucket, key, size] = fields(task);
+                lines.push(porcelain::record(
+                    kind,
+                    &[&task.output(), &bucket, &key, &size],
+                ));
+            }
+        }
+        for Changed { before, after } in self.changed.iter() {
+            let [bucket, key, size] = fields(before);
+            let [new_bucket, new_key, new_size] = fields(after);
+            lines.push(porcelain::record(
+                "changed",
+                &[
+                    &after.output(),
+                    &bucket,
+                    &key,
+                    &size,
+                    &new_bucket,
+                    &new_key,
+                    &new_size,
+                ],
+            ));
+        }
+        lines.join("\n")
+    }
+
+    pub fn print(self: &Self) {
+        match porcelain::enabled() {
+            true => println!("{}", self.to_porcelain()),
+            false => println!("{}", self.to_text()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let task = |key: &str, output: &str, size: u64| {
+            DownloadTask::new("bucket", key, output).with_size(Some(size))
+        };
+        let a = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                task("A/B04.tif", "A/B04.tif", 100),
+                task("A/B08.tif", "A/B08.tif", 100),
+                task("B/B04.tif", "B/B04.tif", 100),
+            ],
+        );
+        let b = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                task("A/B04.tif", "A/B04.tif", 100),
+                task("A/B08_v2.tif", "A/B08.tif", 300),
+                task("C/B04.tif", "C/B04.tif", 1024 * 1024),
+            ],
+        );
+        let diff = diff(&a, &b);
+        assert_eq!(diff.added[0].output(), "C/B04.tif");
+        assert_eq!(diff.removed[0].output(), "B/B04.tif");
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].after.key(), "A/B08_v2.tif");
+        assert_eq!(diff.extra_bytes(), 1024 * 1024 + 300);
+        assert!(diff
+            .to_text()
+            .ends_with("1 added, 1 removed, 1 changed, 1.0 MiB to fetch"));
+        assert_eq!(
+            diff.to_porcelain().lines().last().unwrap(),
+            "changed\tA/B08.tif\tbucket\tA/B08.tif\t100\tbucket\tA/B08_v2.tif\t300"
+        );
+        assert!(super::diff(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let root = std::path::Path::new("/tmp/slow_stac_reconcile");
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(root.join("A")).unwrap();
+        std::fs::write(root.join("A/B04.tif"), "b04").unwrap();
+        let plan = |tasks: &[(&str, &str)]| {
+            let tasks = tasks
+                .iter()
+                .map(|(key, output)| DownloadTask::new("bucket", key, output))
+                .collect();
+            DownloadPlan::new("provider.collection", tasks).with_root(root)
+        };
+        let existing = plan(&[
+            ("v1/A/B04.tif", "A/B04.tif"),
+            ("v1/A/B08.tif", "A/B08.tif"),
+            ("v1/B/B04.tif", "B/B04.tif"),
+        ]);
+        let prepared = plan(&[
+            ("v2/A/B04.tif", "A/B04.tif"),
+            ("v2/A/B08.tif", "A/B08.tif"),
+            ("v2/C/B04.tif", "C/B04.tif"),
+        ]);
+        let (plan, diff) = reconcile(&existing, prepared).unwrap();
+        let keys: Vec<_> = plan.tasks().iter().map(|task| task.key()).collect();
+        // The downloaded file keeps its task, the pending one is replaced
+        assert_eq!(keys, ["v1/A/B04.tif", "v2/A/B08.tif", "v2/C/B04.tif"]);
+        assert_eq!(plan.root(), Some("/tmp/slow_stac_reconcile"));
+        assert_eq!(diff.removed[0].output(), "B/B04.tif");
+        assert!(diff
+            .to_porcelain()
+            .contains("removed\tB/B04.tif\tbucket\tv1/B/B04.tif\t"));
+        assert_eq!(diff.added[0].output(), "C/B04.tif");
+        assert_eq!(diff.changed[0].after.output(), "A/B08.tif");
+
+        let routed = existing
+            .routed_to("mirror", |bucket, key| {
+                Some((format!("{}-mirror", bucket), key.to_string()))
+            })
+            .unwrap();
+        assert!(reconcile(&routed, plan.clone()).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Landsat Collection 2 Level-2 from Microsoft Planetary Computer. Assets are unsigned Azure Blob
+//! Storage urls, signed with a SAS token by the `Provider` as they are downloaded, so plans never
+//! hold tokens that expire before they run.
+use crate::download_plan::{DownloadPlan, DownloadTask};
+use crate::image_selection::{expand_products, ImageSelection, Product};
+use crate::prepare::PrepareOptions;
+use crate::resolve::{file_checksum, file_size, resolve_asset, Location, Transport};
+use crate::search::search_items;
+use crate::user_agent;
+use anyhow::{anyhow, Result};
+use stac::{Asset, Item};
+use std::path::{Path, PathBuf};
+use toml;
+use tracing::instrument;
+
+pub const STAC_API: &str = "https://planetarycomputer.microsoft.com/api/stac/v1";
+pub const COLLECTION_ID: &str = "landsat-c2-l2";
+
+pub fn image_selection_toml() -> toml::Table {
+    toml::toml! {
+        id = "planetarycomputer.landsatc2l2"
+
+        provider = "Microsoft Planetary Computer"
+
+        name = "Landsat Collection 2 Level-2 Surface Reflectance and Surface Temperature"
+
+        description = "Landsat Collection 2 Level-2 products provide atmospherically corrected Surface\n\
+        Reflectance and Surface Temperature from the Landsat 4-5 TM, 7 ETM+ and 8-9 OLI/TIRS\n\
+        sensors, as Cloud Optimized GeoTIFFs per band. Planetary Computer serves them from Azure\n\
+        without an AWS account, unlike the requester-pays USGS bucket."
+
+        docs = "https://planetarycomputer.microsoft.com/dataset/landsat-c2-l2"
+
+        ids_to_download = [
+            "LC09_L2SP_047027_20240504_02_T1",
+        ]
+
+        [[products]]
+        id = "red"
+        name = "Red"
+        download = true
+
+        [[products]]
+        id = "green"
+        name = "Green"
+        download = true
+
+        [[products]]
+        id = "blue"
+        name = "Blue"
+        download = true
+
+        [[products]]
+        id = "nir08"
+        name = "NIR"
+        download = false
+
+        [[products]]
+        id = "swir16"
+        name = "SWIR 1.6"
+        download = false
+
+        [[products]]
+        id = "qa_pixel"
+        name = "Pixel Quality Assessment"
+        download = false
+    }
+}
+
+/// The curated template with products generated from the collection's `item_assets`, falling back
+/// to the curated products when the catalogue can't be reached.
+pub async fn image_selection() -> ImageSelection {
+    ImageSelection::from_template(&image_selection_toml())
+        .with_item_assets(STAC_API, COLLECTION_ID)
+        .await
+}
+
+pub async fn generate_download_plan(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+) -> Result<DownloadPlan> {
+    generate_download_plan_with(selection, output_dir, &PrepareOptions::default()).await
+}
+
+/// Generate a plan, fetching the selection's items as `options` set out
+#[instrument(skip_all, fields(selection = %selection.id))]
+pub async fn generate_download_plan_with(
+    selection: &ImageSelection,
+    output_dir: PathBuf,
+    options: &PrepareOptions,
+) -> Result<DownloadPlan> {
+    let stac_api = selection.stac_api(STAC_API);
+    match (selection.ids_to_download(), &selection.search) {
+        (Some(ids_to_download), _) => {
+            let products_to_download = selection
+                .products_to_download()
+                .ok_or(anyhow!("No products selected for download"))?;
+            let products = &products_to_download;
+            let inputs = ids_to_download
+                .into_iter()
+                .map(|id| (id.clone(), id))
+                .collect();
+            let tasks = options
+                .resolve(selection, inputs, |id: String| async move {
+                    let item = fetch_single_item(stac_api, COLLECTION_ID, &id).await?;
+                    item_tasks(&item, products)
+                })
+                .await?;
+            Ok(DownloadPlan::new(&selection.id, tasks).with_root(&output_dir))
+        }
+        (None, Some(search)) => {
+            let items = search_items(stac_api, COLLECTION_ID, search).await?;
+            generate_download_plan_from_items(selection, &items, output_dir)
+        }
+        (None, None) => Err(anyhow!("No ids to download or search defined")),
+    }
+}
+
+/// Generate a plan for STAC Items that have already been retrieved (e.g. an ItemCollection saved
+/// from a search), ignoring the selection's `ids_to_download`.
+#[instrument(skip_all, fields(selection = %selection.id))]
+pub fn generate_download_plan_from_items(
+    selection: &ImageSelection,
+    items: &[Item],
+    output_dir: PathBuf,
+) -> Result<DownloadPlan> {
+    let products_to_download = selection
+        .products_to_download()
+        .ok_or(anyhow!("No products selected for download"))?;
+
+    let mut tasks: Vec<DownloadTask> = vec![];
+    for item in items {
+        tasks.extend(item_tasks(item, &products_to_download)?);
+    }
+    Ok(DownloadPlan::new(&selection.id, tasks).with_root(&output_dir))
+}
+
+/// The tasks downloading an item's selected assets
+fn item_tasks(item: &Item, products_to_download: &[Product]) -> Result<Vec<DownloadTask>> {
+    let mut tasks: Vec<DownloadTask> = vec![];
+    let assets = map_products_to_assets(item, products_to_download)?;
+    for (product, asset) in assets {
+        let (host, path) = match resolve_asset(&asset, Transport::Https)? {
+            Location::Https { host, path } => (host, path),
+            _ => return Err(anyhow!("No https location found for asset: {}", asset.href)),
+        };
+
+        let file_name = Path::new(&path).file_name().unwrap();
+        let output = Path::new(&item.id).join(file_name);
+
+        let task = DownloadTask::new(&host, &path, output.to_str().unwrap())
+            .with_size(file_size(&asset))
+            .with_checksum(file_checksum(&asset))
+            .with_asset_key(&product.id)
+            .with_overviews_only(product.overviews_only)
+            .with_item(item);
+        tasks.push(task)
+    }
+    Ok(tasks)
+}
+
+#[instrument(skip(stac_api))]
+async fn fetch_single_item(stac_api: &str, collection: &str, id: &str) -> Result<Item> {
+    let url = format!("{stac_api}/collections/{collection}/items/{id}");
+    let item = user_agent::get(url).await?.json::<Item>().await?;
+    Ok(item)
+}
+
+fn map_products_to_assets(item: &Item, products: &[Product]) -> Result<Vec<(Product, Asset)>> {
+    let keys = item.assets.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+    let mut assets = vec![];
+    for product in expand_products(&item.id, products, &keys)? {
+        let asset = item.assets.get(&product.id).ok_or(anyhow!(
+            "Did not find matching asset for product {} in {}",
+            product.id,
+            item.id
+        ))?;
+        assets.push((product, asset.clone()));
+    }
+    Ok(assets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_item_tasks() {
+        let href =
+            "https://landsateuwest.blob.core.windows.net/landsat-c2/level-2/standard/oli-tirs/\
+                    2024/047/027/LC09_L2SP_047027_20240504_20240505_02_T1/\
+                    LC09_L2SP_047027_20240504_20240505_02_T1_SR_B4.TIF";
+        let item: Item = serde_json::from_value(json!({
+            "type": "Feature",
+            "stac_version": "1.0.0",
+            "id": "LC09_L2SP_047027_20240504_02_T1",
+            "geometry": null,
+            "properties": {"datetime": "2024-05-04T18:52:11Z"},
+            "links": [],
+            "assets": {"red": {"href": href}}
+        }))
+        .unwrap();
+        let selection = ImageSelection::from_template(&image_selection_toml());
+        let products = selection.products_to_download().unwrap();
+        let tasks = item_tasks(&item, &products[..1]).unwrap();
+        assert_eq!(tasks[0].bucket(), "landsateuwest.blob.core.windows.net");
+        assert!(tasks[0].key().starts_with("landsat-c2/level-2/"));
+        assert_eq!(
+            tasks[0].output(),
+            "LC09_L2SP_047027_20240504_02_T1/LC09_L2SP_047027_20240504_20240505_02_T1_SR_B4.TIF"
+        );
+    }
+}
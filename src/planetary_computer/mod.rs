@@ -0,0 +1,4 @@
+pub mod landsat_c2_l2;
+mod provider;
+
+pub use provider::Provider;
@@ -0,0 +1,268 @@
+use crate::s3::ListedObject;
+use crate::{http, s3, user_agent};
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::instrument;
+
+const SAS_URL: &str = "https://planetarycomputer.microsoft.com/api/sas/v1/token";
+/// Header raising the token endpoint's rate limits for registered users
+const SUBSCRIPTION_KEY_HEADER: &str = "Ocp-Apim-Subscription-Key";
+/// How long a token is reused for. Tokens are valid for at least an hour, so downloads resuming
+/// after an auth error never hold one much past its expiry.
+const TOKEN_REUSE: Duration = Duration::from_secs(30 * 60);
+
+/// Serves downloads from the Azure Blob Storage behind Microsoft Planetary Computer, signing each
+/// request with a SAS token issued anonymously by the Planetary Computer token endpoint.
+///
+/// Tasks for this provider store the storage account host (e.g.
+/// `landsateuwest.blob.core.windows.net`) as the `bucket` and the url path, starting with the
+/// container, as the `key`. A token is issued per account and container and cached.
+pub struct Provider {
+    client: Client,
+    /// Optional `PC_SDK_SUBSCRIPTION_KEY`
+    subscription_key: Option<String>,
+    tokens: Mutex<HashMap<String, SasToken>>,
+}
+
+struct SasToken {
+    /// Query string to append to blob urls
+    value: String,
+    fetched_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: String,
+}
+
+impl Provider {
+    pub fn new(subscription_key: Option<String>) -> Self {
+        Self {
+            client: user_agent::client(),
+            subscription_key,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Anonymous, or with the subscription key in `PC_SDK_SUBSCRIPTION_KEY` if it is set
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("PC_SDK_SUBSCRIPTION_KEY").ok())
+    }
+
+    async fn request(self: &Self, bucket: &str, key: &str) -> Result<RequestBuilder> {
+        let token = self.sas_token(bucket, key).await?;
+        let url = format!("https://{}/{}?{}", bucket, key, token);
+        Ok(self.client.get(url))
+    }
+
+    /// One page of Azure's List Blobs of the container `key` starts with, from `marker` on
+    async fn list_page(
+        self: &Self,
+        bucket: &str,
+        prefix: &str,
+        marker: Option<&str>,
+    ) -> Result<(Vec<ListedObject>, Option<String>)> {
+        let (container, blob_prefix) = prefix.split_once('/').unwrap_or((prefix, ""));
+        let token = self.sas_token(bucket, &format!("{}/", container)).await?;
+        let mut request = self
+            .client
+            .get(format!("https://{}/{}?{}", bucket, container, token))
+            .query(&[
+                ("restype", "container"),
+                ("comp", "list"),
+                ("prefix", blob_prefix),
+            ]);
+        if let Some(marker) = marker {
+            request = request.query(&[("marker", marker)]);
+        }
+        let listing = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| without_token(e.into()))?
+            .text()
+            .await?;
+        parse_blob_list(container, &listing)
+    }
+
+    /// The cached token of the blob's account and container, fetching one if it is missing or old
+    async fn sas_token(self: &Self, bucket: &str, key: &str) -> Result<String> {
+        let account = bucket
+            .strip_suffix(".blob.core.windows.net")
+            .ok_or(anyhow!("{} is not an Azure Blob Storage host", bucket))?;
+        let container = key
+            .split_once('/')
+            .map(|(container, _)| container)
+            .ok_or(anyhow!("No container found in {}", key))?;
+        let scope = format!("{}/{}", account, container);
+        let mut tokens = self.tokens.lock().await;
+        if let Some(token) = tokens.get(&scope) {
+            if token.fetched_at.elapsed() < TOKEN_REUSE {
+                return Ok(token.value.clone());
+            }
+        }
+        let mut request = self.client.get(format!("{}/{}", SAS_URL, scope));
+        if let Some(subscription_key) = &self.subscription_key {
+            request = request.header(SUBSCRIPTION_KEY_HEADER, subscription_key);
+        }
+        let response = request
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(|e| anyhow!("Planetary Computer token request failed: {}", e))?
+            .json::<TokenResponse>()
+            .await?;
+        tokens.insert(
+            scope,
+            SasToken {
+                value: response.token.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+        Ok(response.token)
+    }
+}
+
+/// The error with the urls it mentions cut before their query, which holds the SAS token
+fn without_token(e: anyhow::Error) -> anyhow::Error {
+    match e.downcast::<reqwest::Error>() {
+        Ok(e) => e.without_url().into(),
+        Err(e) => e,
+    }
+}
+
+/// The blobs of a List Blobs response as objects keyed `<container>/<blob>`, and the marker of the
+/// next page
+fn parse_blob_list(container: &str, listing: &str) -> Result<(Vec<ListedObject>, Option<String>)> {
+    let doc = roxmltree::Document::parse(listing)?;
+    let child_text = |node: roxmltree::Node, name: &str| -> Option<String> {
+        let child = node.children().find(|child| child.has_tag_name(name))?;
+        Some(child.text().unwrap_or_default().to_string())
+    };
+    let mut objects = vec![];
+    for blob in doc.descendants().filter(|node| node.has_tag_name("Blob")) {
+        let name = child_text(blob, "Name").ok_or(anyhow!("Blob without a name in listing"))?;
+        let properties = blob
+            .children()
+            .find(|child| child.has_tag_name("Properties"));
+        let property = |name: &str| properties.and_then(|properties| child_text(properties, name));
+        objects.push(ListedObject {
+            key: format!("{}/{}", container, name),
+            size: property("Content-Length")
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(0),
+            e_tag: property("Etag"),
+        });
+    }
+    let next_marker =
+        child_text(doc.root_element(), "NextMarker").filter(|marker| !marker.is_empty());
+    Ok((objects, next_marker))
+}
+
+impl s3::S3ObjOps for Provider {
+    #[instrument(skip(self))]
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        http::head_object(self.request(bucket, key).await?)
+            .await
+            .map_err(without_token)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        http::get_object(self.request(bucket, key).await?)
+            .await
+            .map_err(without_token)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        http::get_object_range(self.request(bucket, key).await?, start_byte, end_byte)
+            .await
+            .map_err(without_token)
+    }
+
+    /// With Azure's List Blobs, `prefix` starting with the container
+    #[instrument(skip(self))]
+    async fn list_objects(self: &Self, bucket: &str, prefix: &str) -> Result<Vec<ListedObject>> {
+        let mut objects = vec![];
+        let mut marker = None;
+        loop {
+            let (page, next_marker) = self.list_page(bucket, prefix, marker.as_deref()).await?;
+            objects.extend(page);
+            marker = match next_marker {
+                Some(next_marker) => Some(next_marker),
+                None => return Ok(objects),
+            };
+        }
+    }
+
+    /// Drop the cached tokens so the next request fetches new ones.
+    async fn refresh_credentials(self: &Self) -> Result<bool> {
+        self.tokens.lock().await.clear();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_blob_list() {
+        let listing = r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults
+    ServiceEndpoint="https://landsateuwest.blob.core.windows.net/"
+    ContainerName="landsat-c2">
+  <Prefix>level-2/standard/oli-tirs/2024/</Prefix>
+  <Blobs>
+    <Blob>
+      <Name>level-2/standard/oli-tirs/2024/LC09_B4.TIF</Name>
+      <Properties>
+        <Content-Length>1024</Content-Length>
+        <Etag>0x8DC1</Etag>
+      </Properties>
+    </Blob>
+  </Blobs>
+  <NextMarker>2!96!MDAw</NextMarker>
+</EnumerationResults>"#;
+        let (objects, next_marker) = parse_blob_list("landsat-c2", listing).unwrap();
+        assert_eq!(
+            objects,
+            [ListedObject {
+                key: "landsat-c2/level-2/standard/oli-tirs/2024/LC09_B4.TIF".to_string(),
+                size: 1024,
+                e_tag: Some("0x8DC1".to_string()),
+            }]
+        );
+        assert_eq!(next_marker.as_deref(), Some("2!96!MDAw"));
+        let last_page = listing.replace("<NextMarker>2!96!MDAw</NextMarker>", "<NextMarker />");
+        assert_eq!(parse_blob_list("landsat-c2", &last_page).unwrap().1, None);
+    }
+
+    #[tokio::test]
+    async fn test_without_token() {
+        // Nothing listens on port 1, so the request fails with its url in the error
+        let url = "http://127.0.0.1:1/landsat-c2/LC09_B4.TIF?sv=2021-06-08&sig=secret";
+        let error = Provider::new(None)
+            .client
+            .get(url)
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("sig=secret"));
+        let error = without_token(error.into());
+        assert!(!format!("{:?}", error).contains("sig=secret"));
+    }
+}
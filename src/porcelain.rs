@@ -0,0 +1,50 @@
+//! Stable output for scripts, selected with `--porcelain`. Tables and sizes are printed as
+//! tab-separated records instead of for people: each line starts with the kind of record and is
+//! followed by its fields unformatted, sizes in bytes, rates in bytes per second, durations in
+//! seconds and missing values empty. New fields are only ever appended to a record, so scripts
+//! keep working when the human formatting (see `units`) changes.
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Print records instead of tables from now on
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A record of `kind` with its fields, tabs and line breaks in them replaced by spaces so each
+/// record stays on one line
+pub fn record(kind: &str, fields: &[&dyn Display]) -> String {
+    let mut line = kind.to_string();
+    for field in fields {
+        line.push('\t');
+        line.push_str(&field.to_string().replace(['\t', '\n', '\r'], " "));
+    }
+    line
+}
+
+/// An optional value, empty when missing
+pub fn optional<T: Display>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        assert_eq!(
+            record(
+                "item",
+                &[&"S2A\tB", &2, &1048576u64, &optional(None::<u64>)]
+            ),
+            "item\tS2A B\t2\t1048576\t"
+        );
+    }
+}
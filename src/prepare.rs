@@ -0,0 +1,323 @@
+//! Resolving a selection's ids to download tasks several at a time, for selections of hundreds of
+//! items whose lookups (STAC items, and for Copernicus their manifests) would take long one after
+//! another. Progress is printed as items resolve, and with a journal each item's tasks are appended
+//! to it as they're planned, so a prepare that fails or is interrupted resumes where it stopped.
+//! Items that can't be resolved, e.g. withdrawn from the catalogue, can instead be listed in a file
+//! for a later attempt while the rest are planned.
+use crate::download_plan::DownloadTask;
+use crate::image_selection::ImageSelection;
+use anyhow::Result;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Items resolved at the same time, by default
+pub const DEFAULT_JOBS: usize = 8;
+/// Written to the output directory with `--skip-unresolved`
+pub const UNRESOLVED_FILE: &str = "unresolved.json";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrepareOptions {
+    /// Items resolved at the same time
+    pub jobs: usize,
+    /// NDJSON file the tasks of each resolved item are appended to
+    pub journal: Option<PathBuf>,
+    /// Plan the items that resolve and list those that don't in this file, instead of failing
+    pub unresolved: Option<PathBuf>,
+}
+
+impl Default for PrepareOptions {
+    fn default() -> Self {
+        Self {
+            jobs: DEFAULT_JOBS,
+            journal: None,
+            unresolved: None,
+        }
+    }
+}
+
+/// The journal of a selection's prepare into `output_dir`
+pub fn journal_path(output_dir: &Path, selection_id: &str) -> PathBuf {
+    output_dir.join(format!(".{}.prepare.ndjson", selection_id))
+}
+
+/// Remove a journal once the plan it was resolving for has been written.
+pub fn remove_journal(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// An item that couldn't be resolved, and why
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unresolved {
+    pub id: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Resolved {
+    id: String,
+    tasks: Vec<DownloadTask>,
+}
+
+/// The journal's first line, identifying what its tasks were planned for. Ids can be added to a
+/// selection without losing the journal, changing its products can't.
+fn journal_header(selection: &ImageSelection) -> Value {
+    json!({
+        "selection": selection.id,
+        "products": selection.products_to_download(),
+        "resolution_fallback": selection.resolution_fallback,
+    })
+}
+
+/// Open a journal, reading the items it has already resolved. A journal of another selection is
+/// started over.
+fn open_journal(
+    path: &Path,
+    selection: &ImageSelection,
+) -> Result<(File, HashMap<String, Vec<DownloadTask>>)> {
+    let header = journal_header(selection);
+    let mut resolved = HashMap::new();
+    if let Ok(content) = fs::read_to_string(path) {
+        let mut lines = content.lines();
+        match lines.next().map(serde_json::from_str::<Value>) {
+            Some(Ok(existing)) if existing == header => {
+                // The last line may be cut short by the interruption
+                for item in lines.filter_map(|line| serde_json::from_str::<Resolved>(line).ok()) {
+                    resolved.insert(item.id, item.tasks);
+                }
+            }
+            _ => println!(
+                "Warning: {:?} was resolving a different selection, starting over",
+                path
+            ),
+        }
+    }
+    if resolved.is_empty() {
+        fs::write(path, format!("{}\n", header))?;
+    }
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok((file, resolved))
+}
+
+impl PrepareOptions {
+    pub fn with_jobs(self, jobs: usize) -> Self {
+        Self {
+            jobs: jobs.max(1),
+            ..self
+        }
+    }
+
+    pub fn with_journal(self, journal: PathBuf) -> Self {
+        Self {
+            journal: Some(journal),
+            ..self
+        }
+    }
+
+    pub fn with_unresolved(self, unresolved: PathBuf) -> Self {
+        Self {
+            unresolved: Some(unresolved),
+            ..self
+        }
+    }
+
+    /// Resolve each `(id, input)` to its tasks with `resolve`, `jobs` at a time, returning the
+    /// tasks in input order. Ids already in the journal aren't resolved again, and an id listed
+    /// twice is resolved once. The first failure fails the whole, unless `unresolved` is set.
+    pub async fn resolve<T, F, Fut>(
+        self: &Self,
+        selection: &ImageSelection,
+        inputs: Vec<(String, T)>,
+        resolve: F,
+    ) -> Result<Vec<DownloadTask>>
+    where
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<Vec<DownloadTask>>>,
+    {
+        let (mut journal, mut resolved) = match &self.journal {
+            Some(path) => {
+                let (file, resolved) = open_journal(path, selection)?;
+                (Some(file), resolved)
+            }
+            None => (None, HashMap::new()),
+        };
+        let order: Vec<String> = inputs.iter().map(|(id, _)| id.clone()).collect();
+        let mut seen: HashSet<String> = resolved.keys().cloned().collect();
+        let total = order.iter().collect::<HashSet<_>>().len();
+        let mut done = order.iter().filter(|id| resolved.contains_key(*id)).count();
+        if done > 0 {
+            println!(
+                "Resuming prepare, {} of {} items already resolved",
+                done, total
+            );
+        }
+
+        let pending: Vec<_> = inputs
+            .into_iter()
+            .filter(|(id, _)| seen.insert(id.clone()))
+            .map(|(id, input)| {
+                let tasks = resolve(input);
+                async move { (id, tasks.await) }
+            })
+            .collect();
+        let mut unresolved = vec![];
+        let mut results = futures_util::stream::iter(pending).buffer_unordered(self.jobs);
+        while let Some((id, tasks)) = results.next().await {
+            done += 1;
+            let tasks = match (tasks, &self.unresolved) {
+                (Ok(tasks), _) => tasks,
+                (Err(e), Some(_)) => {
+                    println!(
+                        "Warning: could not resolve {} ({} of {}): {:#}",
+                        id, done, total, e
+                    );
+                    let reason = format!("{:#}", e);
+                    unresolved.push(Unresolved { id, reason });
+                    continue;
+                }
+                // Items resolved so far stay in the journal for the next attempt
+                (Err(e), None) => return Err(e),
+            };
+            println!(
+                "Resolved {} ({} of {}, {} files)",
+                id,
+                done,
+                total,
+                tasks.len()
+            );
+            let item = Resolved { id, tasks };
+            if let Some(journal) = &mut journal {
+                writeln!(journal, "{}", serde_json::to_string(&item)?)?;
+            }
+            resolved.insert(item.id, item.tasks);
+        }
+        if let Some(path) = &self.unresolved {
+            write_unresolved(path, &order, unresolved)?;
+        }
+
+        Ok(order
+            .iter()
+            .flat_map(|id| resolved.get(id).cloned().unwrap_or_default())
+            .collect())
+    }
+}
+
+/// List unresolved items in selection order, or remove the list of an earlier prepare when every
+/// item resolved
+fn write_unresolved(path: &Path, order: &[String], mut unresolved: Vec<Unresolved>) -> Result<()> {
+    if unresolved.is_empty() {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+    unresolved.sort_by_key(|item| order.iter().position(|id| *id == item.id));
+    println!(
+        "Warning: {} items could not be resolved and are left out of the plan, see {:?}",
+        unresolved.len(),
+        path
+    );
+    fs::write(path, serde_json::to_string_pretty(&unresolved)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let dir = Path::new("/tmp/slow_stac_prepare");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let selection = ImageSelection::from_template(
+            &crate::element84::sentinel2collection1level2a::image_selection_toml(),
+        );
+        let options = PrepareOptions::default()
+            .with_jobs(1)
+            .with_journal(journal_path(dir, &selection.id));
+        let inputs = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| (id.to_string(), id.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let tasks = |id: String| async move {
+            if id == "C" {
+                return Err(anyhow::anyhow!("{} is unavailable", id));
+            }
+            Ok(vec![DownloadTask::new(
+                "bucket",
+                &id,
+                &format!("{}/B04.tif", id),
+            )])
+        };
+
+        // The failure loses nothing resolved before it
+        assert!(options
+            .resolve(&selection, inputs(&["A", "B", "C"]), tasks)
+            .await
+            .is_err());
+        let options = options.with_jobs(2);
+        let resolved = options
+            .resolve(
+                &selection,
+                inputs(&["B", "A", "A"]),
+                |id: String| async move {
+                    Err::<Vec<DownloadTask>, _>(anyhow::anyhow!("{} resolved again", id))
+                },
+            )
+            .await
+            .unwrap();
+        let keys: Vec<_> = resolved.iter().map(|task| task.key()).collect();
+        assert_eq!(keys, ["B", "A", "A"]);
+
+        // Another selection starts over
+        let other = selection.clone().with_overviews_only();
+        let resolved = options
+            .resolve(&other, inputs(&["A"]), tasks)
+            .await
+            .unwrap();
+        assert_eq!(resolved[0].key(), "A");
+        remove_journal(&journal_path(dir, &selection.id)).unwrap();
+        assert!(!journal_path(dir, &selection.id).exists());
+    }
+
+    #[tokio::test]
+    async fn test_skip_unresolved() {
+        let dir = Path::new("/tmp/slow_stac_prepare_unresolved");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let selection = ImageSelection::from_template(
+            &crate::element84::sentinel2collection1level2a::image_selection_toml(),
+        );
+        let path = dir.join(UNRESOLVED_FILE);
+        let options = PrepareOptions::default().with_unresolved(path.clone());
+        let inputs = ["C", "A", "D"]
+            .iter()
+            .map(|id| (id.to_string(), id.to_string()))
+            .collect();
+        let resolved = options
+            .resolve(&selection, inputs, |id: String| async move {
+                match id.as_str() {
+                    "A" => Ok(vec![DownloadTask::new("bucket", "A", "A/B04.tif")]),
+                    _ => Err(anyhow::anyhow!("404 Not Found")),
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(resolved.len(), 1);
+        let unresolved: Vec<Unresolved> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let ids: Vec<_> = unresolved.iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, ["C", "D"]);
+        assert_eq!(unresolved[0].reason, "404 Not Found");
+    }
+}
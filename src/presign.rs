@@ -0,0 +1,132 @@
+//! Executing plans whose tasks carry presigned URLs (see `DownloadPlan::presigned`), which need
+//! no credentials at all, e.g. on a field laptop. The URLs are re-signed from a machine with
+//! credentials by `DownloadPlan::refreshed` before they expire.
+use crate::download_plan::DownloadPlan;
+use crate::http;
+use crate::s3::S3ObjOps;
+use crate::user_agent;
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use reqwest::RequestBuilder;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::instrument;
+
+/// Source recorded on presigned plans
+pub const SOURCE: &str = "presigned";
+/// Longest validity S3 allows for a presigned URL
+pub const MAX_EXPIRES_IN: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Bucket, key and pinned version (see `DownloadTask::version_id`) of a presigned object
+pub type ObjectVersion = (String, String, Option<String>);
+
+/// Serves each task's object from its presigned URL
+pub struct Provider {
+    client: reqwest::Client,
+    urls: HashMap<ObjectVersion, String>,
+}
+
+impl Provider {
+    pub fn new(urls: HashMap<ObjectVersion, String>) -> Self {
+        Self {
+            client: user_agent::client(),
+            urls,
+        }
+    }
+
+    pub fn from_plan(plan: &DownloadPlan) -> Result<Self> {
+        Ok(Self::new(plan.presigned_urls()?))
+    }
+
+    /// The URL signed for the version, which the URL itself pins
+    fn request(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<RequestBuilder> {
+        let object = (
+            bucket.to_string(),
+            key.to_string(),
+            version_id.map(str::to_string),
+        );
+        let url = self.urls.get(&object).ok_or(match version_id {
+            Some(version_id) => anyhow!(
+                "No presigned URL for version {} of {}/{}",
+                version_id,
+                bucket,
+                key
+            ),
+            None => anyhow!("No presigned URL for {}/{}", bucket, key),
+        })?;
+        Ok(self.client.get(url))
+    }
+}
+
+impl S3ObjOps for Provider {
+    /// The URLs are signed for the S3 objects themselves
+    fn md5_etags(self: &Self) -> bool {
+        true
+    }
+
+    async fn head_object(self: &Self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        self.head_object_version(bucket, key, None).await
+    }
+
+    async fn get_object(self: &Self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
+        self.get_object_version(bucket, key, None).await
+    }
+
+    async fn get_object_range(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        self.get_object_range_version(bucket, key, None, start_byte, end_byte)
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn head_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectOutput> {
+        http::head_object(self.request(bucket, key, version_id)?).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectOutput> {
+        http::get_object(self.request(bucket, key, version_id)?).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_object_range_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> Result<GetObjectOutput> {
+        let request = self.request(bucket, key, version_id)?;
+        http::get_object_range(request, start_byte, end_byte).await
+    }
+}
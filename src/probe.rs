@@ -0,0 +1,240 @@
+//! Measure how quickly each source of a plan's objects can be reached so tasks can be routed to
+//! the fastest one
+use crate::porcelain;
+use crate::s3::S3ObjOps;
+use crate::units;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Bytes read from the sample object to estimate throughput
+const PROBE_BYTES: u64 = 4 * 1024 * 1024;
+/// Chunks should take long enough to transfer that the per-request latency is a small overhead
+const CHUNK_DURATION: Duration = Duration::from_secs(2);
+const MIN_CHUNK_SIZE: u64 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_JOBS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct ProbeResult {
+    pub source: String,
+    /// Time to first response for a metadata request
+    pub latency: Duration,
+    pub bytes_per_sec: f64,
+}
+
+impl ProbeResult {
+    /// Rough time to download `files` objects totalling `bytes`, paying the latency per object.
+    /// None if nothing was received to measure the throughput with.
+    pub fn estimate(self: &Self, files: usize, bytes: u64) -> Option<Duration> {
+        if self.bytes_per_sec <= 0.0 {
+            return None;
+        }
+        let transfer = Duration::try_from_secs_f64(bytes as f64 / self.bytes_per_sec).ok()?;
+        Some(self.latency * files as u32 + transfer)
+    }
+}
+
+/// Time a HEAD and a ranged read of up to `PROBE_BYTES` of one object at a source.
+pub async fn probe(
+    source: &str,
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+) -> Result<ProbeResult> {
+    let start = Instant::now();
+    let head = provider.head_object(bucket, key).await?;
+    let latency = start.elapsed();
+
+    let size = head.content_length().unwrap_or(0).max(0) as u64;
+    let end = PROBE_BYTES.min(size.max(1)) - 1;
+    let start = Instant::now();
+    let object = provider.get_object_range(bucket, key, 0, end).await?;
+    let bytes = object.body.collect().await?.to_vec().len();
+    let elapsed = start.elapsed().max(Duration::from_millis(1));
+
+    Ok(ProbeResult {
+        source: source.to_string(),
+        latency,
+        bytes_per_sec: bytes as f64 / elapsed.as_secs_f64(),
+    })
+}
+
+/// Read `samples` ranges of up to `PROBE_BYTES` spread across one object, reporting the fastest
+/// request latency and the throughput sustained over all ranges.
+pub async fn speed_test(
+    source: &str,
+    provider: &impl S3ObjOps,
+    bucket: &str,
+    key: &str,
+    samples: u64,
+) -> Result<ProbeResult> {
+    let head = provider.head_object(bucket, key).await?;
+    let size = head.content_length().unwrap_or(0).max(1) as u64;
+    let range = PROBE_BYTES.min(size);
+
+    let mut latency = Duration::MAX;
+    let mut bytes = 0;
+    let mut transfer = Duration::ZERO;
+    for sample in 0..samples.max(1) {
+        let offset = (size - range) * sample / samples.max(1);
+        let start = Instant::now();
+        let object = provider
+            .get_object_range(bucket, key, offset, offset + range - 1)
+            .await?;
+        latency = latency.min(start.elapsed());
+        let received = Instant::now();
+        bytes += object.body.collect().await?.to_vec().len();
+        transfer += received.elapsed();
+    }
+
+    Ok(ProbeResult {
+        source: source.to_string(),
+        latency,
+        bytes_per_sec: bytes as f64 / transfer.max(Duration::from_millis(1)).as_secs_f64(),
+    })
+}
+
+/// Download settings suited to a measured connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recommendation {
+    pub source: String,
+    pub jobs: usize,
+    pub chunk_size: u64,
+    pub latency_ms: u64,
+    pub bytes_per_sec: u64,
+}
+
+impl Recommendation {
+    /// Size chunks to take `CHUNK_DURATION` at the measured throughput, and run enough jobs to keep
+    /// the link busy while each request waits out the latency.
+    pub fn from_result(result: &ProbeResult) -> Self {
+        let ideal = result.bytes_per_sec * CHUNK_DURATION.as_secs_f64();
+        let chunk_size = (ideal as u64)
+            .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+            .next_power_of_two()
+            .min(MAX_CHUNK_SIZE);
+        let chunk_time = chunk_size as f64 / result.bytes_per_sec.max(1.0);
+        let jobs = 1.0 + result.latency.as_secs_f64() / chunk_time;
+        Self {
+            source: result.source.clone(),
+            jobs: (jobs.round() as usize).clamp(1, MAX_JOBS),
+            chunk_size,
+            latency_ms: result.latency.as_millis() as u64,
+            bytes_per_sec: result.bytes_per_sec as u64,
+        }
+    }
+
+    pub fn print(self: &Self) {
+        if porcelain::enabled() {
+            let record = porcelain::record(
+                "recommendation",
+                &[
+                    &self.source,
+                    &self.latency_ms,
+                    &self.bytes_per_sec,
+                    &self.jobs,
+                    &self.chunk_size,
+                ],
+            );
+            println!("{}", record);
+            return;
+        }
+        println!(
+            "{}: latency {} ms, throughput {}",
+            self.source,
+            self.latency_ms,
+            units::rate(self.bytes_per_sec as f64)
+        );
+        println!(
+            "Recommended: --jobs {} with {} chunks",
+            self.jobs,
+            units::size(self.chunk_size)
+        );
+    }
+}
+
+/// The result with the shortest estimated time for the plan's `files` and `bytes`.
+pub fn fastest(results: &[ProbeResult], files: usize, bytes: u64) -> Option<&ProbeResult> {
+    results
+        .iter()
+        .min_by_key(|result| result.estimate(files, bytes).unwrap_or(Duration::MAX))
+}
+
+pub fn print_results(results: &[ProbeResult], files: usize, bytes: u64) {
+    for result in results {
+        if porcelain::enabled() {
+            let record = porcelain::record(
+                "probe",
+                &[
+                    &result.source,
+                    &result.latency.as_millis(),
+                    &(result.bytes_per_sec as u64),
+                    &porcelain::optional(result.estimate(files, bytes).map(|e| e.as_secs())),
+                ],
+            );
+            println!("{}", record);
+            continue;
+        }
+        println!(
+            "{:<12} latency {:>6} ms  throughput {:>12}  estimated {:>8}",
+            result.source,
+            result.latency.as_millis(),
+            units::rate(result.bytes_per_sec),
+            result
+                .estimate(files, bytes)
+                .map_or("unknown".to_string(), units::duration)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_result(source: &str, latency_ms: u64, bytes_per_sec: f64) -> ProbeResult {
+        ProbeResult {
+            source: source.to_string(),
+            latency: Duration::from_millis(latency_ms),
+            bytes_per_sec,
+        }
+    }
+
+    #[test]
+    fn test_fastest() {
+        let results = vec![
+            mock_result("low-latency", 10, 1e6),
+            mock_result("high-throughput", 500, 1e7),
+        ];
+        // Many small files favour latency, few large files favour throughput
+        assert_eq!(
+            fastest(&results, 1000, 1_000_000).unwrap().source,
+            "low-latency"
+        );
+        assert_eq!(
+            fastest(&results, 1, 1_000_000_000).unwrap().source,
+            "high-throughput"
+        );
+        // Nothing received, no estimate
+        assert_eq!(mock_result("stalled", 10, 0.0).estimate(1, 1), None);
+    }
+
+    #[test]
+    fn test_recommendation() {
+        // A slow link is saturated by a single job with the smallest chunks
+        let slow = Recommendation::from_result(&mock_result("slow", 800, 64.0 * 1024.0));
+        assert_eq!(slow.chunk_size, MIN_CHUNK_SIZE);
+        assert_eq!(slow.jobs, 1);
+
+        // A fast link gets large chunks, latency decides the jobs
+        let fast = Recommendation::from_result(&mock_result("fast", 500, 50e6));
+        assert_eq!(fast.chunk_size, MAX_CHUNK_SIZE);
+        assert_eq!(fast.jobs, 1);
+        let distant = Recommendation::from_result(&mock_result("distant", 3000, 50e6));
+        assert_eq!(distant.jobs, 3);
+
+        let mid = Recommendation::from_result(&mock_result("mid", 1000, 1024.0 * 1024.0));
+        assert_eq!(mid.chunk_size, 2 * 1024 * 1024);
+        assert_eq!(mid.jobs, 2);
+    }
+}
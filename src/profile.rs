@@ -0,0 +1,90 @@
+//! Named bundles of download settings for the kind of connection being used
+use crate::download_plan::{DownloadOptions, StallThreshold};
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::Duration;
+
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Low bandwidth: one job, modest chunks, tolerant of a low rate
+    Slow,
+    /// Drops out often: one job, small chunks so little is lost per failure, quick to abort a
+    /// stalled transfer and resume it, many retries
+    Flaky,
+    /// Fast and reliable: eight jobs, large chunks, few retries
+    Fast,
+}
+
+impl Profile {
+    pub fn options(self: &Self) -> DownloadOptions {
+        let defaults = DownloadOptions::default();
+        match self {
+            Profile::Slow => DownloadOptions {
+                jobs: 1,
+                chunk_size: Some(4 * MIB),
+                max_retries: 8,
+                stall: Some(StallThreshold {
+                    min_bytes_per_sec: 256,
+                    window: Duration::from_secs(120),
+                }),
+                ..defaults
+            },
+            Profile::Flaky => DownloadOptions {
+                jobs: 1,
+                chunk_size: Some(MIB),
+                max_retries: 12,
+                max_verify_restarts: 4,
+                stall: Some(StallThreshold {
+                    min_bytes_per_sec: 512,
+                    window: Duration::from_secs(30),
+                }),
+                ..defaults
+            },
+            Profile::Fast => DownloadOptions {
+                jobs: 8,
+                chunk_size: Some(64 * MIB),
+                max_retries: 2,
+                stall: Some(StallThreshold {
+                    min_bytes_per_sec: 64 * 1024,
+                    window: Duration::from_secs(20),
+                }),
+                ..defaults
+            },
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "slow" => Ok(Profile::Slow),
+            "flaky" => Ok(Profile::Flaky),
+            "fast" => Ok(Profile::Fast),
+            _ => Err(anyhow!(
+                "Unknown profile {}, expected slow, flaky or fast",
+                name
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_from_str() {
+        assert_eq!("flaky".parse::<Profile>().unwrap(), Profile::Flaky);
+        assert!("turbo".parse::<Profile>().is_err());
+
+        let options = Profile::Fast.options();
+        assert_eq!(options.jobs, 8);
+        assert!(options.verify);
+    }
+}
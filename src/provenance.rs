@@ -0,0 +1,109 @@
+//! Who prepared a plan and from what, and an HMAC signature over the plan, so that a plan prepared
+//! centrally can be checked for modification in transit before it is downloaded remotely
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Secret shared by the people preparing and downloading plans
+const KEY_ENV: &str = "SLOW_STAC_PLAN_KEY";
+const SIGNATURE_PREFIX: &str = "hmac-sha256:";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub created_by: String,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    /// `sha256:<hex digest>` of the image selection file the plan was prepared from
+    pub selection_hash: String,
+    pub tool_version: String,
+}
+
+impl Provenance {
+    /// Provenance of a plan being prepared now by the current user from `selection`, the content
+    /// of the image selection file.
+    pub fn new(selection: &[u8]) -> Self {
+        let created_by = env::var("USER")
+            .or(env::var("USERNAME"))
+            .unwrap_or("unknown".to_string());
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            created_by,
+            created_at,
+            selection_hash: format!("sha256:{}", to_hex(&Sha256::digest(selection))),
+            tool_version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    pub fn print(self: &Self) {
+        println!("Created by:     {}", self.created_by);
+        println!("Created at:     {} (Unix time)", self.created_at);
+        println!("Selection hash: {}", self.selection_hash);
+        println!("Tool version:   {}", self.tool_version);
+    }
+}
+
+/// The signing key from `SLOW_STAC_PLAN_KEY`, if set
+pub fn signing_key() -> Option<Vec<u8>> {
+    env::var(KEY_ENV)
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(|key| key.into_bytes())
+}
+
+pub fn sign(content: &[u8], key: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(content);
+    format!(
+        "{}{}",
+        SIGNATURE_PREFIX,
+        to_hex(&mac.finalize().into_bytes())
+    )
+}
+
+/// Check a signature made by `sign`, in constant time.
+pub fn verify(content: &[u8], key: &[u8], signature: &str) -> Result<()> {
+    let digest = signature
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(from_hex)
+        .ok_or(anyhow!("Unsupported plan signature: {}", signature))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(content);
+    mac.verify_slice(&digest).map_err(|_| {
+        anyhow!("The plan signature does not match, it was modified or signed with another key")
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let signature = sign(b"plan", b"secret");
+        assert!(signature.starts_with(SIGNATURE_PREFIX));
+        assert!(verify(b"plan", b"secret", &signature).is_ok());
+        assert!(verify(b"plan!", b"secret", &signature).is_err());
+        assert!(verify(b"plan", b"other", &signature).is_err());
+        assert!(verify(b"plan", b"secret", "md5:00").is_err());
+    }
+}
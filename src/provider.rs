@@ -0,0 +1,10 @@
+//! The public provider API. Every source of objects implements `S3ObjOps`, which plan execution,
+//! probing and one-off downloads are generic over; the concrete providers live in the module of
+//! the catalogue they serve and are re-exported here.
+pub use crate::copernicus::Provider as CopernicusProvider;
+pub use crate::earthdata::Provider as EarthdataProvider;
+pub use crate::element84::Provider as Element84Provider;
+pub use crate::fetch::CollectionProvider;
+pub use crate::gcs::Provider as GcsProvider;
+pub use crate::provider_config::ProviderConfig;
+pub use crate::s3::{anon_client, client_from_profile, S3ObjOps};
@@ -0,0 +1,168 @@
+//! How the S3 client of each provider reaches its service: endpoint, region, addressing style and
+//! signing. Each provider has built-in settings that a `[providers.<name>]` table in the config
+//! file overrides field by field, e.g. to use a Copernicus endpoint other than the public one:
+//!
+//! ```toml
+//! [providers.copernicus]
+//! endpoint_url = "https://eodata.ams.dataspace.copernicus.eu"
+//! profile = "copernicus-field"
+//! ```
+use crate::config::Config;
+use crate::s3;
+use anyhow::Result;
+use aws_sdk_s3::config::Region;
+use aws_sdk_s3::Client;
+use serde::{Deserialize, Serialize};
+
+/// Region of clients that neither their settings nor their AWS profile give one
+pub const DEFAULT_REGION: &str = "us-east-1";
+pub const COPERNICUS: &str = "copernicus";
+pub const ELEMENT84: &str = "element84";
+/// Earth Search's Sentinel-2 L1C collection, whose `sentinel-s2-l1c` bucket is in another region
+pub const ELEMENT84_L1C: &str = "element84-l1c";
+const COPERNICUS_ENDPOINT: &str = "https://eodata.dataspace.copernicus.eu";
+/// Region of the Earth Search buckets
+const ELEMENT84_REGION: &str = "us-west-2";
+const ELEMENT84_L1C_REGION: &str = "eu-central-1";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderConfig {
+    /// S3-compatible endpoint, otherwise the profile's `endpoint_url` or AWS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint_url: Option<String>,
+    /// Otherwise the profile's region or `DEFAULT_REGION`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Address buckets as `<endpoint>/<bucket>` rather than `<bucket>.<endpoint>`, which most
+    /// S3-compatible services other than AWS need [default: false]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_style: Option<bool>,
+    /// AWS profile whose credentials sign requests. Without one requests are sent unsigned, for
+    /// public buckets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// AWS profile to sign requests with when a bucket refuses unsigned ones, e.g. because it is
+    /// requester-pays. Only used without `profile`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_profile: Option<String>,
+}
+
+impl ProviderConfig {
+    /// Built-in settings of the provider called `name`, none for unknown providers
+    pub fn defaults(name: &str) -> Self {
+        match name {
+            COPERNICUS => Self {
+                endpoint_url: Some(COPERNICUS_ENDPOINT.to_string()),
+                region: Some(DEFAULT_REGION.to_string()),
+                path_style: Some(true),
+                profile: Some(COPERNICUS.to_string()),
+                fallback_profile: None,
+            },
+            ELEMENT84 => Self {
+                region: Some(ELEMENT84_REGION.to_string()),
+                ..Self::default()
+            },
+            ELEMENT84_L1C => Self {
+                region: Some(ELEMENT84_L1C_REGION.to_string()),
+                ..Self::default()
+            },
+            _ => Self::default(),
+        }
+    }
+
+    /// Signed with the credentials of an AWS profile, addressing buckets by path as S3-compatible
+    /// services expect
+    pub fn from_profile(profile: &str) -> Self {
+        Self {
+            path_style: Some(true),
+            profile: Some(profile.to_string()),
+            ..Self::default()
+        }
+    }
+
+    /// Unsigned requests to public buckets in `region`
+    pub fn anonymous(region: &str) -> Self {
+        Self {
+            region: Some(region.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_profile(self, profile: Option<String>) -> Self {
+        Self { profile, ..self }
+    }
+
+    /// These settings with those set in `overrides` replaced
+    pub fn overridden_by(self, overrides: &Self) -> Self {
+        Self {
+            endpoint_url: overrides.endpoint_url.clone().or(self.endpoint_url),
+            region: overrides.region.clone().or(self.region),
+            path_style: overrides.path_style.or(self.path_style),
+            profile: overrides.profile.clone().or(self.profile),
+            fallback_profile: overrides.fallback_profile.clone().or(self.fallback_profile),
+        }
+    }
+
+    pub async fn client(self: &Self) -> Client {
+        let mut loader =
+            s3::with_app_name(aws_config::defaults(aws_config::BehaviorVersion::latest()))
+                .http_client(s3::http_client());
+        loader = match &self.profile {
+            Some(profile) => loader.profile_name(profile),
+            None => loader.no_credentials(),
+        };
+        if let Some(endpoint_url) = &self.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+        if let Some(region) = &self.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        let base_config = loader.load().await;
+
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&base_config)
+            .force_path_style(self.path_style.unwrap_or(false));
+        if base_config.region().is_none() {
+            s3_config = s3_config.region(Region::new(DEFAULT_REGION));
+        }
+        Client::from_conf(s3_config.build())
+    }
+}
+
+/// The settings of the provider called `name`, its built-in ones overridden by the config file
+pub fn load(name: &str) -> Result<ProviderConfig> {
+    let config = Config::read(Config::path()?)?;
+    Ok(config.provider(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overridden_by() {
+        let config: Config = toml::from_str(
+            "[providers.copernicus]\n\
+             endpoint_url = \"https://eodata.ams.dataspace.copernicus.eu\"\n\
+             profile = \"copernicus-field\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.provider(COPERNICUS),
+            ProviderConfig {
+                endpoint_url: Some("https://eodata.ams.dataspace.copernicus.eu".to_string()),
+                region: Some(DEFAULT_REGION.to_string()),
+                path_style: Some(true),
+                profile: Some("copernicus-field".to_string()),
+                fallback_profile: None,
+            }
+        );
+        assert_eq!(
+            config.provider(ELEMENT84),
+            ProviderConfig::anonymous("us-west-2")
+        );
+        assert_eq!(
+            config.provider(ELEMENT84_L1C),
+            ProviderConfig::anonymous("eu-central-1")
+        );
+    }
+}
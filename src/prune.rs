@@ -0,0 +1,198 @@
+//! Finding items in an archive that a reprocessed version of the same acquisition supersedes, so
+//! the older processing baseline can be deleted once the newer one has downloaded.
+//!
+//! Copernicus SAFE names carry the processing baseline (`N0510`) and generation time of the
+//! product, Earth Search's older `sentinel-2-l2a` ids a sequence number (`_1_L2A`). Earth Search
+//! Collection 1 ids are the same for every processing, so those items are never superseded.
+//!
+//! A newer version only supersedes once it is complete: none of its files is partial, including
+//! the ranges of a `download --parts` download, and every file its plans list is there.
+use crate::download_plan::DownloadPlan;
+use anyhow::Result;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SAFE_VERSION: &str = r"^(?<acquisition>S2[A-D]_MSI(L1C|L2A)_\d{8}T\d{6})_N(?<baseline>\d{4})_(?<tile>R\d{3}_T\w{5})_(?<generated>\d{8}T\d{6})(\.SAFE)?$";
+const EARTH_SEARCH_LEGACY_VERSION: &str =
+    r"^(?<acquisition>S2[A-D]_\w{4,5}_\d{8})_(?<sequence>\d+)_L2A$";
+
+/// An item directory superseded by a newer version of the same acquisition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superseded {
+    pub dir: PathBuf,
+    pub item_id: String,
+    pub superseded_by: String,
+    pub bytes: u64,
+}
+
+/// The acquisition (platform, sensing time and tile) an item id is a version of, and the version,
+/// which orders later processings after earlier ones
+pub fn version(item_id: &str) -> Option<(String, (u32, String))> {
+    let safe = Regex::new(SAFE_VERSION).expect("Regex pattern should always compile");
+    if let Some(captures) = safe.captures(item_id) {
+        let acquisition = format!("{}_{}", &captures["acquisition"], &captures["tile"]);
+        let baseline = captures["baseline"].parse().ok()?;
+        return Some((acquisition, (baseline, captures["generated"].to_string())));
+    }
+    let legacy =
+        Regex::new(EARTH_SEARCH_LEGACY_VERSION).expect("Regex pattern should always compile");
+    let captures = legacy.captures(item_id)?;
+    let sequence = captures["sequence"].parse().ok()?;
+    Some((
+        captures["acquisition"].to_string(),
+        (sequence, String::new()),
+    ))
+}
+
+/// Whether an item directory holds a finished download: some files, no partial ones (`.partial`,
+/// its `.partial.state` and `.partial.<start>-<end>` ranges) and all of `planned`
+fn is_complete(dir: &Path, planned: &[PathBuf]) -> Result<bool> {
+    let mut files = 0;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if name.ends_with(".partial") || name.contains(".partial.") {
+            return Ok(false);
+        }
+        if !name.starts_with('.') {
+            files += 1;
+        }
+    }
+    Ok(files > 0 && planned.iter().all(|output| output.exists()))
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut bytes = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        bytes += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(bytes)
+}
+
+/// Item directories in `dir` superseded by the latest complete version of their acquisition.
+/// Versions that are still downloading don't supersede anything yet, nor do versions missing
+/// files that one of `plans` downloads.
+pub fn find_superseded(dir: &Path, plans: &[DownloadPlan]) -> Result<Vec<Superseded>> {
+    let mut planned: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for plan in plans {
+        for task in plan.tasks() {
+            planned
+                .entry(task.item())
+                .or_default()
+                .push(plan.output_path(task));
+        }
+    }
+    let mut acquisitions: HashMap<String, Vec<((u32, String), String)>> = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.path().is_dir() {
+            continue;
+        }
+        let item_id = entry.file_name().to_string_lossy().to_string();
+        if let Some((acquisition, version)) = version(&item_id) {
+            acquisitions
+                .entry(acquisition)
+                .or_default()
+                .push((version, item_id));
+        }
+    }
+    let mut superseded = vec![];
+    for (_, mut versions) in acquisitions {
+        if versions.len() < 2 {
+            continue;
+        }
+        versions.sort();
+        let mut latest = None;
+        while let Some((_, item_id)) = versions.pop() {
+            let outputs = planned.get(&item_id).map(Vec::as_slice).unwrap_or_default();
+            if is_complete(&dir.join(&item_id), outputs)? {
+                latest = Some(item_id);
+                break;
+            }
+        }
+        let Some(latest) = latest else { continue };
+        for (_, item_id) in versions {
+            let item_dir = dir.join(&item_id);
+            superseded.push(Superseded {
+                bytes: dir_size(&item_dir)?,
+                dir: item_dir,
+                item_id,
+                superseded_by: latest.clone(),
+            });
+        }
+    }
+    superseded.sort_by(|a, b| a.item_id.cmp(&b.item_id));
+    Ok(superseded)
+}
+
+/// Delete superseded item directories.
+pub fn prune(superseded: &[Superseded]) -> Result<()> {
+    for item in superseded {
+        fs::remove_dir_all(&item.dir)?;
+        println!("Deleted {}", item.item_id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_plan::DownloadTask;
+
+    #[test]
+    fn test_find_superseded() {
+        let dir = Path::new("/tmp/slow_stac_prune");
+        let _ = fs::remove_dir_all(dir);
+        let old = "S2A_MSIL2A_20240504T195901_N0500_R128_T08VPH_20240504T235436.SAFE";
+        let new = "S2A_MSIL2A_20240504T195901_N0510_R128_T08VPH_20240505T015750.SAFE";
+        let other_tile = "S2A_MSIL2A_20240504T195901_N0500_R128_T08VPJ_20240504T235436.SAFE";
+        let downloading = "S2A_8VPH_20240504_1_L2A";
+        let legacy = "S2A_8VPH_20240504_0_L2A";
+        for item in [old, new, other_tile, downloading, legacy] {
+            fs::create_dir_all(dir.join(item)).unwrap();
+            fs::write(dir.join(item).join("B04.jp2"), "b04").unwrap();
+        }
+        fs::write(dir.join(downloading).join("B08.tif.partial"), "").unwrap();
+        let parts = "S2A_MSIL2A_20240505T195901_N0510_R128_T08VPH_20240505T235436.SAFE";
+        let parts_old = "S2A_MSIL2A_20240505T195901_N0500_R128_T08VPH_20240505T015750.SAFE";
+        for item in [parts, parts_old] {
+            fs::create_dir_all(dir.join(item)).unwrap();
+            fs::write(dir.join(item).join("B04.jp2"), "b04").unwrap();
+        }
+        fs::write(dir.join(parts).join("B08.jp2.partial.0-99"), "").unwrap();
+
+        // The plan of the newest version lists a file that isn't there yet
+        let unfinished = "S2A_MSIL2A_20240504T195901_N0511_R128_T08VPH_20240506T015750.SAFE";
+        fs::create_dir_all(dir.join(unfinished)).unwrap();
+        fs::write(dir.join(unfinished).join("B04.jp2"), "b04").unwrap();
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("bucket", "B04", &format!("{}/B04.jp2", unfinished)),
+                DownloadTask::new("bucket", "B08", &format!("{}/B08.jp2", unfinished)),
+            ],
+        )
+        .with_root(dir);
+
+        let superseded = find_superseded(dir, &[plan]).unwrap();
+        assert_eq!(
+            superseded,
+            [Superseded {
+                dir: dir.join(old),
+                item_id: old.to_string(),
+                superseded_by: new.to_string(),
+                bytes: 3,
+            }]
+        );
+        prune(&superseded).unwrap();
+        assert!(!dir.join(old).exists() && dir.join(new).exists());
+        assert_eq!(version(new).unwrap().0, version(old).unwrap().0);
+        assert_eq!(version("S2A_T08VPH_20240504T195929_L2A"), None);
+    }
+}
@@ -0,0 +1,255 @@
+//! Small PNG previews of a downloaded item, so it can be sanity-checked in the field without a GIS.
+//! Reads single band GeoTIFFs (e.g. Earth Search COGs) using their smallest overview that still
+//! covers `MAX_SIZE` pixels; JPEG 2000 bands (Copernicus) are not supported.
+use crate::hooks::Quicklook;
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use tiff::decoder::{Decoder, DecodingResult};
+
+/// Longest side of a quicklook in pixels
+pub const MAX_SIZE: u32 = 512;
+/// NDVI mapped to the ends of the colour ramp
+const NDVI_RANGE: (f32, f32) = (-0.2, 0.9);
+/// Percentiles a false colour band is stretched between
+const STRETCH_PERCENTILES: (f32, f32) = (0.02, 0.98);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Band {
+    Green,
+    Red,
+    Nir,
+}
+
+impl Band {
+    /// Whether an asset holds this band: Earth Search names (`red`) or Sentinel 2 band ids
+    /// (`B04_10m`)
+    fn matches(self: &Self, asset_key: &str) -> bool {
+        let (name, id) = match self {
+            Band::Green => ("green", "B03"),
+            Band::Red => ("red", "B04"),
+            Band::Nir => ("nir", "B08"),
+        };
+        asset_key == name || asset_key == id || asset_key.starts_with(&format!("{}_", id))
+    }
+}
+
+/// A band resampled to the quicklook's size, 0 is nodata
+struct Grid {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+}
+
+pub fn write_quicklook(
+    quicklook: Quicklook,
+    files: &[(String, impl AsRef<Path>)],
+    output: &Path,
+) -> Result<()> {
+    let bands = match quicklook {
+        Quicklook::Ndvi => vec![Band::Nir, Band::Red],
+        Quicklook::FalseColor => vec![Band::Nir, Band::Red, Band::Green],
+    };
+    let mut grids: Vec<Grid> = vec![];
+    for band in bands {
+        let (_, path) = files
+            .iter()
+            .find(|(asset_key, _)| band.matches(asset_key))
+            .ok_or(anyhow!(
+                "The {} quicklook needs the {:?} band",
+                quicklook,
+                band
+            ))?;
+        let path = path.as_ref();
+        let grid = read_band(path).with_context(|| anyhow!("Could not read {:?}", path))?;
+        // Bands at coarser resolutions are stretched to the first band's size
+        let grid = match grids.first() {
+            Some(first) => resample(&grid, first.width, first.height),
+            None => grid,
+        };
+        grids.push(grid);
+    }
+    let rgba = match quicklook {
+        Quicklook::Ndvi => ndvi_rgba(&grids[0], &grids[1]),
+        Quicklook::FalseColor => false_color_rgba(&grids),
+    };
+    write_png(output, grids[0].width, grids[0].height, &rgba)
+}
+
+/// Read the first band of the smallest image in the file (the full resolution image or one of its
+/// overviews) at least `MAX_SIZE` wide or high, scaled down to at most `MAX_SIZE`.
+fn read_band(path: &Path) -> Result<Grid> {
+    let mut decoder = Decoder::new(File::open(path)?)?;
+    let mut images = vec![];
+    loop {
+        images.push(decoder.dimensions()?);
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+    let index = images
+        .iter()
+        .enumerate()
+        .filter(|(_, (width, height))| *width.max(height) >= MAX_SIZE)
+        .min_by_key(|(_, (width, height))| width * height)
+        .map(|(index, _)| index)
+        .unwrap_or(0);
+    decoder.seek_to_image(index)?;
+    let (width, height) = decoder.dimensions()?;
+    let values: Vec<f32> = match decoder.read_image()? {
+        DecodingResult::U8(values) => values.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::U16(values) => values.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I16(values) => values.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::U32(values) => values.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::F32(values) => values,
+        _ => return Err(anyhow!("Unsupported sample format")),
+    };
+    // Interleaved samples of multiband images, of which the first band is kept
+    let samples = (values.len() / (width as usize * height as usize)).max(1);
+    let grid = Grid {
+        width,
+        height,
+        values: values.into_iter().step_by(samples).collect(),
+    };
+    let scale = (MAX_SIZE as f32 / width.max(height) as f32).min(1.0);
+    let size = |side: u32| ((side as f32 * scale).round() as u32).max(1);
+    Ok(resample(&grid, size(width), size(height)))
+}
+
+/// Nearest neighbour resampling
+fn resample(grid: &Grid, width: u32, height: u32) -> Grid {
+    if grid.width == width && grid.height == height {
+        return Grid {
+            width,
+            height,
+            values: grid.values.clone(),
+        };
+    }
+    let mut values = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        let source_y = (y as u64 * grid.height as u64 / height as u64) as usize;
+        for x in 0..width {
+            let source_x = (x as u64 * grid.width as u64 / width as u64) as usize;
+            values.push(grid.values[source_y * grid.width as usize + source_x]);
+        }
+    }
+    Grid {
+        width,
+        height,
+        values,
+    }
+}
+
+fn ndvi_rgba(nir: &Grid, red: &Grid) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(nir.values.len() * 4);
+    for (nir, red) in nir.values.iter().zip(&red.values) {
+        if *nir <= 0.0 || *red <= 0.0 {
+            rgba.extend([0, 0, 0, 0]);
+            continue;
+        }
+        let ndvi = (nir - red) / (nir + red);
+        let t = ((ndvi - NDVI_RANGE.0) / (NDVI_RANGE.1 - NDVI_RANGE.0)).clamp(0.0, 1.0);
+        rgba.extend(ndvi_colour(t));
+        rgba.push(255);
+    }
+    rgba
+}
+
+/// Brown (bare) through yellow to dark green (dense vegetation)
+fn ndvi_colour(t: f32) -> [u8; 3] {
+    let stops: [[f32; 3]; 3] = [[140., 80., 30.], [240., 220., 90.], [20., 120., 40.]];
+    let (from, to, t) = match t < 0.5 {
+        true => (stops[0], stops[1], t * 2.0),
+        false => (stops[1], stops[2], (t - 0.5) * 2.0),
+    };
+    [0, 1, 2].map(|i| (from[i] + (to[i] - from[i]) * t).round() as u8)
+}
+
+fn false_color_rgba(bands: &[Grid]) -> Vec<u8> {
+    let ranges: Vec<(f32, f32)> = bands.iter().map(stretch_range).collect();
+    let pixels = bands[0].values.len();
+    let mut rgba = Vec::with_capacity(pixels * 4);
+    for i in 0..pixels {
+        if bands.iter().any(|band| band.values[i] <= 0.0) {
+            rgba.extend([0, 0, 0, 0]);
+            continue;
+        }
+        for (band, (low, high)) in bands.iter().zip(&ranges) {
+            let t = ((band.values[i] - low) / (high - low).max(f32::EPSILON)).clamp(0.0, 1.0);
+            rgba.push((t * 255.0).round() as u8);
+        }
+        rgba.push(255);
+    }
+    rgba
+}
+
+/// Values at the stretch percentiles of a band's valid pixels
+fn stretch_range(band: &Grid) -> (f32, f32) {
+    let mut valid: Vec<f32> = band.values.iter().copied().filter(|v| *v > 0.0).collect();
+    if valid.is_empty() {
+        return (0.0, 1.0);
+    }
+    valid.sort_by(|a, b| a.total_cmp(b));
+    let at = |p: f32| valid[((valid.len() - 1) as f32 * p).round() as usize];
+    (at(STRETCH_PERCENTILES.0), at(STRETCH_PERCENTILES.1))
+}
+
+fn write_png(output: &Path, width: u32, height: u32, rgba: &[u8]) -> Result<()> {
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(output)?), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    fn write_band(path: &str, width: u32, height: u32, values: &[u16]) {
+        let file = File::create(path).unwrap();
+        TiffEncoder::new(file)
+            .unwrap()
+            .write_image::<colortype::Gray16>(width, height, values)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_write_quicklook() {
+        let dir = Path::new("/tmp/slow_stac_quicklook");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        write_band(
+            "/tmp/slow_stac_quicklook/nir.tif",
+            2,
+            2,
+            &[4000, 3000, 0, 1000],
+        );
+        // Half the resolution, like the 20 m bands
+        write_band("/tmp/slow_stac_quicklook/red.tif", 1, 1, &[1000]);
+        let files = [
+            ("nir".to_string(), dir.join("nir.tif")),
+            ("red".to_string(), dir.join("red.tif")),
+        ];
+        let output = dir.join("quicklook_ndvi.png");
+        write_quicklook(Quicklook::Ndvi, &files, &output).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&output).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut rgba = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut rgba).unwrap();
+        assert_eq!((reader.info().width, reader.info().height), (2, 2));
+        // Nodata is transparent, NDVI 0 is yellowish and 0.6 green
+        assert_eq!(rgba[8..12], [0, 0, 0, 0]);
+        assert_eq!(&rgba[12..15], &ndvi_colour((0.0 + 0.2) / 1.1));
+        assert!(rgba[1] > rgba[0]);
+
+        assert!(write_quicklook(Quicklook::FalseColor, &files, &output).is_err());
+        assert!(Band::Red.matches("B04_10m"));
+        assert!(!Band::Red.matches("rededge1"));
+    }
+}
@@ -0,0 +1,191 @@
+//! Copying each completed item to an rclone remote the user already has configured, e.g. Google
+//! Drive, OneDrive or Backblaze B2, by running the `rclone` binary once per file. A file that
+//! fails to copy is retried on its own before the item is given up on.
+use crate::hooks::CompletedItem;
+use crate::upload;
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use std::time::Duration;
+
+/// Attempts at copying each file, by default
+pub const DEFAULT_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RcloneOptions {
+    /// Remote and path items are copied under, e.g. `gdrive:sentinel-2`
+    pub destination: String,
+    pub attempts: u32,
+    /// Remove each file once it has copied
+    pub remove_local: bool,
+    /// The rclone binary
+    pub rclone: String,
+}
+
+impl RcloneOptions {
+    pub fn new(destination: &str) -> Result<Self> {
+        if !destination.contains(':') {
+            return Err(anyhow!(
+                "Expected an rclone remote such as gdrive:path, got {}",
+                destination
+            ));
+        }
+        Ok(Self {
+            destination: destination.trim_end_matches('/').to_string(),
+            attempts: DEFAULT_ATTEMPTS,
+            remove_local: false,
+            rclone: "rclone".to_string(),
+        })
+    }
+
+    pub fn with_attempts(self, attempts: u32) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            ..self
+        }
+    }
+
+    pub fn with_remove_local(self, remove_local: bool) -> Self {
+        Self {
+            remove_local,
+            ..self
+        }
+    }
+
+    /// Fail early, before downloading anything, when rclone isn't installed or the remote isn't
+    /// configured.
+    pub async fn check(self: &Self) -> Result<()> {
+        let output = tokio::process::Command::new(&self.rclone)
+            .arg("listremotes")
+            .output()
+            .await
+            .with_context(|| anyhow!("Could not run {:?}, is rclone installed?", self.rclone))?;
+        if !output.status.success() {
+            return Err(anyhow!("{:?} listremotes failed", self.rclone));
+        }
+        let remote = remote_name(&self.destination);
+        let remotes = String::from_utf8_lossy(&output.stdout);
+        if !remotes.lines().any(|line| line.trim() == remote) {
+            return Err(anyhow!("No rclone remote {}, see `rclone config`", remote));
+        }
+        Ok(())
+    }
+
+    pub fn target(self: &Self, item_id: &str, file_name: &str) -> String {
+        let separator = match self.destination.ends_with(':') {
+            true => "",
+            false => "/",
+        };
+        format!("{}{}{}/{}", self.destination, separator, item_id, file_name)
+    }
+}
+
+/// `gdrive:` of `gdrive:sentinel-2`, as `rclone listremotes` lists it
+fn remote_name(destination: &str) -> String {
+    match destination.split_once(':') {
+        Some((remote, _)) => format!("{}:", remote),
+        None => destination.to_string(),
+    }
+}
+
+/// Copy every file in the item's directory to the remote, removing each once it has copied if
+/// `remove_local`.
+pub async fn copy_item(item: &CompletedItem, options: &RcloneOptions) -> Result<()> {
+    for file_name in upload::item_files(item)? {
+        let path = item.dir.join(&file_name);
+        let target = options.target(&item.item_id, &file_name);
+        copy_file(&path, &target, options).await?;
+        println!("Copied {:?} to {}", path, target);
+        if options.remove_local {
+            upload::remove_uploaded(&item.dir, &file_name)?;
+        }
+    }
+    Ok(())
+}
+
+async fn copy_file(path: &Path, target: &str, options: &RcloneOptions) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        // Retried here rather than by rclone, so each failure is reported
+        let output = tokio::process::Command::new(&options.rclone)
+            .args(["copyto", "--retries", "1"])
+            .arg(path)
+            .arg(target)
+            .output()
+            .await?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let error = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if attempt >= options.attempts {
+            return Err(anyhow!(
+                "rclone could not copy {:?} to {}: {}",
+                path,
+                target,
+                error
+            ));
+        }
+        println!(
+            "Warning: rclone could not copy {:?} (attempt {} of {}), retrying: {}",
+            path, attempt, options.attempts, error
+        );
+        tokio::time::sleep(RETRY_DELAY * attempt).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_copy_item() {
+        let root = Path::new("/tmp/slow_stac_rclone");
+        let _ = fs::remove_dir_all(root);
+        let dir = root.join("S2A");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("B04.tif"), "b04").unwrap();
+        fs::write(dir.join("B08.tif.partial"), "").unwrap();
+        // Stands in for rclone, copying locally and logging its arguments
+        let script = root.join("rclone");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {0}/log\nmkdir -p {0}/remote && cp \"$4\" {0}/remote/\n",
+                root.display()
+            ),
+        )
+        .unwrap();
+        std::process::Command::new("chmod")
+            .arg("+x")
+            .arg(&script)
+            .status()
+            .unwrap();
+
+        let options = RcloneOptions {
+            rclone: script.to_string_lossy().to_string(),
+            ..RcloneOptions::new("gdrive:sentinel-2/").unwrap()
+        }
+        .with_remove_local(true);
+        let item = CompletedItem {
+            selection_id: "provider.collection".to_string(),
+            item_id: "S2A".to_string(),
+            dir: dir.clone(),
+            files: vec![("red".to_string(), dir.join("B04.tif"))],
+        };
+        copy_item(&item, &options).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("log")).unwrap(),
+            format!(
+                "copyto --retries 1 {} gdrive:sentinel-2/S2A/B04.tif\n",
+                dir.join("B04.tif").display()
+            )
+        );
+        assert!(root.join("remote/B04.tif").exists());
+        assert!(upload::was_uploaded(&dir.join("B04.tif")));
+        assert!(!dir.join("B04.tif").exists());
+        assert_eq!(remote_name("gdrive:sentinel-2"), "gdrive:");
+        assert!(RcloneOptions::new("/local/path").is_err());
+    }
+}
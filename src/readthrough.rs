@@ -0,0 +1,243 @@
+//! Read-through access to a plan's output tree before it has downloaded, for software that only
+//! reads parts of a scene (GDAL reading the header and a few tiles of a COG). A read fetches the
+//! blocks covering the requested bytes with ranged requests and keeps them in a local block cache,
+//! so reading the same bytes again, or after a restart, costs nothing. Outputs that have already
+//! downloaded are read from disk.
+//!
+//! This is the storage side of `slow-stac mount` (see `mount`), which lists `paths`, answers
+//! `size` for attributes and serves reads with `read`.
+use crate::download_plan::DownloadPlan;
+use crate::s3::S3ObjOps;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Bytes fetched at a time. GDAL reads COG tiles of 256 to 512 pixels, tens to hundreds of KiB.
+pub const BLOCK_SIZE: u64 = 1024 * 1024;
+
+struct Entry {
+    bucket: String,
+    key: String,
+    /// Where the plan downloads the file, read from once it exists
+    output: PathBuf,
+    size: Option<u64>,
+}
+
+/// A plan's outputs by their path relative to the plan root
+pub struct ReadThrough<P> {
+    provider: P,
+    cache_dir: PathBuf,
+    entries: BTreeMap<PathBuf, Entry>,
+    /// Sizes looked up for tasks planned without one
+    sizes: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl<P: S3ObjOps> ReadThrough<P> {
+    pub fn new(provider: P, plan: &DownloadPlan, cache_dir: &Path) -> Self {
+        let entries = plan
+            .tasks()
+            .iter()
+            .map(|task| {
+                let entry = Entry {
+                    bucket: task.bucket().to_string(),
+                    key: task.key().to_string(),
+                    output: plan.output_path(task),
+                    size: task.size(),
+                };
+                (PathBuf::from(task.output().trim_start_matches('/')), entry)
+            })
+            .collect();
+        Self {
+            provider,
+            cache_dir: cache_dir.to_path_buf(),
+            entries,
+            sizes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Every file of the tree, e.g. `S2A_T08VPH_20240504T195929_L2A/B04.tif`
+    pub fn paths(self: &Self) -> Vec<&Path> {
+        self.entries.keys().map(|path| path.as_path()).collect()
+    }
+
+    fn entry(self: &Self, path: &Path) -> Result<&Entry> {
+        self.entries
+            .get(path)
+            .ok_or(anyhow!("{:?} is not in the plan", path))
+    }
+
+    pub async fn size(self: &Self, path: &Path) -> Result<u64> {
+        let entry = self.entry(path)?;
+        if let Some(size) = entry.size {
+            return Ok(size);
+        }
+        if let Some(size) = self.sizes.lock().expect("Sizes lock poisoned").get(path) {
+            return Ok(*size);
+        }
+        let size = self
+            .provider
+            .head_object(&entry.bucket, &entry.key)
+            .await?
+            .content_length()
+            .ok_or(anyhow!("Error reading size of remote object"))? as u64;
+        self.sizes
+            .lock()
+            .expect("Sizes lock poisoned")
+            .insert(path.to_path_buf(), size);
+        Ok(size)
+    }
+
+    /// Up to `length` bytes from `offset`, fewer at the end of the file
+    pub async fn read(self: &Self, path: &Path, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let entry = self.entry(path)?;
+        if entry.output.exists() {
+            let mut file = fs::File::open(&entry.output)?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut bytes = vec![];
+            file.take(length).read_to_end(&mut bytes)?;
+            return Ok(bytes);
+        }
+        let size = self.size(path).await?;
+        let end = (offset + length).min(size);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+        let mut bytes = Vec::with_capacity((end - offset) as usize);
+        for block in offset / BLOCK_SIZE..=(end - 1) / BLOCK_SIZE {
+            let block_start = block * BLOCK_SIZE;
+            let content = self.block(path, entry, block, size).await?;
+            let from = offset.saturating_sub(block_start) as usize;
+            let to = ((end - block_start) as usize).min(content.len());
+            bytes.extend_from_slice(&content[from..to]);
+        }
+        Ok(bytes)
+    }
+
+    fn block_path(self: &Self, path: &Path, block: u64) -> PathBuf {
+        let mut blocks = self.cache_dir.join(path).into_os_string();
+        blocks.push(".blocks");
+        PathBuf::from(blocks).join(block.to_string())
+    }
+
+    async fn block(
+        self: &Self,
+        path: &Path,
+        entry: &Entry,
+        block: u64,
+        size: u64,
+    ) -> Result<Vec<u8>> {
+        let block_path = self.block_path(path, block);
+        if block_path.exists() {
+            return Ok(fs::read(&block_path)?);
+        }
+        let start = block * BLOCK_SIZE;
+        let end = (start + BLOCK_SIZE).min(size) - 1;
+        let response = self
+            .provider
+            .get_object_range(&entry.bucket, &entry.key, start, end)
+            .await?;
+        let content = response.body.collect().await?.to_vec();
+        if content.len() as u64 != end + 1 - start {
+            return Err(anyhow!(
+                "Expected {} bytes of {}, received {}",
+                end + 1 - start,
+                entry.key,
+                content.len()
+            ));
+        }
+        // Written under another name so a block cut short isn't taken as complete
+        if let Some(parent) = block_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = block_path.with_extension("partial");
+        fs::write(&partial, &content)?;
+        fs::rename(&partial, &block_path)?;
+        Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_plan::DownloadTask;
+    use aws_sdk_s3::operation::get_object::GetObjectOutput;
+    use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+    use aws_sdk_s3::primitives::ByteStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counting {
+        content: Vec<u8>,
+        ranges: AtomicUsize,
+    }
+
+    impl S3ObjOps for Counting {
+        async fn head_object(self: &Self, _: &str, _: &str) -> Result<HeadObjectOutput> {
+            Ok(HeadObjectOutput::builder()
+                .content_length(self.content.len() as i64)
+                .build())
+        }
+
+        async fn get_object(self: &Self, _: &str, _: &str) -> Result<GetObjectOutput> {
+            Err(anyhow!("Only ranges are served"))
+        }
+
+        async fn get_object_range(
+            self: &Self,
+            _: &str,
+            _: &str,
+            start_byte: u64,
+            end_byte: u64,
+        ) -> Result<GetObjectOutput> {
+            self.ranges.fetch_add(1, Ordering::SeqCst);
+            let range = self.content[start_byte as usize..=end_byte as usize].to_vec();
+            Ok(GetObjectOutput::builder()
+                .body(ByteStream::from(range))
+                .build())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let root = Path::new("/tmp/slow_stac_readthrough");
+        let _ = fs::remove_dir_all(root);
+        let content: Vec<u8> = (0..BLOCK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        let provider = Counting {
+            content: content.clone(),
+            ranges: AtomicUsize::new(0),
+        };
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![
+                DownloadTask::new("bucket", "tiles/B04.tif", "S2A/B04.tif"),
+                DownloadTask::new("bucket", "tiles/B08.tif", "S2A/B08.tif"),
+            ],
+        )
+        .with_root(root.join("output"));
+        fs::create_dir_all(root.join("output/S2A")).unwrap();
+        fs::write(root.join("output/S2A/B08.tif"), "b08").unwrap();
+        let view = ReadThrough::new(provider, &plan, &root.join("cache"));
+        assert_eq!(
+            view.paths(),
+            [Path::new("S2A/B04.tif"), Path::new("S2A/B08.tif")]
+        );
+
+        let b04 = Path::new("S2A/B04.tif");
+        assert_eq!(view.size(b04).await.unwrap(), content.len() as u64);
+        let offset = BLOCK_SIZE - 5;
+        let bytes = view.read(b04, offset, 10).await.unwrap();
+        assert_eq!(bytes, content[offset as usize..offset as usize + 10]);
+        assert_eq!(view.provider.ranges.load(Ordering::SeqCst), 2);
+        // Cached blocks, and the short last block
+        let bytes = view.read(b04, BLOCK_SIZE * 2, 100).await.unwrap();
+        assert_eq!(bytes, content[BLOCK_SIZE as usize * 2..]);
+        view.read(b04, 0, 5).await.unwrap();
+        assert_eq!(view.provider.ranges.load(Ordering::SeqCst), 3);
+
+        // Downloaded outputs are read from disk
+        let b08 = Path::new("S2A/B08.tif");
+        assert_eq!(view.read(b08, 1, 10).await.unwrap(), b"08");
+    }
+}
@@ -0,0 +1,253 @@
+//! Resolve STAC assets to the storage location they should be downloaded from
+use crate::verify;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde_json::Value;
+use stac::Asset;
+use std::fmt;
+use url::Url;
+
+/// Where an asset can be fetched from. HTTPS locations use the host in place of a bucket and the
+/// url path in place of a key, which is what the HTTPS providers expect.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Location {
+    S3 {
+        bucket: String,
+        key: String,
+        region: Option<String>,
+    },
+    Gcs {
+        bucket: String,
+        key: String,
+    },
+    Https {
+        host: String,
+        path: String,
+    },
+}
+
+impl Location {
+    pub fn bucket(self: &Self) -> &str {
+        match self {
+            Location::S3 { bucket, .. } | Location::Gcs { bucket, .. } => bucket,
+            Location::Https { host, .. } => host,
+        }
+    }
+
+    pub fn key(self: &Self) -> &str {
+        match self {
+            Location::S3 { key, .. } | Location::Gcs { key, .. } => key,
+            Location::Https { path, .. } => path,
+        }
+    }
+
+    pub fn transport(self: &Self) -> Transport {
+        match self {
+            Location::S3 { .. } => Transport::S3,
+            Location::Gcs { .. } => Transport::Gcs,
+            Location::Https { .. } => Transport::Https,
+        }
+    }
+}
+
+/// How a provider reads objects, which decides the location of an asset it downloads from
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Transport {
+    S3,
+    Gcs,
+    Https,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::S3 => write!(f, "S3"),
+            Transport::Gcs => write!(f, "GCS"),
+            Transport::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// Pick the location to download an asset from with the provider's transport, considering the
+/// main href and any hrefs listed under the alternate assets extension in that order.
+pub fn resolve_asset(asset: &Asset, transport: Transport) -> Result<Location> {
+    asset_locations(asset)
+        .into_iter()
+        .find(|location| location.transport() == transport)
+        .ok_or(anyhow!(
+            "No {} location found for asset: {}",
+            transport,
+            asset.href
+        ))
+}
+
+/// Size in bytes published in the asset's `file:size` field, if any.
+pub fn file_size(asset: &Asset) -> Option<u64> {
+    asset.additional_fields.get("file:size")?.as_u64()
+}
+
+/// Checksum published in the asset's `file:checksum` multihash, as `<algorithm>:<hex digest>`.
+pub fn file_checksum(asset: &Asset) -> Option<String> {
+    verify::from_multihash(asset.additional_fields.get("file:checksum")?.as_str()?)
+}
+
+/// Every location an asset is published at, with the main href first.
+pub fn asset_locations(asset: &Asset) -> Vec<Location> {
+    let region = storage_region(asset);
+    let mut hrefs = vec![asset.href.as_str()];
+    if let Some(Value::Object(alternates)) = asset.additional_fields.get("alternate") {
+        hrefs.extend(
+            alternates
+                .values()
+                .filter_map(|alternate| alternate.get("href")?.as_str()),
+        );
+    }
+    hrefs
+        .into_iter()
+        .filter_map(|href| parse_href(href, region.as_deref()).ok())
+        .collect()
+}
+
+/// Region from the storage extension, either the v1 `storage:region` field or the region of the
+/// first v2 `storage:schemes` entry.
+fn storage_region(asset: &Asset) -> Option<String> {
+    if let Some(region) = asset.additional_fields.get("storage:region") {
+        return region.as_str().map(|r| r.to_string());
+    }
+    asset
+        .additional_fields
+        .get("storage:schemes")?
+        .as_object()?
+        .values()
+        .find_map(|scheme| scheme.get("region")?.as_str())
+        .map(|r| r.to_string())
+}
+
+/// Parse an href into a location. Supports `s3://` and `gs://` urls, virtual-hosted and path-style
+/// AWS urls, and any other HTTPS url. Local paths, relative or not, are an error.
+pub fn parse_href(href: &str, region: Option<&str>) -> Result<Location> {
+    let region = region.map(|r| r.to_string());
+
+    if let Some(path) = href.strip_prefix("s3://") {
+        let (bucket, key) = split_bucket_and_key(path, href)?;
+        return Ok(Location::S3 {
+            bucket,
+            key,
+            region,
+        });
+    }
+    if let Some(path) = href.strip_prefix("gs://") {
+        let (bucket, key) = split_bucket_and_key(path, href)?;
+        return Ok(Location::Gcs { bucket, key });
+    }
+
+    let virtual_hosted = Regex::new(
+        r"^https://(?<bucket>[^/]+)\.s3[.-](?<region>[^./]+)\.amazonaws\.com/(?<key>.+)$",
+    )
+    .expect("Regex pattern should always compile");
+    if let Some(captures) = virtual_hosted.captures(href) {
+        let (_, [bucket, url_region, key]) = captures.extract();
+        return Ok(Location::S3 {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            region: Some(url_region.to_string()),
+        });
+    }
+    let path_style = Regex::new(
+        r"^https://s3[.-](?<region>[^./]+)\.amazonaws\.com/(?<bucket>[^/]+)/(?<key>.+)$",
+    )
+    .expect("Regex pattern should always compile");
+    if let Some(captures) = path_style.captures(href) {
+        let (_, [url_region, bucket, key]) = captures.extract();
+        return Ok(Location::S3 {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            region: Some(url_region.to_string()),
+        });
+    }
+
+    let url = Url::parse(href)?;
+    if url.scheme() != "https" {
+        return Err(anyhow!("Unsupported url scheme: {}", href));
+    }
+    let host = url
+        .host_str()
+        .ok_or(anyhow!("No host found in url: {}", href))?
+        .to_string();
+    let path = url.path().trim_start_matches('/').to_string();
+    Ok(Location::Https { host, path })
+}
+
+/// Parse a bare `/bucket/key` S3 path, as Copernicus publishes the location of its products
+pub fn parse_s3_path(path: &str) -> Result<Location> {
+    let bucket_and_key = path
+        .strip_prefix('/')
+        .ok_or(anyhow!("Not an absolute S3 path: {}", path))?;
+    parse_href(&format!("s3://{}", bucket_and_key), None)
+}
+
+fn split_bucket_and_key(path: &str, href: &str) -> Result<(String, String)> {
+    let (bucket, key) = path
+        .split_once('/')
+        .ok_or(anyhow!("No object key found in href: {}", href))?;
+    Ok((bucket.to_string(), key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mock_asset(href: &str, alternates: Value) -> Asset {
+        let mut asset: Asset = serde_json::from_value(json!({ "href": href })).unwrap();
+        asset
+            .additional_fields
+            .insert("alternate".to_string(), alternates);
+        asset
+    }
+
+    #[test]
+    fn test_parse_virtual_hosted_href() {
+        let href =
+            "https://sentinel-cogs.s3.us-west-2.amazonaws.com/sentinel-s2-l2a-cogs/8/V/PH/TCI.tif";
+        let location = parse_href(href, None).unwrap();
+        assert_eq!(
+            location,
+            Location::S3 {
+                bucket: "sentinel-cogs".to_string(),
+                key: "sentinel-s2-l2a-cogs/8/V/PH/TCI.tif".to_string(),
+                region: Some("us-west-2".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_bare_path_href() {
+        let path = "/eodata/Sentinel-2/MSI/L2A/2024/05/04/product.SAFE";
+        let location = parse_s3_path(path).unwrap();
+        assert_eq!(location.bucket(), "eodata");
+        assert_eq!(location.key(), "Sentinel-2/MSI/L2A/2024/05/04/product.SAFE");
+        // Otherwise a path is local, not a bucket and key
+        assert!(parse_href(path, None).is_err());
+        assert!(parse_href("S2A/B04.tif", None).is_err());
+        assert!(parse_s3_path("S2A/B04.tif").is_err());
+    }
+
+    #[test]
+    fn test_resolve_by_transport() {
+        let asset = mock_asset(
+            "https://data.lpdaac.earthdatacloud.nasa.gov/lp-prod-protected/file.hdf",
+            json!({
+                "local": { "href": "lp-prod-protected/file.hdf" },
+                "s3": { "href": "s3://lp-prod-protected/file.hdf" },
+            }),
+        );
+        let location = resolve_asset(&asset, Transport::S3).unwrap();
+        assert_eq!(location.bucket(), "lp-prod-protected");
+
+        let location = resolve_asset(&asset, Transport::Https).unwrap();
+        assert_eq!(location.bucket(), "data.lpdaac.earthdatacloud.nasa.gov");
+        assert_eq!(location.key(), "lp-prod-protected/file.hdf");
+        assert!(resolve_asset(&asset, Transport::Gcs).is_err());
+    }
+}
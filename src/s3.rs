@@ -1,33 +1,82 @@
 //! Utility functions for creating s3 clients and modifying s3 requests
-use aws_sdk_s3::config::Region;
+use crate::provider_config::ProviderConfig;
+use crate::user_agent;
+use aws_config::{AppName, ConfigLoader};
 use aws_sdk_s3::operation::get_object::GetObjectOutput;
 use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::types::RequestPayer;
 use aws_sdk_s3::Client;
+use aws_smithy_http_client::proxy::ProxyConfig;
+use aws_smithy_http_client::tls::{self, rustls_provider::CryptoMode};
+use aws_smithy_http_client::Connector;
+use aws_smithy_runtime_api::client::http::SharedHttpClient;
+use std::time::Duration;
 
-const DEFAULT_REGION: &str = "us-east-1";
-
+/// A client signing requests with an AWS profile, see `ProviderConfig::from_profile`
 pub async fn client_from_profile(profile_name: &str) -> Client {
-    let base_config = aws_config::from_env()
-        .profile_name(profile_name)
-        .load()
-        .await;
+    ProviderConfig::from_profile(profile_name).client().await
+}
 
-    let s3_config = aws_sdk_s3::config::Builder::from(&base_config)
-        .region(Region::new(DEFAULT_REGION))
-        .force_path_style(true)
-        .build();
+pub async fn anon_client(region: &str) -> Client {
+    ProviderConfig::anonymous(region).client().await
+}
 
-    Client::from_conf(s3_config)
+/// The SDK's HTTPS client, connecting through the proxy in `HTTPS_PROXY` or `HTTP_PROXY` (see
+/// `Environment::use_proxy`) like the other HTTP clients, which the SDK's default one doesn't.
+pub(crate) fn http_client() -> SharedHttpClient {
+    aws_smithy_http_client::Builder::new().build_with_connector_fn(|settings, components| {
+        let mut builder = Connector::builder().proxy_config(ProxyConfig::from_env());
+        builder.set_connector_settings(settings.cloned());
+        if let Some(components) = components {
+            builder.set_sleep_impl(components.sleep_impl());
+        }
+        builder
+            .tls_provider(tls::Provider::Rustls(CryptoMode::AwsLc))
+            .build()
+    })
 }
 
-pub async fn anon_client(region: &str) -> Client {
-    let region = Region::new(region.to_string());
-    let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-        .no_credentials()
-        .region(region)
-        .load()
-        .await;
-    Client::new(&config)
+/// Identify requests with the crate's user agent (see `user_agent`), which the SDK appends to its own.
+pub(crate) fn with_app_name(loader: ConfigLoader) -> ConfigLoader {
+    match AppName::new(user_agent::aws_app_name()) {
+        Ok(app_name) => loader.app_name(app_name),
+        Err(_) => loader,
+    }
+}
+
+/// An object found by `S3ObjOps::list_objects`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedObject {
+    pub key: String,
+    pub size: u64,
+    pub e_tag: Option<String>,
+}
+
+/// Every object under `prefix` with ListObjectsV2, following continuation tokens
+pub async fn list_objects_v2(
+    client: &Client,
+    bucket: &str,
+    prefix: &str,
+    request_payer: Option<RequestPayer>,
+) -> anyhow::Result<Vec<ListedObject>> {
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .set_request_payer(request_payer)
+        .into_paginator()
+        .send();
+    let mut objects = vec![];
+    while let Some(page) = pages.next().await {
+        for object in page?.contents() {
+            objects.push(ListedObject {
+                key: object.key().unwrap_or_default().to_string(),
+                size: object.size().unwrap_or(0).max(0) as u64,
+                e_tag: object.e_tag().map(|e_tag| e_tag.to_string()),
+            });
+        }
+    }
+    Ok(objects)
 }
 
 pub trait S3ObjOps {
@@ -42,4 +91,157 @@ pub trait S3ObjOps {
         start_byte: u64,
         end_byte: u64,
     ) -> anyhow::Result<GetObjectOutput>;
+
+    /// HEAD a pinned version of the object (see `DownloadTask::version_id`), or the current one
+    /// for None. Providers without object versions keep these defaults, which fail for a version.
+    async fn head_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> anyhow::Result<HeadObjectOutput> {
+        match version_id {
+            None => self.head_object(bucket, key).await,
+            Some(version_id) => Err(unsupported_version(bucket, key, version_id)),
+        }
+    }
+
+    async fn get_object_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> anyhow::Result<GetObjectOutput> {
+        match version_id {
+            None => self.get_object(bucket, key).await,
+            Some(version_id) => Err(unsupported_version(bucket, key, version_id)),
+        }
+    }
+
+    async fn get_object_range_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        start_byte: u64,
+        end_byte: u64,
+    ) -> anyhow::Result<GetObjectOutput> {
+        match version_id {
+            None => {
+                self.get_object_range(bucket, key, start_byte, end_byte)
+                    .await
+            }
+            Some(version_id) => Err(unsupported_version(bucket, key, version_id)),
+        }
+    }
+
+    /// Forget anything remembered about the object, e.g. before downloading it again after it
+    /// failed verification. Providers that remember nothing keep this default.
+    fn invalidate(self: &Self, _bucket: &str, _key: &str, _version_id: Option<&str>) {}
+
+    /// Whether the ETag of an object uploaded in one part is the MD5 of its content, as with S3.
+    /// HTTP servers, GCS and Azure send opaque ETags, so providers keep this default.
+    fn md5_etags(self: &Self) -> bool {
+        false
+    }
+
+    /// Re-authenticate after requests were rejected, e.g. because temporary credentials expired
+    /// during a long download. Returns whether anything was refreshed; providers without
+    /// renewable credentials keep this default.
+    async fn refresh_credentials(self: &Self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// The objects of `bucket` whose keys start with `prefix`. Providers that cannot list keep this
+    /// default.
+    async fn list_objects(
+        self: &Self,
+        bucket: &str,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<ListedObject>> {
+        Err(anyhow::anyhow!(
+            "Cannot list {}/{}, the provider does not support listing",
+            bucket,
+            prefix
+        ))
+    }
+
+    /// A URL that downloads the object without credentials until it expires. Providers that
+    /// cannot sign URLs keep this default.
+    async fn presign_get(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        _expires_in: Duration,
+    ) -> anyhow::Result<String> {
+        Err(anyhow::anyhow!(
+            "Cannot presign {}/{}, the provider does not support presigned URLs",
+            bucket,
+            key
+        ))
+    }
+
+    /// A presigned URL of a pinned version of the object, or the current one for None. Providers
+    /// without object versions keep this default, which fails for a version.
+    async fn presign_get_version(
+        self: &Self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expires_in: Duration,
+    ) -> anyhow::Result<String> {
+        match version_id {
+            None => self.presign_get(bucket, key, expires_in).await,
+            Some(version_id) => Err(unsupported_version(bucket, key, version_id)),
+        }
+    }
+}
+
+fn unsupported_version(bucket: &str, key: &str, version_id: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Cannot get version {} of {}/{}, the provider does not support object versions",
+        version_id,
+        bucket,
+        key
+    )
+}
+
+/// Markers of rejected credentials in S3 SDK and HTTP errors
+const AUTH_ERROR_MARKERS: [&str; 10] = [
+    "ExpiredToken",
+    "InvalidAccessKeyId",
+    "InvalidToken",
+    "SignatureDoesNotMatch",
+    "AccessDenied",
+    "StatusCode(401)",
+    "StatusCode(403)",
+    "401 Unauthorized",
+    "403 Forbidden",
+    "rejected or has expired",
+];
+
+/// Whether a request failed because the credentials were rejected, as opposed to the object
+/// being missing or the connection dropping.
+pub fn is_auth_error(error: &anyhow::Error) -> bool {
+    let error = format!("{:?}", error);
+    AUTH_ERROR_MARKERS
+        .iter()
+        .any(|marker| error.contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_is_auth_error() {
+        assert!(is_auth_error(&anyhow!(
+            "Request to https://example.com/a failed with status 401 Unauthorized"
+        )));
+        assert!(is_auth_error(&anyhow!("service error: ExpiredToken")));
+        assert!(!is_auth_error(&anyhow!(
+            "Request to https://example.com/a failed with status 404 Not Found"
+        )));
+    }
 }
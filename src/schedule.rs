@@ -0,0 +1,135 @@
+//! Daily windows to download in, e.g. overnight when the connection is otherwise unused
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A daily span of time in UTC, written as `HH:MM-HH:MM`. A window ending before it starts runs
+/// past midnight, e.g. `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window {
+    /// Seconds after midnight UTC
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Window {
+    /// How long from `now` (seconds after midnight UTC) until the window opens, zero if it is
+    /// open, and how long it then stays open.
+    pub fn next(self: &Self, now: u64) -> (Duration, Duration) {
+        let length = (self.end + SECS_PER_DAY - self.start) % SECS_PER_DAY;
+        let since_start = (now + SECS_PER_DAY - self.start) % SECS_PER_DAY;
+        match since_start < length {
+            true => (Duration::ZERO, Duration::from_secs(length - since_start)),
+            false => (
+                Duration::from_secs(SECS_PER_DAY - since_start),
+                Duration::from_secs(length),
+            ),
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or(anyhow!("Expected a window as HH:MM-HH:MM, got {}", s))?;
+        let window = Window {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+        if window.start == window.end {
+            return Err(anyhow!("Window {} is empty", s));
+        }
+        Ok(window)
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Window> for String {
+    fn from(window: Window) -> Self {
+        window.to_string()
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 3600,
+            self.start % 3600 / 60,
+            self.end / 3600,
+            self.end % 3600 / 60
+        )
+    }
+}
+
+fn parse_time(s: &str) -> Result<u64> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .ok_or(anyhow!("Expected a time as HH:MM, got {}", s))?;
+    let hours: u64 = hours.parse()?;
+    let minutes: u64 = minutes.parse()?;
+    if hours > 23 || minutes > 59 {
+        return Err(anyhow!("Time out of range: {}", s));
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+/// Seconds after midnight UTC
+pub fn time_of_day() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() % SECS_PER_DAY)
+        .unwrap_or(0)
+}
+
+/// The soonest of `windows` to open from `now`: how long until it does and how long it stays open.
+pub fn next_window(windows: &[Window], now: u64) -> Option<(Duration, Duration)> {
+    windows
+        .iter()
+        .map(|window| window.next(now))
+        .min_by_key(|(wait, _)| *wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_window() {
+        let night: Window = "22:00-06:00".parse().unwrap();
+        assert_eq!(night.to_string(), "22:00-06:00");
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+        // Open, past midnight
+        assert_eq!(night.next(1 * 3600), (Duration::ZERO, hours(5)));
+        // Closed until the evening
+        assert_eq!(night.next(12 * 3600), (hours(10), hours(8)));
+
+        let lunch: Window = "12:30-13:00".parse().unwrap();
+        let windows = [night, lunch];
+        assert_eq!(
+            next_window(&windows, 7 * 3600),
+            Some((
+                Duration::from_secs(5 * 3600 + 1800),
+                Duration::from_secs(1800)
+            ))
+        );
+        assert!("25:00-01:00".parse::<Window>().is_err());
+        assert!("01:00-01:00".parse::<Window>().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! Usability of downloaded Sentinel 2 L2A scenes from their scene classification (SCL) band: the
+//! share of cloud, cloud shadow and snow over the area of interest, so scenes that are mostly
+//! obscured can be dropped from the selection without opening them. Uses GDAL's `gdal_translate`
+//! and `gdalinfo`, since Copernicus SCL bands are JPEG 2000.
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The GDAL programs scoring runs
+const PROGRAMS: [&str; 2] = ["gdal_translate", "gdalinfo"];
+
+/// Scenes with at least this percentage of cloud and cloud shadow are flagged as likely unusable
+pub const UNUSABLE_PERCENT: f64 = 50.0;
+
+/// SCL classes, see the Sentinel 2 L2A product specification
+const NO_DATA: usize = 0;
+const CLOUD_SHADOWS: usize = 3;
+const CLOUD_MEDIUM_PROBABILITY: usize = 8;
+const CLOUD_HIGH_PROBABILITY: usize = 9;
+const THIN_CIRRUS: usize = 10;
+const SNOW: usize = 11;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneScore {
+    pub item_id: String,
+    /// Pixels with data over the area of interest
+    pub valid_pixels: u64,
+    /// Percentages of the valid pixels
+    pub cloud: f64,
+    pub shadow: f64,
+    pub snow: f64,
+}
+
+impl SceneScore {
+    /// Score a scene from the pixel count of each SCL class
+    pub fn from_counts(item_id: &str, counts: &[u64]) -> Self {
+        let count = |class: usize| counts.get(class).copied().unwrap_or(0);
+        let valid_pixels = counts.iter().sum::<u64>() - count(NO_DATA);
+        let percent = |pixels: u64| match valid_pixels {
+            0 => 0.0,
+            _ => pixels as f64 * 100.0 / valid_pixels as f64,
+        };
+        let cloud =
+            count(CLOUD_MEDIUM_PROBABILITY) + count(CLOUD_HIGH_PROBABILITY) + count(THIN_CIRRUS);
+        Self {
+            item_id: item_id.to_string(),
+            valid_pixels,
+            cloud: percent(cloud),
+            shadow: percent(count(CLOUD_SHADOWS)),
+            snow: percent(count(SNOW)),
+        }
+    }
+
+    /// Whether the scene is mostly cloud and shadow, or has no data over the area of interest.
+    /// Snow isn't counted, it may be what the scene was selected for.
+    pub fn is_usable(self: &Self) -> bool {
+        self.valid_pixels > 0 && self.cloud + self.shadow < UNUSABLE_PERCENT
+    }
+}
+
+impl fmt::Display for SceneScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.valid_pixels == 0 {
+            return write!(f, "{}: no data over the area of interest", self.item_id);
+        }
+        write!(
+            f,
+            "{}: {:.1}% cloud, {:.1}% shadow, {:.1}% snow",
+            self.item_id, self.cloud, self.shadow, self.snow
+        )?;
+        if !self.is_usable() {
+            write!(f, ", likely unusable")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether an asset is a scene classification band: Earth Search's `scl` or Copernicus' `SCL_20m`
+pub fn is_scl(asset_key: &str) -> bool {
+    asset_key == "scl" || asset_key == "SCL" || asset_key.starts_with("SCL_")
+}
+
+/// The downloaded SCL band of an item, if one was selected
+pub fn scl_file(files: &[(String, PathBuf)]) -> Option<&Path> {
+    files
+        .iter()
+        .find(|(asset_key, _)| is_scl(asset_key))
+        .map(|(_, path)| path.as_path())
+}
+
+/// Whether GDAL is installed to score scenes with, checked once per run so that without it there is
+/// a single warning rather than a failure per item
+pub async fn is_available() -> bool {
+    match crate::stack::check(&PROGRAMS).await {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Warning: {}, not scoring scenes from their SCL bands", e);
+            false
+        }
+    }
+}
+
+/// Score a scene from its SCL band, counting only the pixels within `bbox` (`[min lon, min lat,
+/// max lon, max lat]`) when the plan has one.
+pub async fn score(item_id: &str, scl: &Path, bbox: Option<[f64; 4]>) -> Result<SceneScore> {
+    let clipped = scl.with_extension("aoi.vrt");
+    let input = match bbox {
+        Some([min_lon, min_lat, max_lon, max_lat]) => {
+            run(tokio::process::Command::new("gdal_translate")
+                .args(["-q", "-of", "VRT", "-projwin_srs", "EPSG:4326", "-projwin"])
+                .args([min_lon, max_lat, max_lon, min_lat].map(|v| v.to_string()))
+                .arg(scl)
+                .arg(&clipped))
+            .await?;
+            clipped.as_path()
+        }
+        None => scl,
+    };
+    // Without PAM gdalinfo doesn't leave a .aux.xml of the histogram behind
+    let info = run(tokio::process::Command::new("gdalinfo")
+        .args(["-json", "-hist"])
+        .arg(input)
+        .env("GDAL_PAM_ENABLED", "NO"))
+    .await;
+    let _ = std::fs::remove_file(&clipped);
+    let info: Value = serde_json::from_slice(&info?)?;
+    Ok(SceneScore::from_counts(item_id, &class_counts(&info)?))
+}
+
+/// Pixel count of each class from the first band's histogram in `gdalinfo -json -hist` output
+fn class_counts(info: &Value) -> Result<Vec<u64>> {
+    let histogram = &info["bands"][0]["histogram"];
+    let (Some(min), Some(max), Some(buckets)) = (
+        histogram["min"].as_f64(),
+        histogram["max"].as_f64(),
+        histogram["buckets"].as_array(),
+    ) else {
+        return Err(anyhow!("gdalinfo did not report a histogram"));
+    };
+    let width = (max - min) / buckets.len().max(1) as f64;
+    let mut counts = vec![];
+    for (i, bucket) in buckets.iter().enumerate() {
+        let class = (min + (i as f64 + 0.5) * width).round();
+        let pixels = bucket.as_u64().unwrap_or(0);
+        if class < 0.0 || pixels == 0 {
+            continue;
+        }
+        let class = class as usize;
+        if counts.len() <= class {
+            counts.resize(class + 1, 0);
+        }
+        counts[class] += pixels;
+    }
+    Ok(counts)
+}
+
+async fn run(command: &mut tokio::process::Command) -> Result<Vec<u8>> {
+    let output = command.output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{:?} failed: {}",
+            command.as_std().get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_scene_score() {
+        // The default histogram of a Byte band, one bucket per value
+        let mut buckets = vec![0; 256];
+        buckets[0] = 500;
+        buckets[4] = 40;
+        buckets[9] = 50;
+        buckets[3] = 10;
+        let info =
+            json!({"bands": [{"histogram": {"min": -0.5, "max": 255.5, "buckets": buckets}}]});
+        let score = SceneScore::from_counts("S2A", &class_counts(&info).unwrap());
+        assert_eq!(score.valid_pixels, 100);
+        assert_eq!((score.cloud, score.shadow, score.snow), (50.0, 10.0, 0.0));
+        assert!(!score.is_usable());
+        assert_eq!(
+            score.to_string(),
+            "S2A: 50.0% cloud, 10.0% shadow, 0.0% snow, likely unusable"
+        );
+
+        let empty = SceneScore::from_counts("S2B", &[20]);
+        assert!(!empty.is_usable());
+        assert!(class_counts(&json!({"bands": [{}]})).is_err());
+        assert!(is_scl("SCL_20m") && is_scl("scl") && !is_scl("B04_10m"));
+    }
+}
@@ -0,0 +1,372 @@
+//! STAC API item search driven by the `[search]` table of an image selection
+use crate::coverage::{self, Polygon};
+use crate::user_agent;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use stac::Item;
+use std::path::{Path, PathBuf};
+
+/// Kilometres per degree of latitude (and of longitude at the equator)
+const KM_PER_DEGREE: f64 = 111.32;
+const PAGE_LIMIT: u32 = 100;
+
+/// Area and time of interest used to find items when a selection has no `ids_to_download`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Search {
+    /// Single datetime or `start/end` interval (RFC 3339, open ends as `..`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datetime: Option<String>,
+    /// `[min lon, min lat, max lon, max lat]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bbox: Option<[f64; 4]>,
+    /// `[lon, lat]` of a site, expanded by `buffer_km` into a bbox
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub point: Option<[f64; 2]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer_km: Option<f64>,
+    /// Shapefile (`.shp`) or GeoPackage (`.gpkg`) whose polygons are searched with `intersects`.
+    /// Requires the `aoi-files` feature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aoi_file: Option<PathBuf>,
+    /// CQL2 filter passed to APIs implementing the filter extension, as CQL2-text or CQL2-JSON,
+    /// e.g. `"eo:cloud_cover < 20 AND platform = 'sentinel-2a'"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Maximum number of items to return
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<usize>,
+    /// Skip items whose footprint covers less than this percentage of the bbox or AOI file, e.g.
+    /// the edge tiles that barely touch it (see `coverage`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_aoi_coverage: Option<f64>,
+}
+
+impl Search {
+    /// The bbox to search, from `bbox` or else from `point` and `buffer_km`.
+    pub fn search_bbox(self: &Self) -> Result<Option<[f64; 4]>> {
+        if let Some(bbox) = self.bbox {
+            return Ok(Some(bbox));
+        }
+        match (self.point, self.buffer_km) {
+            (Some(point), buffer_km) => {
+                Ok(Some(point_buffer_bbox(point, buffer_km.unwrap_or(0.0))))
+            }
+            (None, Some(_)) => Err(anyhow!("buffer_km requires a point")),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The polygons searched, of the AOI file or else the bbox
+    pub fn aoi_polygons(self: &Self) -> Result<Vec<Polygon>> {
+        if let Some(path) = &self.aoi_file {
+            return coverage::polygons(&read_aoi_file(path)?);
+        }
+        match self.search_bbox()? {
+            Some(bbox) => Ok(vec![coverage::bbox_polygon(bbox)]),
+            None => Err(anyhow!(
+                "min_aoi_coverage requires a bbox, point or aoi_file"
+            )),
+        }
+    }
+
+    /// Whether an item's GeoJSON feature covers enough of the AOI, given its polygons. Items
+    /// without a geometry are kept.
+    fn covers(self: &Self, aoi: &[Polygon], feature: &Value) -> Result<bool> {
+        let (Some(min_coverage), Some(geometry)) = (self.min_aoi_coverage, feature.get("geometry"))
+        else {
+            return Ok(true);
+        };
+        if geometry.is_null() {
+            return Ok(true);
+        }
+        Ok(coverage::coverage(aoi, &coverage::polygons(geometry)?) >= min_coverage)
+    }
+
+    /// Body of a POST `/search` request for `collection`.
+    pub fn request_body(self: &Self, collection: &str) -> Result<Value> {
+        let mut body = Map::new();
+        body.insert("collections".to_string(), json!([collection]));
+        body.insert("limit".to_string(), json!(PAGE_LIMIT));
+        if let Some(bbox) = self.search_bbox()? {
+            body.insert("bbox".to_string(), json!(bbox));
+        }
+        if let Some(path) = &self.aoi_file {
+            body.insert("intersects".to_string(), read_aoi_file(path)?);
+        }
+        if let Some(datetime) = &self.datetime {
+            body.insert("datetime".to_string(), json!(datetime));
+        }
+        if let Some(filter) = &self.filter {
+            let (filter, lang) = cql2_filter(filter);
+            body.insert("filter".to_string(), filter);
+            body.insert("filter-lang".to_string(), json!(lang));
+        }
+        Ok(Value::Object(body))
+    }
+}
+
+/// Filters that parse as a JSON object are sent as CQL2-JSON, anything else as CQL2-text.
+fn cql2_filter(filter: &str) -> (Value, &'static str) {
+    match serde_json::from_str::<Value>(filter) {
+        Ok(json @ Value::Object(_)) => (json, "cql2-json"),
+        _ => (json!(filter), "cql2-text"),
+    }
+}
+
+#[cfg(feature = "aoi-files")]
+fn read_aoi_file(path: &Path) -> Result<Value> {
+    crate::aoi::read_aoi(path)
+}
+
+#[cfg(not(feature = "aoi-files"))]
+fn read_aoi_file(path: &Path) -> Result<Value> {
+    Err(anyhow!(
+        "Cannot read {:?}: slow-stac was built without the aoi-files feature",
+        path
+    ))
+}
+
+/// Approximate a point and buffer radius as a bbox, widening the longitude span with latitude.
+pub fn point_buffer_bbox(point: [f64; 2], buffer_km: f64) -> [f64; 4] {
+    let [lon, lat] = point;
+    let dlat = buffer_km / KM_PER_DEGREE;
+    let dlon = buffer_km / (KM_PER_DEGREE * lat.to_radians().cos().max(1e-6));
+    [
+        (lon - dlon).max(-180.0),
+        (lat - dlat).max(-90.0),
+        (lon + dlon).min(180.0),
+        (lat + dlat).min(90.0),
+    ]
+}
+
+/// Search a STAC API for items of `collection`, following `next` links until all pages (or
+/// `max_items`) have been read.
+pub async fn search_items(stac_api: &str, collection: &str, search: &Search) -> Result<Vec<Item>> {
+    let client = user_agent::client();
+    let mut items: Vec<Item> = vec![];
+    let aoi = match search.min_aoi_coverage {
+        Some(_) => search.aoi_polygons()?,
+        None => vec![],
+    };
+    let mut uncovered = 0;
+    let mut request = client
+        .post(format!("{stac_api}/search"))
+        .json(&search.request_body(collection)?);
+
+    loop {
+        let page: Value = request.send().await?.error_for_status()?.json().await?;
+        let features = page
+            .get("features")
+            .and_then(|f| f.as_array())
+            .ok_or(anyhow!("Search response has no 'features' array"))?;
+        for feature in features {
+            if !search.covers(&aoi, feature)? {
+                uncovered += 1;
+                continue;
+            }
+            items.push(serde_json::from_value(feature.clone())?);
+        }
+        println!("Found {} items", items.len());
+        if uncovered > 0 {
+            println!(
+                "Skipped {} items covering less than {}% of the area of interest",
+                uncovered,
+                search.min_aoi_coverage.unwrap_or_default()
+            );
+        }
+
+        if let Some(max_items) = search.max_items {
+            if items.len() >= max_items {
+                items.truncate(max_items);
+                break;
+            }
+        }
+        match next_link(&page) {
+            Some(next) => request = next_request(&client, next)?,
+            None => break,
+        }
+    }
+    Ok(items)
+}
+
+/// A property that can be used in CQL2 filters
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Queryable {
+    pub name: String,
+    pub title: Option<String>,
+    pub kind: String,
+}
+
+/// Fetch the queryables of a collection, falling back to the API-wide queryables for APIs that
+/// don't publish them per collection.
+pub async fn fetch_queryables(stac_api: &str, collection: &str) -> Result<Vec<Queryable>> {
+    let mut response =
+        user_agent::get(format!("{stac_api}/collections/{collection}/queryables")).await?;
+    if !response.status().is_success() {
+        response = user_agent::get(format!("{stac_api}/queryables")).await?;
+    }
+    let schema: Value = response.error_for_status()?.json().await?;
+    Ok(parse_queryables(&schema))
+}
+
+fn parse_queryables(schema: &Value) -> Vec<Queryable> {
+    let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) else {
+        return vec![];
+    };
+    properties
+        .iter()
+        .map(|(name, property)| Queryable {
+            name: name.clone(),
+            title: property
+                .get("title")
+                .and_then(|t| t.as_str())
+                .map(|t| t.to_string()),
+            kind: queryable_kind(property),
+        })
+        .collect()
+}
+
+/// Describe a JSON Schema property, e.g. `number`, `string (date-time)`, `enum [a, b]` or the
+/// last segment of a `$ref`.
+fn queryable_kind(property: &Value) -> String {
+    if let Some(values) = property.get("enum").and_then(|e| e.as_array()) {
+        let values = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        return format!("enum [{}]", values.join(", "));
+    }
+    let kind = match property.get("type") {
+        Some(Value::String(kind)) => kind.clone(),
+        Some(Value::Array(kinds)) => kinds
+            .iter()
+            .filter_map(|k| k.as_str())
+            .collect::<Vec<_>>()
+            .join(" | "),
+        _ => property
+            .get("$ref")
+            .and_then(|r| r.as_str())
+            .and_then(|r| r.rsplit('/').next())
+            .unwrap_or("any")
+            .to_string(),
+    };
+    match property.get("format").and_then(|f| f.as_str()) {
+        Some(format) => format!("{} ({})", kind, format),
+        None => kind,
+    }
+}
+
+pub fn print_queryables(queryables: &[Queryable]) {
+    let width = queryables
+        .iter()
+        .map(|q| q.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let kind_width = queryables
+        .iter()
+        .map(|q| q.kind.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!("{:<width$}  {:<kind_width$}  Title", "Name", "Type");
+    for q in queryables {
+        println!(
+            "{:<width$}  {:<kind_width$}  {}",
+            q.name,
+            q.kind,
+            q.title.as_deref().unwrap_or("")
+        );
+    }
+}
+
+fn next_link(page: &Value) -> Option<&Value> {
+    page.get("links")?
+        .as_array()?
+        .iter()
+        .find(|link| link.get("rel").and_then(|r| r.as_str()) == Some("next"))
+}
+
+fn next_request(client: &reqwest::Client, link: &Value) -> Result<reqwest::RequestBuilder> {
+    let href = link
+        .get("href")
+        .and_then(|h| h.as_str())
+        .ok_or(anyhow!("Next link has no href"))?;
+    match link.get("method").and_then(|m| m.as_str()) {
+        Some("POST") => {
+            let body = link.get("body").cloned().unwrap_or(json!({}));
+            Ok(client.post(href).json(&body))
+        }
+        _ => Ok(client.get(href)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_buffer_bbox() {
+        let [min_lon, min_lat, max_lon, max_lat] = point_buffer_bbox([-135.0, 60.0], 5.0);
+        assert!((max_lat - min_lat - 10.0 / KM_PER_DEGREE).abs() < 1e-9);
+        // A degree of longitude is half as long at 60 degrees latitude
+        assert!(((max_lon - min_lon) - 2.0 * (max_lat - min_lat)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_request_body() {
+        let search = Search {
+            datetime: Some("2024-05-01T00:00:00Z/2024-05-31T23:59:59Z".to_string()),
+            point: Some([-135.0, 60.0]),
+            buffer_km: Some(5.0),
+            ..Default::default()
+        };
+        let body = search.request_body("sentinel-2-c1-l2a").unwrap();
+        assert_eq!(body["collections"], json!(["sentinel-2-c1-l2a"]));
+        assert_eq!(body["bbox"].as_array().unwrap().len(), 4);
+        assert!(body.get("datetime").is_some());
+    }
+
+    #[test]
+    fn test_cql2_filter() {
+        let (filter, lang) = cql2_filter("eo:cloud_cover < 20");
+        assert_eq!(filter, json!("eo:cloud_cover < 20"));
+        assert_eq!(lang, "cql2-text");
+
+        let (filter, lang) =
+            cql2_filter(r#"{"op": "<", "args": [{"property": "eo:cloud_cover"}, 20]}"#);
+        assert_eq!(filter["op"], json!("<"));
+        assert_eq!(lang, "cql2-json");
+    }
+
+    #[test]
+    fn test_parse_queryables() {
+        let schema = json!({
+            "properties": {
+                "datetime": { "title": "Acquired", "type": "string", "format": "date-time" },
+                "eo:cloud_cover": { "type": ["number", "null"] },
+                "platform": { "enum": ["sentinel-2a", "sentinel-2b"] },
+                "geometry": { "$ref": "https://geojson.org/schema/Geometry.json" }
+            }
+        });
+        let queryables = parse_queryables(&schema);
+        let kind = |name: &str| {
+            queryables
+                .iter()
+                .find(|q| q.name == name)
+                .map(|q| q.kind.clone())
+                .unwrap()
+        };
+        assert_eq!(kind("datetime"), "string (date-time)");
+        assert_eq!(kind("eo:cloud_cover"), "number | null");
+        assert_eq!(kind("platform"), r#"enum ["sentinel-2a", "sentinel-2b"]"#);
+        assert_eq!(kind("geometry"), "Geometry.json");
+    }
+
+    #[test]
+    fn test_buffer_without_point() {
+        let search = Search {
+            buffer_km: Some(5.0),
+            ..Default::default()
+        };
+        assert!(search.search_bbox().is_err());
+    }
+}
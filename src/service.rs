@@ -0,0 +1,178 @@
+//! systemd units for unattended downloads on remote Linux boxes
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+/// Seconds systemd waits before restarting a failed download
+const RESTART_SEC: u64 = 300;
+
+/// A service running `slow-stac download` until the plan completes. It is restarted when the
+/// download fails, except when the credentials are rejected (exit code 3, see
+/// `failure::FailureKind`), which won't fix itself, or the plan is paused (exit code 7), which
+/// `slow-stac resume` continues.
+#[derive(Debug, Clone)]
+pub struct ServiceUnit {
+    /// Unit name without the `.service` suffix
+    pub name: String,
+    pub description: String,
+    /// Program and arguments to run
+    pub exec_start: Vec<String>,
+    pub working_directory: PathBuf,
+    /// Variables set for the program, e.g. `SLOW_STAC_CONFIG` so a system unit reads the config
+    /// file of the user who installed it
+    pub environment: Vec<(String, String)>,
+    /// Install for the current user rather than system wide
+    pub user: bool,
+}
+
+impl ServiceUnit {
+    /// `$XDG_CONFIG_HOME/systemd/user` (or `~/.config/systemd/user`) for user units,
+    /// `/etc/systemd/system` otherwise
+    pub fn path(self: &Self) -> Result<PathBuf> {
+        let file_name = format!("{}.service", self.name);
+        if !self.user {
+            return Ok(PathBuf::from("/etc/systemd/system").join(file_name));
+        }
+        let config_dir = match std::env::var("XDG_CONFIG_HOME") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => std::env::var("HOME")
+                .map(|home| PathBuf::from(home).join(".config"))
+                .map_err(|_| anyhow!("Set HOME or XDG_CONFIG_HOME to install a user service"))?,
+        };
+        Ok(config_dir.join("systemd/user").join(file_name))
+    }
+
+    pub fn to_unit(self: &Self) -> String {
+        let exec_start: Vec<String> = self.exec_start.iter().map(|arg| quote(arg)).collect();
+        let environment: String = self
+            .environment
+            .iter()
+            .map(|(name, value)| format!("Environment={}\n", assignment(name, value)))
+            .collect();
+        let wanted_by = match self.user {
+            true => "default.target",
+            false => "multi-user.target",
+        };
+        format!(
+            "[Unit]
+Description={}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=exec
+WorkingDirectory={}
+{}ExecStart={}
+Restart=on-failure
+RestartSec={}
+RestartPreventExitStatus=3 7
+
+[Install]
+WantedBy={}
+",
+            self.description,
+            working_directory(&self.working_directory.to_string_lossy()),
+            environment,
+            exec_start.join(" "),
+            RESTART_SEC,
+            wanted_by
+        )
+    }
+
+    pub fn write(self: &Self) -> Result<PathBuf> {
+        let path = self.path()?;
+        if path.exists() {
+            return Err(anyhow!("File already exists {:?}", path));
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, self.to_unit())?;
+        Ok(path)
+    }
+}
+
+/// A unit name from a plan file name, e.g. `slow-stac-e84_sentinel2_download_plan`
+pub fn unit_name(plan: &str) -> String {
+    let stem = plan.split('.').next().unwrap_or(plan);
+    let stem: String = stem
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                true => c,
+                false => '-',
+            },
+        )
+        .collect();
+    format!("slow-stac-{}", stem)
+}
+
+/// A `WorkingDirectory=` value. The rest of the line is the path, spaces included and without
+/// quotes, so only specifiers are escaped.
+fn working_directory(path: &str) -> String {
+    path.replace('%', "%%")
+}
+
+/// A quoted `Environment=` assignment. Specifiers are expanded there, variables aren't.
+fn assignment(name: &str, value: &str) -> String {
+    let escaped = format!("{}={}", name, value)
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{}\"", escaped)
+}
+
+/// Quote an ExecStart argument, escaping systemd's specifiers and variable expansion
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    match escaped.is_empty() || escaped.contains(char::is_whitespace) || escaped != arg {
+        true => format!("\"{}\"", escaped),
+        false => escaped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_unit() {
+        let unit = ServiceUnit {
+            name: unit_name("e84 plan.json"),
+            description: "slow-stac download of e84 plan.json".to_string(),
+            exec_start: vec![
+                "/usr/local/bin/slow-stac".to_string(),
+                "download".to_string(),
+                "/data/e84 plan.json".to_string(),
+                "--env".to_string(),
+                "field".to_string(),
+            ],
+            working_directory: PathBuf::from("/data/100% cloud free"),
+            environment: vec![(
+                "SLOW_STAC_CONFIG".to_string(),
+                "/home/field/.config/slow-stac/config.toml".to_string(),
+            )],
+            user: false,
+        };
+        assert_eq!(unit.name, "slow-stac-e84-plan");
+        assert_eq!(
+            unit.path().unwrap(),
+            PathBuf::from("/etc/systemd/system/slow-stac-e84-plan.service")
+        );
+        let text = unit.to_unit();
+        assert!(text.contains(
+            "ExecStart=/usr/local/bin/slow-stac download \"/data/e84 plan.json\" --env field\n"
+        ));
+        assert!(text.contains("WorkingDirectory=/data/100%% cloud free\n"));
+        assert!(text.contains(
+            "Environment=\"SLOW_STAC_CONFIG=/home/field/.config/slow-stac/config.toml\"\n"
+        ));
+        assert!(text.contains("Restart=on-failure\n"));
+        assert!(text.contains("RestartPreventExitStatus=3 7\n"));
+        assert!(text.contains("WantedBy=multi-user.target\n"));
+        assert_eq!(quote("50%"), "\"50%%\"");
+    }
+}
@@ -0,0 +1,104 @@
+//! Items left out of a plan while it runs, e.g. a scene found to be cloudy part way through a
+//! download. `<plan>.skip` lists one item id per line, added with `plan skip` or by editing the
+//! file; `#` starts a comment. It's read again before each task, so items added during a run are
+//! skipped from then on. Their tasks are reported as skipped rather than failed.
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkipList {
+    path: PathBuf,
+}
+
+impl SkipList {
+    /// The skip list of the plan at `plan`
+    pub fn of_plan(plan: &Path) -> Self {
+        Self {
+            path: PathBuf::from(format!("{}.skip", plan.to_string_lossy())),
+        }
+    }
+
+    pub fn path(self: &Self) -> &Path {
+        &self.path
+    }
+
+    /// Ids of the listed items, none if the file doesn't exist
+    pub fn items(self: &Self) -> Result<HashSet<String>> {
+        if !self.path.exists() {
+            return Ok(HashSet::new());
+        }
+        Ok(fs::read_to_string(&self.path)?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|item| !item.is_empty())
+            .map(|item| item.to_string())
+            .collect())
+    }
+
+    /// Whether `item` is listed. A list that can't be read skips nothing, so a bad edit doesn't
+    /// stop the download.
+    pub fn contains(self: &Self, item: &str) -> bool {
+        match self.items() {
+            Ok(items) => items.contains(item),
+            Err(e) => {
+                println!("Warning: could not read {:?}: {}", self.path, e);
+                false
+            }
+        }
+    }
+
+    /// Append the items not listed yet, returning how many were added
+    pub fn add(self: &Self, items: &[String]) -> Result<usize> {
+        let listed = self.items()?;
+        // A hand-edited last line may lack its newline, which would join it with the first item
+        let unterminated = match self.path.exists() {
+            true => fs::read(&self.path)?
+                .last()
+                .is_some_and(|byte| *byte != b'\n'),
+            false => false,
+        };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if unterminated {
+            writeln!(file)?;
+        }
+        let mut added = 0;
+        for item in items {
+            if !listed.contains(item) {
+                writeln!(file, "{}", item)?;
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_list() {
+        let skip_list = SkipList::of_plan(Path::new("/tmp/slow_stac_skip_list.json"));
+        let _ = fs::remove_file(skip_list.path());
+        assert!(!skip_list.contains("S2A_1"));
+        fs::write(skip_list.path(), "# cloudy\nS2A_1  # 80%\n\n").unwrap();
+        assert!(skip_list.contains("S2A_1"));
+
+        let items = ["S2A_1".to_string(), "S2B_2".to_string()];
+        assert_eq!(skip_list.add(&items).unwrap(), 1);
+        assert_eq!(skip_list.items().unwrap(), HashSet::from(items));
+
+        fs::write(skip_list.path(), "S2A_1").unwrap();
+        assert_eq!(skip_list.add(&["S2B_2".to_string()]).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(skip_list.path()).unwrap(),
+            "S2A_1\nS2B_2\n"
+        );
+    }
+}
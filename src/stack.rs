@@ -0,0 +1,180 @@
+//! Stacking an item's bands into one multiband GeoTIFF with GDAL, in the order the selection
+//! listed them, once they have all downloaded.
+use crate::hooks::CompletedItem;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Lists the band files removed after stacking, so resuming the plan doesn't download them again
+pub const STACKED_MARKER: &str = ".slow-stac-stacked";
+pub const RASTER_EXTENSIONS: [&str; 3] = ["tif", "tiff", "jp2"];
+/// The GDAL programs stacking runs
+pub const PROGRAMS: [&str; 2] = ["gdalbuildvrt", "gdal_translate"];
+
+/// `[stack]` in the config file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StackOptions {
+    /// Keep the individual band files next to the stack
+    #[serde(default = "default_keep_bands")]
+    pub keep_bands: bool,
+    /// GDAL COG driver compression, e.g. DEFLATE, ZSTD or LZW
+    #[serde(default = "default_compression")]
+    pub compression: String,
+}
+
+fn default_keep_bands() -> bool {
+    true
+}
+
+fn default_compression() -> String {
+    crate::cog::DEFAULT_COMPRESSION.to_string()
+}
+
+impl Default for StackOptions {
+    fn default() -> Self {
+        Self {
+            keep_bands: default_keep_bands(),
+            compression: default_compression(),
+        }
+    }
+}
+
+/// The stack written to an item's directory, e.g. `S2A_T08VPH_20240504T195929_L2A_stack.tif`
+pub fn stack_path(item: &CompletedItem) -> PathBuf {
+    stack_file(&item.dir, &item.item_id)
+}
+
+pub(crate) fn stack_file(dir: &Path, item_id: &str) -> PathBuf {
+    dir.join(format!("{}_stack.tif", item_id))
+}
+
+/// The raster files of an item in plan (and so selection) order, skipping metadata and previews
+pub fn band_files(item: &CompletedItem) -> Vec<&Path> {
+    item.files
+        .iter()
+        .map(|(_, path)| path.as_path())
+        .filter(|path| {
+            path.extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .is_some_and(|extension| RASTER_EXTENSIONS.contains(&extension.as_str()))
+        })
+        .collect()
+}
+
+/// Whether a task's output is missing because it was stacked and removed.
+pub fn was_stacked(output: &Path) -> bool {
+    let (Some(dir), Some(file_name)) = (output.parent(), output.file_name()) else {
+        return false;
+    };
+    match fs::read_to_string(dir.join(STACKED_MARKER)) {
+        Ok(removed) => removed
+            .lines()
+            .any(|line| line == file_name.to_string_lossy()),
+        Err(_) => false,
+    }
+}
+
+/// Stack the item's bands through a VRT (resampling coarser bands to the finest resolution) into
+/// a COG, then remove the bands unless `keep_bands`.
+pub async fn stack_item(item: &CompletedItem, options: &StackOptions) -> Result<PathBuf> {
+    let bands = band_files(item);
+    if bands.is_empty() {
+        return Err(anyhow!("{} has no bands to stack", item.item_id));
+    }
+    let output = stack_path(item);
+    let vrt = item.dir.join(".stack.vrt");
+    let converting = output.with_extension("converting.tif");
+    run(tokio::process::Command::new("gdalbuildvrt")
+        .args(["-q", "-separate", "-resolution", "highest"])
+        .arg(&vrt)
+        .args(&bands))
+    .await?;
+    let translated = run(tokio::process::Command::new("gdal_translate")
+        .args(["-q", "-of", "COG"])
+        .args(["-co", &format!("COMPRESS={}", options.compression)])
+        .args(["-co", "BIGTIFF=IF_SAFER"])
+        .arg(&vrt)
+        .arg(&converting))
+    .await;
+    let _ = fs::remove_file(&vrt);
+    translated?;
+    fs::rename(&converting, &output)?;
+    println!("Stacked {} bands into {:?}", bands.len(), output);
+
+    if !options.keep_bands {
+        // Recorded before removing anything, it's what stops the bands downloading again
+        let marker = item.dir.join(STACKED_MARKER);
+        let mut lines = fs::read_to_string(&marker).unwrap_or_default();
+        for band in &bands {
+            if let Some(file_name) = band.file_name() {
+                lines.push_str(&format!("{}\n", file_name.to_string_lossy()));
+            }
+        }
+        fs::write(marker, lines)?;
+        for band in bands {
+            fs::remove_file(band)?;
+        }
+    }
+    Ok(output)
+}
+
+/// Check the GDAL programs run, so a missing GDAL fails before downloading rather than once the
+/// first item completes
+pub async fn check(programs: &[&str]) -> Result<()> {
+    for program in programs {
+        let output = tokio::process::Command::new(program)
+            .arg("--version")
+            .output()
+            .await
+            .with_context(|| anyhow!("Could not run {}, is GDAL installed?", program))?;
+        if !output.status.success() {
+            return Err(anyhow!("{} --version failed", program));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn run(command: &mut tokio::process::Command) -> Result<()> {
+    let output = command.output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{:?} failed: {}",
+            command.as_std().get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_band_files() {
+        let dir = PathBuf::from("/tmp/slow_stac_stack/S2A");
+        let _ = fs::remove_dir_all("/tmp/slow_stac_stack");
+        fs::create_dir_all(&dir).unwrap();
+        let item = CompletedItem {
+            selection_id: "provider.collection".to_string(),
+            item_id: "S2A".to_string(),
+            dir: dir.clone(),
+            files: vec![
+                ("nir".to_string(), dir.join("B08.tif")),
+                ("metadata".to_string(), dir.join("metadata.xml")),
+                ("red".to_string(), dir.join("B04.TIF")),
+            ],
+        };
+        assert_eq!(
+            band_files(&item),
+            [dir.join("B08.tif"), dir.join("B04.TIF")]
+        );
+        assert_eq!(stack_path(&item), dir.join("S2A_stack.tif"));
+
+        assert!(!was_stacked(&dir.join("B08.tif")));
+        fs::write(dir.join(STACKED_MARKER), "B08.tif\n").unwrap();
+        assert!(was_stacked(&dir.join("B08.tif")));
+        assert!(!was_stacked(&dir.join("B04.TIF")));
+    }
+}
@@ -0,0 +1,175 @@
+//! Listing a plan's tasks with their status, to find the failed or pending files of a large plan
+//! from the terminal. A task is done once its output (or what it was stacked into or uploaded as)
+//! exists, and failed when a download left a partial file behind, which the next run resumes.
+use crate::download_plan::{DownloadPlan, DownloadTask};
+use crate::porcelain;
+use crate::units;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    Done,
+    /// Stopped part way, or still downloading in another process
+    Failed,
+    Pending,
+}
+
+impl TaskStatus {
+    fn name(self: &Self) -> &'static str {
+        match self {
+            TaskStatus::Done => "done",
+            TaskStatus::Failed => "failed",
+            TaskStatus::Pending => "pending",
+        }
+    }
+
+    /// Green, red and yellow
+    fn color(self: &Self) -> &'static str {
+        match self {
+            TaskStatus::Done => "\x1b[32m",
+            TaskStatus::Failed => "\x1b[31m",
+            TaskStatus::Pending => "\x1b[33m",
+        }
+    }
+}
+
+pub fn status(plan: &DownloadPlan, task: &DownloadTask) -> TaskStatus {
+    if plan.is_done(task) {
+        return TaskStatus::Done;
+    }
+    let output = plan.output_path(task);
+    match PathBuf::from(format!("{}.partial", output.to_string_lossy())).exists() {
+        true => TaskStatus::Failed,
+        false => TaskStatus::Pending,
+    }
+}
+
+/// Which tasks to list, all of them when neither is set
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TaskFilter {
+    pub failed: bool,
+    /// Tasks not done yet, including the failed ones
+    pub pending: bool,
+}
+
+impl TaskFilter {
+    fn matches(self: &Self, status: TaskStatus) -> bool {
+        match status {
+            _ if !self.failed && !self.pending => true,
+            TaskStatus::Done => false,
+            TaskStatus::Failed => self.failed || self.pending,
+            TaskStatus::Pending => self.pending,
+        }
+    }
+}
+
+/// The filtered tasks as a table of item, product, size and status, the statuses colored for a
+/// terminal if `color`, followed by counts of each status
+pub fn to_table(plan: &DownloadPlan, filter: TaskFilter, color: bool) -> String {
+    let rows: Vec<_> = plan
+        .tasks()
+        .iter()
+        .map(|task| (task, status(plan, task)))
+        .filter(|(_, status)| filter.matches(*status))
+        .map(|(task, status)| {
+            let size = task.size().map_or("?".to_string(), units::size);
+            let product = task.asset_key().unwrap_or_default().to_string();
+            (task.item(), product, size, status)
+        })
+        .collect();
+    let item_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0).max(4);
+    let product_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0).max(7);
+    let mut lines = vec![format!(
+        "{:<item_width$}  {:<product_width$}  {:>10}  {}",
+        "Item", "Product", "Size", "Status"
+    )];
+    for (item, product, size, status) in rows.iter() {
+        let status = match color {
+            true => format!("{}{}\x1b[0m", status.color(), status.name()),
+            false => status.name().to_string(),
+        };
+        lines.push(format!(
+            "{:<item_width$}  {:<product_width$}  {:>10}  {}",
+            item, product, size, status
+        ));
+    }
+    let count = |status| rows.iter().filter(|row| row.3 == status).count();
+    lines.push(format!(
+        "\n{} tasks: {} done, {} failed, {} pending",
+        rows.len(),
+        count(TaskStatus::Done),
+        count(TaskStatus::Failed),
+        count(TaskStatus::Pending)
+    ));
+    lines.join("\n")
+}
+
+/// The filtered tasks as `--porcelain` records of item, product, size, status and output
+pub fn to_porcelain(plan: &DownloadPlan, filter: TaskFilter) -> String {
+    plan.tasks()
+        .iter()
+        .map(|task| (task, status(plan, task)))
+        .filter(|(_, status)| filter.matches(*status))
+        .map(|(task, status)| {
+            porcelain::record(
+                "task",
+                &[
+                    &task.item(),
+                    &task.asset_key().unwrap_or_default(),
+                    &porcelain::optional(task.size()),
+                    &status.name(),
+                    &task.output(),
+                ],
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_to_table() {
+        let root = Path::new("/tmp/slow_stac_task_list");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("S2A")).unwrap();
+        fs::write(root.join("S2A/B04.tif"), "b04").unwrap();
+        fs::write(root.join("S2A/B08.tif.partial"), "b0").unwrap();
+        let task = |asset_key: &str| {
+            DownloadTask::new("bucket", asset_key, &format!("S2A/{}.tif", asset_key))
+                .with_asset_key(asset_key)
+                .with_size(Some(2048))
+        };
+        let plan = DownloadPlan::new(
+            "provider.collection",
+            vec![task("B04"), task("B08"), task("B11")],
+        )
+        .with_root(root);
+        let statuses: Vec<_> = plan.tasks().iter().map(|t| status(&plan, t)).collect();
+        assert_eq!(
+            statuses,
+            [TaskStatus::Done, TaskStatus::Failed, TaskStatus::Pending]
+        );
+
+        let pending = TaskFilter {
+            pending: true,
+            ..Default::default()
+        };
+        let table = to_table(&plan, pending, false);
+        assert!(table.contains("S2A   B08         2.0 KiB  failed"));
+        assert!(!table.contains("B04"));
+        assert!(table.ends_with("2 tasks: 0 done, 1 failed, 1 pending"));
+        let failed = TaskFilter {
+            failed: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            to_porcelain(&plan, failed),
+            "task\tS2A\tB08\t2048\tfailed\tS2A/B08.tif"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Time-series manifests of a downloaded plan, with `download --timeseries`, for scripts building
+//! data cubes with xarray or rasterio. Each downloaded item is a row with its acquisition date,
+//! tile and the path of each band, sorted by date, written as `timeseries.csv` (a column per band)
+//! and `timeseries.json` into the plan's root. Paths are relative to the root, so the directory
+//! can be moved with its manifest. Bands stacked and removed by `--stack-remove-bands` are listed
+//! as the item's stack, and files uploaded and removed by an upload hook as where they were
+//! uploaded to.
+use crate::download_plan::DownloadPlan;
+use crate::hooks::ItemHook;
+use crate::ids::Acquisition;
+use crate::stack;
+use crate::upload;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CSV_FILE: &str = "timeseries.csv";
+pub const JSON_FILE: &str = "timeseries.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acquired {
+    /// `YYYY-MM-DD`
+    pub date: String,
+    /// MGRS tile, for ids that have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tile: Option<String>,
+    pub item_id: String,
+    /// Path by band or asset, e.g. `red` or `B04`
+    pub files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub selection_id: String,
+    /// Every band of any item, in the order of the CSV columns
+    pub bands: Vec<String>,
+    pub items: Vec<Acquired>,
+}
+
+impl Manifest {
+    /// The downloaded files of `plan`, with paths relative to `dir`, after `hooks` have run.
+    /// Items without a date in their task or id are left out.
+    pub fn new(plan: &DownloadPlan, dir: &Path, hooks: &[ItemHook]) -> Self {
+        let mut items: BTreeMap<String, Acquired> = BTreeMap::new();
+        for task in plan.tasks() {
+            let output = plan.output_path(task);
+            let item_id = task.item();
+            let Some(location) = location(&output, &item_id, dir, hooks) else {
+                continue;
+            };
+            let acquisition = Acquisition::from_id(&item_id);
+            let date = match (task.datetime(), &acquisition) {
+                (Some(datetime), _) if datetime.len() >= 10 => datetime[..10].to_string(),
+                (_, Some(acquisition)) => {
+                    let (year, rest) = acquisition.date.split_at(4);
+                    let (month, day) = rest.split_at(2);
+                    format!("{}-{}-{}", year, month, day)
+                }
+                _ => continue,
+            };
+            let band = match task.asset_key() {
+                Some(asset_key) => asset_key.to_string(),
+                None => output
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            };
+            let item = items.entry(item_id.clone()).or_insert(Acquired {
+                date,
+                tile: acquisition.map(|acquisition| acquisition.tile),
+                item_id,
+                files: BTreeMap::new(),
+            });
+            item.files.insert(band, location);
+        }
+        let bands: BTreeSet<_> = items
+            .values()
+            .flat_map(|item| item.files.keys().cloned())
+            .collect();
+        let mut items: Vec<_> = items.into_values().collect();
+        items.sort_by(|a, b| (&a.date, &a.item_id).cmp(&(&b.date, &b.item_id)));
+        Self {
+            selection_id: plan.selection_id.clone(),
+            bands: bands.into_iter().collect(),
+            items,
+        }
+    }
+
+    /// A row per item, with empty cells for the bands it lacks
+    pub fn to_csv(self: &Self) -> String {
+        let mut lines = vec![["date", "tile", "item_id"]
+            .iter()
+            .map(|column| column.to_string())
+            .chain(self.bands.iter().map(|band| csv_field(band)))
+            .collect::<Vec<_>>()
+            .join(",")];
+        for item in &self.items {
+            let mut fields = vec![
+                item.date.clone(),
+                item.tile.clone().unwrap_or_default(),
+                csv_field(&item.item_id),
+            ];
+            for band in &self.bands {
+                fields.push(csv_field(item.files.get(band).map_or("", String::as_str)));
+            }
+            lines.push(fields.join(","));
+        }
+        lines.join("\n") + "\n"
+    }
+}
+
+/// Where a task's output is now: the item's stack if it was stacked and removed, uploaded if it
+/// was uploaded and removed. None if it didn't download.
+fn location(output: &Path, item_id: &str, dir: &Path, hooks: &[ItemHook]) -> Option<String> {
+    let file = match (output.parent(), stack::was_stacked(output)) {
+        (Some(item_dir), true) => stack::stack_file(item_dir, item_id),
+        _ => output.to_path_buf(),
+    };
+    if file.exists() {
+        let path = file.strip_prefix(dir).unwrap_or(&file);
+        return Some(path.to_string_lossy().to_string());
+    }
+    let file_name = file.file_name()?.to_string_lossy();
+    match upload::was_uploaded(&file) {
+        true => hooks
+            .iter()
+            .find_map(|hook| hook.uploaded_to(item_id, &file_name)),
+        false => None,
+    }
+}
+
+/// Quoted when it holds a comma or a quote
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/// Write the manifests of `plan` into its root, or the working directory without one, returning
+/// the path of the CSV
+pub fn write(plan: &DownloadPlan, hooks: &[ItemHook]) -> Result<PathBuf> {
+    let dir = PathBuf::from(plan.root().unwrap_or("."));
+    let manifest = Manifest::new(plan, &dir, hooks);
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join(JSON_FILE),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    let csv = dir.join(CSV_FILE);
+    fs::write(&csv, manifest.to_csv())?;
+    Ok(csv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_plan::DownloadTask;
+
+    #[test]
+    fn test_manifest() {
+        let root = Path::new("/tmp/slow_stac_timeseries");
+        let _ = fs::remove_dir_all(root);
+        let later = "S2A_T33UUP_20240611T101601_L2A";
+        let earlier = "S2B_T33UUP_20240503T101559_L2A";
+        let task = |item: &str, band: &str| {
+            DownloadTask::new("bucket", band, &format!("{}/{}.tif", item, band))
+                .with_asset_key(band)
+        };
+        let plan = DownloadPlan::new(
+            "element84.sentinel2collection1level2a",
+            vec![
+                task(later, "red"),
+                task(later, "nir"),
+                task(earlier, "red"),
+                task(earlier, "nir"),
+            ],
+        )
+        .with_root(root);
+        for output in [
+            format!("{}/red.tif", later),
+            format!("{}/nir.tif", later),
+            format!("{}/red.tif", earlier),
+        ] {
+            fs::create_dir_all(root.join(&output).parent().unwrap()).unwrap();
+            fs::write(root.join(&output), "band").unwrap();
+        }
+
+        let csv = write(&plan, &[]).unwrap();
+        assert_eq!(
+            fs::read_to_string(csv).unwrap(),
+            format!(
+                "date,tile,item_id,nir,red\n\
+                 2024-05-03,33UUP,{earlier},,{earlier}/red.tif\n\
+                 2024-06-11,33UUP,{later},{later}/nir.tif,{later}/red.tif\n"
+            )
+        );
+        let json = fs::read_to_string(root.join(JSON_FILE)).unwrap();
+        let manifest: Manifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(manifest.items[1].files["nir"], format!("{}/nir.tif", later));
+
+        // Stacked and removed, then uploaded and removed
+        fs::remove_file(root.join(later).join("nir.tif")).unwrap();
+        fs::write(root.join(later).join(stack::STACKED_MARKER), "nir.tif\n").unwrap();
+        fs::remove_file(root.join(earlier).join("red.tif")).unwrap();
+        fs::write(
+            root.join(earlier).join(upload::UPLOADED_MARKER),
+            "red.tif\n",
+        )
+        .unwrap();
+        let stack = format!("{}/{}_stack.tif", later, later);
+        fs::write(root.join(&stack), "stack").unwrap();
+        let hooks = [ItemHook::Upload(
+            upload::UploadOptions::new("s3://bucket/prefix", "default").unwrap(),
+        )];
+        let manifest = Manifest::new(&plan, root, &hooks);
+        assert_eq!(
+            manifest.items[0].files["red"],
+            format!("s3://bucket/prefix/{}/red.tif", earlier)
+        );
+        assert_eq!(manifest.items[1].files["nir"], stack);
+        assert_eq!(manifest.items[1].files["red"], format!("{}/red.tif", later));
+    }
+}
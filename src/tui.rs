@@ -0,0 +1,313 @@
+//! Live dashboard of a download, shown with `download --tui`: a progress bar for each running
+//! task with its retries, the throughput over the last minutes and the time left. Keys steer the
+//! download through its `Control`: `p` pauses and resumes, `s` skips the selected task, `-` and
+//! `+` lower and raise how many tasks run at once, and `q` quits, leaving partial files for the
+//! next run to resume.
+//!
+//! The dashboard runs on its own thread and draws to the terminal it's given, which should be the
+//! original stdout once messages printed while downloading are redirected elsewhere.
+use crate::control::Control;
+use crate::units;
+use anyhow::Result;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::crossterm::{cursor, execute};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph, Sparkline};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Time between throughput samples
+const SAMPLE: Duration = Duration::from_secs(1);
+/// Samples kept for the sparkline, the rate is averaged over the last `RATE_SAMPLES`
+const SAMPLES: usize = 300;
+const RATE_SAMPLES: usize = 30;
+
+/// Bytes received in each of the last `SAMPLES` seconds
+#[derive(Debug, Default)]
+struct Throughput {
+    samples: VecDeque<u64>,
+    last: u64,
+}
+
+impl Throughput {
+    /// Record the total bytes received so far
+    fn sample(self: &mut Self, received: u64) {
+        self.samples.push_back(received.saturating_sub(self.last));
+        self.last = received;
+        if self.samples.len() > SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second over the last `RATE_SAMPLES` samples
+    fn rate(self: &Self) -> f64 {
+        let recent = self.samples.iter().rev().take(RATE_SAMPLES);
+        let count = recent.len().max(1);
+        recent.sum::<u64>() as f64 / count as f64
+    }
+
+    /// Time to receive what's left of `remaining` bytes at the current rate
+    fn eta(self: &Self, remaining: Option<u64>) -> Option<Duration> {
+        let left = remaining?.saturating_sub(self.last);
+        let rate = self.rate();
+        match rate > 0.0 {
+            true => Some(Duration::from_secs_f64(left as f64 / rate)),
+            false => None,
+        }
+    }
+}
+
+struct Dashboard {
+    control: Arc<Control>,
+    /// Bytes left in the plan when it started, for the time left
+    remaining: Option<u64>,
+    throughput: Throughput,
+    /// Index of the selected running task
+    selected: usize,
+}
+
+impl Dashboard {
+    fn draw(self: &Self, frame: &mut Frame) {
+        let running = self.control.running();
+        let [status, sparkline, tasks, keys] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(6),
+            Constraint::Min(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let state = match self.control.is_paused() {
+            true => "Paused",
+            false => "Downloading",
+        };
+        let eta = self
+            .throughput
+            .eta(self.remaining)
+            .map_or("unknown".to_string(), units::duration);
+        frame.render_widget(
+            Paragraph::new(format!(
+                "{}, {} of {} jobs running, {} done, {} failed, {} left",
+                state,
+                running.len(),
+                self.control.jobs(),
+                self.control.completed(),
+                self.control.failed(),
+                eta
+            ))
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+            status,
+        );
+
+        let samples: Vec<u64> = self.throughput.samples.iter().copied().collect();
+        let width = sparkline.width.saturating_sub(2) as usize;
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!(
+                    "Throughput {}",
+                    units::rate(self.throughput.rate())
+                )))
+                .data(&samples[samples.len().saturating_sub(width)..])
+                .style(Style::default().fg(Color::Cyan)),
+            sparkline,
+        );
+
+        let block = Block::bordered().title("Tasks");
+        let inner = block.inner(tasks);
+        frame.render_widget(block, tasks);
+        // Scroll so the selected task stays in view
+        let rows = inner.height.max(1) as usize;
+        let first = self.selected.saturating_sub(rows - 1);
+        for (row, (index, task)) in running
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(rows)
+            .enumerate()
+        {
+            let ratio = match task.size {
+                Some(size) if size > 0 => (task.received as f64 / size as f64).min(1.0),
+                _ => 0.0,
+            };
+            let mut label = format!(
+                "{} {} of {}",
+                task.output.to_string_lossy(),
+                units::size(task.received),
+                task.size.map_or("?".to_string(), units::size)
+            );
+            if task.attempts > 1 {
+                label += &format!(", retry {}", task.attempts - 1);
+            }
+            let color = match index == self.selected {
+                true => Color::Yellow,
+                false => Color::Green,
+            };
+            let mut area = inner;
+            area.y += row as u16;
+            area.height = 1;
+            frame.render_widget(
+                Gauge::default()
+                    .ratio(ratio)
+                    .label(label)
+                    .gauge_style(Style::default().fg(color).bg(Color::DarkGray)),
+                area,
+            );
+        }
+
+        frame.render_widget(
+            Line::from("p pause/resume  \u{2191}/\u{2193} select  s skip  -/+ jobs  q quit")
+                .style(Style::default().add_modifier(Modifier::DIM)),
+            keys,
+        );
+    }
+
+    /// Act on a key, returning false to quit
+    fn key(self: &mut Self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        let running = self.control.running();
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            // Raw mode turns Ctrl-C into a key rather than a signal
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char('p') if self.control.is_paused() => self.control.resume(),
+            KeyCode::Char('p') => self.control.pause(),
+            KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down => self.selected += 1,
+            KeyCode::Char('s') => {
+                if let Some(task) = running.get(self.selected) {
+                    self.control.skip(&task.id);
+                }
+            }
+            KeyCode::Char('-') => {
+                self.control.set_jobs(self.control.jobs().saturating_sub(1));
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') => {
+                self.control.set_jobs(self.control.jobs() + 1);
+            }
+            _ => {}
+        }
+        self.selected = self.selected.min(running.len().saturating_sub(1));
+        true
+    }
+
+    /// Draw and handle keys until quit or `stop` is set
+    fn run(self: &mut Self, terminal: File, stop: &AtomicBool) -> Result<()> {
+        let mut terminal = Terminal::new(CrosstermBackend::new(terminal))?;
+        let mut sampled = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            if sampled.elapsed() >= SAMPLE {
+                sampled += SAMPLE;
+                self.throughput.sample(self.control.received());
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(Duration::from_millis(200))? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.key(key.code, key.modifiers) {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The terminal in raw mode on the alternate screen, restored when dropped however the dashboard
+/// ends, including when setting it up fails part way
+struct Screen {
+    terminal: File,
+}
+
+impl Screen {
+    fn enter(terminal: File) -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut screen = Self { terminal };
+        execute!(screen.terminal, EnterAlternateScreen, cursor::Hide)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(self.terminal, LeaveAlternateScreen, cursor::Show);
+    }
+}
+
+/// The dashboard's thread, which restores the terminal when it ends
+pub struct Tui {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Tui {
+    /// Show the dashboard of `control` on `terminal`, `remaining` being the bytes the plan has
+    /// left to download if known
+    pub fn start(control: Arc<Control>, terminal: File, remaining: Option<u64>) -> Result<Self> {
+        let screen = Screen::enter(terminal)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = tokio::task::spawn_blocking({
+            let stop = stop.clone();
+            move || {
+                let mut dashboard = Dashboard {
+                    control,
+                    remaining,
+                    throughput: Throughput::default(),
+                    selected: 0,
+                };
+                dashboard.run(screen.terminal.try_clone()?, &stop)
+            }
+        });
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Wait until the dashboard is quit, e.g. in a `select!` with the download
+    pub async fn closed(self: &mut Self) -> Result<()> {
+        match self.thread.as_mut() {
+            Some(thread) => {
+                let closed = thread.await?;
+                self.thread = None;
+                closed
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Close the dashboard and give the terminal back
+    pub async fn stop(mut self: Self) -> Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take() {
+            Some(thread) => thread.await?,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput() {
+        let mut throughput = Throughput::default();
+        assert_eq!(throughput.eta(Some(1000)), None);
+        for received in [100, 300, 600] {
+            throughput.sample(received);
+        }
+        assert_eq!(throughput.samples, [100, 200, 300]);
+        assert_eq!(throughput.rate(), 200.0);
+        assert_eq!(throughput.eta(Some(1000)), Some(Duration::from_secs(2)));
+        assert_eq!(throughput.eta(None), None);
+    }
+}
@@ -0,0 +1,136 @@
+//! Sizes, rates and durations as people read them, e.g. `1.4 GiB`, `3.2 Mbit/s` and `2h 13m`,
+//! for status, estimate and progress output. Rates are shown in bytes or bits per second as set
+//! by `rate_unit` in the config file; link speeds are usually quoted in bits.
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+static RATE_UNIT: RwLock<RateUnit> = RwLock::new(RateUnit::Bytes);
+
+const BINARY_UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+const BIT_UNITS: [&str; 4] = ["bit/s", "kbit/s", "Mbit/s", "Gbit/s"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateUnit {
+    /// KiB/s, MiB/s
+    #[default]
+    Bytes,
+    /// kbit/s, Mbit/s in powers of 1000
+    Bits,
+}
+
+impl FromStr for RateUnit {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "bytes" => Ok(RateUnit::Bytes),
+            "bits" => Ok(RateUnit::Bits),
+            _ => Err(anyhow!(
+                "Unknown rate unit {}, expected bytes or bits",
+                name
+            )),
+        }
+    }
+}
+
+/// Set how rates are shown from now on
+pub fn set_rate_unit(unit: RateUnit) {
+    *RATE_UNIT.write().expect("Rate unit lock poisoned") = unit;
+}
+
+pub fn rate_unit() -> RateUnit {
+    *RATE_UNIT.read().expect("Rate unit lock poisoned")
+}
+
+/// `value` scaled down by `base` until it's below it, with the unit it was scaled to
+fn scaled(value: f64, base: f64, units: &[&'static str]) -> (f64, &'static str) {
+    let mut value = value;
+    let mut unit = 0;
+    while value >= base && unit + 1 < units.len() {
+        value /= base;
+        unit += 1;
+    }
+    (value, units[unit])
+}
+
+/// Bytes as e.g. `512 B`, `12.3 MiB` or `1.40 GiB`
+pub fn size(bytes: u64) -> String {
+    let (value, unit) = scaled(bytes as f64, 1024.0, &BINARY_UNITS);
+    match unit {
+        "B" => format!("{} B", bytes),
+        "KiB" | "MiB" => format!("{:.1} {}", value, unit),
+        _ => format!("{:.2} {}", value, unit),
+    }
+}
+
+/// Bytes per second in the configured `rate_unit`, e.g. `1.5 MiB/s` or `12.6 Mbit/s`
+pub fn rate(bytes_per_sec: f64) -> String {
+    rate_in(bytes_per_sec, rate_unit())
+}
+
+fn rate_in(bytes_per_sec: f64, unit: RateUnit) -> String {
+    let (value, unit) = match unit {
+        RateUnit::Bytes => scaled(bytes_per_sec, 1024.0, &BINARY_UNITS),
+        RateUnit::Bits => scaled(bytes_per_sec * 8.0, 1000.0, &BIT_UNITS),
+    };
+    match unit {
+        "B" => format!("{:.0} B/s", value),
+        "bit/s" => format!("{:.0} {}", value, unit),
+        "KiB" | "MiB" | "GiB" | "TiB" => format!("{:.1} {}/s", value, unit),
+        _ => format!("{:.1} {}", value, unit),
+    }
+}
+
+/// Time to transfer `bytes` at `bytes_per_sec`, `unknown` without a rate to go by
+pub fn eta(bytes: u64, bytes_per_sec: f64) -> String {
+    match bytes_per_sec > 0.0 {
+        true => Duration::try_from_secs_f64(bytes as f64 / bytes_per_sec)
+            .map_or("unknown".to_string(), duration),
+        false => "unknown".to_string(),
+    }
+}
+
+/// A duration in its two largest units, e.g. `45s`, `4m 05s`, `2h 13m` or `3d 4h`
+pub fn duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64().round() as u64;
+    let (days, hours, minutes, seconds) =
+        (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, _) => format!("{}m {:02}s", minutes, seconds),
+        (0, _, _) => format!("{}h {:02}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        assert_eq!(size(512), "512 B");
+        assert_eq!(size(1536), "1.5 KiB");
+        assert_eq!(size(12 * 1024 * 1024 + 300 * 1024), "12.3 MiB");
+        assert_eq!(size(1503238554), "1.40 GiB");
+        assert_eq!(duration(Duration::from_secs(45)), "45s");
+        assert_eq!(duration(Duration::from_secs(245)), "4m 05s");
+        assert_eq!(
+            duration(Duration::from_secs(2 * 3600 + 13 * 60 + 20)),
+            "2h 13m"
+        );
+        assert_eq!(duration(Duration::from_secs(3 * 86400 + 4 * 3600)), "3d 4h");
+        assert_eq!(eta(2048, 1024.0), "2s");
+        assert_eq!(eta(2048, 0.0), "unknown");
+        assert_eq!(eta(2048, f64::NAN), "unknown");
+        assert_eq!(rate_in(1.5 * 1024.0 * 1024.0, RateUnit::Bytes), "1.5 MiB/s");
+        assert_eq!(rate_in(512.0, RateUnit::Bytes), "512 B/s");
+        assert_eq!(rate_in(1_575_000.0, RateUnit::Bits), "12.6 Mbit/s");
+        assert_eq!("bits".parse::<RateUnit>().unwrap(), RateUnit::Bits);
+        assert!("bytes/s".parse::<RateUnit>().is_err());
+    }
+}
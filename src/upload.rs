@@ -0,0 +1,272 @@
+//! Uploading each completed item to the user's own S3 bucket, for field machines that are only a
+//! relay to an institution's storage. Items are uploaded to `<prefix>/<item id>/<file>` once their
+//! files have downloaded and verified, in parts that a later run resumes, and can then be removed
+//! locally.
+use crate::hooks::CompletedItem;
+use crate::inventory;
+use crate::s3;
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Lists the files uploaded (here or with `rclone`) and removed, so resuming the plan doesn't
+/// download them again
+pub const UPLOADED_MARKER: &str = ".slow-stac-uploaded";
+/// Files larger than this are uploaded in parts of this size. S3 allows at most 10,000 parts, so
+/// files up to about 160 GB.
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadOptions {
+    pub bucket: String,
+    /// Prepended to `<item id>/<file>`, without a trailing slash
+    pub prefix: String,
+    /// AWS profile with credentials for the bucket, and its `endpoint_url` for S3-compatible
+    /// storage
+    pub profile: String,
+    /// Remove each file once it has uploaded
+    pub remove_local: bool,
+}
+
+impl UploadOptions {
+    /// Options for a destination such as `s3://bucket/prefix`
+    pub fn new(destination: &str, profile: &str) -> Result<Self> {
+        let path = destination
+            .strip_prefix("s3://")
+            .ok_or(anyhow!("Expected an s3://bucket/prefix destination"))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(anyhow!("No bucket in {}", destination));
+        }
+        Ok(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            profile: profile.to_string(),
+            remove_local: false,
+        })
+    }
+
+    pub fn with_remove_local(self, remove_local: bool) -> Self {
+        Self {
+            remove_local,
+            ..self
+        }
+    }
+
+    pub fn destination(self: &Self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+
+    /// The url a file of an item is uploaded to
+    pub fn url(self: &Self, item_id: &str, file_name: &str) -> String {
+        format!("s3://{}/{}", self.bucket, self.key(item_id, file_name))
+    }
+
+    fn key(self: &Self, item_id: &str, file_name: &str) -> String {
+        match self.prefix.is_empty() {
+            true => format!("{}/{}", item_id, file_name),
+            false => format!("{}/{}/{}", self.prefix, item_id, file_name),
+        }
+    }
+}
+
+/// Whether a task's output is missing because it was uploaded and removed.
+pub fn was_uploaded(output: &Path) -> bool {
+    let (Some(dir), Some(file_name)) = (output.parent(), output.file_name()) else {
+        return false;
+    };
+    match fs::read_to_string(dir.join(UPLOADED_MARKER)) {
+        Ok(removed) => removed
+            .lines()
+            .any(|line| line == file_name.to_string_lossy()),
+        Err(_) => false,
+    }
+}
+
+/// Upload every file in the item's directory, including those written by earlier hooks (COGs,
+/// stacks and quicklooks), removing each once it has uploaded if `remove_local`.
+pub async fn upload_item(item: &CompletedItem, options: &UploadOptions) -> Result<()> {
+    let client = s3::client_from_profile(&options.profile).await;
+    for file_name in item_files(item)? {
+        let path = item.dir.join(&file_name);
+        let key = options.key(&item.item_id, &file_name);
+        upload_file(&client, &path, &options.bucket, &key).await?;
+        println!("Uploaded {:?} to s3://{}/{}", path, options.bucket, key);
+        if options.remove_local {
+            remove_uploaded(&item.dir, &file_name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Names of the files in an item's directory that belong in an upload, sorted
+pub(crate) fn item_files(item: &CompletedItem) -> Result<Vec<String>> {
+    let mut files: Vec<_> = fs::read_dir(&item.dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|file_name| inventory::is_archived(file_name))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Remove an uploaded file, recording it first since that's what stops it downloading again
+pub(crate) fn remove_uploaded(dir: &Path, file_name: &str) -> Result<()> {
+    record_uploaded(dir, file_name)?;
+    fs::remove_file(dir.join(file_name))?;
+    Ok(())
+}
+
+/// Record a file of the item in `dir` as uploaded and removed
+pub(crate) fn record_uploaded(dir: &Path, file_name: &str) -> Result<()> {
+    let marker = dir.join(UPLOADED_MARKER);
+    let mut lines = fs::read_to_string(&marker).unwrap_or_default();
+    lines.push_str(&format!("{}\n", file_name));
+    fs::write(marker, lines)?;
+    Ok(())
+}
+
+/// Upload a file, skipping it if an object of the same size is already there and resuming a
+/// multipart upload an earlier run left unfinished.
+pub async fn upload_file(client: &Client, path: &Path, bucket: &str, key: &str) -> Result<()> {
+    let size = fs::metadata(path)?.len();
+    if let Ok(head) = client.head_object().bucket(bucket).key(key).send().await {
+        if head.content_length() == Some(size as i64) {
+            return Ok(());
+        }
+    }
+    if size <= PART_SIZE {
+        client
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .body(ByteStream::from_path(path).await?)
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let unfinished = client
+        .list_multipart_uploads()
+        .bucket(bucket)
+        .prefix(key)
+        .send()
+        .await?;
+    let upload_id = unfinished
+        .uploads()
+        .iter()
+        .filter(|upload| upload.key() == Some(key))
+        .find_map(|upload| upload.upload_id());
+    let (upload_id, uploaded) = match upload_id {
+        Some(upload_id) => {
+            let parts = client
+                .list_parts()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await?;
+            let uploaded: HashMap<i32, (i64, String)> = parts
+                .parts()
+                .iter()
+                .filter_map(|part| {
+                    let e_tag = part.e_tag()?.to_string();
+                    Some((part.part_number()?, (part.size()?, e_tag)))
+                })
+                .collect();
+            println!(
+                "Resuming the upload of {} ({} parts done)",
+                key,
+                uploaded.len()
+            );
+            (upload_id.to_string(), uploaded)
+        }
+        None => {
+            let created = client
+                .create_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .send()
+                .await?;
+            let upload_id = created
+                .upload_id()
+                .ok_or(anyhow!("No upload id for {}", key))?;
+            (upload_id.to_string(), HashMap::new())
+        }
+    };
+
+    let mut completed = vec![];
+    for (index, offset) in (0..size).step_by(PART_SIZE as usize).enumerate() {
+        let part_number = index as i32 + 1;
+        let length = PART_SIZE.min(size - offset);
+        let e_tag = match uploaded.get(&part_number) {
+            Some((part_size, e_tag)) if *part_size == length as i64 => e_tag.clone(),
+            _ => {
+                let body = ByteStream::read_from()
+                    .path(path)
+                    .offset(offset)
+                    .length(Length::Exact(length))
+                    .build()
+                    .await?;
+                let part = client
+                    .upload_part()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .part_number(part_number)
+                    .body(body)
+                    .send()
+                    .await?;
+                part.e_tag()
+                    .ok_or(anyhow!("No ETag for part {} of {}", part_number, key))?
+                    .to_string()
+            }
+        };
+        completed.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .e_tag(e_tag)
+                .build(),
+        );
+    }
+    client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(&upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(completed))
+                .build(),
+        )
+        .send()
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_options() {
+        let options = UploadOptions::new("s3://archive/sentinel-2/", "default").unwrap();
+        assert_eq!(options.bucket, "archive");
+        assert_eq!(options.key("S2A", "B04.tif"), "sentinel-2/S2A/B04.tif");
+        let options = UploadOptions::new("s3://archive", "default").unwrap();
+        assert_eq!(options.key("S2A", "B04.tif"), "S2A/B04.tif");
+        assert!(UploadOptions::new("archive/sentinel-2", "default").is_err());
+
+        let dir = Path::new("/tmp/slow_stac_upload/S2A");
+        let _ = fs::remove_dir_all("/tmp/slow_stac_upload");
+        fs::create_dir_all(dir).unwrap();
+        assert!(!was_uploaded(&dir.join("B04.tif")));
+        fs::write(dir.join(UPLOADED_MARKER), "B04.tif\n").unwrap();
+        assert!(was_uploaded(&dir.join("B04.tif")));
+    }
+}
@@ -0,0 +1,73 @@
+//! The user agent sent with every request, so catalogue and bucket operators can identify traffic
+//! from slow-stac (and from the application embedding it) when debugging server-side.
+use anyhow::{anyhow, Result};
+use reqwest::header::HeaderValue;
+use reqwest::{Client, ClientBuilder, IntoUrl, Response};
+use std::sync::RwLock;
+
+static USER_AGENT: RwLock<Option<String>> = RwLock::new(None);
+
+/// The default user agent, e.g. `slow-stac/0.1.0`
+pub fn default_user_agent() -> String {
+    format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// Replace the user agent, e.g. with `my-app/2.1 slow-stac/0.1.0`. Applies to clients created
+/// afterwards, so set it before creating providers or preparing plans. Fails for a user agent
+/// that isn't a valid header value, which clients would otherwise fail to build with.
+pub fn set_user_agent(user_agent: &str) -> Result<()> {
+    HeaderValue::from_str(user_agent)
+        .map_err(|_| anyhow!("Invalid user agent {:?}", user_agent))?;
+    *USER_AGENT.write().expect("User agent lock poisoned") = Some(user_agent.to_string());
+    Ok(())
+}
+
+pub fn user_agent() -> String {
+    USER_AGENT
+        .read()
+        .expect("User agent lock poisoned")
+        .clone()
+        .unwrap_or_else(default_user_agent)
+}
+
+/// The user agent reduced to the characters the AWS SDK accepts in an app name, which it appends
+/// to its own user agent.
+pub(crate) fn aws_app_name() -> String {
+    user_agent()
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c) => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+pub(crate) fn client_builder() -> ClientBuilder {
+    Client::builder().user_agent(user_agent())
+}
+
+pub(crate) fn client() -> Client {
+    client_builder()
+        .build()
+        .expect("Client configuration should always be valid")
+}
+
+/// Drop-in replacement for `reqwest::get` that sends the user agent.
+pub(crate) async fn get<U: IntoUrl>(url: U) -> reqwest::Result<Response> {
+    client().get(url).send().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aws_app_name() {
+        set_user_agent("my app/1.0 (slow-stac)").unwrap();
+        assert_eq!(aws_app_name(), "my_app_1.0__slow-stac_");
+        assert!(set_user_agent("my app\n").is_err());
+        assert_eq!(aws_app_name(), "my_app_1.0__slow-stac_");
+        set_user_agent(&default_user_agent()).unwrap();
+        assert!(user_agent().starts_with("slow-stac/"));
+    }
+}
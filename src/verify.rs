@@ -0,0 +1,175 @@
+//! Integrity checks of downloaded files, from strongest to weakest: a checksum published by the
+//! catalogue, the MD5 that S3 reports as the ETag of objects uploaded in one part, and the size.
+use anyhow::{anyhow, Result};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use thiserror::Error;
+
+/// A downloaded file that did not match its checksum, ETag or size however often it was fetched
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct VerificationFailed(pub String);
+
+/// How thoroughly a downloaded file was checked
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Verification {
+    /// Matched the checksum published by the catalogue
+    Checksum,
+    /// Matched the MD5 ETag of a single-part object (weak: the ETag is reported by the server
+    /// that served the file)
+    ETag,
+    /// Only the size could be compared
+    Size,
+    /// Nothing was published to check against
+    None,
+}
+
+/// Checksum as `<algorithm>:<hex digest>` from a STAC `file:checksum` multihash, e.g.
+/// `1220<sha256>` becomes `sha2-256:<sha256>`. Unsupported hash functions are ignored.
+pub fn from_multihash(multihash: &str) -> Option<String> {
+    let multihash = multihash.to_lowercase();
+    if let Some(digest) = multihash.strip_prefix("1220") {
+        return Some(format!("sha2-256:{}", digest));
+    }
+    if let Some(digest) = multihash.strip_prefix("d50110") {
+        return Some(format!("md5:{}", digest));
+    }
+    None
+}
+
+/// The MD5 digest in an S3 ETag, for providers whose ETags are MD5s (see `S3ObjOps::md5_etags`).
+/// ETags of multipart uploads (`<hex>-<parts>`) are not digests of the content.
+pub fn etag_md5(e_tag: &str) -> Option<String> {
+    let e_tag = e_tag.trim_matches('"').to_lowercase();
+    match e_tag.len() == 32 && e_tag.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Some(e_tag),
+        false => None,
+    }
+}
+
+/// Whether `file_digest` can compute the algorithm of a checksum in the `<algorithm>:<hex digest>`
+/// form. Files whose checksums use another one are checked as if no checksum was published.
+pub fn is_supported(checksum: &str) -> bool {
+    checksum
+        .split_once(':')
+        .is_some_and(|(algorithm, _)| StreamDigest::new(algorithm).is_some())
+}
+
+/// Compare a file with a checksum in the `<algorithm>:<hex digest>` form.
+pub fn check_checksum(path: &Path, checksum: &str) -> Result<()> {
+    let (algorithm, expected) = checksum
+        .split_once(':')
+        .ok_or(anyhow!("Malformed checksum: {}", checksum))?;
+    let actual = file_digest(path, algorithm)?;
+    if actual != expected.to_lowercase() {
+        return Err(anyhow!(
+            "{:?} has {} {} but {} was expected",
+            path,
+            algorithm,
+            actual,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+pub fn file_digest(path: &Path, algorithm: &str) -> Result<String> {
+    match algorithm.to_lowercase().as_str() {
+        "md5" => digest_file::<Md5>(path),
+        "sha2-256" | "sha256" => digest_file::<Sha256>(path),
+        _ => Err(anyhow!("Unsupported checksum algorithm: {}", algorithm)),
+    }
+}
+
+/// Digest of content seen a piece at a time, e.g. streamed without being written to a file
+pub enum StreamDigest {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl StreamDigest {
+    /// None for algorithms `file_digest` doesn't support either
+    pub fn new(algorithm: &str) -> Option<Self> {
+        match algorithm.to_lowercase().as_str() {
+            "md5" => Some(StreamDigest::Md5(Md5::new())),
+            "sha2-256" | "sha256" => Some(StreamDigest::Sha256(Sha256::new())),
+            _ => None,
+        }
+    }
+
+    pub fn update(self: &mut Self, data: &[u8]) {
+        match self {
+            StreamDigest::Md5(hasher) => hasher.update(data),
+            StreamDigest::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn hex(self) -> String {
+        let digest = match self {
+            StreamDigest::Md5(hasher) => hasher.finalize().to_vec(),
+            StreamDigest::Sha256(hasher) => hasher.finalize().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+fn digest_file<D: Digest>(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_multihash() {
+        assert_eq!(from_multihash("1220ABCD").as_deref(), Some("sha2-256:abcd"));
+        assert_eq!(from_multihash("d50110abcd").as_deref(), Some("md5:abcd"));
+        assert_eq!(from_multihash("1114abcd"), None);
+    }
+
+    #[test]
+    fn test_etag_md5() {
+        let md5 = "\"781e5e245d69b566979b86e28d23f2c7\"";
+        assert_eq!(
+            etag_md5(md5).as_deref(),
+            Some("781e5e245d69b566979b86e28d23f2c7")
+        );
+        assert_eq!(etag_md5("\"781e5e245d69b566979b86e28d23f2c7-4\""), None);
+    }
+
+    #[test]
+    fn test_check_checksum() {
+        let path = Path::new("/tmp/slow_stac_checksum.txt");
+        fs::write(path, "0123456789").unwrap();
+        assert!(check_checksum(path, "md5:781e5e245d69b566979b86e28d23f2c7").is_ok());
+        assert!(check_checksum(
+            path,
+            "sha2-256:84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882"
+        )
+        .is_ok());
+        assert!(check_checksum(path, "md5:00000000000000000000000000000000").is_err());
+        assert!(is_supported("SHA256:84d8"));
+        assert!(!is_supported("blake3:84d8"));
+        assert!(!is_supported("84d8"));
+    }
+}
@@ -0,0 +1,160 @@
+//! Watching an image selection for edits with inotify, so a service downloading its plan can
+//! prepare it again when ids are added or another band is enabled, without being restarted.
+//!
+//! The selection's directory is watched rather than the file, since editors often save by
+//! writing a new file and renaming it over the old one.
+use anyhow::{anyhow, Result};
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+
+/// Time without further events before an edit is taken as saved, since editors write a file in
+/// several steps
+const SETTLE: Duration = Duration::from_secs(2);
+const EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+
+pub struct SelectionWatcher {
+    fd: AsyncFd<OwnedFd>,
+    path: PathBuf,
+    file_name: OsString,
+    /// Content when last changed, so saving the file unchanged isn't taken as an edit
+    content: Vec<u8>,
+    settle: Duration,
+}
+
+impl SelectionWatcher {
+    pub fn new(path: &Path) -> Result<Self> {
+        let path = fs::canonicalize(path)?;
+        let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+            return Err(anyhow!("Cannot watch {:?}", path));
+        };
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let dir_name = CString::new(dir.as_os_str().as_bytes())?;
+        if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir_name.as_ptr(), EVENTS) } < 0 {
+            return Err(anyhow!(
+                "Could not watch {:?}: {}",
+                dir,
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            file_name: file_name.to_os_string(),
+            content: fs::read(&path)?,
+            path,
+            settle: SETTLE,
+        })
+    }
+
+    /// Names of the files in the directory that events were read for
+    async fn events(self: &Self) -> Result<Vec<OsString>> {
+        let mut buffer = [0u8; 4096];
+        let read = loop {
+            let mut guard = self.fd.readable().await?;
+            let read = guard.try_io(|fd| {
+                let read = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len(),
+                    )
+                };
+                match read {
+                    read if read < 0 => Err(std::io::Error::last_os_error()),
+                    read => Ok(read as usize),
+                }
+            });
+            if let Ok(read) = read {
+                break read?;
+            }
+        };
+        // Each event is an inotify_event followed by its NUL padded name
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut names = vec![];
+        let mut offset = 0;
+        while offset + header <= read {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const _) };
+            let name = &buffer[offset + header..offset + header + event.len as usize];
+            let name = name.split(|byte| *byte == 0).next().unwrap_or_default();
+            names.push(std::ffi::OsStr::from_bytes(name).to_os_string());
+            offset += header + event.len as usize;
+        }
+        Ok(names)
+    }
+
+    /// Wait until the selection has been saved with different content. Cancelling it, e.g. in a
+    /// `select!` with the download, loses nothing: an edit whose events a cancelled call read is
+    /// found by comparing the content at the start of the next.
+    pub async fn changed(self: &mut Self) -> Result<()> {
+        let mut pending = fs::read(&self.path).is_ok_and(|content| content != self.content);
+        loop {
+            if !pending {
+                while !self.events().await?.contains(&self.file_name) {}
+            }
+            pending = false;
+            while let Ok(events) = tokio::time::timeout(self.settle, self.events()).await {
+                events?;
+            }
+            // Missing while an editor replaces it, the next event is for the new file
+            let Ok(content) = fs::read(&self.path) else {
+                continue;
+            };
+            if content != self.content {
+                self.content = content;
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_changed() {
+        let dir = Path::new("/tmp/slow_stac_watch");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("selection.toml");
+        fs::write(&path, "ids = [\"A\"]").unwrap();
+        let mut watcher = SelectionWatcher::new(&path).unwrap();
+        watcher.settle = Duration::from_millis(100);
+
+        tokio::spawn({
+            let path = path.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                fs::write(dir.join("other.toml"), "").unwrap();
+                // Saved unchanged, then replaced by an edited copy
+                fs::write(&path, "ids = [\"A\"]").unwrap();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                fs::write(dir.join("selection.toml.tmp"), "ids = [\"A\", \"B\"]").unwrap();
+                fs::rename(dir.join("selection.toml.tmp"), &path).unwrap();
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(watcher.content, b"ids = [\"A\", \"B\"]");
+
+        // An edit whose events were read by a call cancelled since
+        fs::write(&path, "ids = [\"C\"]").unwrap();
+        watcher.events().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(watcher.content, b"ids = [\"C\"]");
+    }
+}